@@ -0,0 +1,650 @@
+// Address book of every peer we have come across.
+//
+// Keyed by the peer's stable identity: the hex public key of the static key
+// it keeps on disk (see `noise::Keypair`), advertised as the `id` entry of
+// its discovery info. Display names are only labels, any number of peers
+// may share one. Peers that don't tell us an identity aren't recorded.
+// Besides the names and the last discovery info it keeps first/last seen
+// times, how often connecting worked and a trust level set by the user.
+// Peers the user paired with (see `pairing`) also keep their static key,
+// and the name and node id they paired under. Sightings are unauthenticated,
+// anybody can advertise a paired peer's identity, so they don't rename those.
+// The session consults it before accepting invitations and re-inviting
+// peers: blocked peers are turned away, everything else goes through.
+//
+// Like the outbox it is written to disk on every change and uses wall-clock
+// time, since the whole point is to remember peers across runs.
+//
+// File format, all integers big endian:
+//   [magic "IDPA"][version: u8][count: u32]
+//   count * [identity len: u8][identity][name len: u8][name]
+//           [node len: u8][node][first seen: u64 secs][last seen: u64 secs]
+//           [attempts: u32][successes: u32][trust: u8]
+//           [key len: u8, 0 or 32][key]
+//           [info count: u8] info count * [key len: u8][key][value len: u8][value]
+// Version 1 files have no key.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::discovery_info::DiscoveryInfo;
+use crate::file_format::{Reader, unix_secs, write_atomic};
+use crate::mesh::NodeId;
+use crate::noise::{PublicKey, key_to_hex};
+
+/// Discovery info key carrying a peer's stable identity
+pub const IDENTITY_KEY: &str = "id";
+
+/// Identity of the peer holding `key`
+pub fn identity_of_key(key: &PublicKey) -> String {
+    key_to_hex(key)
+}
+
+const FILE_MAGIC: &[u8; 4] = b"IDPA";
+const FILE_VERSION: u8 = 2;
+
+#[derive(Debug, Clone)]
+pub struct AddressBookConfig {
+    /// Peers not seen for this long are dropped by `prune`, unless the user
+    /// gave them a trust level
+    pub forget_after: Duration,
+    /// Entries kept at most, least recently seen go first
+    pub max_entries: usize,
+}
+
+impl Default for AddressBookConfig {
+    fn default() -> Self {
+        Self {
+            forget_after: Duration::from_secs(30 * 24 * 60 * 60),
+            max_entries: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trust {
+    /// Invitations are declined and the peer is never re-invited
+    Blocked = 0,
+    #[default]
+    Unknown = 1,
+    Known = 2,
+    Trusted = 3,
+}
+
+impl Trust {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Trust::Blocked),
+            1 => Some(Trust::Unknown),
+            2 => Some(Trust::Known),
+            3 => Some(Trust::Trusted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerEntry {
+    pub identity: String,
+    pub display_name: String,
+    pub node_id: NodeId,
+    pub discovery_info: DiscoveryInfo,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    /// Invitations sent or accepted
+    pub attempts: u32,
+    /// Attempts that ended up connected
+    pub successes: u32,
+    pub trust: Trust,
+    /// Static key of the secure channel the user paired over
+    pub paired_key: Option<PublicKey>,
+}
+
+impl PeerEntry {
+    /// Fraction of attempts that connected, `None` before the first one
+    pub fn success_rate(&self) -> Option<f64> {
+        if self.attempts == 0 {
+            return None;
+        }
+        Some(self.successes as f64 / self.attempts as f64)
+    }
+}
+
+#[derive(Debug)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    config: AddressBookConfig,
+    entries: BTreeMap<String, PeerEntry>,
+}
+
+impl AddressBook {
+    /// Address book backed by `path`, loading whatever was stored there
+    pub fn open(path: impl AsRef<Path>, config: AddressBookConfig) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let mut book = Self::in_memory(config);
+        match fs::read(&path) {
+            Ok(bytes) => book.load(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+        book.path = Some(path);
+        Ok(book)
+    }
+
+    /// Address book that forgets everything when dropped
+    pub fn in_memory(config: AddressBookConfig) -> Self {
+        Self {
+            path: None,
+            config,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AddressBookConfig {
+        &self.config
+    }
+
+    /// Record a sighting of the peer with `identity`, adding it if it is new.
+    /// Paired peers keep the names they paired under.
+    pub fn observe(
+        &mut self,
+        identity: &str,
+        display_name: &str,
+        node_id: &str,
+        info: &DiscoveryInfo,
+        now: SystemTime,
+    ) -> Result<(), String> {
+        if identity.is_empty() {
+            return Err("Empty identity".to_string());
+        }
+        for field in [identity, display_name, node_id] {
+            if field.len() > u8::MAX as usize {
+                return Err(format!("Name too long for the address book: {}", field));
+            }
+        }
+
+        let entry = self
+            .entries
+            .entry(identity.to_string())
+            .or_insert_with(|| PeerEntry {
+                identity: identity.to_string(),
+                display_name: display_name.to_string(),
+                node_id: node_id.to_string(),
+                discovery_info: DiscoveryInfo::new(),
+                first_seen: now,
+                last_seen: now,
+                attempts: 0,
+                successes: 0,
+                trust: Trust::default(),
+                paired_key: None,
+            });
+        if entry.paired_key.is_none() {
+            entry.display_name = display_name.to_string();
+            entry.node_id = node_id.to_string();
+            // A plain invitation carries no discovery info, keep what we had
+            if !info.is_empty() {
+                entry.discovery_info = info.clone();
+            }
+        }
+        entry.last_seen = entry.last_seen.max(now);
+        self.save()
+    }
+
+    /// An invitation to or from the peer is under way
+    pub fn record_attempt(&mut self, identity: &str) -> Result<(), String> {
+        let Some(entry) = self.entries.get_mut(identity) else {
+            return Ok(());
+        };
+        entry.attempts = entry.attempts.saturating_add(1);
+        self.save()
+    }
+
+    /// The peer connected
+    pub fn record_connected(&mut self, identity: &str, now: SystemTime) -> Result<(), String> {
+        let Some(entry) = self.entries.get_mut(identity) else {
+            return Ok(());
+        };
+        entry.successes = entry.successes.saturating_add(1);
+        // Connections we didn't see the invitation for still count as one
+        entry.attempts = entry.attempts.max(entry.successes);
+        entry.last_seen = entry.last_seen.max(now);
+        self.save()
+    }
+
+    pub fn set_trust(&mut self, identity: &str, trust: Trust) -> Result<(), String> {
+        let Some(entry) = self.entries.get_mut(identity) else {
+            return Err(format!("Unknown peer {}", identity));
+        };
+        entry.trust = trust;
+        self.save()
+    }
+
+    /// The user paired with the peer: trust it, and only through the key it
+    /// paired with. The names it proved that key under are the ones kept.
+    pub fn record_pairing(
+        &mut self,
+        identity: &str,
+        display_name: &str,
+        node_id: &str,
+        key: PublicKey,
+    ) -> Result<(), String> {
+        let Some(entry) = self.entries.get_mut(identity) else {
+            return Err(format!("Unknown peer {}", identity));
+        };
+        entry.trust = Trust::Trusted;
+        entry.paired_key = Some(key);
+        entry.display_name = display_name.to_string();
+        entry.node_id = node_id.to_string();
+        self.save()
+    }
+
+    /// Display names and keys of trusted peers the user paired with
+    pub fn paired_keys(&self) -> Vec<(&str, PublicKey)> {
+        self.entries
+            .values()
+            .filter(|entry| entry.trust == Trust::Trusted)
+            .filter_map(|entry| Some((entry.display_name.as_str(), entry.paired_key?)))
+            .collect()
+    }
+
+    pub fn get(&self, identity: &str) -> Option<&PeerEntry> {
+        self.entries.get(identity)
+    }
+
+    /// Most recently seen entry with this display name. Names aren't unique,
+    /// this is for showing entries to the user.
+    pub fn find_by_name(&self, display_name: &str) -> Option<&PeerEntry> {
+        self.entries
+            .values()
+            .filter(|entry| entry.display_name == display_name)
+            .max_by_key(|entry| entry.last_seen)
+    }
+
+    pub fn find_by_node(&self, node_id: &str) -> Option<&PeerEntry> {
+        self.entries
+            .values()
+            .filter(|entry| entry.node_id == node_id)
+            .max_by_key(|entry| entry.last_seen)
+    }
+
+    /// Whether an invitation from or to the peer with this identity should
+    /// go ahead. Peers we have never seen are allowed.
+    pub fn allows(&self, identity: &str) -> bool {
+        self.entries
+            .get(identity)
+            .is_none_or(|entry| entry.trust != Trust::Blocked)
+    }
+
+    /// Entries seen at or after `since`, most recent first
+    pub fn seen_since(&self, since: SystemTime) -> Vec<&PeerEntry> {
+        let mut entries: Vec<&PeerEntry> = self
+            .entries
+            .values()
+            .filter(|entry| entry.last_seen >= since)
+            .collect();
+        entries.sort_by_key(|entry| Reverse(entry.last_seen));
+        entries
+    }
+
+    /// Entries trusted at least `trust`
+    pub fn with_trust(&self, trust: Trust) -> Vec<&PeerEntry> {
+        self.entries
+            .values()
+            .filter(|entry| entry.trust >= trust)
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerEntry> {
+        self.entries.values()
+    }
+
+    pub fn remove(&mut self, identity: &str) -> Result<Option<PeerEntry>, String> {
+        let removed = self.entries.remove(identity);
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Drop stale peers and trim to `max_entries`, returning the identities
+    /// removed. Peers with a trust level set are only removed to make room,
+    /// after everyone else.
+    pub fn prune(&mut self, now: SystemTime) -> Result<Vec<String>, String> {
+        let mut removed: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.trust == Trust::Unknown)
+            .filter(|entry| {
+                now.duration_since(entry.last_seen)
+                    .is_ok_and(|age| age > self.config.forget_after)
+            })
+            .map(|entry| entry.identity.clone())
+            .collect();
+        for identity in &removed {
+            self.entries.remove(identity);
+        }
+
+        if self.entries.len() > self.config.max_entries {
+            let mut by_age: Vec<&PeerEntry> = self.entries.values().collect();
+            by_age.sort_by_key(|entry| (entry.trust != Trust::Unknown, entry.last_seen));
+            let excess: Vec<String> = by_age
+                .iter()
+                .take(self.entries.len() - self.config.max_entries)
+                .map(|entry| entry.identity.clone())
+                .collect();
+            for identity in excess {
+                self.entries.remove(&identity);
+                removed.push(identity);
+            }
+        }
+
+        if !removed.is_empty() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => write_atomic(path, &self.encode()),
+            None => Ok(()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(FILE_MAGIC);
+        out.push(FILE_VERSION);
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in self.entries.values() {
+            for field in [&entry.identity, &entry.display_name, &entry.node_id] {
+                out.push(field.len() as u8);
+                out.extend_from_slice(field.as_bytes());
+            }
+            out.extend_from_slice(&unix_secs(entry.first_seen).to_be_bytes());
+            out.extend_from_slice(&unix_secs(entry.last_seen).to_be_bytes());
+            out.extend_from_slice(&entry.attempts.to_be_bytes());
+            out.extend_from_slice(&entry.successes.to_be_bytes());
+            out.push(entry.trust as u8);
+            match &entry.paired_key {
+                Some(key) => {
+                    out.push(key.len() as u8);
+                    out.extend_from_slice(key);
+                }
+                None => out.push(0),
+            }
+            // Entries are at most 255 bytes, so both halves fit a length byte
+            let info: Vec<(&str, &str)> = entry.discovery_info.iter().collect();
+            out.push(info.len() as u8);
+            for (key, value) in info {
+                out.push(key.len() as u8);
+                out.extend_from_slice(key.as_bytes());
+                out.push(value.len() as u8);
+                out.extend_from_slice(value.as_bytes());
+            }
+        }
+        out
+    }
+
+    fn load(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut reader = Reader::new(bytes, "address book file");
+        if reader.take(4)? != FILE_MAGIC {
+            return Err("Not an address book file".to_string());
+        }
+        let version = reader.u8()?;
+        if version != 1 && version != FILE_VERSION {
+            return Err(format!("Unsupported address book version {}", version));
+        }
+        let count = reader.u32()?;
+        for _ in 0..count {
+            let identity = reader.string()?;
+            let display_name = reader.string()?;
+            let node_id = reader.string()?;
+            let first_seen = reader.time()?;
+            let last_seen = reader.time()?;
+            let attempts = reader.u32()?;
+            let successes = reader.u32()?;
+            let trust = reader.u8()?;
+            let trust =
+                Trust::from_u8(trust).ok_or_else(|| format!("Unknown trust level {}", trust))?;
+            let paired_key = match version {
+                1 => None,
+                _ => match reader.u8()? {
+                    0 => None,
+                    32 => {
+                        let mut key = [0u8; 32];
+                        key.copy_from_slice(reader.take(32)?);
+                        Some(key)
+                    }
+                    len => return Err(format!("Bad key length {}", len)),
+                },
+            };
+            let mut discovery_info = DiscoveryInfo::new();
+            for _ in 0..reader.u8()? {
+                let key = reader.string()?;
+                let value = reader.string()?;
+                discovery_info.insert(&key, &value)?;
+            }
+            self.entries.insert(
+                identity.clone(),
+                PeerEntry {
+                    identity,
+                    display_name,
+                    node_id,
+                    discovery_info,
+                    first_seen,
+                    last_seen,
+                    attempts,
+                    successes,
+                    trust,
+                    paired_key,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_format::scratch;
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn info(role: &str) -> DiscoveryInfo {
+        DiscoveryInfo::new().with("role", role).unwrap()
+    }
+
+    #[test]
+    fn sightings_merge_into_one_entry_per_identity() {
+        let mut book = AddressBook::in_memory(AddressBookConfig::default());
+        book.observe("k1", "alice", "alice", &info("a"), at(10))
+            .unwrap();
+        book.observe(
+            "k1",
+            "alice-phone",
+            "alice-phone",
+            &DiscoveryInfo::new(),
+            at(20),
+        )
+        .unwrap();
+        // Out of order sightings don't move last seen back
+        book.observe(
+            "k1",
+            "alice-phone",
+            "alice-phone",
+            &DiscoveryInfo::new(),
+            at(15),
+        )
+        .unwrap();
+
+        let entry = book.get("k1").unwrap();
+        assert_eq!(book.len(), 1);
+        assert_eq!(entry.display_name, "alice-phone");
+        assert_eq!(entry.discovery_info.get("role"), Some("a"));
+        assert_eq!((entry.first_seen, entry.last_seen), (at(10), at(20)));
+    }
+
+    #[test]
+    fn shared_display_names_stay_separate_peers() {
+        let mut book = AddressBook::in_memory(AddressBookConfig::default());
+        book.observe("k1", "phone", "phone", &info("a"), at(10))
+            .unwrap();
+        book.observe("k2", "phone", "phone#2", &info("b"), at(20))
+            .unwrap();
+        book.set_trust("k1", Trust::Blocked).unwrap();
+
+        assert_eq!(book.len(), 2);
+        assert!(!book.allows("k1"));
+        assert!(book.allows("k2"));
+        assert!(book.allows("never-seen"));
+        assert_eq!(book.find_by_name("phone").unwrap().identity, "k2");
+        assert_eq!(book.find_by_node("phone").unwrap().identity, "k1");
+    }
+
+    #[test]
+    fn peers_without_an_identity_are_refused() {
+        let mut book = AddressBook::in_memory(AddressBookConfig::default());
+        assert!(
+            book.observe("", "alice", "alice", &info("a"), at(10))
+                .is_err()
+        );
+        assert!(
+            book.observe("k1", &"x".repeat(256), "alice", &info("a"), at(10))
+                .is_err()
+        );
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn attempts_and_successes_are_counted() {
+        let mut book = AddressBook::in_memory(AddressBookConfig::default());
+        book.observe("k1", "alice", "alice", &info("a"), at(10))
+            .unwrap();
+        assert_eq!(book.get("k1").unwrap().success_rate(), None);
+
+        book.record_attempt("k1").unwrap();
+        book.record_attempt("k1").unwrap();
+        book.record_connected("k1", at(30)).unwrap();
+        let entry = book.get("k1").unwrap();
+        assert_eq!(entry.success_rate(), Some(0.5));
+        assert_eq!(entry.last_seen, at(30));
+
+        // Unknown peers are ignored
+        book.record_attempt("k2").unwrap();
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn pairing_trusts_the_peer_and_keeps_its_key() {
+        let mut book = AddressBook::in_memory(AddressBookConfig::default());
+        assert!(
+            book.record_pairing("k1", "alice", "alice", [7; 32])
+                .is_err()
+        );
+        book.observe("k1", "alice", "alice", &info("a"), at(10))
+            .unwrap();
+        book.observe("k2", "bob", "bob", &info("b"), at(10))
+            .unwrap();
+        book.record_pairing("k1", "alice", "alice", [7; 32])
+            .unwrap();
+
+        assert_eq!(book.get("k1").unwrap().trust, Trust::Trusted);
+        assert_eq!(book.paired_keys(), [("alice", [7; 32])]);
+        assert_eq!(book.with_trust(Trust::Known).len(), 1);
+    }
+
+    #[test]
+    fn sightings_dont_rename_paired_peers() {
+        let mut book = AddressBook::in_memory(AddressBookConfig::default());
+        book.observe("k1", "alice", "alice", &info("a"), at(10))
+            .unwrap();
+        book.record_pairing("k1", "alice", "alice", [7; 32])
+            .unwrap();
+        // Somebody else advertising alice's identity
+        book.observe("k1", "mallory", "mallory", &info("m"), at(20))
+            .unwrap();
+
+        let entry = book.get("k1").unwrap();
+        assert_eq!(
+            (entry.display_name.as_str(), entry.node_id.as_str()),
+            ("alice", "alice")
+        );
+        assert_eq!(entry.discovery_info.get("role"), Some("a"));
+        assert_eq!(entry.last_seen, at(20));
+        assert_eq!(book.paired_keys(), [("alice", [7; 32])]);
+
+        // Pairing again is what moves it
+        book.record_pairing("k1", "alice-laptop", "alice-laptop", [7; 32])
+            .unwrap();
+        assert_eq!(book.paired_keys(), [("alice-laptop", [7; 32])]);
+    }
+
+    #[test]
+    fn stale_and_excess_peers_are_pruned() {
+        let config = AddressBookConfig {
+            forget_after: Duration::from_secs(100),
+            max_entries: 2,
+        };
+        let mut book = AddressBook::in_memory(config);
+        book.observe("old", "old", "old", &info("a"), at(0))
+            .unwrap();
+        book.observe("kept", "kept", "kept", &info("a"), at(0))
+            .unwrap();
+        book.set_trust("kept", Trust::Known).unwrap();
+        book.observe("a", "a", "a", &info("a"), at(150)).unwrap();
+        book.observe("b", "b", "b", &info("a"), at(160)).unwrap();
+
+        let removed = book.prune(at(200)).unwrap();
+        // Stale first, then the oldest untrusted peer to make room
+        assert_eq!(removed, ["old", "a"]);
+        let left: Vec<&str> = book.iter().map(|entry| entry.identity.as_str()).collect();
+        assert_eq!(left, ["b", "kept"]);
+        assert_eq!(book.seen_since(at(100)).len(), 1);
+    }
+
+    #[test]
+    fn entries_survive_a_restart() {
+        let path = scratch("address-book-restart");
+        let mut book = AddressBook::open(&path, AddressBookConfig::default()).unwrap();
+        book.observe("k1", "alice", "alice", &info("a"), at(10))
+            .unwrap();
+        book.record_connected("k1", at(20)).unwrap();
+        book.record_pairing("k1", "alice", "alice", [7; 32])
+            .unwrap();
+        book.observe("k2", "bob", "bob", &info("b"), at(30))
+            .unwrap();
+        let before: Vec<PeerEntry> = book.iter().cloned().collect();
+        drop(book);
+
+        let book = AddressBook::open(&path, AddressBookConfig::default()).unwrap();
+        let after: Vec<PeerEntry> = book.iter().cloned().collect();
+        assert_eq!(after, before);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn foreign_files_are_refused() {
+        let path = scratch("address-book-foreign");
+        fs::write(&path, b"nope").unwrap();
+        assert!(AddressBook::open(&path, AddressBookConfig::default()).is_err());
+        fs::write(&path, b"IDPA\x09\0\0\0\0").unwrap();
+        assert!(AddressBook::open(&path, AddressBookConfig::default()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,485 @@
+// One view of every peer, whichever way it was discovered.
+//
+// MPC browsing, mDNS and static configuration each report found/lost
+// sightings; the aggregator merges them per NodeId and keeps what every
+// source last said about the peer. The preference order decides which
+// source, and so which transport, to use when several can reach a peer.
+// Sources that report addresses are cross-checked: if two of them place the
+// same NodeId at entirely different addresses that is reported as a
+// conflict, since one of them is stale or someone took the name. The same
+// goes for a source re-reporting a peer somewhere else entirely, which is
+// how two devices under one NodeId on the same LAN show up.
+//
+// Pure state machine like the reconnect supervisor, time is passed in.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::discovery_info::DiscoveryInfo;
+use crate::mdns::MdnsEvent;
+use crate::mesh::NodeId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Source {
+    Mpc,
+    Mdns,
+    Static,
+}
+
+/// Found/lost model shared by the discovery backends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    Found {
+        node: NodeId,
+        info: DiscoveryInfo,
+        /// Empty for sources that don't deal in addresses, like MPC
+        addrs: Vec<SocketAddr>,
+    },
+    Lost {
+        node: NodeId,
+    },
+}
+
+impl From<&MdnsEvent> for DiscoveryEvent {
+    fn from(event: &MdnsEvent) -> Self {
+        match event {
+            MdnsEvent::Found { name, info, addrs } => DiscoveryEvent::Found {
+                node: name.clone(),
+                info: info.clone(),
+                addrs: addrs.clone(),
+            },
+            MdnsEvent::Lost { name } => DiscoveryEvent::Lost { node: name.clone() },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AggregatorConfig {
+    /// Sources in the order they should be used, missing ones are never
+    /// preferred
+    pub preference: Vec<Source>,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self {
+            preference: vec![Source::Mpc, Source::Mdns, Source::Static],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sighting {
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub info: DiscoveryInfo,
+    pub addrs: Vec<SocketAddr>,
+    /// What the source reported before the latest sighting
+    pub previous_addrs: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregatedPeer {
+    pub node: NodeId,
+    pub sources: BTreeMap<Source, Sighting>,
+    /// Source to use according to the preference order
+    pub preferred: Option<Source>,
+    /// Two sources, or two sightings from one source, disagree about where
+    /// the peer is
+    pub conflict: bool,
+}
+
+impl AggregatedPeer {
+    /// Discovery info from the preferred source, falling back to any
+    pub fn info(&self) -> Option<&DiscoveryInfo> {
+        self.preferred
+            .and_then(|source| self.sources.get(&source))
+            .or_else(|| self.sources.values().next())
+            .map(|sighting| &sighting.info)
+    }
+
+    /// Every address any source reported
+    pub fn addrs(&self) -> BTreeSet<SocketAddr> {
+        self.sources
+            .values()
+            .flat_map(|sighting| sighting.addrs.iter().copied())
+            .collect()
+    }
+
+    pub fn last_seen(&self) -> Option<Instant> {
+        self.sources
+            .values()
+            .map(|sighting| sighting.last_seen)
+            .max()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AggregatorEvent {
+    /// First source reported the peer, whether or not it is preferred
+    Appeared { node: NodeId, source: Source },
+    /// A better source showed up, or the preferred one lost the peer and
+    /// another preferred source still sees it
+    PreferredChanged { node: NodeId, source: Source },
+    /// No source sees the peer anymore
+    Disappeared { node: NodeId },
+    /// Sources place the peer at addresses that have nothing in common. A
+    /// source that moved the peer is listed with its previous addresses too
+    Conflict {
+        node: NodeId,
+        addrs: Vec<(Source, Vec<SocketAddr>)>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct DiscoveryAggregator {
+    config: AggregatorConfig,
+    peers: BTreeMap<NodeId, AggregatedPeer>,
+    events: Vec<AggregatorEvent>,
+}
+
+impl DiscoveryAggregator {
+    pub fn new(config: AggregatorConfig) -> Self {
+        Self {
+            config,
+            peers: BTreeMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn on_event(&mut self, source: Source, event: &DiscoveryEvent, now: Instant) {
+        match event {
+            DiscoveryEvent::Found { node, info, addrs } => {
+                self.on_found(source, node, info, addrs, now)
+            }
+            DiscoveryEvent::Lost { node } => self.on_lost(source, node),
+        }
+    }
+
+    pub fn on_found(
+        &mut self,
+        source: Source,
+        node: &str,
+        info: &DiscoveryInfo,
+        addrs: &[SocketAddr],
+        now: Instant,
+    ) {
+        let appeared = !self.peers.contains_key(node);
+        let peer = self
+            .peers
+            .entry(node.to_string())
+            .or_insert_with(|| AggregatedPeer {
+                node: node.to_string(),
+                sources: BTreeMap::new(),
+                preferred: None,
+                conflict: false,
+            });
+        let sighting = peer.sources.entry(source).or_insert_with(|| Sighting {
+            first_seen: now,
+            last_seen: now,
+            info: DiscoveryInfo::new(),
+            addrs: addrs.to_vec(),
+            previous_addrs: Vec::new(),
+        });
+        sighting.last_seen = now;
+        sighting.info = info.clone();
+        sighting.previous_addrs = std::mem::replace(&mut sighting.addrs, addrs.to_vec());
+
+        if appeared {
+            peer.preferred = preferred_of(&self.config.preference, peer);
+            self.events.push(AggregatorEvent::Appeared {
+                node: node.to_string(),
+                source,
+            });
+        }
+        self.update(node);
+    }
+
+    pub fn on_lost(&mut self, source: Source, node: &str) {
+        let Some(peer) = self.peers.get_mut(node) else {
+            return;
+        };
+        if peer.sources.remove(&source).is_none() {
+            return;
+        }
+        if peer.sources.is_empty() {
+            self.peers.remove(node);
+            self.events.push(AggregatorEvent::Disappeared {
+                node: node.to_string(),
+            });
+            return;
+        }
+        self.update(node);
+    }
+
+    /// Peers from configuration, reported as permanently found
+    pub fn add_static(&mut self, node: &str, addrs: &[SocketAddr], now: Instant) {
+        self.on_found(Source::Static, node, &DiscoveryInfo::new(), addrs, now);
+    }
+
+    pub fn remove_static(&mut self, node: &str) {
+        self.on_lost(Source::Static, node);
+    }
+
+    pub fn get(&self, node: &str) -> Option<&AggregatedPeer> {
+        self.peers.get(node)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &AggregatedPeer> {
+        self.peers.values()
+    }
+
+    /// Source to reach `node` through, if anything sees it
+    pub fn preferred(&self, node: &str) -> Option<Source> {
+        self.peers.get(node).and_then(|peer| peer.preferred)
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &AggregatedPeer> {
+        self.peers.values().filter(|peer| peer.conflict)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn drain_events(&mut self) -> Vec<AggregatorEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Recompute the preferred source and the conflict flag after a change
+    fn update(&mut self, node: &str) {
+        let Some(peer) = self.peers.get_mut(node) else {
+            return;
+        };
+
+        let preferred = preferred_of(&self.config.preference, peer);
+        if preferred != peer.preferred {
+            if let Some(source) = preferred {
+                self.events.push(AggregatorEvent::PreferredChanged {
+                    node: node.to_string(),
+                    source,
+                });
+            }
+            peer.preferred = preferred;
+        }
+
+        let with_addrs: Vec<(Source, Vec<SocketAddr>)> = peer
+            .sources
+            .iter()
+            .filter(|(_, sighting)| !sighting.addrs.is_empty())
+            .map(|(source, sighting)| (*source, sighting.addrs.clone()))
+            .collect();
+        let moved: Vec<(Source, Vec<SocketAddr>)> = peer
+            .sources
+            .iter()
+            .filter(|(_, sighting)| {
+                !sighting.previous_addrs.is_empty()
+                    && disjoint(&sighting.previous_addrs, &sighting.addrs)
+            })
+            .map(|(source, sighting)| (*source, sighting.previous_addrs.clone()))
+            .collect();
+        let conflict = !moved.is_empty()
+            || with_addrs
+                .iter()
+                .enumerate()
+                .any(|(i, (_, a))| with_addrs[i + 1..].iter().any(|(_, b)| disjoint(a, b)));
+        if conflict && !peer.conflict {
+            self.events.push(AggregatorEvent::Conflict {
+                node: node.to_string(),
+                addrs: moved.into_iter().chain(with_addrs).collect(),
+            });
+        }
+        peer.conflict = conflict;
+    }
+}
+
+fn disjoint(a: &[SocketAddr], b: &[SocketAddr]) -> bool {
+    !a.is_empty() && !b.is_empty() && !a.iter().any(|addr| b.contains(addr))
+}
+
+fn preferred_of(preference: &[Source], peer: &AggregatedPeer) -> Option<Source> {
+    preference
+        .iter()
+        .copied()
+        .find(|source| peer.sources.contains_key(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn info(role: &str) -> DiscoveryInfo {
+        DiscoveryInfo::new().with("role", role).unwrap()
+    }
+
+    #[test]
+    fn sources_merge_into_one_peer_by_preference() {
+        let now = Instant::now();
+        let mut aggregator = DiscoveryAggregator::default();
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[], now);
+        aggregator.on_found(Source::Mpc, "b", &info("mpc"), &[], now);
+
+        assert_eq!(aggregator.len(), 1);
+        assert_eq!(aggregator.preferred("b"), Some(Source::Mpc));
+        assert_eq!(aggregator.get("b").unwrap().info(), Some(&info("mpc")));
+        assert_eq!(
+            aggregator.drain_events(),
+            [
+                AggregatorEvent::Appeared {
+                    node: "b".to_string(),
+                    source: Source::Mdns
+                },
+                AggregatorEvent::PreferredChanged {
+                    node: "b".to_string(),
+                    source: Source::Mpc
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn peers_disappear_once_every_source_lost_them() {
+        let now = Instant::now();
+        let mut aggregator = DiscoveryAggregator::default();
+        aggregator.on_found(Source::Mpc, "b", &info("mpc"), &[], now);
+        aggregator.add_static("b", &[addr("10.0.0.1:1")], now);
+        aggregator.drain_events();
+
+        aggregator.on_lost(Source::Mpc, "b");
+        aggregator.on_lost(Source::Mdns, "b");
+        assert_eq!(aggregator.preferred("b"), Some(Source::Static));
+        aggregator.remove_static("b");
+        assert!(aggregator.is_empty());
+        assert_eq!(
+            aggregator.drain_events(),
+            [
+                AggregatorEvent::PreferredChanged {
+                    node: "b".to_string(),
+                    source: Source::Static
+                },
+                AggregatorEvent::Disappeared {
+                    node: "b".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn disjoint_addresses_are_a_conflict() {
+        let now = Instant::now();
+        let mut aggregator = DiscoveryAggregator::default();
+        aggregator.add_static("b", &[addr("10.0.0.1:1"), addr("10.0.0.2:1")], now);
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[addr("10.0.0.2:1")], now);
+        assert_eq!(aggregator.conflicts().count(), 0);
+
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[addr("10.9.9.9:1")], now);
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[addr("10.9.9.9:1")], now);
+        let conflicts: Vec<AggregatorEvent> = aggregator
+            .drain_events()
+            .into_iter()
+            .filter(|event| matches!(event, AggregatorEvent::Conflict { .. }))
+            .collect();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(aggregator.get("b").unwrap().addrs().len(), 3);
+
+        aggregator.on_lost(Source::Mdns, "b");
+        assert_eq!(aggregator.conflicts().count(), 0);
+    }
+
+    #[test]
+    fn a_source_moving_a_peer_elsewhere_is_a_conflict() {
+        let now = Instant::now();
+        let mut aggregator = DiscoveryAggregator::default();
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[addr("10.0.0.1:1")], now);
+        aggregator.on_found(
+            Source::Mdns,
+            "b",
+            &info("mdns"),
+            &[addr("10.0.0.1:1"), addr("10.0.0.2:1")],
+            now,
+        );
+        assert_eq!(aggregator.conflicts().count(), 0);
+
+        aggregator.drain_events();
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[addr("10.0.0.9:1")], now);
+        assert!(aggregator.get("b").unwrap().conflict);
+        let conflicts: Vec<AggregatorEvent> = aggregator
+            .drain_events()
+            .into_iter()
+            .filter(|event| matches!(event, AggregatorEvent::Conflict { .. }))
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![AggregatorEvent::Conflict {
+                node: "b".to_string(),
+                addrs: vec![
+                    (Source::Mdns, vec![addr("10.0.0.1:1"), addr("10.0.0.2:1")]),
+                    (Source::Mdns, vec![addr("10.0.0.9:1")]),
+                ],
+            }]
+        );
+
+        // Settles once the source keeps reporting the same place
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[addr("10.0.0.9:1")], now);
+        assert_eq!(aggregator.conflicts().count(), 0);
+    }
+
+    #[test]
+    fn sightings_keep_when_they_were_first_and_last_seen() {
+        let now = Instant::now();
+        let later = now + std::time::Duration::from_secs(5);
+        let mut aggregator = DiscoveryAggregator::default();
+        let found = DiscoveryEvent::Found {
+            node: "b".to_string(),
+            info: info("mdns"),
+            addrs: Vec::new(),
+        };
+        aggregator.on_event(Source::Mdns, &found, now);
+        aggregator.on_event(Source::Mdns, &found, later);
+
+        let peer = aggregator.get("b").unwrap();
+        let sighting = &peer.sources[&Source::Mdns];
+        assert_eq!((sighting.first_seen, sighting.last_seen), (now, later));
+        assert_eq!(peer.last_seen(), Some(later));
+    }
+
+    #[test]
+    fn sources_left_out_of_the_preference_are_never_preferred() {
+        let now = Instant::now();
+        let mut aggregator = DiscoveryAggregator::new(AggregatorConfig {
+            preference: vec![Source::Mdns],
+        });
+        aggregator.on_found(Source::Mpc, "b", &info("mpc"), &[], now);
+        assert_eq!(aggregator.preferred("b"), None);
+        assert_eq!(aggregator.get("b").unwrap().info(), Some(&info("mpc")));
+
+        aggregator.on_found(Source::Mdns, "b", &info("mdns"), &[], now);
+        aggregator.on_lost(Source::Mdns, "b");
+        aggregator.on_lost(Source::Mpc, "b");
+        assert_eq!(
+            aggregator.drain_events(),
+            vec![
+                AggregatorEvent::Appeared {
+                    node: "b".to_string(),
+                    source: Source::Mpc,
+                },
+                AggregatorEvent::PreferredChanged {
+                    node: "b".to_string(),
+                    source: Source::Mdns,
+                },
+                AggregatorEvent::Disappeared {
+                    node: "b".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// Data path abstraction shared by MPC and the non-Apple transports.
+//
+// A backend connects us to peers by name and carries frames to them, so the
+// same application protocol runs over MPC, plain TCP or anything else.
+// Events come in through a callback handed to the backend when it is
+// created; `Data` frames are unwrapped, every other kind is passed on as is
+// for the layers that understand it.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::frame::Frame;
+use crate::mesh::NodeId;
+
+/// Byte stream to a peer, the equivalent of an MPC stream. Other backends
+/// carry data both ways, MPC streams only from the side that opened them.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+pub enum BackendEvent {
+    /// A peer connected
+    Joined { peer: NodeId },
+    /// A peer disconnected or said goodbye
+    Left { peer: NodeId },
+    /// Application payload from a `Data` frame
+    Data { peer: NodeId, data: Vec<u8> },
+    /// Any other frame, for the layers built on top
+    Frame { peer: NodeId, frame: Frame },
+    /// The peer opened a stream to us
+    Stream {
+        peer: NodeId,
+        name: String,
+        stream: Box<dyn Stream>,
+    },
+}
+
+// Manual Debug implementation since streams aren't Debug
+impl fmt::Debug for BackendEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendEvent::Joined { peer } => f.debug_struct("Joined").field("peer", peer).finish(),
+            BackendEvent::Left { peer } => f.debug_struct("Left").field("peer", peer).finish(),
+            BackendEvent::Data { peer, data } => f
+                .debug_struct("Data")
+                .field("peer", peer)
+                .field("len", &data.len())
+                .finish(),
+            BackendEvent::Frame { peer, frame } => f
+                .debug_struct("Frame")
+                .field("peer", peer)
+                .field("frame", frame)
+                .finish(),
+            BackendEvent::Stream { peer, name, .. } => f
+                .debug_struct("Stream")
+                .field("peer", peer)
+                .field("name", name)
+                .finish(),
+        }
+    }
+}
+
+pub trait Backend {
+    /// Name the other peers know us by
+    fn local_name(&self) -> String;
+
+    fn connected_peers(&self) -> Vec<NodeId>;
+
+    /// Send `frame` to each of `peers`. Backends without an unreliable mode
+    /// treat `reliably` as a hint.
+    fn send_frame(&self, frame: &Frame, peers: &[NodeId], reliably: bool) -> Result<(), String>;
+
+    /// Send application data, wrapped in a `Data` frame
+    fn send(&self, data: &[u8], peers: &[NodeId], reliably: bool) -> Result<(), String> {
+        self.send_frame(&Frame::data(data), peers, reliably)
+    }
+
+    /// Open a named byte stream to a connected peer
+    fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String>;
+
+    /// `frame` compressed the way the backend would compress it for `peer`,
+    /// for layers that encrypt frames before handing them over and so have
+    /// to compress first
+    fn compress_for(&self, _peer: &str, frame: &Frame) -> Frame {
+        frame.clone()
+    }
+
+    /// Undo `compress_for` on a frame such a layer decrypted
+    fn decompress(&self, frame: Frame) -> Result<Frame, String> {
+        Ok(frame)
+    }
+}
+
+// Lets a borrowed backend stand in wherever an owned one is expected
+impl<B: Backend + ?Sized> Backend for &B {
+    fn local_name(&self) -> String {
+        (**self).local_name()
+    }
+
+    fn connected_peers(&self) -> Vec<NodeId> {
+        (**self).connected_peers()
+    }
+
+    fn send_frame(&self, frame: &Frame, peers: &[NodeId], reliably: bool) -> Result<(), String> {
+        (**self).send_frame(frame, peers, reliably)
+    }
+
+    fn send(&self, data: &[u8], peers: &[NodeId], reliably: bool) -> Result<(), String> {
+        (**self).send(data, peers, reliably)
+    }
+
+    fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String> {
+        (**self).open_stream(peer, name)
+    }
+
+    fn compress_for(&self, peer: &str, frame: &Frame) -> Frame {
+        (**self).compress_for(peer, frame)
+    }
+
+    fn decompress(&self, frame: Frame) -> Result<Frame, String> {
+        (**self).decompress(frame)
+    }
+}
+
+// Checks every backend has to pass, run from each backend's own tests on
+// nodes it set up
+#[cfg(test)]
+pub(crate) mod conformance {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::frame::FrameKind;
+
+    pub(crate) const WAIT: Duration = Duration::from_secs(5);
+
+    pub(crate) struct Node<B> {
+        pub backend: B,
+        pub events: mpsc::Receiver<BackendEvent>,
+    }
+
+    /// Callback to create a backend with, and where its events end up
+    pub(crate) fn events() -> (
+        impl Fn(BackendEvent) + Send + Sync + 'static,
+        mpsc::Receiver<BackendEvent>,
+    ) {
+        let (tx, rx) = mpsc::channel();
+        let on_event = move |event| {
+            let _ = tx.send(event);
+        };
+        (on_event, rx)
+    }
+
+    impl<B: Backend> Node<B> {
+        // Skips events until one `f` picks
+        pub(crate) fn expect<T>(&self, mut f: impl FnMut(BackendEvent) -> Option<T>) -> T {
+            let deadline = Instant::now() + WAIT;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                let event = self.events.recv_timeout(left).expect("timed out");
+                if let Some(found) = f(event) {
+                    return found;
+                }
+            }
+        }
+
+        pub(crate) fn expect_joined(&self, peer: &str) {
+            self.expect(|event| match event {
+                BackendEvent::Joined { peer: joined } if joined == peer => Some(()),
+                _ => None,
+            })
+        }
+
+        pub(crate) fn expect_left(&self, peer: &str) {
+            self.expect(|event| match event {
+                BackendEvent::Left { peer: left } if left == peer => Some(()),
+                _ => None,
+            })
+        }
+    }
+
+    /// `a` and `b` are connected to each other
+    pub(crate) fn frames_round_trip<B: Backend>(a: &Node<B>, b: &Node<B>) {
+        let (a_name, b_name) = (a.backend.local_name(), b.backend.local_name());
+        assert_eq!(a.backend.connected_peers(), vec![b_name.clone()]);
+
+        a.backend
+            .send(b"hello", std::slice::from_ref(&b_name), true)
+            .unwrap();
+        let data = b.expect(|event| match event {
+            BackendEvent::Data { peer, data } if peer == a_name => Some(data),
+            _ => None,
+        });
+        assert_eq!(data, b"hello");
+
+        let frame = Frame::new(FrameKind::Relay, vec![1, 2, 3]);
+        b.backend
+            .send_frame(&frame, std::slice::from_ref(&a_name), true)
+            .unwrap();
+        let got = a.expect(|event| match event {
+            BackendEvent::Frame { peer, frame } if peer == b_name => Some(frame),
+            _ => None,
+        });
+        assert_eq!(got, frame);
+    }
+
+    /// `a` and `b` are connected to each other
+    pub(crate) fn streams_round_trip<B: Backend>(a: &Node<B>, b: &Node<B>) {
+        let a_name = a.backend.local_name();
+        let mut outgoing = a
+            .backend
+            .open_stream(&b.backend.local_name(), "file.txt")
+            .unwrap();
+        let (name, mut incoming) = b.expect(|event| match event {
+            BackendEvent::Stream { peer, name, stream } if peer == a_name => Some((name, stream)),
+            _ => None,
+        });
+        assert_eq!(name, "file.txt");
+
+        outgoing.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        incoming.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        incoming.write_all(b"pong").unwrap();
+        outgoing.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    /// `a` isn't connected to anybody
+    pub(crate) fn streams_need_a_connected_peer<B: Backend>(a: &Node<B>) {
+        assert!(a.backend.open_stream("nobody", "file.txt").is_err());
+    }
+}
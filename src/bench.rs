@@ -0,0 +1,929 @@
+// Latency and throughput measurements between two peers.
+//
+// `run_bench` runs a series of tests against one peer and the peer's
+// `BenchResponder` answers them. Everything rides in ordinary `Data`
+// payloads starting with `MAGIC`, so any backend that carries data carries
+// the bench, MPC included; only the stream test needs `open_stream`.
+//
+// - latency: unreliable pings one at a time, round trip percentiles and loss
+// - reliable, unreliable: a burst of messages as fast as the backend takes
+//   them, the responder reports how many arrived
+// - stream: a fixed number of bytes through one stream
+//
+// Every test uses its own run id so late answers to an earlier test, or to
+// an earlier bench, are ignored.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::backend::{Backend, Stream};
+use crate::mesh::NodeId;
+
+pub const MAGIC: &[u8; 4] = b"IDPB";
+pub const VERSION: u8 = 1;
+/// Magic, version, kind, flags, run and sequence number
+pub const HEADER_LEN: usize = 15;
+/// Streams opened by the bench are named `bench/<run>`
+pub const STREAM_PREFIX: &str = "bench/";
+
+/// Pause before ending an unreliable burst so stragglers arrive first
+const SETTLE: Duration = Duration::from_millis(100);
+const STREAM_CHUNK: usize = 64 * 1024;
+const FLAG_RELIABLE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Kind {
+    Ping = 0,
+    Pong = 1,
+    Burst = 2,
+    BurstEnd = 3,
+    Report = 4,
+}
+
+impl Kind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Kind::Ping),
+            1 => Some(Kind::Pong),
+            2 => Some(Kind::Burst),
+            3 => Some(Kind::BurstEnd),
+            4 => Some(Kind::Report),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchMessage {
+    /// Answered with a `Pong` sent the same way the ping was
+    Ping {
+        run: u32,
+        seq: u32,
+        reliably: bool,
+    },
+    Pong {
+        run: u32,
+        seq: u32,
+    },
+    /// One message of a burst, padded to the configured size
+    Burst {
+        run: u32,
+        seq: u32,
+    },
+    /// Always sent reliably, asks for the `Report`
+    BurstEnd {
+        run: u32,
+        sent: u32,
+    },
+    /// What arrived of a burst or stream, and how long it took from the
+    /// first to the last byte
+    Report {
+        run: u32,
+        received: u32,
+        bytes: u64,
+        elapsed: Duration,
+    },
+}
+
+impl BenchMessage {
+    /// Encode, padding with zeros up to `len` bytes
+    pub fn encode(&self, len: usize) -> Vec<u8> {
+        let (kind, flags, run, seq) = match *self {
+            BenchMessage::Ping { run, seq, reliably } => (
+                Kind::Ping,
+                if reliably { FLAG_RELIABLE } else { 0 },
+                run,
+                seq,
+            ),
+            BenchMessage::Pong { run, seq } => (Kind::Pong, 0, run, seq),
+            BenchMessage::Burst { run, seq } => (Kind::Burst, 0, run, seq),
+            BenchMessage::BurstEnd { run, sent } => (Kind::BurstEnd, 0, run, sent),
+            BenchMessage::Report { run, received, .. } => (Kind::Report, 0, run, received),
+        };
+        let mut out = Vec::with_capacity(len.max(HEADER_LEN + 16));
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(kind as u8);
+        out.push(flags);
+        out.extend_from_slice(&run.to_be_bytes());
+        out.extend_from_slice(&seq.to_be_bytes());
+        if let BenchMessage::Report { bytes, elapsed, .. } = self {
+            out.extend_from_slice(&bytes.to_be_bytes());
+            out.extend_from_slice(&(elapsed.as_micros() as u64).to_be_bytes());
+        }
+        if out.len() < len {
+            out.resize(len, 0);
+        }
+        out
+    }
+
+    /// `None` for anything that isn't a bench message
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return None;
+        }
+        let kind = Kind::from_u8(bytes[5])?;
+        let flags = bytes[6];
+        let run = u32::from_be_bytes(bytes[7..11].try_into().ok()?);
+        let seq = u32::from_be_bytes(bytes[11..15].try_into().ok()?);
+        Some(match kind {
+            Kind::Ping => BenchMessage::Ping {
+                run,
+                seq,
+                reliably: flags & FLAG_RELIABLE != 0,
+            },
+            Kind::Pong => BenchMessage::Pong { run, seq },
+            Kind::Burst => BenchMessage::Burst { run, seq },
+            Kind::BurstEnd => BenchMessage::BurstEnd { run, sent: seq },
+            Kind::Report => BenchMessage::Report {
+                run,
+                received: seq,
+                bytes: u64::from_be_bytes(bytes.get(15..23)?.try_into().ok()?),
+                elapsed: Duration::from_micros(u64::from_be_bytes(
+                    bytes.get(23..31)?.try_into().ok()?,
+                )),
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Burst {
+    received: u32,
+    bytes: u64,
+    first: Instant,
+    last: Instant,
+}
+
+/// Answers bench requests from any number of peers
+#[derive(Debug, Default)]
+pub struct BenchResponder {
+    // Bursts under way, per peer and run
+    bursts: HashMap<(NodeId, u32), Burst>,
+}
+
+impl BenchResponder {
+    /// Answer `data` if it is a bench request. Returns false for anything
+    /// else, answers included, so the caller can handle it.
+    pub fn on_data(&mut self, backend: &dyn Backend, peer: &str, data: &[u8]) -> bool {
+        let (reply, reliably) = match BenchMessage::decode(data) {
+            Some(BenchMessage::Ping { run, seq, reliably }) => {
+                (BenchMessage::Pong { run, seq }, reliably)
+            }
+            Some(BenchMessage::Burst { run, .. }) => {
+                let now = Instant::now();
+                let burst = self.bursts.entry((peer.to_string(), run)).or_insert(Burst {
+                    received: 0,
+                    bytes: 0,
+                    first: now,
+                    last: now,
+                });
+                burst.received += 1;
+                burst.bytes += data.len() as u64;
+                burst.last = now;
+                return true;
+            }
+            Some(BenchMessage::BurstEnd { run, sent }) => {
+                let report = match self.bursts.remove(&(peer.to_string(), run)) {
+                    Some(burst) => BenchMessage::Report {
+                        run,
+                        received: burst.received,
+                        bytes: burst.bytes,
+                        elapsed: burst.last - burst.first,
+                    },
+                    None => BenchMessage::Report {
+                        run,
+                        received: 0,
+                        bytes: 0,
+                        elapsed: Duration::ZERO,
+                    },
+                };
+                debug!("Burst {} from {}: {:?} of {} sent", run, peer, report, sent);
+                (report, true)
+            }
+            _ => return false,
+        };
+        if let Err(e) = backend.send(&reply.encode(0), &[peer.to_string()], reliably) {
+            warn!("Failed to answer bench request from {}: {}", peer, e);
+        }
+        true
+    }
+
+    pub fn is_bench_stream(name: &str) -> bool {
+        name.starts_with(STREAM_PREFIX)
+    }
+
+    /// Read a bench stream to the end, then report how much arrived. Blocks
+    /// until the peer closes the stream.
+    pub fn on_stream(
+        &self,
+        backend: &dyn Backend,
+        peer: &str,
+        name: &str,
+        mut stream: Box<dyn Stream>,
+    ) {
+        let Some(run) = name
+            .strip_prefix(STREAM_PREFIX)
+            .and_then(|run| run.parse().ok())
+        else {
+            warn!("Ignoring bench stream {:?} from {}", name, peer);
+            return;
+        };
+
+        let started = Instant::now();
+        let mut buf = vec![0; STREAM_CHUNK];
+        let mut bytes = 0u64;
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => bytes += n as u64,
+                Err(e) => {
+                    warn!("Bench stream from {} failed: {}", peer, e);
+                    break;
+                }
+            }
+        }
+        let report = BenchMessage::Report {
+            run,
+            received: 0,
+            bytes,
+            elapsed: started.elapsed(),
+        };
+        if let Err(e) = backend.send(&report.encode(0), &[peer.to_string()], true) {
+            warn!("Failed to report bench stream to {}: {}", peer, e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BenchTest {
+    Latency,
+    Reliable,
+    Unreliable,
+    Stream,
+}
+
+impl BenchTest {
+    pub fn name(self) -> &'static str {
+        match self {
+            BenchTest::Latency => "latency",
+            BenchTest::Reliable => "reliable",
+            BenchTest::Unreliable => "unreliable",
+            BenchTest::Stream => "stream",
+        }
+    }
+}
+
+impl FromStr for BenchTest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latency" => Ok(BenchTest::Latency),
+            "reliable" => Ok(BenchTest::Reliable),
+            "unreliable" => Ok(BenchTest::Unreliable),
+            "stream" => Ok(BenchTest::Stream),
+            _ => Err(format!(
+                "Unknown test {}, expected latency, reliable, unreliable or stream",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Run in this order
+    pub tests: Vec<BenchTest>,
+    pub pings: u32,
+    /// Time between the start of two pings
+    pub ping_interval: Duration,
+    /// A ping without a pong by then is lost
+    pub ping_timeout: Duration,
+    /// Messages per burst
+    pub messages: u32,
+    /// Size of each burst message, header included
+    pub message_size: usize,
+    pub stream_bytes: u64,
+    /// How long to wait for a report once a burst or stream is sent
+    pub timeout: Duration,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            tests: vec![
+                BenchTest::Latency,
+                BenchTest::Reliable,
+                BenchTest::Unreliable,
+                BenchTest::Stream,
+            ],
+            pings: 100,
+            ping_interval: Duration::from_millis(10),
+            ping_timeout: Duration::from_secs(1),
+            messages: 1000,
+            message_size: 1024,
+            stream_bytes: 8 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyReport {
+    pub sent: u32,
+    /// Round trip of every answered ping, sorted
+    pub rtts: Vec<Duration>,
+}
+
+impl LatencyReport {
+    pub fn received(&self) -> u32 {
+        self.rtts.len() as u32
+    }
+
+    pub fn loss(&self) -> f64 {
+        loss(self.sent as u64, self.received() as u64)
+    }
+
+    /// `q` from 0 to 1, `None` if no ping was answered
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let index = ((self.rtts.len() - 1) as f64 * q.clamp(0.0, 1.0)).round() as usize;
+        Some(self.rtts[index])
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThroughputReport {
+    pub test: BenchTest,
+    /// Zero for streams
+    pub messages_sent: u32,
+    pub messages_received: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// From the first to the last byte arriving at the responder
+    pub elapsed: Duration,
+}
+
+impl ThroughputReport {
+    pub fn loss(&self) -> f64 {
+        if self.messages_sent > 0 {
+            loss(self.messages_sent as u64, self.messages_received as u64)
+        } else {
+            loss(self.bytes_sent, self.bytes_received)
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        per_sec(self.bytes_received as f64, self.elapsed)
+    }
+
+    pub fn messages_per_sec(&self) -> f64 {
+        per_sec(self.messages_received as f64, self.elapsed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub peer: NodeId,
+    pub config: BenchConfig,
+    pub latency: Option<LatencyReport>,
+    pub throughput: Vec<ThroughputReport>,
+    /// Tests that didn't produce a result, with the reason
+    pub failed: Vec<(BenchTest, String)>,
+}
+
+impl BenchReport {
+    /// One line of JSON, durations in microseconds
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"peer\":{},\"config\":{{\"pings\":{},\"messages\":{},\"message_size\":{},\"stream_bytes\":{}}}",
+            json_string(&self.peer),
+            self.config.pings,
+            self.config.messages,
+            self.config.message_size,
+            self.config.stream_bytes
+        );
+
+        out.push_str(",\"latency\":");
+        match &self.latency {
+            Some(latency) => {
+                let micros = |d: Option<Duration>| match d {
+                    Some(d) => d.as_micros().to_string(),
+                    None => "null".to_string(),
+                };
+                out.push_str(&format!(
+                    "{{\"sent\":{},\"received\":{},\"loss\":{:.4},\"min_us\":{},\"p50_us\":{},\"p90_us\":{},\"p99_us\":{},\"max_us\":{},\"mean_us\":{}}}",
+                    latency.sent,
+                    latency.received(),
+                    latency.loss(),
+                    micros(latency.percentile(0.0)),
+                    micros(latency.percentile(0.5)),
+                    micros(latency.percentile(0.9)),
+                    micros(latency.percentile(0.99)),
+                    micros(latency.percentile(1.0)),
+                    micros(latency.mean())
+                ));
+            }
+            None => out.push_str("null"),
+        }
+
+        let throughput: Vec<String> = self
+            .throughput
+            .iter()
+            .map(|t| {
+                format!(
+                    "{{\"test\":\"{}\",\"messages_sent\":{},\"messages_received\":{},\"bytes_sent\":{},\"bytes_received\":{},\"elapsed_us\":{},\"loss\":{:.4},\"bytes_per_sec\":{:.0},\"messages_per_sec\":{:.1}}}",
+                    t.test.name(),
+                    t.messages_sent,
+                    t.messages_received,
+                    t.bytes_sent,
+                    t.bytes_received,
+                    t.elapsed.as_micros(),
+                    t.loss(),
+                    t.bytes_per_sec(),
+                    t.messages_per_sec()
+                )
+            })
+            .collect();
+        out.push_str(&format!(",\"throughput\":[{}]", throughput.join(",")));
+
+        let failed: Vec<String> = self
+            .failed
+            .iter()
+            .map(|(test, error)| {
+                format!(
+                    "{{\"test\":\"{}\",\"error\":{}}}",
+                    test.name(),
+                    json_string(error)
+                )
+            })
+            .collect();
+        out.push_str(&format!(",\"failed\":[{}]}}", failed.join(",")));
+        out
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Bench against {}", self.peer)?;
+        if let Some(latency) = &self.latency {
+            write!(
+                f,
+                "{:<11} {}/{} answered, {:.1}% loss",
+                "latency",
+                latency.received(),
+                latency.sent,
+                latency.loss() * 100.0
+            )?;
+            if let (Some(min), Some(p50), Some(p90), Some(p99), Some(max)) = (
+                latency.percentile(0.0),
+                latency.percentile(0.5),
+                latency.percentile(0.9),
+                latency.percentile(0.99),
+                latency.percentile(1.0),
+            ) {
+                write!(
+                    f,
+                    ", min {:.2?} p50 {:.2?} p90 {:.2?} p99 {:.2?} max {:.2?}",
+                    min, p50, p90, p99, max
+                )?;
+            }
+            writeln!(f)?;
+        }
+        for t in &self.throughput {
+            write!(f, "{:<11} ", t.test.name())?;
+            if t.messages_sent > 0 {
+                write!(
+                    f,
+                    "{}/{} messages, {:.1}% loss, ",
+                    t.messages_received,
+                    t.messages_sent,
+                    t.loss() * 100.0
+                )?;
+            }
+            write!(
+                f,
+                "{} bytes in {:.2?}, {:.2} MB/s",
+                t.bytes_received,
+                t.elapsed,
+                t.bytes_per_sec() / 1_000_000.0
+            )?;
+            if t.messages_sent > 0 {
+                write!(f, ", {:.0} msg/s", t.messages_per_sec())?;
+            }
+            writeln!(f)?;
+        }
+        for (test, error) in &self.failed {
+            writeln!(f, "{:<11} failed: {}", test.name(), error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `config.tests` against `peer`, which must be connected and answer
+/// with a `BenchResponder`.
+///
+/// `recv` waits up to the given time for the next data payload from any
+/// peer and returns `None` on timeout.
+pub fn run_bench(
+    backend: &dyn Backend,
+    peer: &str,
+    config: &BenchConfig,
+    mut recv: impl FnMut(Duration) -> Option<(NodeId, Vec<u8>)>,
+) -> Result<BenchReport, String> {
+    let mut report = BenchReport {
+        peer: peer.to_string(),
+        config: config.clone(),
+        latency: None,
+        throughput: Vec::new(),
+        failed: Vec::new(),
+    };
+    let mut run: u32 = rand::random();
+
+    for &test in &config.tests {
+        run = run.wrapping_add(1);
+        debug!(
+            "Running {} bench against {} as run {}",
+            test.name(),
+            peer,
+            run
+        );
+        let result = match test {
+            BenchTest::Latency => {
+                latency(backend, peer, config, run, &mut recv).map(|l| report.latency = Some(l))
+            }
+            BenchTest::Reliable | BenchTest::Unreliable => burst(
+                backend,
+                peer,
+                config,
+                run,
+                test == BenchTest::Reliable,
+                &mut recv,
+            )
+            .map(|t| report.throughput.push(t)),
+            BenchTest::Stream => {
+                stream(backend, peer, config, run, &mut recv).map(|t| report.throughput.push(t))
+            }
+        };
+        if let Err(e) = result {
+            warn!("{} bench against {} failed: {}", test.name(), peer, e);
+            report.failed.push((test, e));
+        }
+    }
+    Ok(report)
+}
+
+fn latency(
+    backend: &dyn Backend,
+    peer: &str,
+    config: &BenchConfig,
+    run: u32,
+    recv: &mut impl FnMut(Duration) -> Option<(NodeId, Vec<u8>)>,
+) -> Result<LatencyReport, String> {
+    let mut rtts = Vec::new();
+    for seq in 0..config.pings {
+        let ping = BenchMessage::Ping {
+            run,
+            seq,
+            reliably: false,
+        };
+        let sent_at = Instant::now();
+        backend.send(&ping.encode(0), &[peer.to_string()], false)?;
+
+        let answered = wait_for(peer, sent_at + config.ping_timeout, recv, |message| {
+            message == BenchMessage::Pong { run, seq }
+        });
+        if answered.is_some() {
+            rtts.push(sent_at.elapsed());
+        }
+
+        let next = sent_at + config.ping_interval;
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+    }
+    rtts.sort();
+    Ok(LatencyReport {
+        sent: config.pings,
+        rtts,
+    })
+}
+
+fn burst(
+    backend: &dyn Backend,
+    peer: &str,
+    config: &BenchConfig,
+    run: u32,
+    reliably: bool,
+    recv: &mut impl FnMut(Duration) -> Option<(NodeId, Vec<u8>)>,
+) -> Result<ThroughputReport, String> {
+    let peers = [peer.to_string()];
+    let size = config.message_size.max(HEADER_LEN);
+    for seq in 0..config.messages {
+        backend.send(
+            &BenchMessage::Burst { run, seq }.encode(size),
+            &peers,
+            reliably,
+        )?;
+    }
+    if !reliably {
+        thread::sleep(SETTLE);
+    }
+    let end = BenchMessage::BurstEnd {
+        run,
+        sent: config.messages,
+    };
+    backend.send(&end.encode(0), &peers, true)?;
+
+    let (received, bytes, elapsed) = wait_for_report(peer, run, config.timeout, recv)?;
+    Ok(ThroughputReport {
+        test: if reliably {
+            BenchTest::Reliable
+        } else {
+            BenchTest::Unreliable
+        },
+        messages_sent: config.messages,
+        messages_received: received,
+        bytes_sent: config.messages as u64 * size as u64,
+        bytes_received: bytes,
+        elapsed,
+    })
+}
+
+fn stream(
+    backend: &dyn Backend,
+    peer: &str,
+    config: &BenchConfig,
+    run: u32,
+    recv: &mut impl FnMut(Duration) -> Option<(NodeId, Vec<u8>)>,
+) -> Result<ThroughputReport, String> {
+    let mut stream = backend.open_stream(peer, &format!("{}{}", STREAM_PREFIX, run))?;
+    let chunk = vec![0u8; STREAM_CHUNK];
+    let mut left = config.stream_bytes;
+    while left > 0 {
+        let n = left.min(STREAM_CHUNK as u64) as usize;
+        stream.write_all(&chunk[..n]).map_err(|e| e.to_string())?;
+        left -= n as u64;
+    }
+    stream.flush().map_err(|e| e.to_string())?;
+    // Closing it is what makes the responder report
+    drop(stream);
+
+    let (_, bytes, elapsed) = wait_for_report(peer, run, config.timeout, recv)?;
+    Ok(ThroughputReport {
+        test: BenchTest::Stream,
+        messages_sent: 0,
+        messages_received: 0,
+        bytes_sent: config.stream_bytes,
+        bytes_received: bytes,
+        elapsed,
+    })
+}
+
+fn wait_for_report(
+    peer: &str,
+    run: u32,
+    timeout: Duration,
+    recv: &mut impl FnMut(Duration) -> Option<(NodeId, Vec<u8>)>,
+) -> Result<(u32, u64, Duration), String> {
+    match wait_for(
+        peer,
+        Instant::now() + timeout,
+        recv,
+        |message| matches!(message, BenchMessage::Report { run: r, .. } if r == run),
+    ) {
+        Some(BenchMessage::Report {
+            received,
+            bytes,
+            elapsed,
+            ..
+        }) => Ok((received, bytes, elapsed)),
+        _ => Err(format!("No report from {} within {:?}", peer, timeout)),
+    }
+}
+
+// Receive until a message from `peer` matches or `deadline` passes.
+// Everything else is dropped.
+fn wait_for(
+    peer: &str,
+    deadline: Instant,
+    recv: &mut impl FnMut(Duration) -> Option<(NodeId, Vec<u8>)>,
+    matches: impl Fn(BenchMessage) -> bool,
+) -> Option<BenchMessage> {
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return None;
+        }
+        let (from, data) = recv(left)?;
+        if from != peer {
+            continue;
+        }
+        if let Some(message) = BenchMessage::decode(&data)
+            && matches(message)
+        {
+            return Some(message);
+        }
+    }
+}
+
+fn loss(sent: u64, received: u64) -> f64 {
+    if sent == 0 {
+        return 0.0;
+    }
+    sent.saturating_sub(received) as f64 / sent as f64
+}
+
+fn per_sec(amount: f64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    amount / elapsed.as_secs_f64()
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc};
+
+    use super::*;
+    use crate::backend::BackendEvent;
+    use crate::memory_backend::{MemoryBackend, MemoryNetwork};
+    use crate::sim::{LinkConfig, SimConfig};
+
+    // "a" runs the bench, "b" answers it from a thread of its own
+    fn bench_pair(link: LinkConfig) -> (MemoryBackend, mpsc::Receiver<BackendEvent>) {
+        let network = MemoryNetwork::new(SimConfig {
+            default_link: link,
+            ..SimConfig::default()
+        })
+        .unwrap();
+        let (tx, rx) = mpsc::channel();
+        let a = network
+            .join("a", move |event| drop(tx.send(event)))
+            .unwrap();
+        let (tx, b_rx) = mpsc::channel();
+        let b = Arc::new(
+            network
+                .join("b", move |event| drop(tx.send(event)))
+                .unwrap(),
+        );
+        thread::spawn(move || {
+            let mut responder = BenchResponder::default();
+            for event in b_rx {
+                match event {
+                    BackendEvent::Data { peer, data } => {
+                        responder.on_data(&*b, &peer, &data);
+                    }
+                    BackendEvent::Stream { peer, name, stream } => {
+                        let b = b.clone();
+                        thread::spawn(move || {
+                            BenchResponder::default().on_stream(&*b, &peer, &name, stream)
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        });
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(BackendEvent::Joined { peer }) => assert_eq!(peer, "b"),
+            other => panic!("Expected b to join, got {:?}", other),
+        }
+        (a, rx)
+    }
+
+    fn run(
+        a: &MemoryBackend,
+        rx: &mpsc::Receiver<BackendEvent>,
+        config: &BenchConfig,
+    ) -> BenchReport {
+        run_bench(a, "b", config, |timeout| {
+            loop {
+                match rx.recv_timeout(timeout).ok()? {
+                    BackendEvent::Data { peer, data } => return Some((peer, data)),
+                    _ => continue,
+                }
+            }
+        })
+        .unwrap()
+    }
+
+    fn small_config() -> BenchConfig {
+        BenchConfig {
+            pings: 20,
+            ping_interval: Duration::from_millis(1),
+            messages: 200,
+            message_size: 256,
+            stream_bytes: 256 * 1024,
+            timeout: Duration::from_secs(5),
+            ..BenchConfig::default()
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            BenchMessage::Ping {
+                run: 7,
+                seq: 3,
+                reliably: true,
+            },
+            BenchMessage::Pong { run: 7, seq: 3 },
+            BenchMessage::Burst { run: 1, seq: 99 },
+            BenchMessage::BurstEnd { run: 1, sent: 100 },
+            BenchMessage::Report {
+                run: 1,
+                received: 98,
+                bytes: 98 * 1024,
+                elapsed: Duration::from_micros(1234),
+            },
+        ];
+        for message in messages {
+            let encoded = message.encode(100);
+            assert!(encoded.len() >= 100);
+            assert_eq!(BenchMessage::decode(&encoded), Some(message));
+        }
+        assert_eq!(BenchMessage::decode(b"IDPBnot a bench message"), None);
+    }
+
+    #[test]
+    fn every_test_passes_on_a_clean_link() {
+        let (a, rx) = bench_pair(LinkConfig::default());
+        let report = run(&a, &rx, &small_config());
+
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        let latency = report.latency.as_ref().unwrap();
+        assert_eq!(latency.received(), 20);
+        // One way delay is 5ms
+        assert!(latency.percentile(0.5).unwrap() >= Duration::from_millis(10));
+        assert_eq!(report.throughput.len(), 3);
+        for throughput in &report.throughput {
+            assert_eq!(throughput.loss(), 0.0, "{:?}", throughput);
+            assert_eq!(throughput.bytes_received, throughput.bytes_sent);
+        }
+        assert!(report.to_json().starts_with("{\"peer\":\"b\""));
+    }
+
+    #[test]
+    fn loss_shows_in_unreliable_tests_only() {
+        let (a, rx) = bench_pair(LinkConfig {
+            loss: 0.3,
+            ..LinkConfig::default()
+        });
+        let config = BenchConfig {
+            tests: vec![
+                BenchTest::Latency,
+                BenchTest::Reliable,
+                BenchTest::Unreliable,
+            ],
+            ping_timeout: Duration::from_millis(200),
+            ..small_config()
+        };
+        let report = run(&a, &rx, &config);
+
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert!(report.latency.unwrap().loss() > 0.0);
+        let [reliable, unreliable] = &report.throughput[..] else {
+            panic!("{:?}", report.throughput);
+        };
+        assert_eq!(reliable.loss(), 0.0);
+        assert!(
+            unreliable.loss() > 0.1 && unreliable.loss() < 0.5,
+            "{:?}",
+            unreliable
+        );
+    }
+}
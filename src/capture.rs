@@ -0,0 +1,555 @@
+// Wire capture of session traffic, and replay into the memory backend.
+//
+// A `CaptureWriter` appends every frame a device sends or receives, plus
+// peers being found, lost, connected and disconnected, to a file as it
+// happens. `Capture::open` reads it back and `replay` plays it into a
+// `MemoryNetwork`: every remote peer in the capture joins the network and
+// sends the device under test exactly the bytes the captured device
+// received, at the same pace, so a bug seen on a phone can be reproduced
+// on a laptop. Frames the captured device sent are kept for reference but
+// not replayed, the device under test produces its own.
+//
+// File format, all integers big endian. Records are written one by one, a
+// capture cut short by a crash reads fine up to the last complete record.
+//   [magic "IDPC"][version: u8][started at: u64 micros since the epoch]
+//   [local len: u8][local name]
+//   records: [at: u64 micros since start][kind: u8][flags: u8]
+//            [peer len: u8][peer][data len: u32][data]
+//
+// Kinds: 0 sent (flag 1: reliably), 1 received, 2 found, 3 lost,
+// 4 connected, 5 disconnected. Data is the encoded frame for sent and
+// received, the reason for disconnected and empty otherwise.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::file_format::Reader;
+use crate::memory_backend::{MemoryBackend, MemoryNetwork};
+use crate::mesh::NodeId;
+use crate::sim::PeerState;
+
+const FILE_MAGIC: &[u8; 4] = b"IDPC";
+const FILE_VERSION: u8 = 1;
+const FLAG_RELIABLE: u8 = 1;
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum CaptureEvent {
+    Sent { frame: Vec<u8>, reliably: bool },
+    Received { frame: Vec<u8> },
+    Found,
+    Lost,
+    Connected,
+    Disconnected { reason: String },
+}
+
+impl CaptureEvent {
+    fn kind(&self) -> u8 {
+        match self {
+            CaptureEvent::Sent { .. } => 0,
+            CaptureEvent::Received { .. } => 1,
+            CaptureEvent::Found => 2,
+            CaptureEvent::Lost => 3,
+            CaptureEvent::Connected => 4,
+            CaptureEvent::Disconnected { .. } => 5,
+        }
+    }
+}
+
+impl fmt::Debug for CaptureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureEvent::Sent { frame, reliably } => f
+                .debug_struct("Sent")
+                .field("len", &frame.len())
+                .field("reliably", reliably)
+                .finish(),
+            CaptureEvent::Received { frame } => f
+                .debug_struct("Received")
+                .field("len", &frame.len())
+                .finish(),
+            CaptureEvent::Found => f.write_str("Found"),
+            CaptureEvent::Lost => f.write_str("Lost"),
+            CaptureEvent::Connected => f.write_str("Connected"),
+            CaptureEvent::Disconnected { reason } => f
+                .debug_struct("Disconnected")
+                .field("reason", reason)
+                .finish(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time since the capture started
+    pub at: Duration,
+    pub peer: NodeId,
+    pub event: CaptureEvent,
+}
+
+#[derive(Debug)]
+pub struct CaptureWriter {
+    path: PathBuf,
+    file: File,
+    started: Instant,
+}
+
+impl CaptureWriter {
+    /// Start a new capture at `path` for the device called `local`,
+    /// replacing whatever was there
+    pub fn create(path: impl AsRef<Path>, local: &str) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let mut header = Vec::new();
+        header.extend_from_slice(FILE_MAGIC);
+        header.push(FILE_VERSION);
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        header.extend_from_slice(&(since_epoch.as_micros() as u64).to_be_bytes());
+        put_name(&mut header, local)?;
+
+        let mut file = File::create(&path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        file.write_all(&header)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(Self {
+            path,
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, peer: &str, event: &CaptureEvent) -> Result<(), String> {
+        let (flags, data): (u8, &[u8]) = match event {
+            CaptureEvent::Sent { frame, reliably } => {
+                (if *reliably { FLAG_RELIABLE } else { 0 }, frame)
+            }
+            CaptureEvent::Received { frame } => (0, frame),
+            CaptureEvent::Disconnected { reason } => (0, reason.as_bytes()),
+            CaptureEvent::Found | CaptureEvent::Lost | CaptureEvent::Connected => (0, &[]),
+        };
+
+        let mut record = Vec::with_capacity(19 + peer.len() + data.len());
+        record.extend_from_slice(&(self.started.elapsed().as_micros() as u64).to_be_bytes());
+        record.push(event.kind());
+        record.push(flags);
+        put_name(&mut record, peer)?;
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        self.file
+            .write_all(&record)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) -> Result<(), String> {
+    let len = u8::try_from(name.len()).map_err(|_| format!("Name {} is too long", name))?;
+    out.push(len);
+    out.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// The device the capture was taken on
+    pub local: NodeId,
+    pub started: SystemTime,
+    pub records: Vec<CaptureRecord>,
+}
+
+impl Capture {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let bytes =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::decode(&bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes, "capture file");
+        if reader.take(4)? != FILE_MAGIC {
+            return Err("Not a capture file".to_string());
+        }
+        let version = reader.u8()?;
+        if version != FILE_VERSION {
+            return Err(format!("Unsupported capture version {}", version));
+        }
+        let started = UNIX_EPOCH + Duration::from_micros(reader.u64()?);
+        let local = reader.string()?;
+
+        let mut records = Vec::new();
+        while !reader.is_empty() {
+            match read_record(&mut reader) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    // Whatever the device managed to write before it died
+                    warn!("Capture ends early after {} records: {}", records.len(), e);
+                    break;
+                }
+            }
+        }
+        Ok(Self {
+            local,
+            started,
+            records,
+        })
+    }
+
+    /// Every remote peer that shows up in the capture
+    pub fn peers(&self) -> BTreeSet<NodeId> {
+        self.records
+            .iter()
+            .map(|record| record.peer.clone())
+            .collect()
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.records
+            .last()
+            .map_or(Duration::ZERO, |record| record.at)
+    }
+}
+
+fn read_record(reader: &mut Reader) -> Result<CaptureRecord, String> {
+    let at = Duration::from_micros(reader.u64()?);
+    let kind = reader.u8()?;
+    let flags = reader.u8()?;
+    let peer = reader.string()?;
+    let len = reader.u32()? as usize;
+    let data = reader.take(len)?.to_vec();
+    let event = match kind {
+        0 => CaptureEvent::Sent {
+            frame: data,
+            reliably: flags & FLAG_RELIABLE != 0,
+        },
+        1 => CaptureEvent::Received { frame: data },
+        2 => CaptureEvent::Found,
+        3 => CaptureEvent::Lost,
+        4 => CaptureEvent::Connected,
+        5 => CaptureEvent::Disconnected {
+            reason: String::from_utf8_lossy(&data).to_string(),
+        },
+        _ => return Err(format!("Unknown record kind {}", kind)),
+    };
+    Ok(CaptureRecord { at, peer, event })
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// 2.0 plays twice as fast as captured
+    pub speed: f64,
+    /// How long a received frame waits for its peer to be connected before
+    /// it is skipped
+    pub connect_timeout: Duration,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            connect_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Frames handed to the device under test
+    pub delivered: usize,
+    /// Frames dropped because their peer never connected
+    pub skipped: usize,
+    pub elapsed: Duration,
+}
+
+/// Play `capture` into `network`, towards the device called `target` that
+/// has already joined it. Blocks until the last record; `on_record` is
+/// called as each one is played.
+///
+/// Found and connected records bring the peer into range of `target`, the
+/// network then connects them on its own; lost and disconnected records take
+/// it out of range again. Received frames go out reliably whichever way
+/// they came in, so link loss doesn't get in the way of reproducing what
+/// actually arrived.
+pub fn replay(
+    capture: &Capture,
+    network: &MemoryNetwork,
+    target: &str,
+    config: &ReplayConfig,
+    mut on_record: impl FnMut(&CaptureRecord),
+) -> Result<ReplayReport, String> {
+    if config.speed.is_nan() || config.speed <= 0.0 {
+        return Err("Replay speed must be above 0".to_string());
+    }
+    // When each record is due, a slow enough speed puts some out of reach
+    let offsets = capture
+        .records
+        .iter()
+        .map(|record| Duration::try_from_secs_f64(record.at.as_secs_f64() / config.speed))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("Replay speed {} is too slow", config.speed))?;
+    let peers = capture.peers();
+    if peers.contains(target) {
+        return Err(format!("{} is a peer in the capture", target));
+    }
+
+    // Peers the capture starts with traffic for were connected before it
+    // began, the rest stay out of range until it says otherwise
+    let mut first_seen = HashMap::new();
+    for record in &capture.records {
+        first_seen.entry(&record.peer).or_insert(&record.event);
+    }
+    network.with_sim(|sim| {
+        for (peer, event) in &first_seen {
+            let connected = matches!(
+                event,
+                CaptureEvent::Sent { .. } | CaptureEvent::Received { .. }
+            );
+            sim.set_in_range(target, peer, connected);
+        }
+    });
+    let remotes = peers
+        .iter()
+        .map(|peer| network.join(peer, |_| {}))
+        .collect::<Result<Vec<MemoryBackend>, String>>()?;
+
+    let started = Instant::now();
+    let mut report = ReplayReport::default();
+    for (record, offset) in capture.records.iter().zip(offsets) {
+        let due = started
+            .checked_add(offset)
+            .ok_or_else(|| format!("Replay speed {} is too slow", config.speed))?;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+
+        let peer = &record.peer;
+        match &record.event {
+            CaptureEvent::Found | CaptureEvent::Connected => {
+                network.with_sim(|sim| sim.set_in_range(target, peer, true));
+            }
+            CaptureEvent::Lost | CaptureEvent::Disconnected { .. } => {
+                network.with_sim(|sim| sim.set_in_range(target, peer, false));
+            }
+            CaptureEvent::Received { frame } => {
+                let sent = wait_connected(network, target, peer, config.connect_timeout)
+                    && network
+                        .with_sim(|sim| sim.send(peer, target, frame, true))
+                        .inspect_err(|e| debug!("Failed to replay frame from {}: {}", peer, e))
+                        .is_ok();
+                if sent {
+                    report.delivered += 1;
+                } else {
+                    warn!(
+                        "Skipping frame from {} at {:?}, not connected",
+                        peer, record.at
+                    );
+                    report.skipped += 1;
+                }
+            }
+            CaptureEvent::Sent { .. } => {}
+        }
+        on_record(record);
+    }
+    // Goodbyes from the remote peers would overtake frames still on the way
+    let deadline = Instant::now() + config.connect_timeout;
+    while network.with_sim(|sim| sim.next_event_at()).is_some() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(1));
+    }
+    report.elapsed = started.elapsed();
+    drop(remotes);
+    Ok(report)
+}
+
+fn wait_connected(network: &MemoryNetwork, target: &str, peer: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if network.with_sim(|sim| sim.state(target, peer)) == PeerState::Connected {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::backend::BackendEvent;
+    use crate::file_format::scratch;
+    use crate::frame::{Frame, FrameKind};
+    use crate::sim::SimConfig;
+
+    fn events() -> Vec<(&'static str, CaptureEvent)> {
+        vec![
+            ("b", CaptureEvent::Found),
+            ("b", CaptureEvent::Connected),
+            (
+                "b",
+                CaptureEvent::Sent {
+                    frame: vec![1, 2],
+                    reliably: true,
+                },
+            ),
+            ("c", CaptureEvent::Received { frame: vec![3] }),
+            (
+                "b",
+                CaptureEvent::Disconnected {
+                    reason: "goodbye".to_string(),
+                },
+            ),
+            ("c", CaptureEvent::Lost),
+        ]
+    }
+
+    #[test]
+    fn captures_read_back_what_was_written() {
+        let path = scratch("capture-round-trip");
+        let mut writer = CaptureWriter::create(&path, "phone").unwrap();
+        for (peer, event) in events() {
+            writer.record(peer, &event).unwrap();
+        }
+        drop(writer);
+
+        let capture = Capture::open(&path).unwrap();
+        assert_eq!(capture.local, "phone");
+        let read: Vec<(&str, CaptureEvent)> = capture
+            .records
+            .iter()
+            .map(|record| (record.peer.as_str(), record.event.clone()))
+            .collect();
+        assert_eq!(read, events());
+        assert!(capture.records.is_sorted_by_key(|record| record.at));
+        assert_eq!(
+            capture.peers(),
+            BTreeSet::from(["b".to_string(), "c".to_string()])
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn captures_cut_short_keep_their_complete_records() {
+        let path = scratch("capture-cut-short");
+        let mut writer = CaptureWriter::create(&path, "phone").unwrap();
+        for (peer, event) in events() {
+            writer.record(peer, &event).unwrap();
+        }
+        drop(writer);
+
+        let bytes = fs::read(&path).unwrap();
+        let capture = Capture::decode(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(capture.records.len(), events().len() - 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn foreign_files_are_refused() {
+        assert!(Capture::decode(b"nope").is_err());
+        assert!(Capture::decode(b"IDPC\x09").is_err());
+        assert!(CaptureWriter::create(scratch("capture-long"), &"x".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn replay_delivers_received_frames_to_the_target() {
+        let frame = Frame::new(FrameKind::Data, b"hi".to_vec());
+        let capture = Capture {
+            local: "phone".to_string(),
+            started: UNIX_EPOCH,
+            records: vec![
+                CaptureRecord {
+                    at: Duration::ZERO,
+                    peer: "b".to_string(),
+                    event: CaptureEvent::Received {
+                        frame: frame.encode(),
+                    },
+                },
+                CaptureRecord {
+                    at: Duration::from_millis(10),
+                    peer: "b".to_string(),
+                    event: CaptureEvent::Sent {
+                        frame: frame.encode(),
+                        reliably: false,
+                    },
+                },
+            ],
+        };
+
+        let network = MemoryNetwork::new(SimConfig::default()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _target = network
+            .join("laptop", move |event| {
+                let _ = tx.send(event);
+            })
+            .unwrap();
+        let mut played = 0;
+        let report = replay(
+            &capture,
+            &network,
+            "laptop",
+            &ReplayConfig::default(),
+            |_| played += 1,
+        )
+        .unwrap();
+        assert_eq!((report.delivered, report.skipped, played), (1, 0, 2));
+
+        let data = std::iter::from_fn(|| rx.recv_timeout(Duration::from_secs(5)).ok())
+            .find_map(|event| match event {
+                BackendEvent::Data { peer, data } if peer == "b" => Some(data),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(data, b"hi");
+    }
+
+    #[test]
+    fn replay_refuses_a_target_from_the_capture() {
+        let capture = Capture {
+            local: "phone".to_string(),
+            started: UNIX_EPOCH,
+            records: vec![CaptureRecord {
+                at: Duration::ZERO,
+                peer: "b".to_string(),
+                event: CaptureEvent::Found,
+            }],
+        };
+        let network = MemoryNetwork::new(SimConfig::default()).unwrap();
+        let config = ReplayConfig::default();
+        assert!(replay(&capture, &network, "b", &config, |_| {}).is_err());
+
+        let stopped = ReplayConfig {
+            speed: 0.0,
+            ..config.clone()
+        };
+        assert!(replay(&capture, &network, "laptop", &stopped, |_| {}).is_err());
+    }
+
+    #[test]
+    fn replay_refuses_speeds_too_slow_to_schedule() {
+        let capture = Capture {
+            local: "phone".to_string(),
+            started: UNIX_EPOCH,
+            records: vec![CaptureRecord {
+                at: Duration::from_secs(1),
+                peer: "b".to_string(),
+                event: CaptureEvent::Found,
+            }],
+        };
+        let network = MemoryNetwork::new(SimConfig::default()).unwrap();
+        let config = ReplayConfig {
+            speed: 1e-300,
+            ..ReplayConfig::default()
+        };
+        assert!(replay(&capture, &network, "laptop", &config, |_| {}).is_err());
+    }
+}
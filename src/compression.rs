@@ -0,0 +1,275 @@
+// Payload compression, negotiated per peer.
+//
+// Right after connecting each side sends a Hello frame listing the codecs it
+// can decode. Until a peer's Hello arrives, and for peers that never send
+// one because they predate it, everything goes out uncompressed, so old
+// peers keep working. Peers that predate it also count a Hello as a
+// malformed frame, so it only goes to peers advertising `HELLO_KEY`.
+// Payloads of at least `threshold` bytes are compressed with the first codec
+// in our preference order the peer can decode, and only sent compressed if
+// that made them smaller.
+//
+// The codec travels in the top two bits of the frame flags, which no frame
+// kind uses for anything else. Any kind can be compressed but Secure and
+// Handshake frames, whose payloads are ciphertext and keys; the secure
+// channel compresses the frames it carries before encrypting them instead.
+//
+// Hello payload: [version: u8][codecs: u8, one bit per codec]
+// lz4 payloads start with the uncompressed length as a little endian u32.
+
+use std::collections::HashMap;
+
+use crate::frame::{Frame, FrameKind};
+use crate::mesh::NodeId;
+
+/// Frame flag: the payload is zstd compressed
+pub const FLAG_ZSTD: u8 = 0x80;
+/// Frame flag: the payload is lz4 compressed
+pub const FLAG_LZ4: u8 = 0x40;
+const CODEC_FLAGS: u8 = FLAG_ZSTD | FLAG_LZ4;
+
+const HELLO_VERSION: u8 = 1;
+
+/// Discovery info entry of peers that understand Hello frames
+pub const HELLO_KEY: &str = "hello";
+/// Value of `HELLO_KEY`, the Hello version we speak
+pub const HELLO_VALUE: &str = "1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    /// Better ratio, the one to pick for slow links
+    Zstd,
+    /// Faster, for when CPU time matters more than bytes
+    Lz4,
+}
+
+impl Codec {
+    fn flag(self) -> u8 {
+        match self {
+            Codec::Zstd => FLAG_ZSTD,
+            Codec::Lz4 => FLAG_LZ4,
+        }
+    }
+
+    fn from_flags(flags: u8) -> Result<Option<Self>, String> {
+        match flags & CODEC_FLAGS {
+            0 => Ok(None),
+            FLAG_ZSTD => Ok(Some(Codec::Zstd)),
+            FLAG_LZ4 => Ok(Some(Codec::Lz4)),
+            _ => Err("Frame flags name two codecs".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// Codecs we decode, and send with in this order of preference
+    pub codecs: Vec<Codec>,
+    /// Smaller payloads aren't worth compressing
+    pub threshold: usize,
+    pub zstd_level: i32,
+    /// Payloads that would decompress to more than this are rejected
+    pub max_decompressed: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codecs: vec![Codec::Zstd, Codec::Lz4],
+            threshold: 256,
+            zstd_level: 3,
+            max_decompressed: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Compression {
+    config: CompressionConfig,
+    // Codec each peer gets, `None` if it has none in common with us
+    peers: HashMap<NodeId, Option<Codec>>,
+    // Off after `disable`, we only decode from then on
+    offering: bool,
+}
+
+impl Compression {
+    pub fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+            offering: true,
+        }
+    }
+
+    pub fn config(&self) -> &CompressionConfig {
+        &self.config
+    }
+
+    /// Frame to send a peer as soon as it connects
+    pub fn hello(&self) -> Frame {
+        let codecs = if self.offering {
+            self.config
+                .codecs
+                .iter()
+                .fold(0, |bits, codec| bits | codec.flag())
+        } else {
+            0
+        };
+        Frame::new(FrameKind::Hello, vec![HELLO_VERSION, codecs])
+    }
+
+    /// Stop compressing. `hello` offers no codecs from now on, so peers that
+    /// get it stop compressing too; what they already sent still decodes.
+    pub fn disable(&mut self) {
+        self.offering = false;
+        self.peers.clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.offering
+    }
+
+    /// Pick the codec for a peer from its Hello
+    pub fn on_hello(&mut self, peer: &str, frame: &Frame) -> Result<Option<Codec>, String> {
+        let [version, codecs, ..] = frame.payload[..] else {
+            return Err(format!("Truncated hello from {}", peer));
+        };
+        // Later versions only ever add to the end
+        if version < HELLO_VERSION {
+            return Err(format!(
+                "Unsupported hello version {} from {}",
+                version, peer
+            ));
+        }
+        let codec = self
+            .config
+            .codecs
+            .iter()
+            .copied()
+            .find(|codec| self.offering && codecs & codec.flag() != 0);
+        self.peers.insert(peer.to_string(), codec);
+        Ok(codec)
+    }
+
+    pub fn peer_gone(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+
+    /// Codec negotiated with the peer, `None` before its Hello
+    pub fn codec_for(&self, peer: &str) -> Option<Codec> {
+        self.peers.get(peer).copied().flatten()
+    }
+
+    /// The frame as it should go to `peer`: compressed if the peer can take
+    /// it and it is worth it, unchanged otherwise
+    pub fn compress(&self, peer: &str, frame: &Frame) -> Frame {
+        self.compress_with(self.codec_for(peer), frame)
+    }
+
+    /// Like `compress`, for every peer that negotiated `codec` at once
+    pub fn compress_with(&self, codec: Option<Codec>, frame: &Frame) -> Frame {
+        let Some(codec) = codec else {
+            return frame.clone();
+        };
+        if matches!(
+            frame.kind,
+            FrameKind::Hello | FrameKind::Secure | FrameKind::Handshake
+        ) || frame.payload.len() < self.config.threshold
+        {
+            return frame.clone();
+        }
+        let compressed = match codec {
+            Codec::Zstd => match zstd::bulk::compress(&frame.payload, self.config.zstd_level) {
+                Ok(compressed) => compressed,
+                Err(_) => return frame.clone(),
+            },
+            Codec::Lz4 => lz4_flex::compress_prepend_size(&frame.payload),
+        };
+        if compressed.len() >= frame.payload.len() {
+            return frame.clone();
+        }
+        Frame {
+            kind: frame.kind,
+            flags: frame.flags | codec.flag(),
+            payload: compressed,
+        }
+    }
+
+    /// Undo `compress` on a frame that came in
+    pub fn decompress(&self, frame: Frame) -> Result<Frame, String> {
+        let Some(codec) = Codec::from_flags(frame.flags)? else {
+            return Ok(frame);
+        };
+        if !self.config.codecs.contains(&codec) {
+            return Err(format!(
+                "Frame compressed with {:?}, which we didn't offer",
+                codec
+            ));
+        }
+        let max = self.config.max_decompressed;
+        let payload = match codec {
+            Codec::Zstd => zstd::bulk::decompress(&frame.payload, max)
+                .map_err(|e| format!("Bad zstd payload: {}", e))?,
+            Codec::Lz4 => {
+                let Some(size) = frame.payload.get(..4) else {
+                    return Err("Truncated lz4 payload".to_string());
+                };
+                let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+                if size > max {
+                    return Err(format!("lz4 payload decompresses to {} bytes", size));
+                }
+                lz4_flex::decompress_size_prepended(&frame.payload)
+                    .map_err(|e| format!("Bad lz4 payload: {}", e))?
+            }
+        };
+        Ok(Frame {
+            kind: frame.kind,
+            flags: frame.flags & !CODEC_FLAGS,
+            payload,
+        })
+    }
+}
+
+/// Whether the frame's payload is compressed, whatever the codec
+pub fn is_compressed(frame: &Frame) -> bool {
+    frame.flags & CODEC_FLAGS != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large() -> Frame {
+        Frame::data(&vec![b'x'; 4096])
+    }
+
+    #[test]
+    fn compresses_once_the_peer_said_hello() {
+        let mut ours = Compression::new(CompressionConfig::default());
+        let theirs = Compression::new(CompressionConfig::default());
+        assert!(!is_compressed(&ours.compress("b", &large())));
+
+        assert_eq!(ours.on_hello("b", &theirs.hello()), Ok(Some(Codec::Zstd)));
+        let sent = ours.compress("b", &large());
+        assert!(is_compressed(&sent));
+        assert_eq!(theirs.decompress(sent), Ok(large()));
+    }
+
+    #[test]
+    fn disabling_stops_both_sides() {
+        let mut ours = Compression::new(CompressionConfig::default());
+        let mut theirs = Compression::new(CompressionConfig::default());
+        ours.on_hello("b", &theirs.hello()).unwrap();
+        theirs.on_hello("a", &ours.hello()).unwrap();
+        let in_flight = theirs.compress("a", &large());
+
+        ours.disable();
+        assert!(!is_compressed(&ours.compress("b", &large())));
+        assert_eq!(theirs.on_hello("a", &ours.hello()), Ok(None));
+        assert!(!is_compressed(&theirs.compress("a", &large())));
+        // Sent before our Hello got there
+        assert_eq!(ours.decompress(in_flight), Ok(large()));
+        // A Hello after disabling doesn't turn it back on
+        assert_eq!(ours.on_hello("b", &theirs.hello()), Ok(None));
+    }
+}
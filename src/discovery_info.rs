@@ -0,0 +1,189 @@
+// Discovery info advertised alongside our peer.
+//
+// MPC hands the dictionary to Bonjour, which puts every entry into the TXT
+// record as `key=value`. Bonjour limits each entry to 255 bytes and Apple
+// recommends keeping the whole record small, so we validate up front instead
+// of letting `initWithPeer:discoveryInfo:serviceType:` throw.
+
+use std::collections::BTreeMap;
+
+/// Maximum length of a single `key=value` TXT entry
+pub const MAX_ENTRY_LEN: usize = 255;
+/// Maximum size of the whole TXT record we are willing to advertise
+pub const MAX_TOTAL_LEN: usize = 400;
+
+/// Bonjour service type for `service_name`. It has to be at most 15
+/// characters of letters, numbers and hyphens, anything else is dropped.
+pub fn service_type(service_name: &str) -> String {
+    format!("iroh-{}", service_name)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .take(15)
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiscoveryInfo {
+    entries: BTreeMap<String, String>,
+}
+
+impl DiscoveryInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if key.is_empty() {
+            return Err("Discovery info key must not be empty".to_string());
+        }
+        if key.contains('=') {
+            return Err(format!("Discovery info key {:?} must not contain '='", key));
+        }
+        if key.len() + 1 + value.len() > MAX_ENTRY_LEN {
+            return Err(format!(
+                "Discovery info entry {:?} is longer than {} bytes",
+                key, MAX_ENTRY_LEN
+            ));
+        }
+
+        let previous = self.entries.insert(key.to_string(), value.to_string());
+        if self.encoded_len() > MAX_TOTAL_LEN {
+            // Put things back the way they were
+            match previous {
+                Some(previous) => self.entries.insert(key.to_string(), previous),
+                None => self.entries.remove(key),
+            };
+            return Err(format!(
+                "Discovery info would exceed {} bytes",
+                MAX_TOTAL_LEN
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> Result<Self, String> {
+        self.insert(key, value)?;
+        Ok(self)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|v| v.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Size of the TXT record, each entry prefixed by its length byte
+    pub fn encoded_len(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(k, v)| 1 + k.len() + 1 + v.len())
+            .sum()
+    }
+
+    /// The entries laid out like a TXT record, for places where there is no
+    /// dictionary to put them in, such as invitation contexts
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        for (key, value) in &self.entries {
+            out.push((key.len() + 1 + value.len()) as u8);
+            out.extend_from_slice(key.as_bytes());
+            out.push(b'=');
+            out.extend_from_slice(value.as_bytes());
+        }
+        out
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, String> {
+        let mut info = Self::new();
+        while let Some((&len, rest)) = bytes.split_first() {
+            let Some((entry, rest)) = rest.split_at_checked(len as usize) else {
+                return Err("Truncated discovery info entry".to_string());
+            };
+            let entry = std::str::from_utf8(entry)
+                .map_err(|_| "Discovery info entry is not UTF-8".to_string())?;
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Discovery info entry {:?} has no '='", entry))?;
+            info.insert(key, value)?;
+            bytes = rest;
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_limited_to_255_bytes() {
+        let mut info = DiscoveryInfo::new();
+        // "k=" plus the value
+        info.insert("k", &"v".repeat(253)).unwrap();
+        assert_eq!(info.encoded_len(), 256);
+        assert!(info.insert("k", &"v".repeat(254)).is_err());
+        assert_eq!(info.get("k").map(str::len), Some(253));
+    }
+
+    #[test]
+    fn inserts_over_the_total_are_undone() {
+        let mut info = DiscoveryInfo::new();
+        info.insert("a", &"x".repeat(200)).unwrap();
+        info.insert("b", "small").unwrap();
+
+        assert!(info.insert("b", &"y".repeat(200)).is_err());
+        assert_eq!(info.get("b"), Some("small"));
+        assert!(info.insert("c", &"z".repeat(200)).is_err());
+        assert_eq!(info.get("c"), None);
+        assert!(info.encoded_len() <= MAX_TOTAL_LEN);
+    }
+
+    #[test]
+    fn keys_must_be_usable_in_a_txt_record() {
+        let mut info = DiscoveryInfo::new();
+        assert!(info.insert("", "value").is_err());
+        assert!(info.insert("a=b", "value").is_err());
+        assert!(info.is_empty());
+        // Values may contain '=', only the first one splits
+        info.insert("a", "b=c").unwrap();
+        assert_eq!(DiscoveryInfo::decode(&info.encode()).unwrap(), info);
+    }
+
+    #[test]
+    fn encoded_info_decodes() {
+        let info = DiscoveryInfo::new()
+            .with("role", "relay")
+            .unwrap()
+            .with("id", "")
+            .unwrap();
+        let bytes = info.encode();
+        assert_eq!(bytes.len(), info.encoded_len());
+        assert_eq!(DiscoveryInfo::decode(&bytes).unwrap(), info);
+        assert!(DiscoveryInfo::decode(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_info_is_refused() {
+        // Length byte past the end
+        assert!(DiscoveryInfo::decode(&[5, b'a', b'=', b'b']).is_err());
+        assert!(DiscoveryInfo::decode(&[3, b'a', b'=', 0xff]).is_err());
+        assert!(DiscoveryInfo::decode(&[3, b'a', b'b', b'c']).is_err());
+    }
+
+    #[test]
+    fn service_types_fit_bonjour() {
+        assert_eq!(service_type("chat"), "iroh-chat");
+        assert_eq!(service_type("play_ground.demo"), "iroh-playground");
+        assert_eq!(service_type("a-very-long-service-name").len(), 15);
+    }
+}
@@ -0,0 +1,97 @@
+// Per-frame dispatch shared by the MPC session and the harness's virtual
+// peers, so scenarios exercise the decisions the session actually makes.
+//
+// The caller owns the protocol layers and exposes them through `PeerLayers`;
+// this module decides which layer a frame goes to, and what a goodbye or a
+// dropped connection means for each of them.
+
+use log::debug;
+
+use crate::frame::{Frame, FrameKind};
+use crate::metrics::DisconnectReason;
+
+/// The protocol layers frames from one peer are handed to
+pub trait PeerLayers {
+    /// Hand a payload to the application
+    fn deliver(&mut self, peer: &str, payload: Vec<u8>);
+    /// Feed a `Reliable` or `Ack` frame to the peer's channel and send the
+    /// acks it produces. Returns the payload to deliver, if any.
+    fn acked(&mut self, peer: &str, frame: &Frame) -> Option<Vec<u8>>;
+    fn mesh(&mut self, peer: &str, frame: &Frame);
+    fn gossip(&mut self, peer: &str, frame: &Frame);
+    /// `Handshake`, `Secure` and `Pair` frames, for whoever runs the secure
+    /// channel
+    fn secure(&mut self, peer: &str, frame: &Frame);
+    fn hello(&mut self, peer: &str, frame: &Frame);
+    /// Take the peer off the mesh and gossip neighbor lists
+    fn neighbor_down(&mut self, peer: &str);
+    /// Remember that the peer left on purpose. False if it already had.
+    fn mark_departed(&mut self, peer: &str) -> bool;
+    /// Forget a departure, returning whether there was one
+    fn clear_departed(&mut self, peer: &str) -> bool;
+    /// Forget that the peer is connected, returning whether it was
+    fn take_connected(&mut self, peer: &str) -> bool;
+    /// Our invitation to the peer failed before it ever connected
+    fn invite_failed(&mut self, peer: &str);
+    /// Fail everything in flight to the peer and drop what is queued for it
+    fn release(&mut self, peer: &str, reason: DisconnectReason);
+    /// Let the reconnect supervisor try to get the peer back
+    fn reconnect_later(&mut self, peer: &str);
+    /// Stop trying to reconnect to the peer
+    fn forget(&mut self, peer: &str);
+    /// Tell the application the peer left
+    fn left(&mut self, peer: &str);
+}
+
+/// Route a decoded frame from `peer` to the layer that handles it
+pub fn on_frame(layers: &mut impl PeerLayers, peer: &str, frame: Frame) {
+    match frame.kind {
+        FrameKind::Data => layers.deliver(peer, frame.payload),
+        FrameKind::Reliable | FrameKind::Ack => {
+            if let Some(payload) = layers.acked(peer, &frame) {
+                layers.deliver(peer, payload);
+            }
+        }
+        FrameKind::Route | FrameKind::Relay => layers.mesh(peer, &frame),
+        FrameKind::Gossip | FrameKind::GossipDigest | FrameKind::GossipRequest => {
+            layers.gossip(peer, &frame)
+        }
+        FrameKind::Handshake | FrameKind::Secure | FrameKind::Pair => layers.secure(peer, &frame),
+        FrameKind::Hello => layers.hello(peer, &frame),
+        FrameKind::Goodbye => {
+            debug!("Peer {} said goodbye", peer);
+            depart(layers, peer, DisconnectReason::Goodbye);
+        }
+    }
+}
+
+/// The peer is gone before its connection is: it said goodbye or we kicked
+/// it. Its NotConnected later on isn't reported a second time.
+pub fn depart(layers: &mut impl PeerLayers, peer: &str, reason: DisconnectReason) {
+    if !layers.mark_departed(peer) {
+        return;
+    }
+    layers.neighbor_down(peer);
+    layers.release(peer, reason);
+    // It left on purpose, nothing to reconnect to
+    layers.forget(peer);
+    layers.left(peer);
+}
+
+/// The connection to the peer went down, or it never came up. Only peers
+/// that were connected leave, and only those are reconnected.
+pub fn on_disconnected(layers: &mut impl PeerLayers, peer: &str) {
+    if !layers.take_connected(peer) {
+        debug!("Invitation to {} failed", peer);
+        layers.invite_failed(peer);
+        return;
+    }
+    layers.neighbor_down(peer);
+    if layers.clear_departed(peer) {
+        debug!("Peer {} already left", peer);
+        return;
+    }
+    layers.release(peer, DisconnectReason::Dropped);
+    layers.reconnect_later(peer);
+    layers.left(peer);
+}
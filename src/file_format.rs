@@ -0,0 +1,119 @@
+// Pieces shared by the small binary files kept on disk: the address book,
+// the outbox and captures. Integers are big endian, strings carry a length
+// byte.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cursor over a file's bytes. Errors name the kind of file, as in
+/// "Truncated outbox file".
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        Self { bytes, what }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(format!("Truncated {}", self.what));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    // Length byte followed by UTF-8
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| format!("String in {} is not UTF-8", self.what))
+    }
+
+    /// Seconds since the epoch, as written by `unix_secs`
+    pub(crate) fn time(&mut self) -> Result<SystemTime, String> {
+        let secs = self.u64()?;
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(secs))
+            .ok_or_else(|| format!("Bad timestamp {} in {}", secs, self.what))
+    }
+}
+
+/// Write `bytes` to a temporary file first and move it over `path`, so a
+/// crash never leaves half a file
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Fresh path in the temp dir for a test's file, whatever an earlier run
+/// left there is removed
+#[cfg(test)]
+pub(crate) fn scratch(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_was_written() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut bytes = vec![7];
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&unix_secs(now).to_be_bytes());
+        bytes.extend_from_slice(b"\x02hi");
+
+        let mut reader = Reader::new(&bytes, "test file");
+        assert_eq!(reader.u8(), Ok(7));
+        assert_eq!(reader.u32(), Ok(3));
+        assert_eq!(reader.time(), Ok(now));
+        assert_eq!(reader.string().as_deref(), Ok("hi"));
+        assert!(reader.is_empty());
+        assert_eq!(reader.u8(), Err("Truncated test file".to_string()));
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        let mut reader = Reader::new(b"\x01\xff", "test file");
+        assert!(reader.string().is_err());
+
+        let bytes = u64::MAX.to_be_bytes();
+        let mut reader = Reader::new(&bytes, "test file");
+        assert!(reader.time().is_err());
+    }
+}
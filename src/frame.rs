@@ -0,0 +1,170 @@
+// Wire framing shared by everything that goes over `sendData`.
+//
+// Every payload we send is prefixed with a small header so that the receiving
+// side can tell application data apart from control traffic (goodbye, ...).
+// Payloads that don't start with `MAGIC` are treated as legacy raw data so
+// older peers that send plain bytes keep working. Only the first byte tells the
+// two apart: a legacy payload that happens to start with `MAGIC` is taken for a
+// frame, and misread or rejected. Peers that still send raw bytes must not
+// start them with 0xA7. The top two flag bits say how the payload is
+// compressed, see `compression`; the rest belong to the frame kind.
+
+use std::fmt;
+
+pub const MAGIC: u8 = 0xA7;
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Application payload, handed to the data callback untouched
+    Data = 0,
+    /// Sent by a peer right before it tears its session down
+    Goodbye = 1,
+    /// Sequenced payload of the acknowledged delivery layer
+    Reliable = 2,
+    /// Selective acknowledgement for `Reliable` frames
+    Ack = 3,
+    /// Distance-vector advertisement of the mesh layer
+    Route = 4,
+    /// Mesh payload travelling towards a node that may be several hops away
+    Relay = 5,
+    /// Broadcast message of the gossip layer
+    Gossip = 6,
+    /// Ids of gossip messages the sender has
+    GossipDigest = 7,
+    /// Ids of gossip messages the sender is missing
+    GossipRequest = 8,
+    /// Noise handshake message of the secure channel
+    Handshake = 9,
+    /// Another frame, encrypted by the secure channel
+    Secure = 10,
+    /// Pairing decision, only ever sent inside `Secure` frames
+    Pair = 11,
+    /// Sent right after connecting, lists the codecs the sender decodes
+    Hello = 12,
+}
+
+impl FrameKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(FrameKind::Data),
+            1 => Some(FrameKind::Goodbye),
+            2 => Some(FrameKind::Reliable),
+            3 => Some(FrameKind::Ack),
+            4 => Some(FrameKind::Route),
+            5 => Some(FrameKind::Relay),
+            6 => Some(FrameKind::Gossip),
+            7 => Some(FrameKind::GossipDigest),
+            8 => Some(FrameKind::GossipRequest),
+            9 => Some(FrameKind::Handshake),
+            10 => Some(FrameKind::Secure),
+            11 => Some(FrameKind::Pair),
+            12 => Some(FrameKind::Hello),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+// Manual Debug implementation so large payloads don't flood the logs
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("kind", &self.kind)
+            .field("flags", &self.flags)
+            .field("payload_len", &self.payload.len())
+            .finish()
+    }
+}
+
+impl Frame {
+    pub fn new(kind: FrameKind, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            flags: 0,
+            payload,
+        }
+    }
+
+    pub fn data(payload: &[u8]) -> Self {
+        Self::new(FrameKind::Data, payload.to_vec())
+    }
+
+    pub fn goodbye() -> Self {
+        Self::new(FrameKind::Goodbye, Vec::new())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.push(MAGIC);
+        out.push(VERSION);
+        out.push(self.kind as u8);
+        out.push(self.flags);
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    /// Decode a frame received from a peer.
+    ///
+    /// Bytes without our magic prefix are wrapped as a `Data` frame so that
+    /// peers which predate the framing are still understood. Raw bytes that
+    /// start with the prefix are decoded as a frame, see the module comment.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.first() != Some(&MAGIC) {
+            return Ok(Self::data(bytes));
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(format!("Truncated frame header ({} bytes)", bytes.len()));
+        }
+        if bytes[1] != VERSION {
+            return Err(format!("Unsupported frame version {}", bytes[1]));
+        }
+        let kind = FrameKind::from_u8(bytes[2])
+            .ok_or_else(|| format!("Unknown frame kind {}", bytes[2]))?;
+
+        Ok(Self {
+            kind,
+            flags: bytes[3],
+            payload: bytes[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_bytes_are_legacy_data() {
+        let frame = Frame::decode(b"hello").unwrap();
+        assert_eq!(frame, Frame::data(b"hello"));
+        assert_eq!(Frame::decode(&[]).unwrap(), Frame::data(&[]));
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut frame = Frame::new(FrameKind::Ack, vec![1, 2, 3]);
+        frame.flags = 0x41;
+        assert_eq!(Frame::decode(&frame.encode()).unwrap(), frame);
+    }
+
+    // The documented limitation: raw bytes starting with MAGIC aren't
+    // legacy data any more
+    #[test]
+    fn raw_bytes_starting_with_magic_are_taken_for_frames() {
+        assert!(Frame::decode(&[MAGIC, 0x20, 0x21]).is_err());
+        assert!(Frame::decode(&[MAGIC, 9, 0, 0]).is_err());
+        assert!(Frame::decode(&[MAGIC, VERSION, 200, 0]).is_err());
+
+        let frame = Frame::decode(&[MAGIC, VERSION, 1, 0, b'x']).unwrap();
+        assert_eq!(frame.kind, FrameKind::Goodbye);
+    }
+}
@@ -0,0 +1,587 @@
+// Epidemic broadcast over direct connections.
+//
+// A broadcast is pushed eagerly to a random subset of neighbors (the fanout)
+// and announced lazily to the rest with a digest of its id. A neighbor that
+// hears about an id but doesn't receive the message itself within
+// `want_delay` asks for it, so a message spreads through the whole connected
+// mesh even with a small fanout. Every node keeps the most recent messages
+// around and offers their ids to a peer as soon as it connects, which fills
+// in whatever it missed while it was away (anti-entropy).
+//
+// Duplicates are recognised by message id. Ids are remembered in a bounded
+// seen-set, and a message stops being forwarded after `max_hops`.
+//
+// Pure state machine again: report neighbors, feed frames and the current
+// time, and send whatever `poll` returns to the named neighbor.
+//
+// Gossip frame payload: [id: u64][hops: u8][origin len: u8][origin][data]
+// Digest and request payload: [count: u16][id: u64*]
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::frame::{Frame, FrameKind};
+use crate::mesh::NodeId;
+
+pub type MessageId = u64;
+
+/// Most ids a single digest or request frame carries
+const MAX_IDS_PER_FRAME: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    /// Neighbors a message is pushed to right away, the rest only get its id
+    pub fanout: usize,
+    /// Hops after which a message is no longer forwarded
+    pub max_hops: u8,
+    /// How long to wait for a pushed copy before asking for an announced one
+    pub want_delay: Duration,
+    /// Announced messages waiting to be asked for, further ids are ignored
+    pub want_capacity: usize,
+    /// Message ids remembered for duplicate suppression
+    pub seen_capacity: usize,
+    /// Recent messages kept to answer requests and catch up new peers
+    pub store_capacity: usize,
+    /// Messages older than this are no longer offered to new peers
+    pub store_for: Duration,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            fanout: 3,
+            max_hops: 16,
+            want_delay: Duration::from_millis(150),
+            want_capacity: 1024,
+            seen_capacity: 4096,
+            store_capacity: 256,
+            store_for: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GossipMessage {
+    pub id: MessageId,
+    pub origin: NodeId,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GossipStats {
+    pub broadcast: u64,
+    pub delivered: u64,
+    pub duplicates: u64,
+    /// Messages pulled after only hearing their id
+    pub requested: u64,
+}
+
+#[derive(Debug)]
+struct Stored {
+    origin: NodeId,
+    data: Vec<u8>,
+    hops: u8,
+    stored_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct Gossip {
+    local: NodeId,
+    config: GossipConfig,
+    rng: StdRng,
+    neighbors: Vec<NodeId>,
+    seen: HashSet<MessageId>,
+    seen_order: VecDeque<MessageId>,
+    store: BTreeMap<MessageId, Stored>,
+    store_order: VecDeque<MessageId>,
+    // Announced but not yet received: when to ask, and whom
+    wanted: HashMap<MessageId, (Instant, NodeId)>,
+    outgoing: Vec<(NodeId, Frame)>,
+    stats: GossipStats,
+}
+
+impl Gossip {
+    pub fn new(local: &str, config: GossipConfig, seed: u64) -> Self {
+        Self {
+            local: local.to_string(),
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            neighbors: Vec::new(),
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            store: BTreeMap::new(),
+            store_order: VecDeque::new(),
+            wanted: HashMap::new(),
+            outgoing: Vec::new(),
+            stats: GossipStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> GossipStats {
+        self.stats
+    }
+
+    /// A peer connected, offer it everything we still have
+    pub fn neighbor_up(&mut self, peer: &str, now: Instant) {
+        if peer == self.local || self.neighbors.iter().any(|n| n == peer) {
+            return;
+        }
+        self.neighbors.push(peer.to_string());
+
+        self.expire(now);
+        let ids: Vec<MessageId> = self.store.keys().copied().collect();
+        for chunk in ids.chunks(MAX_IDS_PER_FRAME) {
+            let frame = encode_ids(FrameKind::GossipDigest, chunk);
+            self.outgoing.push((peer.to_string(), frame));
+        }
+    }
+
+    pub fn neighbor_down(&mut self, peer: &str) {
+        self.neighbors.retain(|n| n != peer);
+        // Ask somebody else, or drop the want once nobody is left to ask
+        let fallback = self.neighbors.first().cloned();
+        self.wanted.retain(|_, (_, from)| match &fallback {
+            Some(other) if from == peer => {
+                *from = other.clone();
+                true
+            }
+            Some(_) => true,
+            None => false,
+        });
+    }
+
+    /// Broadcast `data` to every node in the connected mesh
+    pub fn broadcast(&mut self, data: &[u8], now: Instant) -> MessageId {
+        let mut id: MessageId = self.rng.r#gen();
+        while self.seen.contains(&id) {
+            id = self.rng.r#gen();
+        }
+        self.remember(id);
+        let local = self.local.clone();
+        self.store(id, &local, data, 0, now);
+        self.spread(id, None);
+        self.stats.broadcast += 1;
+        id
+    }
+
+    /// Handle a gossip frame from the neighbor `from`. Returns a message we
+    /// haven't seen before.
+    pub fn on_frame(
+        &mut self,
+        from: &str,
+        frame: &Frame,
+        now: Instant,
+    ) -> Result<Option<GossipMessage>, String> {
+        match frame.kind {
+            FrameKind::Gossip => self.on_gossip(from, &frame.payload, now),
+            FrameKind::GossipDigest => {
+                let ids = decode_ids(&frame.payload)?;
+                // Only neighbors get asked, so only they get to announce
+                if !self.neighbors.iter().any(|n| n == from) {
+                    return Ok(None);
+                }
+                for id in ids {
+                    if self.wanted.len() >= self.config.want_capacity {
+                        break;
+                    }
+                    if !self.seen.contains(&id) && !self.wanted.contains_key(&id) {
+                        self.wanted
+                            .insert(id, (now + self.config.want_delay, from.to_string()));
+                    }
+                }
+                Ok(None)
+            }
+            FrameKind::GossipRequest => {
+                for id in decode_ids(&frame.payload)? {
+                    if let Some(stored) = self.store.get(&id) {
+                        let frame = encode_gossip(id, stored.hops, &stored.origin, &stored.data)?;
+                        self.outgoing.push((from.to_string(), frame));
+                    }
+                }
+                Ok(None)
+            }
+            other => Err(format!("Unexpected {:?} frame on gossip", other)),
+        }
+    }
+
+    /// Frames to send, each paired with the neighbor it goes to
+    pub fn poll(&mut self, now: Instant) -> Vec<(NodeId, Frame)> {
+        self.expire(now);
+
+        let mut due: BTreeMap<NodeId, Vec<MessageId>> = BTreeMap::new();
+        self.wanted.retain(|id, (at, from)| {
+            if *at > now {
+                return true;
+            }
+            due.entry(from.clone()).or_default().push(*id);
+            false
+        });
+        for (peer, mut ids) in due {
+            ids.sort_unstable();
+            self.stats.requested += ids.len() as u64;
+            for chunk in ids.chunks(MAX_IDS_PER_FRAME) {
+                let frame = encode_ids(FrameKind::GossipRequest, chunk);
+                self.outgoing.push((peer.clone(), frame));
+            }
+        }
+
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Earliest instant `poll` has something to do
+    pub fn next_timeout(&self) -> Option<Instant> {
+        let want = self.wanted.values().map(|(at, _)| *at).min();
+        let expiry = self
+            .store
+            .values()
+            .map(|stored| stored.stored_at + self.config.store_for)
+            .min();
+        want.into_iter().chain(expiry).min()
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    fn on_gossip(
+        &mut self,
+        from: &str,
+        body: &[u8],
+        now: Instant,
+    ) -> Result<Option<GossipMessage>, String> {
+        let (id, hops, origin, data) = decode_gossip(body)?;
+        self.wanted.remove(&id);
+        if !self.remember(id) {
+            self.stats.duplicates += 1;
+            return Ok(None);
+        }
+
+        let hops = hops.saturating_add(1);
+        self.store(id, &origin, data, hops, now);
+        if hops < self.config.max_hops {
+            self.spread(id, Some(from));
+        }
+
+        self.stats.delivered += 1;
+        Ok(Some(GossipMessage {
+            id,
+            origin,
+            data: data.to_vec(),
+        }))
+    }
+
+    // Push to `fanout` random neighbors, announce to the others
+    fn spread(&mut self, id: MessageId, from: Option<&str>) {
+        let Some(stored) = self.store.get(&id) else {
+            return;
+        };
+        let mut targets: Vec<&NodeId> = self
+            .neighbors
+            .iter()
+            .filter(|n| Some(n.as_str()) != from && **n != stored.origin)
+            .collect();
+        targets.shuffle(&mut self.rng);
+
+        let split = self.config.fanout.min(targets.len());
+        match encode_gossip(id, stored.hops, &stored.origin, &stored.data) {
+            Ok(frame) => {
+                for peer in &targets[..split] {
+                    self.outgoing.push(((*peer).clone(), frame.clone()));
+                }
+            }
+            Err(e) => warn!("Can't gossip message {:016x}: {}", id, e),
+        }
+        for peer in &targets[split..] {
+            let frame = encode_ids(FrameKind::GossipDigest, &[id]);
+            self.outgoing.push(((*peer).clone(), frame));
+        }
+    }
+
+    fn store(&mut self, id: MessageId, origin: &str, data: &[u8], hops: u8, now: Instant) {
+        self.store.insert(
+            id,
+            Stored {
+                origin: origin.to_string(),
+                data: data.to_vec(),
+                hops,
+                stored_at: now,
+            },
+        );
+        self.store_order.push_back(id);
+        while self.store_order.len() > self.config.store_capacity {
+            if let Some(old) = self.store_order.pop_front() {
+                self.store.remove(&old);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(id) = self.store_order.front() {
+            let expired = self.store.get(id).is_none_or(|stored| {
+                now.saturating_duration_since(stored.stored_at) >= self.config.store_for
+            });
+            if !expired {
+                break;
+            }
+            self.store.remove(id);
+            self.store_order.pop_front();
+        }
+    }
+
+    // Returns false if the id was already seen
+    fn remember(&mut self, id: MessageId) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        while self.seen_order.len() > self.config.seen_capacity {
+            if let Some(old) = self.seen_order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+fn encode_gossip(id: MessageId, hops: u8, origin: &str, data: &[u8]) -> Result<Frame, String> {
+    let origin_len =
+        u8::try_from(origin.len()).map_err(|_| format!("Node id too long: {}", origin))?;
+    let mut body = Vec::with_capacity(10 + origin.len() + data.len());
+    body.extend_from_slice(&id.to_be_bytes());
+    body.push(hops);
+    body.push(origin_len);
+    body.extend_from_slice(origin.as_bytes());
+    body.extend_from_slice(data);
+    Ok(Frame::new(FrameKind::Gossip, body))
+}
+
+fn decode_gossip(body: &[u8]) -> Result<(MessageId, u8, NodeId, &[u8]), String> {
+    if body.len() < 10 {
+        return Err(format!("Truncated gossip frame ({} bytes)", body.len()));
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&body[0..8]);
+    let hops = body[8];
+    let origin_len = body[9] as usize;
+    let rest = &body[10..];
+    if rest.len() < origin_len {
+        return Err("Truncated gossip origin".to_string());
+    }
+    let origin = String::from_utf8(rest[..origin_len].to_vec()).map_err(|e| e.to_string())?;
+    Ok((u64::from_be_bytes(id), hops, origin, &rest[origin_len..]))
+}
+
+fn encode_ids(kind: FrameKind, ids: &[MessageId]) -> Frame {
+    let mut body = Vec::with_capacity(2 + ids.len() * 8);
+    body.extend_from_slice(&(ids.len() as u16).to_be_bytes());
+    for id in ids {
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+    Frame::new(kind, body)
+}
+
+fn decode_ids(body: &[u8]) -> Result<Vec<MessageId>, String> {
+    if body.len() < 2 {
+        return Err(format!("Truncated id list ({} bytes)", body.len()));
+    }
+    let count = u16::from_be_bytes([body[0], body[1]]) as usize;
+    if body.len() < 2 + count * 8 {
+        return Err(format!("Id list announces {} ids but is too short", count));
+    }
+    Ok(body[2..2 + count * 8]
+        .chunks_exact(8)
+        .map(|chunk| {
+            let mut id = [0u8; 8];
+            id.copy_from_slice(chunk);
+            u64::from_be_bytes(id)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::testing::{self, settle};
+
+    impl testing::Overlay for Gossip {
+        fn neighbor_up(&mut self, peer: &str, now: Instant) {
+            Gossip::neighbor_up(self, peer, now)
+        }
+
+        fn poll(&mut self, now: Instant) -> Vec<(NodeId, Frame)> {
+            Gossip::poll(self, now)
+        }
+
+        fn on_frame(
+            &mut self,
+            from: &str,
+            frame: &Frame,
+            now: Instant,
+        ) -> Option<(NodeId, Vec<u8>)> {
+            let message = Gossip::on_frame(self, from, frame, now).unwrap()?;
+            Some((message.origin, message.data))
+        }
+    }
+
+    fn config(fanout: usize) -> GossipConfig {
+        GossipConfig {
+            fanout,
+            ..GossipConfig::default()
+        }
+    }
+
+    // Nodes linked in a line, in order
+    fn line(names: &[&str], config: GossipConfig, now: Instant) -> BTreeMap<NodeId, Gossip> {
+        testing::line(names, now, |i, name| {
+            Gossip::new(name, config.clone(), i as u64)
+        })
+    }
+
+    #[test]
+    fn broadcasts_reach_every_node_once() {
+        let now = Instant::now();
+        let mut nodes = line(&["a", "b", "c", "d"], config(3), now);
+        settle(&mut nodes, now);
+
+        nodes.get_mut("a").unwrap().broadcast(b"hi", now);
+        let mut delivered = settle(&mut nodes, now);
+        delivered.sort();
+        let expected: Vec<(NodeId, NodeId, Vec<u8>)> = ["b", "c", "d"]
+            .iter()
+            .map(|node| (node.to_string(), "a".to_string(), b"hi".to_vec()))
+            .collect();
+        assert_eq!(delivered, expected);
+        assert_eq!(nodes["a"].stats().broadcast, 1);
+    }
+
+    #[test]
+    fn announced_messages_are_requested_after_the_delay() {
+        let now = Instant::now();
+        let mut nodes = line(&["a", "b"], config(0), now);
+        settle(&mut nodes, now);
+
+        nodes.get_mut("a").unwrap().broadcast(b"hi", now);
+        assert!(settle(&mut nodes, now).is_empty());
+
+        let later = now + GossipConfig::default().want_delay;
+        assert_eq!(nodes["b"].next_timeout(), Some(later));
+        let delivered = settle(&mut nodes, later);
+        assert_eq!(
+            delivered,
+            [("b".to_string(), "a".to_string(), b"hi".to_vec())]
+        );
+        assert_eq!(nodes["b"].stats().requested, 1);
+    }
+
+    #[test]
+    fn new_neighbors_catch_up_on_stored_messages() {
+        let now = Instant::now();
+        let mut nodes = line(&["a"], config(3), now);
+        nodes.get_mut("a").unwrap().broadcast(b"early", now);
+        settle(&mut nodes, now);
+
+        nodes.insert("b".to_string(), Gossip::new("b", config(3), 1));
+        nodes.get_mut("a").unwrap().neighbor_up("b", now);
+        nodes.get_mut("b").unwrap().neighbor_up("a", now);
+        settle(&mut nodes, now);
+
+        let later = now + GossipConfig::default().want_delay;
+        let delivered = settle(&mut nodes, later);
+        assert_eq!(
+            delivered,
+            [("b".to_string(), "a".to_string(), b"early".to_vec())]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_counted_not_delivered() {
+        let now = Instant::now();
+        let mut a = Gossip::new("a", config(3), 0);
+        let mut b = Gossip::new("b", config(3), 1);
+        a.neighbor_up("b", now);
+        b.neighbor_up("a", now);
+
+        a.broadcast(b"hi", now);
+        let (_, frame) = a.poll(now).pop().unwrap();
+        assert!(b.on_frame("a", &frame, now).unwrap().is_some());
+        assert!(b.on_frame("a", &frame, now).unwrap().is_none());
+        assert_eq!(b.stats().duplicates, 1);
+    }
+
+    #[test]
+    fn messages_stop_after_max_hops() {
+        let now = Instant::now();
+        let limited = GossipConfig {
+            max_hops: 2,
+            ..config(3)
+        };
+        let mut nodes = line(&["a", "b", "c", "d"], limited, now);
+        settle(&mut nodes, now);
+
+        nodes.get_mut("a").unwrap().broadcast(b"hi", now);
+        let mut reached: Vec<NodeId> = settle(&mut nodes, now)
+            .into_iter()
+            .map(|(node, _, _)| node)
+            .collect();
+        reached.sort();
+        assert_eq!(reached, ["b", "c"]);
+    }
+
+    #[test]
+    fn stored_messages_expire() {
+        let now = Instant::now();
+        let mut a = Gossip::new("a", config(3), 0);
+        a.broadcast(b"hi", now);
+        let later = now + GossipConfig::default().store_for;
+        assert_eq!(a.next_timeout(), Some(later));
+
+        a.poll(later);
+        a.neighbor_up("b", later);
+        assert!(a.poll(later).is_empty());
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let now = Instant::now();
+        let mut a = Gossip::new("a", config(3), 0);
+        for kind in [
+            FrameKind::Gossip,
+            FrameKind::GossipDigest,
+            FrameKind::GossipRequest,
+        ] {
+            assert!(a.on_frame("b", &Frame::new(kind, vec![0]), now).is_err());
+        }
+        let short = Frame::new(FrameKind::GossipDigest, vec![0, 2, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(a.on_frame("b", &short, now).is_err());
+    }
+
+    #[test]
+    fn digests_only_count_from_neighbors_and_up_to_the_cap() {
+        let now = Instant::now();
+        let later = now + GossipConfig::default().want_delay;
+        let config = GossipConfig {
+            want_capacity: 2,
+            ..GossipConfig::default()
+        };
+        let mut a = Gossip::new("a", config, 0);
+        let digest = encode_ids(FrameKind::GossipDigest, &[1, 2, 3]);
+
+        a.on_frame("b", &digest, now).unwrap();
+        assert!(a.poll(later).is_empty());
+
+        a.neighbor_up("b", now);
+        a.on_frame("b", &digest, now).unwrap();
+        let requests = a.poll(later);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(decode_ids(&requests[0].1.payload).unwrap(), [1, 2]);
+    }
+}
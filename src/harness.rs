@@ -0,0 +1,1004 @@
+// Discrete-event harness for multi-peer scenarios.
+//
+// Spins up virtual peers on top of the simulated network and runs the same
+// protocol logic the MPC session uses (framing, goodbye handling, reconnect
+// supervisor, acknowledged delivery, mesh relay, gossip) on the virtual clock.
+// Incoming frames go through the session's own `dispatch`. A scenario drives
+// it by advancing time and injecting events, then checks the transcript of
+// what each peer's application would have seen.
+//
+// Scenarios are run per seed with `check_seeds`. A failure names the seed,
+// and setting HARNESS_SEED to it replays exactly that run.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::dispatch::{self, PeerLayers};
+use crate::frame::{Frame, FrameKind};
+use crate::gossip::{Gossip, GossipConfig, GossipMessage, MessageId};
+use crate::mesh::{Mesh, MeshConfig, MeshEvent};
+use crate::metrics::DisconnectReason;
+use crate::reconnect::{ReconnectEvent, ReconnectPolicy, ReconnectSupervisor};
+use crate::reliable::{ReliableChannel, ReliableConfig, ReliableEvent};
+use crate::sim::{PeerState, SimConfig, SimEvent, SimEventKind, SimNetwork};
+
+/// Environment variable that pins `seeds` to a single value
+pub const SEED_ENV: &str = "HARNESS_SEED";
+
+/// Longest step taken at once, so peer timers are polled regularly
+const MAX_STEP: Duration = Duration::from_millis(50);
+
+/// What the application on a virtual peer observes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Found(String),
+    Lost(String),
+    Joined(String),
+    Left(String),
+    Data {
+        peer: String,
+        bytes: Vec<u8>,
+    },
+    Reconnect(ReconnectEvent<String>),
+    Delivery {
+        peer: String,
+        event: ReliableEvent,
+    },
+    /// Data that came in over the mesh, possibly relayed
+    MeshData {
+        source: String,
+        bytes: Vec<u8>,
+    },
+    Mesh(MeshEvent),
+    /// A broadcast that reached this peer for the first time
+    Gossip(GossipMessage),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HarnessEvent {
+    pub at: Duration,
+    pub device: String,
+    pub event: PeerEvent,
+}
+
+impl fmt::Display for HarnessEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>10.3?} {:<8} {:?}", self.at, self.device, self.event)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerConfig {
+    /// Run the reconnect supervisor on this peer
+    pub reconnect: Option<ReconnectPolicy>,
+    pub reliable: ReliableConfig,
+    /// Join the mesh relay
+    pub mesh: Option<MeshConfig>,
+    /// Take part in gossip broadcasts
+    pub gossip: Option<GossipConfig>,
+}
+
+// Things a virtual peer wants done on the network
+enum Command {
+    Send {
+        to: String,
+        bytes: Vec<u8>,
+        reliable: bool,
+    },
+    Invite {
+        to: String,
+    },
+}
+
+struct VirtualPeer {
+    config: PeerConfig,
+    reconnect: Option<ReconnectSupervisor<String>>,
+    channels: BTreeMap<String, ReliableChannel>,
+    mesh: Option<Mesh>,
+    gossip: Option<Gossip>,
+    // Peers that already said goodbye, so their NotConnected isn't reported twice
+    departed: HashSet<String>,
+    connected: HashSet<String>,
+    found: HashSet<String>,
+    // Shut down, ignores everything from here on
+    stopped: bool,
+}
+
+impl VirtualPeer {
+    fn new(name: &str, config: PeerConfig, seed: u64, sim: &SimNetwork) -> Self {
+        let reconnect = config
+            .reconnect
+            .clone()
+            .map(|policy| ReconnectSupervisor::new(policy, seed));
+        let mesh = config
+            .mesh
+            .clone()
+            .map(|mesh| Mesh::new(name, mesh, seed, sim.now()));
+        let gossip = config
+            .gossip
+            .clone()
+            .map(|gossip| Gossip::new(name, gossip, seed));
+        Self {
+            config,
+            reconnect,
+            channels: BTreeMap::new(),
+            mesh,
+            gossip,
+            departed: HashSet::new(),
+            connected: HashSet::new(),
+            found: HashSet::new(),
+            stopped: false,
+        }
+    }
+
+    fn channel(&mut self, peer: &str) -> &mut ReliableChannel {
+        let config = &self.config.reliable;
+        self.channels
+            .entry(peer.to_string())
+            .or_insert_with(|| ReliableChannel::new(config.clone()))
+    }
+
+    fn handle(
+        &mut self,
+        kind: SimEventKind,
+        sim: &SimNetwork,
+        out: &mut Vec<PeerEvent>,
+        commands: &mut Vec<Command>,
+    ) {
+        if self.stopped {
+            return;
+        }
+
+        let now = sim.now();
+        match kind {
+            SimEventKind::PeerFound { peer } => {
+                self.found.insert(peer.clone());
+                if let Some(supervisor) = &mut self.reconnect {
+                    supervisor.on_found(&peer, now);
+                }
+                out.push(PeerEvent::Found(peer));
+            }
+            SimEventKind::PeerLost { peer } => {
+                self.found.remove(&peer);
+                if let Some(supervisor) = &mut self.reconnect {
+                    supervisor.on_lost(&peer, now);
+                }
+                out.push(PeerEvent::Lost(peer));
+            }
+            SimEventKind::StateChanged { peer, state } => match state {
+                PeerState::Connected => {
+                    self.departed.remove(&peer);
+                    self.connected.insert(peer.clone());
+                    if let Some(supervisor) = &mut self.reconnect {
+                        supervisor.on_connected(&peer);
+                    }
+                    if let Some(mesh) = &mut self.mesh {
+                        mesh.neighbor_up(&peer, now);
+                    }
+                    if let Some(gossip) = &mut self.gossip {
+                        gossip.neighbor_up(&peer, now);
+                    }
+                    out.push(PeerEvent::Joined(peer));
+                }
+                PeerState::NotConnected => {
+                    let mut layers = Layers {
+                        peer: self,
+                        now,
+                        out,
+                        commands,
+                    };
+                    dispatch::on_disconnected(&mut layers, &peer);
+                }
+                PeerState::Connecting => {}
+            },
+            SimEventKind::Data { peer, bytes, .. } => {
+                let Ok(frame) = Frame::decode(&bytes) else {
+                    return;
+                };
+                let mut layers = Layers {
+                    peer: self,
+                    now,
+                    out,
+                    commands,
+                };
+                dispatch::on_frame(&mut layers, &peer, frame);
+            }
+        }
+    }
+
+    fn poll(&mut self, sim: &SimNetwork, out: &mut Vec<PeerEvent>, commands: &mut Vec<Command>) {
+        if self.stopped {
+            return;
+        }
+
+        let now = sim.now();
+
+        if let Some(supervisor) = &mut self.reconnect {
+            for peer in supervisor.poll(now) {
+                commands.push(Command::Invite { to: peer });
+            }
+            out.extend(
+                supervisor
+                    .drain_events()
+                    .into_iter()
+                    .map(PeerEvent::Reconnect),
+            );
+        }
+
+        if let Some(mesh) = &mut self.mesh {
+            for (to, frame) in mesh.poll(now) {
+                commands.push(Command::Send {
+                    to,
+                    bytes: frame.encode(),
+                    reliable: frame.kind == FrameKind::Relay,
+                });
+            }
+            out.extend(mesh.drain_events().into_iter().map(PeerEvent::Mesh));
+        }
+
+        if let Some(gossip) = &mut self.gossip {
+            for (to, frame) in gossip.poll(now) {
+                commands.push(Command::Send {
+                    to,
+                    bytes: frame.encode(),
+                    reliable: true,
+                });
+            }
+        }
+
+        for (peer, channel) in self.channels.iter_mut() {
+            for frame in channel.poll_transmit(now) {
+                commands.push(Command::Send {
+                    to: peer.clone(),
+                    bytes: frame.encode(),
+                    reliable: false,
+                });
+            }
+            out.extend(
+                channel
+                    .drain_events()
+                    .into_iter()
+                    .map(|event| PeerEvent::Delivery {
+                        peer: peer.clone(),
+                        event,
+                    }),
+            );
+        }
+    }
+
+    fn next_timer(&self, sim: &SimNetwork) -> Option<Duration> {
+        if self.stopped {
+            return None;
+        }
+        let reconnect = self
+            .reconnect
+            .as_ref()
+            .and_then(|supervisor| supervisor.next_deadline());
+        let retransmit = self
+            .channels
+            .values()
+            .filter_map(|channel| channel.next_timeout())
+            .min();
+        let mesh = self.mesh.as_ref().and_then(|mesh| mesh.next_timeout());
+        let gossip = self
+            .gossip
+            .as_ref()
+            .and_then(|gossip| gossip.next_timeout());
+        let base = sim.now() - sim.elapsed();
+        [reconnect, retransmit, mesh, gossip]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|at| at.saturating_duration_since(base))
+    }
+}
+
+// A virtual peer's protocol layers at one point in time, for `dispatch`
+struct Layers<'a> {
+    peer: &'a mut VirtualPeer,
+    now: Instant,
+    out: &'a mut Vec<PeerEvent>,
+    commands: &'a mut Vec<Command>,
+}
+
+impl PeerLayers for Layers<'_> {
+    fn deliver(&mut self, peer: &str, bytes: Vec<u8>) {
+        self.out.push(PeerEvent::Data {
+            peer: peer.to_string(),
+            bytes,
+        });
+    }
+
+    fn acked(&mut self, peer: &str, frame: &Frame) -> Option<Vec<u8>> {
+        let channel = self.peer.channel(peer);
+        let payload = channel.on_frame(frame, self.now).ok().flatten();
+        for frame in channel.poll_transmit(self.now) {
+            self.commands.push(Command::Send {
+                to: peer.to_string(),
+                bytes: frame.encode(),
+                reliable: false,
+            });
+        }
+        payload
+    }
+
+    fn mesh(&mut self, peer: &str, frame: &Frame) {
+        let Some(mesh) = &mut self.peer.mesh else {
+            return;
+        };
+        if let Ok(Some((source, bytes))) = mesh.on_frame(peer, frame, self.now) {
+            self.out.push(PeerEvent::MeshData { source, bytes });
+        }
+    }
+
+    fn gossip(&mut self, peer: &str, frame: &Frame) {
+        let Some(gossip) = &mut self.peer.gossip else {
+            return;
+        };
+        if let Ok(Some(message)) = gossip.on_frame(peer, frame, self.now) {
+            self.out.push(PeerEvent::Gossip(message));
+        }
+    }
+
+    // The harness doesn't run the secure channel or compression
+    fn secure(&mut self, _peer: &str, _frame: &Frame) {}
+
+    fn hello(&mut self, _peer: &str, _frame: &Frame) {}
+
+    fn neighbor_down(&mut self, peer: &str) {
+        if let Some(mesh) = &mut self.peer.mesh {
+            mesh.neighbor_down(peer, self.now);
+        }
+        if let Some(gossip) = &mut self.peer.gossip {
+            gossip.neighbor_down(peer);
+        }
+    }
+
+    fn mark_departed(&mut self, peer: &str) -> bool {
+        self.peer.departed.insert(peer.to_string())
+    }
+
+    fn clear_departed(&mut self, peer: &str) -> bool {
+        self.peer.departed.remove(peer)
+    }
+
+    fn take_connected(&mut self, peer: &str) -> bool {
+        self.peer.connected.remove(peer)
+    }
+
+    fn invite_failed(&mut self, peer: &str) {
+        if let Some(supervisor) = &mut self.peer.reconnect {
+            supervisor.on_invite_failed(&peer.to_string(), self.now);
+        }
+    }
+
+    fn release(&mut self, peer: &str, _reason: DisconnectReason) {
+        if let Some(mut channel) = self.peer.channels.remove(peer) {
+            channel.fail_all();
+            self.out.extend(
+                channel
+                    .drain_events()
+                    .into_iter()
+                    .map(|event| PeerEvent::Delivery {
+                        peer: peer.to_string(),
+                        event,
+                    }),
+            );
+        }
+    }
+
+    fn reconnect_later(&mut self, peer: &str) {
+        if let Some(supervisor) = &mut self.peer.reconnect {
+            let peer = peer.to_string();
+            supervisor.on_disconnected(&peer, self.now);
+            if self.peer.found.contains(&peer) {
+                supervisor.on_found(&peer, self.now);
+            }
+        }
+    }
+
+    fn forget(&mut self, peer: &str) {
+        if let Some(supervisor) = &mut self.peer.reconnect {
+            supervisor.forget(&peer.to_string());
+        }
+    }
+
+    fn left(&mut self, peer: &str) {
+        self.out.push(PeerEvent::Left(peer.to_string()));
+    }
+}
+
+pub struct Harness {
+    sim: SimNetwork,
+    peers: BTreeMap<String, VirtualPeer>,
+    transcript: Vec<HarnessEvent>,
+}
+
+// Manual Debug implementation, the transcript is printed separately
+impl fmt::Debug for Harness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Harness")
+            .field("seed", &self.sim.seed())
+            .field("now", &self.sim.elapsed())
+            .field("peers", &self.peers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Harness {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            sim: SimNetwork::new(config),
+            peers: BTreeMap::new(),
+            transcript: Vec::new(),
+        }
+    }
+
+    /// Harness with `count` peers named `peer-0`, `peer-1`, ...
+    pub fn with_peers(seed: u64, count: usize, config: PeerConfig) -> Self {
+        let mut harness = Self::new(SimConfig {
+            seed,
+            ..SimConfig::default()
+        });
+        for i in 0..count {
+            harness
+                .add_peer(&format!("peer-{}", i), config.clone())
+                .expect("Peer names are unique");
+        }
+        harness
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.sim.seed()
+    }
+
+    pub fn now(&self) -> Duration {
+        self.sim.elapsed()
+    }
+
+    /// The simulated network, e.g. to change link conditions or schedule
+    /// partitions
+    pub fn sim(&mut self) -> &mut SimNetwork {
+        &mut self.sim
+    }
+
+    pub fn add_peer(&mut self, name: &str, config: PeerConfig) -> Result<(), String> {
+        self.sim.add_device(name)?;
+        // Every peer gets its own rng stream derived from the run's seed
+        let seed = self.sim.seed() ^ (self.peers.len() as u64 + 1).wrapping_mul(0x9E37_79B9);
+        let peer = VirtualPeer::new(name, config, seed, &self.sim);
+        self.peers.insert(name.to_string(), peer);
+        Ok(())
+    }
+
+    pub fn invite(&mut self, from: &str, to: &str) -> Result<(), String> {
+        self.sim.invite(from, to)
+    }
+
+    pub fn disconnect(&mut self, device: &str) {
+        self.sim.disconnect(device);
+    }
+
+    /// Say goodbye to every connected peer, then disconnect and vanish from
+    /// everybody's browser
+    pub fn shutdown(&mut self, device: &str) -> Result<(), String> {
+        if !self.peers.contains_key(device) {
+            return Err(format!("Unknown peer {}", device));
+        }
+
+        let goodbye = Frame::goodbye().encode();
+        for peer in self.sim.connected_peers(device) {
+            self.sim.send(device, &peer, &goodbye, true)?;
+        }
+        // Same grace period the real session gives the goodbye
+        self.advance(crate::SHUTDOWN_GRACE);
+
+        if let Some(peer) = self.peers.get_mut(device) {
+            peer.stopped = true;
+        }
+        self.sim.disconnect(device);
+        let others: Vec<String> = self.sim.devices().map(|d| d.to_string()).collect();
+        for other in others {
+            self.sim.set_in_range(device, &other, false);
+        }
+        Ok(())
+    }
+
+    pub fn send(
+        &mut self,
+        from: &str,
+        to: &str,
+        bytes: &[u8],
+        reliable: bool,
+    ) -> Result<(), String> {
+        self.sim
+            .send(from, to, &Frame::data(bytes).encode(), reliable)
+    }
+
+    /// Send through the acknowledged delivery layer, returning the sequence
+    /// number reported in `PeerEvent::Delivery`
+    pub fn send_acked(&mut self, from: &str, to: &str, bytes: &[u8]) -> Result<u64, String> {
+        let now = self.sim.now();
+        let peer = self
+            .peers
+            .get_mut(from)
+            .ok_or_else(|| format!("Unknown peer {}", from))?;
+        let channel = peer.channel(to);
+        let seq = channel.send(bytes, now)?;
+        let frames = channel.poll_transmit(now);
+        for frame in frames {
+            // Losses are covered by the retransmission timer
+            let _ = self.sim.send(from, to, &frame.encode(), false);
+        }
+        Ok(seq)
+    }
+
+    /// Send over the mesh, relayed through other peers if `to` isn't
+    /// directly connected
+    pub fn send_to(&mut self, from: &str, to: &str, bytes: &[u8]) -> Result<(), String> {
+        let now = self.sim.now();
+        let mesh = self
+            .peers
+            .get_mut(from)
+            .ok_or_else(|| format!("Unknown peer {}", from))?
+            .mesh
+            .as_mut()
+            .ok_or_else(|| format!("{} is not on the mesh", from))?;
+        mesh.send_to(to, bytes)?;
+        for (next_hop, frame) in mesh.poll(now) {
+            let reliable = frame.kind == FrameKind::Relay;
+            let _ = self.sim.send(from, &next_hop, &frame.encode(), reliable);
+        }
+        Ok(())
+    }
+
+    /// Gossip `bytes` to every peer reachable from `from`
+    pub fn broadcast(&mut self, from: &str, bytes: &[u8]) -> Result<MessageId, String> {
+        let now = self.sim.now();
+        let gossip = self
+            .peers
+            .get_mut(from)
+            .ok_or_else(|| format!("Unknown peer {}", from))?
+            .gossip
+            .as_mut()
+            .ok_or_else(|| format!("{} doesn't gossip", from))?;
+        let id = gossip.broadcast(bytes, now);
+        for (to, frame) in gossip.poll(now) {
+            let _ = self.sim.send(from, &to, &frame.encode(), true);
+        }
+        Ok(id)
+    }
+
+    /// Feed an event to a peer as if the network had produced it
+    pub fn inject(&mut self, device: &str, kind: SimEventKind) -> Result<(), String> {
+        let event = SimEvent {
+            at: self.sim.elapsed(),
+            device: device.to_string(),
+            kind,
+        };
+        if !self.peers.contains_key(device) {
+            return Err(format!("Unknown peer {}", device));
+        }
+        self.dispatch(vec![event]);
+        Ok(())
+    }
+
+    /// Advance the virtual clock, running the network and every peer's
+    /// timers on the way
+    pub fn advance(&mut self, by: Duration) {
+        let target = self.sim.elapsed() + by;
+        loop {
+            self.poll_peers();
+
+            let now = self.sim.elapsed();
+            if now >= target {
+                break;
+            }
+            let sim_next = self.sim.next_event_at();
+            let mut next = [
+                sim_next,
+                self.peers
+                    .values()
+                    .filter_map(|peer| peer.next_timer(&self.sim))
+                    .min(),
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(target)
+            .max(now);
+            if next == now && sim_next.is_none_or(|at| at > now) {
+                // A timer is due but polling didn't clear it, move on rather than spin
+                next = now + MAX_STEP;
+            }
+            let next = next.min(now + MAX_STEP).min(target);
+
+            self.sim.run_until(next);
+            let events = self.sim.drain_events();
+            self.dispatch(events);
+        }
+    }
+
+    fn dispatch(&mut self, events: Vec<SimEvent>) {
+        let mut commands = Vec::new();
+        for event in events {
+            let Some(peer) = self.peers.get_mut(&event.device) else {
+                continue;
+            };
+            let mut out = Vec::new();
+            peer.handle(event.kind, &self.sim, &mut out, &mut commands);
+            self.record(&event.device, out);
+            self.apply(&event.device, std::mem::take(&mut commands));
+        }
+    }
+
+    fn poll_peers(&mut self) {
+        let names: Vec<String> = self.peers.keys().cloned().collect();
+        for name in names {
+            let mut out = Vec::new();
+            let mut commands = Vec::new();
+            if let Some(peer) = self.peers.get_mut(&name) {
+                peer.poll(&self.sim, &mut out, &mut commands);
+            }
+            self.record(&name, out);
+            self.apply(&name, commands);
+        }
+    }
+
+    fn apply(&mut self, device: &str, commands: Vec<Command>) {
+        for command in commands {
+            // Failures here are what a real peer would see as silent loss
+            let _ = match command {
+                Command::Send {
+                    to,
+                    bytes,
+                    reliable,
+                } => self.sim.send(device, &to, &bytes, reliable),
+                Command::Invite { to } => self.sim.invite(device, &to),
+            };
+        }
+    }
+
+    fn record(&mut self, device: &str, events: Vec<PeerEvent>) {
+        let at = self.sim.elapsed();
+        self.transcript
+            .extend(events.into_iter().map(|event| HarnessEvent {
+                at,
+                device: device.to_string(),
+                event,
+            }));
+    }
+
+    pub fn transcript(&self) -> &[HarnessEvent] {
+        &self.transcript
+    }
+
+    pub fn events_for(&self, device: &str) -> Vec<PeerEvent> {
+        self.transcript
+            .iter()
+            .filter(|event| event.device == device)
+            .map(|event| event.event.clone())
+            .collect()
+    }
+
+    /// Check that `expected` shows up in order in the peer's events, other
+    /// events in between are ignored
+    pub fn expect_sequence(&self, device: &str, expected: &[PeerEvent]) -> Result<(), String> {
+        let events = self.events_for(device);
+        let mut remaining = expected.iter().peekable();
+        for event in &events {
+            if remaining.peek() == Some(&event) {
+                remaining.next();
+            }
+        }
+        match remaining.next() {
+            None => Ok(()),
+            Some(missing) => Err(format!(
+                "{} never saw {:?} (seed {})\n{}",
+                device,
+                missing,
+                self.seed(),
+                self.dump()
+            )),
+        }
+    }
+
+    /// Printable transcript of the whole run
+    pub fn dump(&self) -> String {
+        let mut out = format!("transcript for seed {}:\n", self.seed());
+        for event in &self.transcript {
+            out.push_str(&format!("  {}\n", event));
+        }
+        out
+    }
+}
+
+/// Seeds to run a scenario with: just the one in HARNESS_SEED if it is set,
+/// otherwise `0..count`
+pub fn seeds(count: u64) -> Vec<u64> {
+    match std::env::var(SEED_ENV).ok().and_then(|s| s.parse().ok()) {
+        Some(seed) => vec![seed],
+        None => (0..count).collect(),
+    }
+}
+
+/// Run `scenario` for every seed from `seeds`, stopping at the first
+/// failure with a message that says how to replay it
+pub fn check_seeds(count: u64, scenario: impl Fn(u64) -> Result<(), String>) -> Result<(), String> {
+    for seed in seeds(count) {
+        scenario(seed).map_err(|e| {
+            format!(
+                "scenario failed for seed {}: {}\nreplay with {}={}",
+                seed, e, SEED_ENV, seed
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::LinkConfig;
+
+    const SEEDS: u64 = 8;
+
+    // Every peer invites the ones after it
+    fn connect_all(harness: &mut Harness, names: &[&str]) -> Result<(), String> {
+        harness.advance(Duration::from_millis(200));
+        for (i, from) in names.iter().enumerate() {
+            for to in &names[i + 1..] {
+                if harness.sim().reachable(from, to) {
+                    harness.invite(from, to)?;
+                }
+            }
+        }
+        harness.advance(Duration::from_millis(200));
+        Ok(())
+    }
+
+    fn count(events: &[PeerEvent], wanted: impl Fn(&PeerEvent) -> bool) -> usize {
+        events.iter().filter(|event| wanted(event)).count()
+    }
+
+    #[test]
+    fn goodbye_is_reported_once_and_not_reconnected() {
+        let config = PeerConfig {
+            reconnect: Some(ReconnectPolicy::default()),
+            ..PeerConfig::default()
+        };
+        let result = check_seeds(SEEDS, |seed| {
+            let mut harness = Harness::with_peers(seed, 3, config.clone());
+            connect_all(&mut harness, &["peer-0", "peer-1", "peer-2"])?;
+            harness.shutdown("peer-0")?;
+            harness.advance(Duration::from_secs(30));
+
+            for peer in ["peer-1", "peer-2"] {
+                let events = harness.events_for(peer);
+                let left = count(&events, |e| *e == PeerEvent::Left("peer-0".to_string()));
+                if left != 1 {
+                    return Err(format!(
+                        "{} saw peer-0 leave {} times\n{}",
+                        peer,
+                        left,
+                        harness.dump()
+                    ));
+                }
+                if events.iter().any(|e| matches!(e, PeerEvent::Reconnect(_))) {
+                    return Err(format!("{} tried to reconnect\n{}", peer, harness.dump()));
+                }
+            }
+            Ok(())
+        });
+        result.unwrap();
+    }
+
+    #[test]
+    fn goodbye_fails_messages_in_flight() {
+        check_seeds(SEEDS, |seed| {
+            let mut harness = Harness::with_peers(seed, 2, PeerConfig::default());
+            connect_all(&mut harness, &["peer-0", "peer-1"])?;
+            // Nothing gets through, so the message is still in flight
+            let dead = LinkConfig {
+                loss: 1.0,
+                ..LinkConfig::default()
+            };
+            harness.sim().set_link("peer-1", "peer-0", dead);
+            let seq = harness.send_acked("peer-1", "peer-0", b"late")?;
+            harness.shutdown("peer-0")?;
+            harness.expect_sequence(
+                "peer-1",
+                &[
+                    PeerEvent::Delivery {
+                        peer: "peer-0".to_string(),
+                        event: ReliableEvent::Failed { seq },
+                    },
+                    PeerEvent::Left("peer-0".to_string()),
+                ],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn acked_messages_get_through_a_lossy_link() {
+        check_seeds(SEEDS, |seed| {
+            let mut harness = Harness::with_peers(seed, 2, PeerConfig::default());
+            connect_all(&mut harness, &["peer-0", "peer-1"])?;
+            let lossy = LinkConfig {
+                loss: 0.3,
+                reorder: 0.2,
+                ..LinkConfig::default()
+            };
+            harness.sim().set_link_both("peer-0", "peer-1", lossy);
+
+            for i in 0..20u8 {
+                harness.send_acked("peer-0", "peer-1", &[i])?;
+            }
+            harness.advance(Duration::from_secs(20));
+
+            let sent = harness.events_for("peer-0");
+            let acked = count(&sent, |e| {
+                matches!(
+                    e,
+                    PeerEvent::Delivery {
+                        event: ReliableEvent::Acked { .. },
+                        ..
+                    }
+                )
+            });
+            let received = count(&harness.events_for("peer-1"), |e| {
+                matches!(e, PeerEvent::Data { .. })
+            });
+            if acked != 20 || received != 20 {
+                return Err(format!(
+                    "{} acked, {} received\n{}",
+                    acked,
+                    received,
+                    harness.dump()
+                ));
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn reconnects_after_a_partition_heals() {
+        let config = PeerConfig {
+            reconnect: Some(ReconnectPolicy::default()),
+            ..PeerConfig::default()
+        };
+        check_seeds(SEEDS, |seed| {
+            let mut harness = Harness::with_peers(seed, 2, config.clone());
+            connect_all(&mut harness, &["peer-0", "peer-1"])?;
+            harness
+                .sim()
+                .partition(&[vec!["peer-0".to_string()], vec!["peer-1".to_string()]]);
+            harness.advance(Duration::from_secs(5));
+            harness.sim().heal();
+            harness.advance(Duration::from_secs(10));
+
+            harness.expect_sequence(
+                "peer-0",
+                &[
+                    PeerEvent::Left("peer-1".to_string()),
+                    PeerEvent::Joined("peer-1".to_string()),
+                ],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn failed_invite_is_not_a_departure() {
+        let config = PeerConfig {
+            reconnect: Some(ReconnectPolicy::default()),
+            ..PeerConfig::default()
+        };
+        check_seeds(SEEDS, |seed| {
+            let mut harness = Harness::with_peers(seed, 2, config.clone());
+            harness.sim().set_in_range("peer-0", "peer-1", false);
+            harness.advance(Duration::from_millis(200));
+            harness.invite("peer-0", "peer-1")?;
+            // Well past the invitation timeout
+            harness.advance(Duration::from_secs(30));
+
+            if harness.sim().state("peer-0", "peer-1") != PeerState::NotConnected {
+                return Err(format!("invitation never failed\n{}", harness.dump()));
+            }
+            for peer in ["peer-0", "peer-1"] {
+                let events = harness.events_for(peer);
+                if events
+                    .iter()
+                    .any(|e| matches!(e, PeerEvent::Left(_) | PeerEvent::Reconnect(_)))
+                {
+                    return Err(format!(
+                        "{} treated the failed invite as a departure\n{}",
+                        peer,
+                        harness.dump()
+                    ));
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn mesh_relays_across_a_line() {
+        let config = PeerConfig {
+            mesh: Some(MeshConfig::default()),
+            ..PeerConfig::default()
+        };
+        check_seeds(SEEDS, |seed| {
+            let mut harness = Harness::with_peers(seed, 3, config.clone());
+            harness.sim().set_in_range("peer-0", "peer-2", false);
+            connect_all(&mut harness, &["peer-0", "peer-1", "peer-2"])?;
+            // A couple of advertisement rounds
+            harness.advance(Duration::from_secs(5));
+            harness.send_to("peer-0", "peer-2", b"far")?;
+            harness.advance(Duration::from_secs(1));
+
+            harness.expect_sequence(
+                "peer-2",
+                &[PeerEvent::MeshData {
+                    source: "peer-0".to_string(),
+                    bytes: b"far".to_vec(),
+                }],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn gossip_reaches_everybody_once() {
+        let config = PeerConfig {
+            gossip: Some(GossipConfig::default()),
+            ..PeerConfig::default()
+        };
+        check_seeds(SEEDS, |seed| {
+            let names = ["peer-0", "peer-1", "peer-2", "peer-3"];
+            let mut harness = Harness::with_peers(seed, names.len(), config.clone());
+            connect_all(&mut harness, &names)?;
+            harness.broadcast("peer-0", b"news")?;
+            harness.advance(Duration::from_secs(5));
+
+            for peer in &names[1..] {
+                let copies = count(
+                    &harness.events_for(peer),
+                    |e| matches!(e, PeerEvent::Gossip(message) if message.data == b"news"),
+                );
+                if copies != 1 {
+                    return Err(format!(
+                        "{} got {} copies\n{}",
+                        peer,
+                        copies,
+                        harness.dump()
+                    ));
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn failure_names_the_seed_to_replay() {
+        let failing = *seeds(SEEDS).last().unwrap();
+        let error = check_seeds(SEEDS, |seed| {
+            if seed == failing {
+                Err("boom".to_string())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err();
+        assert!(
+            error.contains(&format!("{}={}", SEED_ENV, failing)),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,539 @@
+// Admission control for traffic coming in from peers.
+//
+// Peers are filtered by NodeId or by any alias the caller knows them by,
+// such as the display name or the identity in the address book:
+// with an allowlist only the peers on it get in, and the denylist always
+// wins. Every peer that gets in has two token buckets, one for messages
+// and one for bytes. A message that finds either bucket empty is dropped
+// and counts as a strike, and so does a frame that doesn't decode. Too many
+// strikes within `strike_window` and the peer is banned for `ban_for`: it
+// is disconnected, and turned away until the ban runs out. The ban covers
+// its identity too, so a device that comes back under a new NodeId stays
+// out.
+//
+// Like the reliable channel this is a pure state machine, the caller passes
+// the current time and acts on the verdicts.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::mesh::NodeId;
+
+/// Token bucket refilling at `per_sec` up to `burst`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    /// Sustained `per_sec`, with bursts of up to two seconds worth
+    pub fn per_sec(per_sec: f64) -> Self {
+        Self {
+            per_sec,
+            burst: per_sec * 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InboundConfig {
+    /// Only these peers get in, by NodeId or alias. Everyone if empty.
+    pub allow: BTreeSet<String>,
+    /// Never get in, by NodeId or alias
+    pub deny: BTreeSet<String>,
+    /// Messages per peer, unlimited if `None`
+    pub messages: Option<RateLimit>,
+    /// Bytes per peer, unlimited if `None`
+    pub bytes: Option<RateLimit>,
+    /// Strikes within `strike_window` that get a peer banned
+    pub max_strikes: usize,
+    pub strike_window: Duration,
+    pub ban_for: Duration,
+}
+
+impl Default for InboundConfig {
+    fn default() -> Self {
+        Self {
+            allow: BTreeSet::new(),
+            deny: BTreeSet::new(),
+            messages: Some(RateLimit::per_sec(200.0)),
+            bytes: Some(RateLimit::per_sec(4.0 * 1024.0 * 1024.0)),
+            max_strikes: 50,
+            strike_window: Duration::from_secs(10),
+            ban_for: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// On the denylist
+    Denied,
+    /// Not on the allowlist
+    NotAllowed,
+    /// Banned earlier and the ban hasn't run out
+    Banned,
+    /// Over the message rate
+    MessageRate,
+    /// Over the byte rate
+    ByteRate,
+    /// Sent a frame that doesn't decode
+    Malformed,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Violation::Denied => "denied",
+            Violation::NotAllowed => "not on the allowlist",
+            Violation::Banned => "banned",
+            Violation::MessageRate => "too many messages",
+            Violation::ByteRate => "too many bytes",
+            Violation::Malformed => "malformed frame",
+        })
+    }
+}
+
+/// Names other than its NodeId the caller knows a peer by
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aliases<'a> {
+    /// Not unique, so it only counts on the lists
+    pub display_name: Option<&'a str>,
+    /// Identity in the address book, the same every time the device shows
+    /// up. Lists and bans both count it.
+    pub identity: Option<&'a str>,
+}
+
+impl<'a> Aliases<'a> {
+    fn iter(&self) -> impl Iterator<Item = &'a str> {
+        self.display_name.into_iter().chain(self.identity)
+    }
+}
+
+#[derive(Debug)]
+struct Ban {
+    // The NodeId it was handed out to, and the identity if known
+    peer: NodeId,
+    identity: Option<String>,
+    until: Instant,
+}
+
+impl Ban {
+    fn covers(&self, name: &str) -> bool {
+        self.peer == name || self.identity.as_deref() == Some(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Drop the message, the peer may stay
+    Drop(Violation),
+    /// Drop the message and disconnect the peer
+    Disconnect(Violation),
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct PeerState {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    strikes: VecDeque<Instant>,
+}
+
+#[derive(Debug)]
+pub struct InboundPolicy {
+    config: InboundConfig,
+    peers: HashMap<NodeId, PeerState>,
+    bans: Vec<Ban>,
+}
+
+impl InboundPolicy {
+    pub fn new(config: InboundConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+            bans: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &InboundConfig {
+        &self.config
+    }
+
+    /// Whether the lists and bans let the peer in at all
+    pub fn admits(&self, peer: &str, aliases: &Aliases, now: Instant) -> Result<(), Violation> {
+        let listed = |list: &BTreeSet<String>| {
+            list.contains(peer) || aliases.iter().any(|alias| list.contains(alias))
+        };
+        if listed(&self.config.deny) {
+            return Err(Violation::Denied);
+        }
+        if !self.config.allow.is_empty() && !listed(&self.config.allow) {
+            return Err(Violation::NotAllowed);
+        }
+        let banned = |name: &str| {
+            self.bans
+                .iter()
+                .any(|ban| ban.until > now && ban.covers(name))
+        };
+        if banned(peer) || aliases.identity.is_some_and(banned) {
+            return Err(Violation::Banned);
+        }
+        Ok(())
+    }
+
+    /// A message of `len` bytes came in from the peer
+    pub fn on_message(
+        &mut self,
+        peer: &str,
+        aliases: &Aliases,
+        len: usize,
+        now: Instant,
+    ) -> Verdict {
+        if let Err(violation) = self.admits(peer, aliases, now) {
+            return Verdict::Disconnect(violation);
+        }
+        self.bans.retain(|ban| ban.until > now);
+
+        let config = &self.config;
+        let state = Self::state(&mut self.peers, config, peer, now);
+        // Take from both buckets only if both have enough, so a dropped
+        // message costs nothing
+        let mut violation = None;
+        if let (Some(bucket), Some(limit)) = (&mut state.messages, &config.messages) {
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                violation = Some(Violation::MessageRate);
+            }
+        }
+        if let (Some(bucket), Some(limit)) = (&mut state.bytes, &config.bytes) {
+            bucket.refill(limit, now);
+            // A message larger than the burst gets through on a full bucket
+            if bucket.tokens < (len as f64).min(limit.burst) {
+                violation = violation.or(Some(Violation::ByteRate));
+            }
+        }
+        match violation {
+            Some(violation) => self.strike(peer, aliases, violation, now),
+            None => {
+                if let Some(bucket) = &mut state.messages {
+                    bucket.tokens -= 1.0;
+                }
+                if let Some(bucket) = &mut state.bytes {
+                    bucket.tokens -= len as f64;
+                }
+                Verdict::Accept
+            }
+        }
+    }
+
+    /// A frame from the peer didn't decode
+    pub fn on_malformed(&mut self, peer: &str, aliases: &Aliases, now: Instant) -> Verdict {
+        self.strike(peer, aliases, Violation::Malformed, now)
+    }
+
+    /// Keep the peer, and the device behind it if its identity is known,
+    /// out for `ban_for`
+    pub fn ban(&mut self, peer: &str, aliases: &Aliases, now: Instant) {
+        self.peers.remove(peer);
+        self.bans.push(Ban {
+            peer: peer.to_string(),
+            identity: aliases.identity.map(str::to_string),
+            until: now + self.config.ban_for,
+        });
+    }
+
+    /// Lift a ban early, by NodeId or identity. Returns whether the peer
+    /// was banned.
+    pub fn unban(&mut self, name: &str) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|ban| !ban.covers(name));
+        self.bans.len() < before
+    }
+
+    /// Banned peers with the time left on their ban
+    pub fn banned(&self, now: Instant) -> Vec<(NodeId, Duration)> {
+        let mut banned: Vec<(NodeId, Duration)> = self
+            .bans
+            .iter()
+            .filter(|ban| ban.until > now)
+            .map(|ban| (ban.peer.clone(), ban.until - now))
+            .collect();
+        banned.sort();
+        banned
+    }
+
+    /// The peer disconnected. Its buckets and strikes are forgotten, a ban
+    /// stays.
+    pub fn peer_gone(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+
+    fn state<'a>(
+        peers: &'a mut HashMap<NodeId, PeerState>,
+        config: &InboundConfig,
+        peer: &str,
+        now: Instant,
+    ) -> &'a mut PeerState {
+        peers.entry(peer.to_string()).or_insert_with(|| PeerState {
+            messages: config.messages.map(|limit| TokenBucket::new(&limit, now)),
+            bytes: config.bytes.map(|limit| TokenBucket::new(&limit, now)),
+            strikes: VecDeque::new(),
+        })
+    }
+
+    fn strike(
+        &mut self,
+        peer: &str,
+        aliases: &Aliases,
+        violation: Violation,
+        now: Instant,
+    ) -> Verdict {
+        let window = self.config.strike_window;
+        let strikes = &mut Self::state(&mut self.peers, &self.config, peer, now).strikes;
+        while strikes
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > window)
+        {
+            strikes.pop_front();
+        }
+        strikes.push_back(now);
+        if strikes.len() >= self.config.max_strikes {
+            self.ban(peer, aliases, now);
+            Verdict::Disconnect(violation)
+        } else {
+            Verdict::Drop(violation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> BTreeSet<String> {
+        list.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn unlimited() -> InboundConfig {
+        InboundConfig {
+            messages: None,
+            bytes: None,
+            ..InboundConfig::default()
+        }
+    }
+
+    #[test]
+    fn the_denylist_wins_over_the_allowlist() {
+        let now = Instant::now();
+        let policy = InboundPolicy::new(InboundConfig {
+            allow: names(&["alice", "bob"]),
+            deny: names(&["bob"]),
+            ..unlimited()
+        });
+        let none = Aliases::default();
+        assert_eq!(policy.admits("alice", &none, now), Ok(()));
+        assert_eq!(policy.admits("bob", &none, now), Err(Violation::Denied));
+        assert_eq!(
+            policy.admits("carol", &none, now),
+            Err(Violation::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn aliases_count_on_both_lists() {
+        let now = Instant::now();
+        let mut policy = InboundPolicy::new(InboundConfig {
+            allow: names(&["k1"]),
+            deny: names(&["mallory"]),
+            ..unlimited()
+        });
+        let phone = Aliases {
+            display_name: Some("phone"),
+            identity: Some("k1"),
+        };
+        assert_eq!(policy.admits("phone#2", &phone, now), Ok(()));
+        let mallory = Aliases {
+            display_name: Some("mallory"),
+            identity: Some("k1"),
+        };
+        assert_eq!(
+            policy.on_message("mallory#2", &mallory, 10, now),
+            Verdict::Disconnect(Violation::Denied)
+        );
+    }
+
+    #[test]
+    fn messages_over_the_rate_are_dropped_until_refilled() {
+        let now = Instant::now();
+        let mut policy = InboundPolicy::new(InboundConfig {
+            messages: Some(RateLimit {
+                per_sec: 1.0,
+                burst: 2.0,
+            }),
+            ..unlimited()
+        });
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, now),
+            Verdict::Accept
+        );
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, now),
+            Verdict::Accept
+        );
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, now),
+            Verdict::Drop(Violation::MessageRate)
+        );
+        // Other peers have buckets of their own
+        assert_eq!(
+            policy.on_message("c", &Aliases::default(), 1, now),
+            Verdict::Accept
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, later),
+            Verdict::Accept
+        );
+    }
+
+    #[test]
+    fn byte_rate_lets_one_oversized_message_through_a_full_bucket() {
+        let now = Instant::now();
+        let mut policy = InboundPolicy::new(InboundConfig {
+            bytes: Some(RateLimit {
+                per_sec: 100.0,
+                burst: 100.0,
+            }),
+            ..unlimited()
+        });
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1000, now),
+            Verdict::Accept
+        );
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, now),
+            Verdict::Drop(Violation::ByteRate)
+        );
+    }
+
+    #[test]
+    fn too_many_strikes_get_a_peer_banned() {
+        let now = Instant::now();
+        let config = InboundConfig {
+            max_strikes: 3,
+            ..unlimited()
+        };
+        let ban_for = config.ban_for;
+        let mut policy = InboundPolicy::new(config);
+        assert_eq!(
+            policy.on_malformed("b", &Aliases::default(), now),
+            Verdict::Drop(Violation::Malformed)
+        );
+        assert_eq!(
+            policy.on_malformed("b", &Aliases::default(), now),
+            Verdict::Drop(Violation::Malformed)
+        );
+        assert_eq!(
+            policy.on_malformed("b", &Aliases::default(), now),
+            Verdict::Disconnect(Violation::Malformed)
+        );
+
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, now),
+            Verdict::Disconnect(Violation::Banned)
+        );
+        assert_eq!(policy.banned(now), [("b".to_string(), ban_for)]);
+        assert_eq!(
+            policy.on_message("b", &Aliases::default(), 1, now + ban_for),
+            Verdict::Accept
+        );
+        assert!(policy.banned(now + ban_for).is_empty());
+    }
+
+    #[test]
+    fn old_strikes_fall_out_of_the_window() {
+        let now = Instant::now();
+        let config = InboundConfig {
+            max_strikes: 2,
+            ..unlimited()
+        };
+        let window = config.strike_window;
+        let mut policy = InboundPolicy::new(config);
+        policy.on_malformed("b", &Aliases::default(), now);
+        assert_eq!(
+            policy.on_malformed(
+                "b",
+                &Aliases::default(),
+                now + window + Duration::from_millis(1)
+            ),
+            Verdict::Drop(Violation::Malformed)
+        );
+    }
+
+    #[test]
+    fn bans_can_be_lifted_early_and_outlive_disconnects() {
+        let now = Instant::now();
+        let mut policy = InboundPolicy::new(unlimited());
+        policy.ban("b", &Aliases::default(), now);
+        policy.peer_gone("b");
+        assert_eq!(
+            policy.admits("b", &Aliases::default(), now),
+            Err(Violation::Banned)
+        );
+        assert!(policy.unban("b"));
+        assert!(!policy.unban("b"));
+        assert_eq!(policy.admits("b", &Aliases::default(), now), Ok(()));
+    }
+
+    #[test]
+    fn bans_follow_the_identity_to_a_new_node_id() {
+        let now = Instant::now();
+        let mut policy = InboundPolicy::new(unlimited());
+        let device = Aliases {
+            display_name: Some("phone"),
+            identity: Some("k1"),
+        };
+        policy.ban("phone#1", &device, now);
+
+        // Relaunched, same identity
+        assert_eq!(
+            policy.admits("phone#2", &device, now),
+            Err(Violation::Banned)
+        );
+        // Another device with the same display name
+        let other = Aliases {
+            display_name: Some("phone"),
+            identity: Some("k2"),
+        };
+        assert_eq!(policy.admits("phone#3", &other, now), Ok(()));
+
+        assert!(policy.unban("k1"));
+        assert_eq!(policy.admits("phone#2", &device, now), Ok(()));
+        assert!(policy.banned(now).is_empty());
+    }
+}
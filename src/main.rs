@@ -1,85 +1,1878 @@
-#![allow(unused_imports)]
-#![allow(dead_code)]
-#![allow(unused_variables)]
-// #![allow(deprecated)]
-// #![allow(unused_must_use)]
 #![allow(non_local_definitions)]
-// #[cfg(not(clippy))]
-#![feature(mpmc_channel)]
 #![allow(clippy::too_many_arguments)]
 
-use iroh_discovery_playground::MultipeerTransport;
-use multipeer_session::MultipeerSession;
-use objc2::AllocAnyThread;
-use objc2::{MainThreadMarker, exception};
-use objc2_foundation::NSString;
-use objc2_multipeer_connectivity::MCPeerID;
-use std::io::Error;
+// Command line tool for poking at devices.
+//
+// Every subcommand runs on one of three backends: MultipeerConnectivity, the
+// LAN (TCP for data, mDNS for discovery) or an in-memory network with a
+// single "echo" peer that sends every message back. Whatever the backend,
+// its callbacks are turned into `Event`s on one channel and the subcommand
+// runs a plain loop over them on the main thread.
+//
+// With `--noise` every peer also goes through a Noise handshake, and only
+// shows up as joined once it is done. Everything sent to it after that is
+// encrypted, whatever the backend does on its own, and streams are refused
+// since they can't be encrypted yet. `pair` builds on it:
+// both users compare a code derived from the handshake and a nonce from
+// each side, and the peer's key goes into the `--address-book` to be pinned
+// in later runs.
+//
+// With `--compress` MPC payloads are compressed for peers that say they can
+// decode them, everyone else keeps getting them as they are. With `--noise`
+// too they are compressed before they are encrypted.
 
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use env_logger::{Builder, Target};
+use log::{LevelFilter, debug, info, warn};
+#[cfg(target_os = "macos")]
+use objc2_multipeer_connectivity::MCEncryptionPreference;
+use tracing::{field, info_span};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+
+use iroh_discovery_playground::address_book::{
+    AddressBook, AddressBookConfig, IDENTITY_KEY, identity_of_key,
+};
+use iroh_discovery_playground::aggregator::{
+    AggregatorConfig, AggregatorEvent, DiscoveryAggregator, DiscoveryEvent, Source,
+};
+use iroh_discovery_playground::backend::{Backend, BackendEvent, Stream};
+use iroh_discovery_playground::bench::{BenchConfig, BenchResponder, BenchTest, run_bench};
+use iroh_discovery_playground::capture::{Capture, ReplayConfig, ReplayReport, replay};
+use iroh_discovery_playground::compression::{Codec, CompressionConfig};
+use iroh_discovery_playground::discovery_info::DiscoveryInfo;
+use iroh_discovery_playground::frame::{Frame, FrameKind};
+use iroh_discovery_playground::inbound::{InboundConfig, RateLimit};
+use iroh_discovery_playground::mdns::MdnsDiscovery;
+use iroh_discovery_playground::memory_backend::{MemoryBackend, MemoryNetwork};
+use iroh_discovery_playground::mesh::NodeId;
+use iroh_discovery_playground::noise::{
+    HandshakePattern, Keypair, NoiseConfig, SecureBackend, SecureChannels, key_from_hex, key_to_hex,
+};
+use iroh_discovery_playground::pairing::{Pairing, PairingOutcome};
+#[cfg(target_os = "macos")]
+use iroh_discovery_playground::reconnect::{ReconnectEvent, ReconnectPolicy};
+use iroh_discovery_playground::sim::{LinkConfig, SimConfig};
+use iroh_discovery_playground::tcp_backend::TcpBackend;
+#[cfg(target_os = "macos")]
+use multipeer_session::{MultipeerSession, SessionOptions};
+
+#[cfg(target_os = "macos")]
 mod multipeer_session;
+mod tui;
+
+/// Name of the peer the memory backend puts next to us
+const ECHO_PEER: &str = "echo";
+
+#[derive(Parser)]
+#[command(about = "Discover, connect to and exchange data with nearby peers")]
+struct Cli {
+    /// Service name, the service type is derived from it
+    #[arg(long, global = true, default_value = "example-service")]
+    service: String,
+
+    /// Name other peers see us as
+    #[arg(long, global = true, default_value = "rust-peer")]
+    name: String,
+
+    /// MPC session encryption, MPC's own default if not given
+    #[arg(long, global = true, value_enum)]
+    encryption: Option<Encryption>,
+
+    /// Advertised discovery info, repeatable
+    #[arg(long = "info", global = true, value_name = "KEY=VALUE")]
+    info: Vec<String>,
+
+    /// Only let these peers in, by name or identity, repeatable. MPC only.
+    #[arg(long, global = true, value_name = "PEER")]
+    allow: Vec<String>,
+
+    /// Never let these peers in, by name or identity, repeatable. MPC only.
+    #[arg(long, global = true, value_name = "PEER")]
+    deny: Vec<String>,
+
+    /// Messages per second a peer may send us. Peers that keep going over
+    /// are disconnected and banned for a while. MPC only.
+    #[arg(long, global = true, value_name = "N")]
+    max_message_rate: Option<f64>,
+
+    /// Bytes per second a peer may send us, like `--max-message-rate`
+    #[arg(long, global = true, value_name = "BYTES")]
+    max_byte_rate: Option<f64>,
+
+    /// Compress payloads for peers that can decode them, with the first of
+    /// these codecs the peer has. Peers without it get them uncompressed.
+    /// MPC only.
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "CODECS"
+    )]
+    compress: Vec<CompressCodec>,
+
+    /// Payloads smaller than this go out uncompressed, 256 bytes by default
+    #[arg(long, global = true, value_name = "BYTES")]
+    compress_threshold: Option<usize>,
+
+    /// Re-invite peers that drop out once they are seen again, backing off
+    /// between attempts. MPC only.
+    #[arg(long, global = true)]
+    reconnect: bool,
+
+    /// Log filter in env_logger syntax, e.g. `debug` or `warn,iroh_discovery_playground=trace`
+    #[arg(long, global = true, default_value = "info")]
+    log_level: String,
+
+    /// Write the log here instead of stderr. Without it `tui` doesn't log.
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Log through `tracing`: every line carries the session, connection
+    /// and message spans it happened in, and closed spans are logged with
+    /// their timings
+    #[arg(long, global = true)]
+    spans: bool,
+
+    /// MPC on macOS, LAN elsewhere if not given
+    #[arg(long, global = true, value_enum, default_value_t = BackendKind::DEFAULT)]
+    backend: BackendKind,
+
+    /// Port the LAN backend listens on, 0 picks a free one
+    #[arg(long, global = true, default_value_t = 0)]
+    port: u16,
+
+    /// LAN peers to dial without waiting for mDNS, repeatable
+    #[arg(long, global = true, value_name = "ADDR")]
+    connect: Vec<SocketAddr>,
+
+    /// One-way delay of the memory backend's links, in milliseconds
+    #[arg(long, global = true, value_name = "MS", default_value_t = 5)]
+    sim_latency: u64,
+
+    /// Share of unreliable messages the memory backend drops, 0 to 1
+    #[arg(long, global = true, value_name = "P", default_value_t = 0.0)]
+    sim_loss: f64,
+
+    /// Bandwidth of the memory backend's links in bytes per second
+    #[arg(long, global = true, value_name = "BYTES")]
+    sim_bandwidth: Option<u64>,
+
+    /// Record every frame and peer state change to this file, for `replay`.
+    /// MPC and memory backends only.
+    #[arg(long, global = true, value_name = "PATH")]
+    capture: Option<PathBuf>,
+
+    /// Encrypt everything sent to peers with a Noise channel, on top of
+    /// whatever the backend does. Peers without it can't talk to us, and
+    /// streams are off.
+    #[arg(long, global = true)]
+    noise: bool,
+
+    /// Static key for `--noise`, and the identity other peers' address
+    /// books know us by. Created if missing. Defaults to the address book
+    /// path with a `.key` extension, a new key every run without either.
+    #[arg(long, global = true, value_name = "PATH")]
+    noise_key: Option<PathBuf>,
+
+    #[arg(long, global = true, value_enum, default_value_t = NoisePattern::Ik)]
+    noise_pattern: NoisePattern,
+
+    /// Only accept the peer if it holds this static key, repeatable
+    #[arg(long = "noise-peer", global = true, value_name = "NAME=KEY")]
+    noise_peers: Vec<String>,
+
+    /// Remember peers paired with `pair` in this file. With `--noise` they
+    /// must hold the key they paired with.
+    #[arg(long, global = true, value_name = "PATH")]
+    address_book: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Advertise and accept whoever connects, without browsing
+    Advertise,
+    /// Print peers as they are found and lost, without connecting
+    Browse,
+    /// Discover and connect for a while, then print what was seen
+    ListPeers {
+        /// Seconds to wait
+        #[arg(long, default_value_t = 5)]
+        wait: u64,
+    },
+    /// Send one message and exit
+    Send {
+        /// Peer to send to, `all` for everyone connected after `--wait`
+        peer: String,
+        message: String,
+        /// Send without delivery guarantees
+        #[arg(long)]
+        unreliable: bool,
+        /// Seconds to wait for the peer to connect
+        #[arg(long, default_value_t = 10)]
+        wait: u64,
+    },
+    /// Chat with every connected peer, `/help` lists the commands
+    Chat,
+    /// Stream a file to a peer and exit
+    SendFile {
+        peer: String,
+        path: PathBuf,
+        /// Seconds to wait for the peer to connect
+        #[arg(long, default_value_t = 10)]
+        wait: u64,
+    },
+    /// Print incoming messages and save incoming files
+    Recv {
+        /// Where received files go
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Count traffic per peer and print it periodically, with session
+    /// metrics on MPC
+    Stats {
+        /// Seconds between reports
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+    },
+    /// Measure latency and throughput to a peer running any other subcommand
+    Bench {
+        peer: String,
+        /// Tests to run, in order
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "latency,reliable,unreliable,stream"
+        )]
+        tests: Vec<BenchTest>,
+        #[arg(long, default_value_t = 100)]
+        pings: u32,
+        /// Messages per throughput burst
+        #[arg(long, default_value_t = 1000)]
+        messages: u32,
+        /// Bytes per burst message
+        #[arg(long, default_value_t = 1024)]
+        size: usize,
+        /// Bytes sent through the stream test
+        #[arg(long, default_value_t = 8 * 1024 * 1024)]
+        stream_bytes: u64,
+        /// Seconds to wait for a report after each test
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+        /// Seconds to wait for the peer to connect
+        #[arg(long, default_value_t = 10)]
+        wait: u64,
+        /// Print the report as one line of JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Full screen chat with a live peer roster, `/help` lists the commands
+    Tui {
+        /// Where received files go
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Pair with a peer running `pair` with us: both sides show a code to
+    /// compare, and the peer is trusted once both users confirmed. Implies
+    /// `--noise`.
+    Pair {
+        peer: String,
+        /// Seconds to wait for the peer to connect
+        #[arg(long, default_value_t = 30)]
+        wait: u64,
+    },
+    /// Play a capture back into the memory backend, whatever `--backend`
+    /// says, and print what arrives
+    Replay {
+        path: PathBuf,
+        /// 2 plays twice as fast as captured
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BackendKind {
+    /// MultipeerConnectivity, Apple devices only
+    Mpc,
+    /// In-process network with an echo peer
+    Memory,
+    /// TCP on the local network, discovered through mDNS
+    Lan,
+}
+
+impl BackendKind {
+    const DEFAULT: Self = if cfg!(target_os = "macos") {
+        BackendKind::Mpc
+    } else {
+        BackendKind::Lan
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum NoisePattern {
+    /// Exchange static keys every time
+    Xx,
+    /// Skip a message when the peer's key is known, XX otherwise
+    Ik,
+}
+
+impl From<NoisePattern> for HandshakePattern {
+    fn from(pattern: NoisePattern) -> Self {
+        match pattern {
+            NoisePattern::Xx => HandshakePattern::Xx,
+            NoisePattern::Ik => HandshakePattern::Ik,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CompressCodec {
+    Zstd,
+    Lz4,
+}
+
+impl From<CompressCodec> for Codec {
+    fn from(codec: CompressCodec) -> Self {
+        match codec {
+            CompressCodec::Zstd => Codec::Zstd,
+            CompressCodec::Lz4 => Codec::Lz4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Encryption {
+    Required,
+    Optional,
+    None,
+}
+
+#[cfg(target_os = "macos")]
+impl From<Encryption> for MCEncryptionPreference {
+    fn from(encryption: Encryption) -> Self {
+        match encryption {
+            Encryption::Required => MCEncryptionPreference::Required,
+            Encryption::Optional => MCEncryptionPreference::Optional,
+            Encryption::None => MCEncryptionPreference::None,
+        }
+    }
+}
+
+enum Event {
+    /// A discovery source found or lost a peer
+    Discovery {
+        source: Source,
+        event: DiscoveryEvent,
+    },
+    Joined {
+        peer: NodeId,
+    },
+    Left {
+        peer: NodeId,
+    },
+    Data {
+        peer: NodeId,
+        data: Vec<u8>,
+    },
+    /// Secure channel traffic
+    Frame {
+        peer: NodeId,
+        frame: Frame,
+    },
+    Stream {
+        peer: NodeId,
+        name: String,
+        stream: Box<dyn Stream>,
+    },
+    /// A line typed into chat, `None` once stdin is closed
+    Input(Option<String>),
+    /// Key press or resize in the TUI
+    Terminal(ratatui::crossterm::event::Event),
+    /// Something a background task wants the user to know
+    Notice(String),
+    /// The capture has been played to the end
+    ReplayDone(Result<ReplayReport, String>),
+    /// Ctrl-C was pressed
+    Interrupted,
+}
+
+impl Event {
+    fn from_backend(event: BackendEvent) -> Option<Self> {
+        Some(match event {
+            BackendEvent::Joined { peer } => Event::Joined { peer },
+            BackendEvent::Left { peer } => Event::Left { peer },
+            BackendEvent::Data { peer, data } => Event::Data { peer, data },
+            BackendEvent::Stream { peer, name, stream } => Event::Stream { peer, name, stream },
+            BackendEvent::Frame { peer, frame }
+                if matches!(
+                    frame.kind,
+                    FrameKind::Handshake | FrameKind::Secure | FrameKind::Pair
+                ) =>
+            {
+                Event::Frame { peer, frame }
+            }
+            // Nothing here speaks the other protocols
+            BackendEvent::Frame { .. } => return None,
+        })
+    }
+}
+
+/// Which halves of discovery a subcommand wants
+#[derive(Debug, Clone, Copy)]
+struct Discovery {
+    advertise: bool,
+    browse: bool,
+    /// Connect to peers we find
+    connect: bool,
+}
+
+enum Transport {
+    #[cfg(target_os = "macos")]
+    Mpc(MultipeerSession),
+    Lan {
+        backend: TcpBackend,
+        mdns: MdnsDiscovery,
+    },
+    Memory {
+        backend: MemoryBackend,
+    },
+}
+
+impl Transport {
+    fn start(
+        cli: &Cli,
+        discovery: Discovery,
+        // Only MPC advertises it
+        #[cfg_attr(not(target_os = "macos"), allow(unused_variables))] identity: &str,
+        events: mpsc::Sender<Event>,
+    ) -> Result<Self, String> {
+        let info = parse_info(&cli.info)?;
+        if cli.encryption.is_some() && cli.backend != BackendKind::Mpc {
+            warn!("--encryption only applies to the MPC backend");
+        }
+        let inbound = inbound_config(cli)?;
+        if inbound.is_some() && cli.backend != BackendKind::Mpc {
+            warn!("--allow, --deny and the rate limits only apply to the MPC backend");
+        }
+        let compression = compression_config(cli);
+        if compression.is_some() && cli.backend != BackendKind::Mpc {
+            warn!("--compress only applies to the MPC backend");
+        }
+        if cli.reconnect && cli.backend != BackendKind::Mpc {
+            warn!("--reconnect only applies to the MPC backend");
+        }
+        if !cli.connect.is_empty() && cli.backend != BackendKind::Lan {
+            warn!("--connect only applies to the LAN backend");
+        }
+
+        // Captures only ever play into the memory backend
+        let replay = matches!(cli.command, Command::Replay { .. });
+        let kind = if replay {
+            BackendKind::Memory
+        } else {
+            cli.backend
+        };
+        match kind {
+            #[cfg(not(target_os = "macos"))]
+            BackendKind::Mpc => Err("The MPC backend only runs on macOS".to_string()),
+            #[cfg(target_os = "macos")]
+            BackendKind::Mpc => {
+                let options = SessionOptions {
+                    display_name: cli.name.clone(),
+                    encryption: cli.encryption.map(Into::into),
+                    identity: Some(identity.to_string()),
+                };
+                // Peers are told apart by node id, not by the MCPeerIDs the
+                // plain callbacks get
+                let mut session = MultipeerSession::with_options(
+                    &cli.service,
+                    options,
+                    |_, _| {},
+                    |_| {},
+                    |_| {},
+                );
+                let on_event = events.clone();
+                session.enable_backend_events(move |event| {
+                    if let Some(event) = Event::from_backend(event) {
+                        let _ = on_event.send(event);
+                    }
+                });
+                session.set_discovery_info(info)?;
+                session.set_advertising(discovery.advertise);
+                session.set_browsing(discovery.browse);
+                session.set_secure(runs_noise(cli));
+                let on_frame = events.clone();
+                session.enable_frame_events(move |peer, frame| {
+                    let _ = on_frame.send(Event::Frame {
+                        peer: peer.to_string(),
+                        frame: frame.clone(),
+                    });
+                });
+                let on_pairing_request = events.clone();
+                session.enable_pairing_requests(move |peer| {
+                    let _ = on_pairing_request.send(Event::Notice(format!(
+                        "{} wants to pair, run `pair {}` to compare codes",
+                        peer, peer
+                    )));
+                });
+                let on_reconnect = events.clone();
+                session.enable_discovery_events(move |event| {
+                    let _ = events.send(Event::Discovery {
+                        source: Source::Mpc,
+                        event: event.clone(),
+                    });
+                });
+                if let Some(path) = &cli.capture {
+                    session.enable_capture(path)?;
+                }
+                if let Some(config) = inbound {
+                    session.enable_inbound_policy(config);
+                }
+                if let Some(config) = compression {
+                    session.enable_compression(config);
+                }
+                if cli.reconnect {
+                    session.enable_reconnect(ReconnectPolicy::default(), move |event| {
+                        if let Some(line) = reconnect_notice(event) {
+                            let _ = on_reconnect.send(Event::Notice(line));
+                        }
+                    })?;
+                }
+                Ok(Transport::Mpc(session))
+            }
+            BackendKind::Lan => {
+                if cli.capture.is_some() {
+                    return Err("--capture only works with the MPC and memory backends".to_string());
+                }
+                // Under the mDNS instance name, so discovery and the data
+                // path agree on who is who even when display names clash
+                let mut mdns = MdnsDiscovery::new(&cli.service, &cli.name)?;
+                let on_event = events.clone();
+                let backend = TcpBackend::bind(
+                    mdns.instance_name(),
+                    SocketAddr::from(([0, 0, 0, 0], cli.port)),
+                    move |event| {
+                        if let Some(event) = Event::from_backend(event) {
+                            let _ = on_event.send(event);
+                        }
+                    },
+                )?;
+                info!(
+                    "Listening on {} as {}",
+                    backend.local_addr(),
+                    mdns.instance_name()
+                );
+
+                if discovery.advertise {
+                    mdns.advertise(&info, backend.local_addr().port())?;
+                }
+                if discovery.browse {
+                    mdns.browse(move |event| {
+                        let _ = events.send(Event::Discovery {
+                            source: Source::Mdns,
+                            event: event.into(),
+                        });
+                    })?;
+                }
+
+                if discovery.connect {
+                    for addr in &cli.connect {
+                        match backend.connect(*addr) {
+                            Ok(peer) => debug!("Dialed {} at {}", peer, addr),
+                            Err(e) => warn!("Failed to connect to {}: {}", addr, e),
+                        }
+                    }
+                }
+                Ok(Transport::Lan { backend, mdns })
+            }
+            BackendKind::Memory => {
+                if cli.name == ECHO_PEER && !replay {
+                    return Err(format!("{} is taken by the echo peer", ECHO_PEER));
+                }
+                if cli.sim_loss.is_nan() || !(0.0..=1.0).contains(&cli.sim_loss) {
+                    return Err("--sim-loss must be between 0 and 1".to_string());
+                }
+                let network = MemoryNetwork::new(SimConfig {
+                    default_link: LinkConfig {
+                        latency: Duration::from_millis(cli.sim_latency),
+                        loss: cli.sim_loss,
+                        bandwidth: cli.sim_bandwidth,
+                        ..LinkConfig::default()
+                    },
+                    ..SimConfig::default()
+                })?;
+                // The capture's peers stand in for it
+                if !replay {
+                    spawn_echo(&network, cli.noise.then(|| noise_config(cli)))?;
+                }
 
-use env_logger::{Builder, Env};
+                let on_event = events.clone();
+                let backend = network.join(&cli.name, move |event| {
+                    if let Some(event) = Event::from_backend(event) {
+                        let _ = on_event.send(event);
+                    }
+                })?;
+                // Range in the simulated network stands in for MPC's radio
+                if discovery.browse {
+                    backend.enable_discovery_events(move |event| {
+                        let _ = events.send(Event::Discovery {
+                            source: Source::Mpc,
+                            event: event.clone(),
+                        });
+                    });
+                }
+                if let Some(path) = &cli.capture {
+                    backend.enable_capture(path)?;
+                    info!("Capturing traffic to {}", path.display());
+                }
+                Ok(Transport::Memory { backend })
+            }
+        }
+    }
+
+    fn backend(&self) -> &dyn Backend {
+        match self {
+            #[cfg(target_os = "macos")]
+            Transport::Mpc(session) => session,
+            Transport::Lan { backend, .. } => backend,
+            Transport::Memory { backend } => backend,
+        }
+    }
+
+    fn is_connected(&self, peer: &str) -> bool {
+        self.backend().connected_peers().iter().any(|p| p == peer)
+    }
+
+    /// Connect to a peer discovery just reported. MPC tells it when we want
+    /// to pair.
+    fn connect(
+        &self,
+        node: &str,
+        addrs: &[SocketAddr],
+        #[cfg_attr(not(target_os = "macos"), allow(unused_variables))] pair: bool,
+    ) {
+        if self.is_connected(node) {
+            return;
+        }
+        match self {
+            #[cfg(target_os = "macos")]
+            Transport::Mpc(session) => {
+                let invited = if pair {
+                    session.invite_to_pair(node)
+                } else {
+                    session.invite(node)
+                };
+                if let Err(e) = invited {
+                    warn!("Failed to invite {}: {}", node, e);
+                }
+            }
+            Transport::Lan { backend, .. } => {
+                // Any address will do, they all lead to the same listener.
+                // IPv4 first, link-local IPv6 ones can't be dialed without a
+                // scope.
+                let mut addrs = addrs.to_vec();
+                addrs.sort_by_key(|addr| !addr.is_ipv4());
+                for addr in addrs {
+                    match backend.connect(addr) {
+                        Ok(_) => return,
+                        Err(e) => debug!("Failed to connect to {} at {}: {}", node, addr, e),
+                    }
+                }
+                // Tried again when mDNS reports more addresses
+                debug!("Failed to connect to {}", node);
+            }
+            // The network connects peers on its own
+            Transport::Memory { .. } => {}
+        }
+    }
+
+    fn shutdown(self) {
+        match self {
+            #[cfg(target_os = "macos")]
+            Transport::Mpc(mut session) => {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_time()
+                    .build()
+                    .expect("Failed to build tokio runtime");
+                runtime.block_on(session.shutdown());
+            }
+            Transport::Lan {
+                mut backend,
+                mut mdns,
+            } => {
+                mdns.shutdown();
+                backend.shutdown();
+            }
+            Transport::Memory { mut backend } => backend.shutdown(),
+        }
+    }
+}
+
+// Peer for the memory backend that answers benches, sends back whatever else
+// it gets and swallows streams. It lives as long as the process. With
+// `noise` it runs secure channels of its own, with a new key every run.
+fn spawn_echo(network: &MemoryNetwork, noise: Option<NoiseConfig>) -> Result<(), String> {
+    let channels = match noise {
+        Some(config) => Some(Mutex::new(SecureChannels::new(
+            ECHO_PEER,
+            Keypair::generate()?,
+            config,
+        ))),
+        None => None,
+    };
+    let (tx, rx) = mpsc::channel();
+    let echo = network.join(ECHO_PEER, move |event| {
+        let _ = tx.send(event);
+    })?;
+    thread::Builder::new()
+        .name("memory-echo".to_string())
+        .spawn(move || {
+            let mut responder = BenchResponder::default();
+            let secure = channels
+                .as_ref()
+                .map(|channels| SecureBackend::new(&echo, channels));
+            let backend: &dyn Backend = match &secure {
+                Some(secure) => secure,
+                None => &echo,
+            };
+            for event in rx {
+                let events = match &secure {
+                    Some(secure) => secure.handle(event),
+                    None => vec![event],
+                };
+                for event in events {
+                    match event {
+                        BackendEvent::Data { peer, data } => {
+                            if responder.on_data(backend, &peer, &data) {
+                                continue;
+                            }
+                            if let Err(e) = backend.send(&data, &[peer], true) {
+                                debug!("Echo failed: {}", e);
+                            }
+                        }
+                        BackendEvent::Stream { peer, name, stream }
+                            if BenchResponder::is_bench_stream(&name) =>
+                        {
+                            responder.on_stream(backend, &peer, &name, stream);
+                        }
+                        BackendEvent::Stream { mut stream, .. } => {
+                            thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn parse_info(entries: &[String]) -> Result<DiscoveryInfo, String> {
+    let mut info = DiscoveryInfo::new();
+    for entry in entries {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("Expected KEY=VALUE, got {}", entry))?;
+        info.insert(key, value)?;
+    }
+    Ok(info)
+}
 
 fn main() {
-    let session = MultipeerSession::new(
-        "example-service",
-        |data, peer| println!("Received data from peer: {:?}", peer),
-        |peer| println!("Peer joined: {:?}", peer),
-        |peer| println!("Peer left: {:?}", peer),
-    );
+    let cli = Cli::parse();
+    if let Err(e) = init_logging(&cli).and_then(|()| run(&cli)) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    // Send data to peers
-    if let Ok(()) = session.send_to_peers(b"Hello!", &session.connected_peers(), true) {
-        println!("Message sent successfully");
+fn init_logging(cli: &Cli) -> Result<(), String> {
+    let file = match &cli.log_file {
+        Some(path) => Some(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+        None => None,
+    };
+    // Log lines would end up all over the screen
+    let off = file.is_none() && matches!(cli.command, Command::Tui { .. });
+
+    if cli.spans {
+        // `log` records from the rest of the code are forwarded as well
+        let filter: Targets = if off {
+            Targets::new()
+        } else {
+            cli.log_level
+                .parse()
+                .map_err(|e| format!("Bad log filter {:?}: {}", cli.log_level, e))?
+        };
+        let ansi = file.is_none();
+        let writer = match file {
+            Some(file) => BoxMakeWriter::new(Mutex::new(file)),
+            None => BoxMakeWriter::new(io::stderr),
+        };
+        return tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .with_ansi(ansi)
+                    .with_span_events(FmtSpan::CLOSE),
+            )
+            .with(filter)
+            .try_init()
+            .map_err(|e| e.to_string());
+    }
+
+    let mut logger = Builder::new();
+    logger
+        .parse_filters(&cli.log_level)
+        .format_timestamp_millis();
+    if let Some(file) = file {
+        logger.target(Target::Pipe(Box::new(file)));
+    }
+    if off {
+        logger.filter_level(LevelFilter::Off);
     }
+    logger.init();
+    Ok(())
 }
 
-fn main_() {
-    println!("Hello, world!");
+fn run(cli: &Cli) -> Result<(), String> {
+    let discovery = match cli.command {
+        Command::Advertise => Discovery {
+            advertise: true,
+            browse: false,
+            connect: false,
+        },
+        Command::Browse => Discovery {
+            advertise: false,
+            browse: true,
+            connect: false,
+        },
+        _ => Discovery {
+            advertise: true,
+            browse: true,
+            connect: true,
+        },
+    };
 
-    Builder::from_env(Env::default().default_filter_or("debug"))
-        .format_timestamp_millis()
-        .init();
+    let (tx, rx) = mpsc::channel();
+    if let Command::Chat | Command::Pair { .. } = cli.command {
+        spawn_stdin(tx.clone())?;
+    }
+    // The TUI reads Ctrl-C as a key, a bench is fine to kill outright
+    if !matches!(cli.command, Command::Tui { .. } | Command::Bench { .. }) {
+        spawn_ctrl_c(tx.clone())?;
+    }
+    let book = match &cli.address_book {
+        Some(path) => Some(AddressBook::open(path, AddressBookConfig::default())?),
+        None => None,
+    };
+    let keypair = identity_keypair(cli)?;
+    let identity = identity_of_key(&keypair.public());
+    let transport = Transport::start(cli, discovery, &identity, tx.clone())?;
+    // Under the name peers know us by, which the LAN backend picks
+    let local = transport.backend().local_name();
+    let noise = if runs_noise(cli) {
+        Some(Mutex::new(secure_channels(
+            cli,
+            &local,
+            keypair,
+            book.as_ref(),
+        )?))
+    } else {
+        if !cli.noise_peers.is_empty() {
+            warn!("--noise-peer only applies with --noise");
+        }
+        None
+    };
+    let mut node = Node {
+        transport,
+        noise,
+        book,
+        pairing_with: None,
+        discovery,
+        events: rx,
+        pending: VecDeque::new(),
+        quiet: matches!(cli.command, Command::Tui { .. } | Command::Bench { .. }),
+        responder: BenchResponder::default(),
+        peers: DiscoveryAggregator::new(AggregatorConfig::default()),
+        stats: BTreeMap::new(),
+    };
 
-    match exception::catch(|| {
-        let main_thread = unsafe { MainThreadMarker::new_unchecked() };
+    let result = match &cli.command {
+        Command::Advertise | Command::Browse => node.watch(),
+        Command::ListPeers { wait } => node.list_peers(Duration::from_secs(*wait)),
+        Command::Send {
+            peer,
+            message,
+            unreliable,
+            wait,
+        } => node.send(peer, message, !unreliable, Duration::from_secs(*wait)),
+        Command::Chat => node.chat(),
+        Command::SendFile { peer, path, wait } => {
+            node.send_file(peer, path, Duration::from_secs(*wait))
+        }
+        Command::Recv { dir } => node.recv(dir),
+        Command::Stats { interval } => node.stats(Duration::from_secs(*interval)),
+        Command::Bench {
+            peer,
+            tests,
+            pings,
+            messages,
+            size,
+            stream_bytes,
+            timeout,
+            wait,
+            json,
+        } => {
+            let config = BenchConfig {
+                tests: tests.clone(),
+                pings: *pings,
+                messages: *messages,
+                message_size: *size,
+                stream_bytes: *stream_bytes,
+                timeout: Duration::from_secs(*timeout),
+                ..BenchConfig::default()
+            };
+            node.bench(peer, &config, Duration::from_secs(*wait), *json)
+        }
+        Command::Tui { dir } => tui::run(&mut node, &cli.name, dir, tx),
+        Command::Pair { peer, wait } => node.pair(peer, Duration::from_secs(*wait)),
+        Command::Replay { path, speed } => node.replay(&cli.name, path, *speed, tx),
+    };
+    // Secure peers ignore the backend's own goodbye, it isn't encrypted
+    if node.noise.is_some() {
+        let backend = node.backend();
+        let peers = backend.connected_peers();
+        if let Err(e) = backend.send_frame(&Frame::goodbye(), &peers, true) {
+            debug!("Failed to say goodbye: {}", e);
+        }
+    }
+    node.transport.shutdown();
+    result
+}
 
-        // Create peer ID with display name
-        let display_name = NSString::from_str("MyDevice");
-        let peer_id = unsafe { MCPeerID::initWithDisplayName(MCPeerID::alloc(), &display_name) };
+// `None` if no flag asks for an inbound policy
+fn inbound_config(cli: &Cli) -> Result<Option<InboundConfig>, String> {
+    if cli.allow.is_empty()
+        && cli.deny.is_empty()
+        && cli.max_message_rate.is_none()
+        && cli.max_byte_rate.is_none()
+    {
+        return Ok(None);
+    }
+    let rates = [cli.max_message_rate, cli.max_byte_rate];
+    if rates
+        .iter()
+        .flatten()
+        .any(|rate| rate.is_nan() || *rate <= 0.0)
+    {
+        return Err("Rate limits must be above 0".to_string());
+    }
+    Ok(Some(InboundConfig {
+        allow: cli.allow.iter().cloned().collect(),
+        deny: cli.deny.iter().cloned().collect(),
+        messages: cli.max_message_rate.map(RateLimit::per_sec),
+        bytes: cli.max_byte_rate.map(RateLimit::per_sec),
+        ..InboundConfig::default()
+    }))
+}
 
-        let mut transport = MultipeerTransport::new(peer_id);
-        /* {
-        peer_id, // Now expects Retained<MCPeerID>
-        session: None,
-        advertiser: None,
-        browser: None,
-        };*/
+// `None` without `--compress`
+fn compression_config(cli: &Cli) -> Option<CompressionConfig> {
+    if cli.compress.is_empty() {
+        return None;
+    }
+    let mut config = CompressionConfig {
+        codecs: Vec::new(),
+        ..CompressionConfig::default()
+    };
+    for codec in &cli.compress {
+        let codec = (*codec).into();
+        if !config.codecs.contains(&codec) {
+            config.codecs.push(codec);
+        }
+    }
+    if let Some(threshold) = cli.compress_threshold {
+        config.threshold = threshold;
+    }
+    Some(config)
+}
 
-        transport.establish_connection();
-        // transport.start_advertising("mpcservice");
-        // transport.start_browsing("mpcservice");
+// Pairing compares codes derived from the secure channel, so it runs one
+// with or without `--noise`
+fn runs_noise(cli: &Cli) -> bool {
+    cli.noise || matches!(cli.command, Command::Pair { .. })
+}
 
-        // std::thread::sleep(std::time::Duration::from_secs(2));
+fn noise_config(cli: &Cli) -> NoiseConfig {
+    NoiseConfig {
+        pattern: cli.noise_pattern.into(),
+        ..NoiseConfig::default()
+    }
+}
+
+// The static key we are known by, see `--noise-key`
+fn identity_keypair(cli: &Cli) -> Result<Keypair, String> {
+    let keypair = match (&cli.noise_key, &cli.address_book) {
+        (Some(path), _) => Keypair::load_or_generate(path)?,
+        (None, Some(book)) => Keypair::load_or_generate(book.with_extension("key"))?,
+        (None, None) => Keypair::generate()?,
+    };
+    info!("Identity {}", key_to_hex(&keypair.public()));
+    Ok(keypair)
+}
+
+fn secure_channels(
+    cli: &Cli,
+    local: &str,
+    keypair: Keypair,
+    book: Option<&AddressBook>,
+) -> Result<SecureChannels, String> {
+    let mut channels = SecureChannels::new(local, keypair, noise_config(cli));
+    if let Some(book) = book {
+        let paired = book.paired_keys();
+        if !paired.is_empty() {
+            info!("Pinning the keys of {} paired peers", paired.len());
+        }
+        for (peer, key) in paired {
+            channels.pin(peer, key);
+        }
+    }
+    for entry in &cli.noise_peers {
+        let (peer, key) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=KEY, got {}", entry))?;
+        channels.pin(peer, key_from_hex(key)?);
+    }
+    Ok(channels)
+}
+
+// What the subcommands send through: the transport's backend, wrapped in
+// the secure channels with `--noise`
+fn backend<'a>(
+    transport: &'a Transport,
+    noise: Option<&'a Mutex<SecureChannels>>,
+) -> Box<dyn Backend + 'a> {
+    match noise {
+        Some(channels) => Box::new(SecureBackend::new(transport.backend(), channels)),
+        None => Box::new(transport.backend()),
+    }
+}
+
+fn spawn_stdin(events: mpsc::Sender<Event>) -> Result<(), String> {
+    thread::Builder::new()
+        .name("stdin".to_string())
+        .spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if events.send(Event::Input(Some(line))).is_err() {
+                    return;
+                }
+            }
+            let _ = events.send(Event::Input(None));
+        })
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
 
-        // let random_message: String = thread_rng()
-        //     .sample_iter(&Alphanumeric)
-        //     .take(10)
-        //     .map(char::from)
-        //     .collect();
+// Turns the first Ctrl-C into `Event::Interrupted`, so the subcommand can
+// return and the transport is shut down properly. A second one exits right
+// away.
+fn spawn_ctrl_c(events: mpsc::Sender<Event>) -> Result<(), String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .map_err(|e| e.to_string())?;
+    thread::Builder::new()
+        .name("ctrl-c".to_string())
+        .spawn(move || {
+            runtime.block_on(async {
+                if tokio::signal::ctrl_c().await.is_err() {
+                    return;
+                }
+                let _ = events.send(Event::Interrupted);
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(130);
+                }
+            })
+        })
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Default)]
+struct PeerStats {
+    connected: bool,
+    joins: u32,
+    messages: u64,
+    bytes: u64,
+    streams: u64,
+}
+
+struct Node {
+    transport: Transport,
+    noise: Option<Mutex<SecureChannels>>,
+    book: Option<AddressBook>,
+    // Peer `pair` runs with, MPC invitations to it say so
+    pairing_with: Option<NodeId>,
+    discovery: Discovery,
+    events: mpsc::Receiver<Event>,
+    // Produced while handling another event, handed out first
+    pending: VecDeque<Event>,
+    // Don't print discovery and membership changes
+    quiet: bool,
+    responder: BenchResponder,
+    // What discovery currently sees, with addresses if the backend has any
+    peers: DiscoveryAggregator,
+    stats: BTreeMap<NodeId, PeerStats>,
+}
+
+impl Node {
+    /// Wait up to `timeout` for the next event, handling the bookkeeping
+    /// every subcommand shares. `Ok(None)` on timeout, or if the event was
+    /// dealt with here.
+    fn next(&mut self, timeout: Duration) -> Result<Option<Event>, String> {
+        let event = match self.pending.pop_front() {
+            Some(event) => event,
+            None => {
+                // Wake up in time to fail handshakes that take too long
+                let timeout = self
+                    .noise
+                    .as_ref()
+                    .and_then(|noise| noise.lock().unwrap().next_timeout())
+                    .map_or(timeout, |at| {
+                        timeout.min(at.saturating_duration_since(Instant::now()))
+                    });
+                let event = match self.events.recv_timeout(timeout) {
+                    Ok(event) => event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        self.secure_poll();
+                        return Ok(None);
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        return Err("Backend stopped".to_string());
+                    }
+                };
+                match self.secure(event) {
+                    Some(event) => event,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        match &event {
+            Event::Discovery { source, event } => {
+                self.peers.on_event(*source, event, Instant::now());
+                self.report_discovery();
+                if let DiscoveryEvent::Found { node, info, .. } = event {
+                    self.pin_claimed_identity(node, info);
+                }
+                if let DiscoveryEvent::Found { node, addrs, .. } = event
+                    && self.discovery.connect
+                {
+                    let pair = self.pairing_with.as_ref() == Some(node);
+                    self.transport.connect(node, addrs, pair);
+                }
+            }
+            Event::Joined { peer } => {
+                if !self.quiet {
+                    println!("{} joined", peer);
+                }
+                let stats = self.stats.entry(peer.clone()).or_default();
+                stats.connected = true;
+                stats.joins += 1;
+            }
+            Event::Left { peer } => {
+                if !self.quiet {
+                    println!("{} left", peer);
+                }
+                self.stats.entry(peer.clone()).or_default().connected = false;
+            }
+            Event::Data { peer, data } => {
+                let stats = self.stats.entry(peer.clone()).or_default();
+                stats.messages += 1;
+                stats.bytes += data.len() as u64;
+                // Bench requests are answered whatever the subcommand
+                let backend = backend(&self.transport, self.noise.as_ref());
+                if self.responder.on_data(&*backend, peer, data) {
+                    return Ok(None);
+                }
+            }
+            Event::Stream { peer, .. } => {
+                self.stats.entry(peer.clone()).or_default().streams += 1;
+            }
+            Event::Notice(line) => {
+                if !self.quiet {
+                    println!("{}", line);
+                }
+            }
+            Event::Frame { .. }
+            | Event::Input(_)
+            | Event::Terminal(_)
+            | Event::ReplayDone(_)
+            | Event::Interrupted => {}
+        }
 
-        // transport.send_message(&random_message);
-        // println!("Sent random message: {}", random_message);
+        match event {
+            Event::Stream { peer, name, stream } if BenchResponder::is_bench_stream(&name) => {
+                let backend = backend(&self.transport, self.noise.as_ref());
+                self.responder.on_stream(&*backend, &peer, &name, stream);
+                Ok(None)
+            }
+            event => Ok(Some(event)),
+        }
+    }
+
+    fn backend(&self) -> Box<dyn Backend + '_> {
+        backend(&self.transport, self.noise.as_ref())
+    }
+
+    // A peer advertising the identity of one we paired with has to prove it
+    // holds that key, whatever name it goes by
+    fn pin_claimed_identity(&self, node: &str, info: &DiscoveryInfo) {
+        let (Some(noise), Some(book)) = (&self.noise, &self.book) else {
+            return;
+        };
+        let paired_key = info
+            .get(IDENTITY_KEY)
+            .and_then(|identity| book.get(identity)?.paired_key);
+        if let Some(key) = paired_key {
+            noise.lock().unwrap().pin(node, key);
+        }
+    }
 
-        Ok::<MultipeerTransport, Error>(transport)
-    }) {
-        Ok(transport) => {
-            println!("Successfully initialized MultipeerConnectivity");
-            std::thread::sleep(std::time::Duration::from_secs(30));
+    fn report_discovery(&mut self) {
+        for event in self.peers.drain_events() {
+            match event {
+                AggregatorEvent::Appeared { node, .. } if !self.quiet => {
+                    println!("Found {}", describe(&node, &self.addrs(&node)))
+                }
+                AggregatorEvent::Disappeared { node } if !self.quiet => println!("Lost {}", node),
+                AggregatorEvent::PreferredChanged { node, source } => {
+                    debug!("Reaching {} through {:?}", node, source)
+                }
+                AggregatorEvent::Conflict { node, addrs } => {
+                    warn!("Sources disagree about where {} is: {:?}", node, addrs)
+                }
+                _ => {}
+            }
         }
-        Err(error) => {
-            eprintln!("Failed to initialize MultipeerConnectivity: {:?}", error);
-            std::process::exit(1);
+    }
+
+    /// Addresses discovery knows for `node`, empty if the backend has none
+    fn addrs(&self, node: &str) -> Vec<SocketAddr> {
+        self.peers
+            .get(node)
+            .map_or_else(Vec::new, |peer| peer.addrs().into_iter().collect())
+    }
+
+    // Runs an event through the secure channels with `--noise`. `None` if
+    // they consumed it, anything else they produce is handed out next.
+    fn secure(&mut self, event: Event) -> Option<Event> {
+        let Some(noise) = &self.noise else {
+            return match event {
+                Event::Frame { peer, frame } => {
+                    debug!("Dropping {:?} frame from {}, no --noise", frame.kind, peer);
+                    None
+                }
+                event => Some(event),
+            };
+        };
+        let event = match event {
+            Event::Joined { peer } => BackendEvent::Joined { peer },
+            Event::Left { peer } => BackendEvent::Left { peer },
+            Event::Data { peer, data } => BackendEvent::Data { peer, data },
+            Event::Frame { peer, frame } => BackendEvent::Frame { peer, frame },
+            Event::Stream { peer, name, stream } => BackendEvent::Stream { peer, name, stream },
+            event => return Some(event),
+        };
+        let events = SecureBackend::new(self.transport.backend(), noise).handle(event);
+        self.pending
+            .extend(events.into_iter().filter_map(Event::from_backend));
+        self.pending.pop_front()
+    }
+
+    // Handshake timeouts and messages, when nothing else happens
+    fn secure_poll(&mut self) {
+        if let Some(noise) = &self.noise {
+            let events = SecureBackend::new(self.transport.backend(), noise).poll();
+            self.pending
+                .extend(events.into_iter().filter_map(Event::from_backend));
+        }
+    }
+
+    /// Wait until `peer` is connected
+    fn wait_for(&mut self, peer: &str, timeout: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + timeout;
+        while !self.backend().connected_peers().iter().any(|p| p == peer) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(format!("{} did not connect within {:?}", peer, timeout));
+            }
+            if let Some(Event::Interrupted) = self.next(left)? {
+                return Err("Interrupted".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle events for `duration`
+    fn run_for(&mut self, duration: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            match self.next(left)? {
+                Some(Event::Data { peer, data }) => print_message(&peer, &data),
+                Some(Event::Interrupted) => return Err("Interrupted".to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    fn watch(&mut self) -> Result<(), String> {
+        loop {
+            match self.next(Duration::MAX)? {
+                Some(Event::Data { peer, data }) => print_message(&peer, &data),
+                Some(Event::Stream { peer, name, .. }) => {
+                    println!("Ignoring stream {} from {}", name, peer)
+                }
+                Some(Event::Interrupted) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn list_peers(&mut self, wait: Duration) -> Result<(), String> {
+        self.run_for(wait)?;
+
+        let connected: BTreeSet<NodeId> = self.backend().connected_peers().into_iter().collect();
+        let known: BTreeSet<&NodeId> = self
+            .peers
+            .peers()
+            .map(|peer| &peer.node)
+            .chain(connected.iter())
+            .collect();
+        println!("{} peers:", known.len());
+        for node in known {
+            let addrs = self.addrs(node);
+            let state = if connected.contains(node) {
+                "connected"
+            } else {
+                "found"
+            };
+            println!("  {:<24} {}", describe(node, &addrs), state);
+        }
+        Ok(())
+    }
+
+    fn send(
+        &mut self,
+        peer: &str,
+        message: &str,
+        reliably: bool,
+        wait: Duration,
+    ) -> Result<(), String> {
+        let peers = if peer == "all" {
+            self.run_for(wait)?;
+            self.backend().connected_peers()
+        } else {
+            self.wait_for(peer, wait)?;
+            vec![peer.to_string()]
+        };
+        if peers.is_empty() {
+            return Err("No peers connected".to_string());
+        }
+
+        self.backend().send(message.as_bytes(), &peers, reliably)?;
+        println!("Sent {} bytes to {}", message.len(), peers.join(", "));
+        // Replies, the echo peer's for instance
+        self.run_for(Duration::from_secs(1))
+    }
+
+    fn chat(&mut self) -> Result<(), String> {
+        println!("Type a line to send it to everyone, /help for commands");
+        loop {
+            match self.next(Duration::MAX)? {
+                Some(Event::Data { peer, data }) => print_message(&peer, &data),
+                Some(Event::Input(None) | Event::Interrupted) => return Ok(()),
+                Some(Event::Input(Some(line))) if line.trim() == "/quit" => return Ok(()),
+                Some(Event::Input(Some(line))) => self.chat_line(line.trim()),
+                _ => {}
+            }
+        }
+    }
+
+    fn chat_line(&self, line: &str) {
+        let backend = self.backend();
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("", _) => {}
+            ("/help", _) => {
+                println!("/peers             list connected peers");
+                println!("/msg <peer> <text> send to one peer");
+                println!("/quit              leave");
+            }
+            ("/peers", _) => println!("Connected: {}", backend.connected_peers().join(", ")),
+            ("/msg", rest) => match rest.split_once(' ') {
+                Some((peer, text)) => {
+                    if let Err(e) = backend.send(text.as_bytes(), &[peer.to_string()], true) {
+                        println!("Not sent: {}", e);
+                    }
+                }
+                None => println!("Usage: /msg <peer> <text>"),
+            },
+            _ if line.starts_with('/') => println!("Unknown command, try /help"),
+            _ => {
+                let peers = backend.connected_peers();
+                if peers.is_empty() {
+                    println!("Nobody is connected");
+                } else if let Err(e) = backend.send(line.as_bytes(), &peers, true) {
+                    println!("Not sent: {}", e);
+                }
+            }
         }
     }
+
+    fn send_file(&mut self, peer: &str, path: &Path, wait: Duration) -> Result<(), String> {
+        let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| format!("{} is not a file", path.display()))?;
+
+        self.wait_for(peer, wait)?;
+        let span = info_span!("send_file", peer, file = %name, bytes = field::Empty);
+        let _enter = span.enter();
+        let mut stream = self.backend().open_stream(peer, &name)?;
+        let started = Instant::now();
+        let sent = io::copy(&mut file, &mut stream).map_err(|e| e.to_string())?;
+        stream.flush().map_err(|e| e.to_string())?;
+        span.record("bytes", sent);
+        println!(
+            "Sent {} ({} bytes) to {} in {:?}",
+            name,
+            sent,
+            peer,
+            started.elapsed()
+        );
+        Ok(())
+    }
+
+    fn recv(&mut self, dir: &Path) -> Result<(), String> {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        loop {
+            match self.next(Duration::MAX)? {
+                Some(Event::Data { peer, data }) => print_message(&peer, &data),
+                Some(Event::Stream { peer, name, stream }) => {
+                    save_stream(dir, peer, &name, stream, |line| println!("{}", line))
+                }
+                Some(Event::Interrupted) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn pair(&mut self, peer: &str, wait: Duration) -> Result<(), String> {
+        if self.book.is_none() {
+            return Err("pair needs --address-book to remember the peer".to_string());
+        }
+        self.pairing_with = Some(peer.to_string());
+        self.wait_for(peer, wait)?;
+
+        let mut pairing = {
+            let noise = self.noise.as_ref().expect("pair runs with --noise");
+            let noise = noise.lock().unwrap();
+            match (noise.remote_key(peer), noise.handshake_hash(peer)) {
+                (Some(key), Some(hash)) => {
+                    Pairing::new(peer, noise.public_key(), key, hash, rand::random())
+                }
+                _ => return Err(format!("No secure channel to {}", peer)),
+            }
+        };
+        if let Some(frame) = pairing.start() {
+            self.backend()
+                .send_frame(&frame, &[peer.to_string()], true)?;
+        }
+
+        loop {
+            match self.next(Duration::MAX)? {
+                Some(Event::Input(line)) if pairing.is_waiting_for_user() => {
+                    let line = line.unwrap_or_default();
+                    let same = matches!(line.trim(), "y" | "yes");
+                    let frame = pairing.confirm(same);
+                    self.backend()
+                        .send_frame(&frame, &[peer.to_string()], true)?;
+                    if same && pairing.outcome().is_none() {
+                        println!("Waiting for {} to confirm", peer);
+                    }
+                }
+                Some(Event::Frame { peer: from, frame })
+                    if from == peer && frame.kind == FrameKind::Pair =>
+                {
+                    let shown = pairing.sas().is_some();
+                    if let Some(reply) = pairing.on_frame(&frame)? {
+                        self.backend()
+                            .send_frame(&reply, &[peer.to_string()], true)?;
+                    }
+                    if let (false, Some(sas)) = (shown, pairing.sas()) {
+                        println!("Pairing code for {}: {}", peer, sas);
+                        let names: Vec<&str> = sas.emoji().iter().map(|(_, name)| *name).collect();
+                        println!("({})", names.join(", "));
+                        println!("Does {} show the same code? [y/n]", peer);
+                    }
+                }
+                Some(Event::Left { peer: left }) if left == peer => {
+                    return Err(format!("{} left before pairing finished", peer));
+                }
+                Some(Event::Interrupted) => return Err("Pairing interrupted".to_string()),
+                _ => {}
+            }
+
+            match pairing.outcome() {
+                Some(PairingOutcome::Paired) => break,
+                Some(PairingOutcome::Rejected) => {
+                    return Err(format!("Not paired with {}", peer));
+                }
+                Some(PairingOutcome::RejectedByPeer) => {
+                    return Err(format!("{} saw a different code, not paired", peer));
+                }
+                None => {}
+            }
+        }
+
+        let book = self.book.as_mut().expect("checked above");
+        // Whatever the peer calls itself, it is the key it paired with
+        let identity = identity_of_key(pairing.remote_key());
+        book.observe(
+            &identity,
+            peer,
+            peer,
+            &DiscoveryInfo::new(),
+            SystemTime::now(),
+        )?;
+        book.record_pairing(&identity, peer, peer, *pairing.remote_key())?;
+        println!(
+            "Paired with {}, key {}",
+            peer,
+            key_to_hex(pairing.remote_key())
+        );
+        Ok(())
+    }
+
+    fn replay(
+        &mut self,
+        local: &str,
+        path: &Path,
+        speed: f64,
+        events: mpsc::Sender<Event>,
+    ) -> Result<(), String> {
+        let Transport::Memory { backend } = &self.transport else {
+            return Err("Captures only replay into the memory backend".to_string());
+        };
+        let capture = Capture::open(path)?;
+        println!(
+            "Replaying {} records over {:?} captured on {}",
+            capture.records.len(),
+            capture.duration(),
+            capture.local
+        );
+        let network = backend.network().clone();
+        let local = local.to_string();
+        let config = ReplayConfig {
+            speed,
+            ..ReplayConfig::default()
+        };
+        thread::Builder::new()
+            .name("capture-replay".to_string())
+            .spawn(move || {
+                let result = replay(&capture, &network, &local, &config, |record| {
+                    debug!("Replayed {:?} {:?}", record.peer, record.event);
+                });
+                let _ = events.send(Event::ReplayDone(result));
+            })
+            .map_err(|e| e.to_string())?;
+
+        loop {
+            match self.next(Duration::MAX)? {
+                Some(Event::Data { peer, data }) => print_message(&peer, &data),
+                Some(Event::ReplayDone(result)) => {
+                    let report = result?;
+                    // Frames still in flight
+                    self.run_for(Duration::from_secs(1))?;
+                    println!(
+                        "Delivered {} frames, skipped {}, in {:?}",
+                        report.delivered, report.skipped, report.elapsed
+                    );
+                    return Ok(());
+                }
+                Some(Event::Interrupted) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn bench(
+        &mut self,
+        peer: &str,
+        config: &BenchConfig,
+        wait: Duration,
+        json: bool,
+    ) -> Result<(), String> {
+        self.wait_for(peer, wait)?;
+
+        // Only the answers matter while the bench runs, everything else is
+        // dropped. With `--noise` they arrive encrypted.
+        let events = &self.events;
+        let (transport, noise) = (&self.transport, self.noise.as_ref());
+        let backend = self.backend();
+        let report = run_bench(&*backend, peer, config, |timeout| {
+            let deadline = Instant::now() + timeout;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match (events.recv_timeout(left).ok()?, noise) {
+                    (Event::Data { peer, data }, None) => return Some((peer, data)),
+                    (Event::Frame { peer, frame }, Some(noise)) => {
+                        let event = BackendEvent::Frame { peer, frame };
+                        let data = SecureBackend::new(transport.backend(), noise)
+                            .handle(event)
+                            .into_iter()
+                            .find_map(|event| match event {
+                                BackendEvent::Data { peer, data } => Some((peer, data)),
+                                _ => None,
+                            });
+                        if data.is_some() {
+                            return data;
+                        }
+                    }
+                    _ if left.is_zero() => return None,
+                    _ => {}
+                }
+            }
+        })?;
+
+        if json {
+            println!("{}", report.to_json());
+        } else {
+            print!("{}", report);
+        }
+        Ok(())
+    }
+
+    fn stats(&mut self, interval: Duration) -> Result<(), String> {
+        let mut report_at = Instant::now() + interval;
+        loop {
+            let left = report_at.saturating_duration_since(Instant::now());
+            if !left.is_zero() {
+                // Streams are counted, their contents dropped
+                match self.next(left)? {
+                    Some(Event::Stream { mut stream, .. }) => {
+                        thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+                    }
+                    Some(Event::Interrupted) => return Ok(()),
+                    _ => {}
+                }
+                continue;
+            }
+
+            println!(
+                "{:<24} {:>9} {:>6} {:>10} {:>12} {:>8}",
+                "peer", "state", "joins", "messages", "bytes", "streams"
+            );
+            for (peer, stats) in &self.stats {
+                println!(
+                    "{:<24} {:>9} {:>6} {:>10} {:>12} {:>8}",
+                    peer,
+                    if stats.connected { "connected" } else { "gone" },
+                    stats.joins,
+                    stats.messages,
+                    stats.bytes,
+                    stats.streams
+                );
+            }
+            println!();
+            // MPC also keeps link level counters of its own
+            #[cfg(target_os = "macos")]
+            if let Transport::Mpc(session) = &self.transport {
+                println!("{}", session.metrics());
+            }
+            report_at += interval;
+        }
+    }
+}
+
+// Attempts and their outcome, the schedule is only logged
+#[cfg(target_os = "macos")]
+fn reconnect_notice(event: &ReconnectEvent<String>) -> Option<String> {
+    match event {
+        ReconnectEvent::Attempt { peer, attempt } => {
+            Some(format!("Reconnecting to {}, attempt {}", peer, attempt))
+        }
+        ReconnectEvent::Reconnected { peer, attempts } => Some(format!(
+            "Reconnected to {} after {} attempts",
+            peer, attempts
+        )),
+        ReconnectEvent::GaveUp { peer, attempts } => Some(format!(
+            "Gave up reconnecting to {} after {} attempts",
+            peer, attempts
+        )),
+        _ => None,
+    }
+}
+
+fn describe(node: &str, addrs: &[SocketAddr]) -> String {
+    if addrs.is_empty() {
+        return node.to_string();
+    }
+    let addrs: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+    format!("{} ({})", node, addrs.join(", "))
+}
+
+fn print_message(peer: &str, data: &[u8]) {
+    match std::str::from_utf8(data) {
+        Ok(text) => println!("<{}> {}", peer, text),
+        Err(_) => println!("<{}> {} bytes of binary data", peer, data.len()),
+    }
+}
+
+// Copy the stream into `dir` on its own thread so other peers aren't held up.
+// Only the last path component of what the peer sent is used, and existing
+// files are never overwritten: "name (1).ext" and so on is used instead.
+// Progress goes to `notify`.
+fn save_stream(
+    dir: &Path,
+    peer: NodeId,
+    name: &str,
+    mut stream: Box<dyn Stream>,
+    notify: impl Fn(String) + Send + 'static,
+) {
+    let Some(file_name) = sanitize_file_name(name) else {
+        notify(format!(
+            "Ignoring stream with unusable name {:?} from {}",
+            name, peer
+        ));
+        return;
+    };
+    let (path, file) = match create_unique(dir, &file_name) {
+        Ok(created) => created,
+        Err(e) => {
+            notify(format!("Failed to save {} from {}: {}", file_name, peer, e));
+            return;
+        }
+    };
+    notify(format!("Receiving {} from {}", path.display(), peer));
+    let span = info_span!(
+        "recv_file",
+        peer = %peer,
+        file = %path.display(),
+        bytes = field::Empty
+    );
+    thread::spawn(move || {
+        let _enter = span.enter();
+        let mut file = file;
+        let result = io::copy(&mut stream, &mut file);
+        if let Ok(bytes) = result {
+            span.record("bytes", bytes);
+        }
+        notify(match result {
+            Ok(bytes) => format!("Saved {} ({} bytes) from {}", path.display(), bytes, peer),
+            Err(e) => format!("Failed to save {}: {}", path.display(), e),
+        });
+    });
+}
+
+// Last component of a name a peer sent, whichever separator it used. Names
+// that would be hidden or refer to a directory are refused.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let last = name.rsplit(['/', '\\']).next()?;
+    let cleaned: String = last
+        .chars()
+        .filter(|c| !c.is_control() && *c != ':')
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() || cleaned.starts_with('.') {
+        return None;
+    }
+    Some(cleaned.to_string())
+}
+
+// Create `file_name` in `dir`, numbering it if the name is taken
+fn create_unique(dir: &Path, file_name: &str) -> io::Result<(PathBuf, File)> {
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (file_name, None),
+    };
+    for n in 0..1000 {
+        let candidate = match (n, extension) {
+            (0, _) => file_name.to_string(),
+            (n, Some(extension)) => format!("{} ({}).{}", stem, n, extension),
+            (n, None) => format!("{} ({})", stem, n),
+        };
+        let path = dir.join(candidate);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("too many files named {}", file_name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_names_are_cut_down_to_a_plain_file_name() {
+        assert_eq!(sanitize_file_name("../x").as_deref(), Some("x"));
+        assert_eq!(sanitize_file_name("a\\b").as_deref(), Some("b"));
+        assert_eq!(sanitize_file_name("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_file_name("c:a\tb.txt").as_deref(), Some("cab.txt"));
+        assert_eq!(sanitize_file_name(".hidden"), None);
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("dir/"), None);
+        assert_eq!(sanitize_file_name(""), None);
+        assert_eq!(sanitize_file_name("  "), None);
+    }
+
+    #[test]
+    fn taken_names_are_numbered() {
+        let dir = std::env::temp_dir().join(format!("create-unique-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let names: Vec<String> = (0..3)
+            .map(|_| {
+                let (path, _) = create_unique(&dir, "notes.txt").unwrap();
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        assert_eq!(names, ["notes.txt", "notes (1).txt", "notes (2).txt"]);
+
+        create_unique(&dir, "README").unwrap();
+        let (path, _) = create_unique(&dir, "README").unwrap();
+        assert_eq!(path, dir.join("README (1)"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
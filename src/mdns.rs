@@ -0,0 +1,278 @@
+// DNS-SD discovery over multicast DNS, for machines without
+// MultipeerConnectivity.
+//
+// MPC advertises over Bonjour as `_<service type>._tcp` with the discovery
+// info in the TXT record, one `key=value` entry per pair. This backend
+// registers and browses the same service type with the same TXT encoding, so
+// Linux nodes and Apple nodes on the same LAN see each other. Only discovery
+// is covered: reaching an MPC peer still takes MPC, and the port advertised
+// here is wherever the caller's own data path listens.
+//
+// Instance and host names are the display name with a random suffix,
+// `bob-1f2e3d4c`, so two machines sharing a display name neither clash on
+// the network nor filter each other out: only our own instance is left out
+// of the browse results. Peers are reported by instance name, so those two
+// machines stay two peers; the LAN backend runs its data path under the
+// instance name for the same reason.
+
+use std::net::SocketAddr;
+use std::thread;
+
+use log::{debug, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::discovery_info::{DiscoveryInfo, service_type};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MdnsEvent {
+    /// A peer was resolved, or its record changed
+    Found {
+        name: String,
+        info: DiscoveryInfo,
+        addrs: Vec<SocketAddr>,
+    },
+    /// The peer withdrew its record or it expired
+    Lost { name: String },
+}
+
+pub struct MdnsDiscovery {
+    daemon: ServiceDaemon,
+    // `_<service type>._tcp.local.`
+    service_domain: String,
+    // Display name and suffix
+    instance: String,
+    advertised: Option<String>,
+    browse_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MdnsDiscovery {
+    /// Discovery for the service MPC would build from `service_name`
+    pub fn new(service_name: &str, display_name: &str) -> Result<Self, String> {
+        let daemon = ServiceDaemon::new().map_err(|e| e.to_string())?;
+        Ok(Self {
+            daemon,
+            service_domain: format!("_{}._tcp.local.", service_type(service_name)),
+            instance: format!("{}-{:08x}", display_name, rand::random::<u32>()),
+            advertised: None,
+            browse_thread: None,
+        })
+    }
+
+    pub fn service_domain(&self) -> &str {
+        &self.service_domain
+    }
+
+    pub fn instance_name(&self) -> &str {
+        &self.instance
+    }
+
+    /// Advertise ourselves with `info` in the TXT record, pointing at `port`.
+    /// Calling it again replaces the previous record.
+    pub fn advertise(&mut self, info: &DiscoveryInfo, port: u16) -> Result<(), String> {
+        self.stop_advertising();
+
+        let host_name = format!(
+            "{}.local.",
+            self.instance
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect::<String>()
+        );
+        let properties: Vec<(&str, &str)> = info.iter().collect();
+        let service = ServiceInfo::new(
+            &self.service_domain,
+            &self.instance,
+            &host_name,
+            "",
+            port,
+            &properties[..],
+        )
+        .map_err(|e| e.to_string())?
+        .enable_addr_auto();
+
+        let fullname = service.get_fullname().to_string();
+        self.daemon.register(service).map_err(|e| e.to_string())?;
+        debug!("Advertising {}", fullname);
+        self.advertised = Some(fullname);
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) {
+        if let Some(fullname) = self.advertised.take() {
+            debug!("Withdrawing {}", fullname);
+            if let Err(e) = self.daemon.unregister(&fullname) {
+                warn!("Failed to withdraw {}: {}", fullname, e);
+            }
+        }
+    }
+
+    pub fn is_advertising(&self) -> bool {
+        self.advertised.is_some()
+    }
+
+    /// Browse for other peers. `on_event` is called from a background
+    /// thread until browsing stops.
+    pub fn browse(&mut self, on_event: impl Fn(&MdnsEvent) + Send + 'static) -> Result<(), String> {
+        self.stop_browsing();
+
+        let events = self
+            .daemon
+            .browse(&self.service_domain)
+            .map_err(|e| e.to_string())?;
+        let own = format!("{}.{}", self.instance, self.service_domain);
+        let suffix = format!(".{}", self.service_domain);
+
+        let handle = thread::Builder::new()
+            .name("mdns-browse".to_string())
+            .spawn(move || {
+                while let Ok(event) = events.recv() {
+                    match event {
+                        ServiceEvent::ServiceResolved(service) => {
+                            if service.get_fullname() == own {
+                                continue;
+                            }
+                            let Some(instance) = service.get_fullname().strip_suffix(&suffix)
+                            else {
+                                continue;
+                            };
+                            on_event(&MdnsEvent::Found {
+                                name: instance.to_string(),
+                                info: txt_to_info(&service),
+                                addrs: service
+                                    .get_addresses()
+                                    .iter()
+                                    .map(|ip| SocketAddr::new(*ip, service.get_port()))
+                                    .collect(),
+                            });
+                        }
+                        ServiceEvent::ServiceRemoved(_, fullname) => {
+                            if fullname == own {
+                                continue;
+                            }
+                            if let Some(instance) = fullname.strip_suffix(&suffix) {
+                                on_event(&MdnsEvent::Lost {
+                                    name: instance.to_string(),
+                                });
+                            }
+                        }
+                        ServiceEvent::SearchStopped(_) => break,
+                        _ => {}
+                    }
+                }
+                debug!("mDNS browse thread stopped");
+            })
+            .map_err(|e| e.to_string())?;
+        self.browse_thread = Some(handle);
+        Ok(())
+    }
+
+    pub fn stop_browsing(&mut self) {
+        let Some(handle) = self.browse_thread.take() else {
+            return;
+        };
+        if let Err(e) = self.daemon.stop_browse(&self.service_domain) {
+            warn!("Failed to stop browsing: {}", e);
+        }
+        let _ = handle.join();
+    }
+
+    pub fn is_browsing(&self) -> bool {
+        self.browse_thread.is_some()
+    }
+
+    /// Withdraw our record and stop the daemon. Safe to call more than once.
+    pub fn shutdown(&mut self) {
+        self.stop_advertising();
+        self.stop_browsing();
+        // The daemon reports when it is done; nothing is waiting on that
+        let _ = self.daemon.shutdown();
+    }
+}
+
+impl Drop for MdnsDiscovery {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Entries that don't fit a TXT record MPC would accept are skipped
+fn txt_to_info(service: &ServiceInfo) -> DiscoveryInfo {
+    let mut info = DiscoveryInfo::new();
+    for property in service.get_properties().iter() {
+        if let Err(e) = info.insert(property.key(), property.val_str()) {
+            debug!("Ignoring TXT entry from {}: {}", service.get_fullname(), e);
+        }
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process::{Command, Stdio};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // Set for the child process of the loopback test
+    const CHILD_SERVICE: &str = "MDNS_TEST_SERVICE";
+
+    #[test]
+    fn instance_names_are_unique_per_discovery() {
+        let a = MdnsDiscovery::new("test", "bob").unwrap();
+        let b = MdnsDiscovery::new("test", "bob").unwrap();
+        assert_ne!(a.instance_name(), b.instance_name());
+        assert!(a.instance_name().starts_with("bob-"));
+    }
+
+    // Advertises as another "bob" for a while when run by the loopback test
+    #[test]
+    #[ignore = "only run as the loopback test's child"]
+    fn advertise_for_the_loopback_test() {
+        let Ok(service) = env::var(CHILD_SERVICE) else {
+            return;
+        };
+        let mut mdns = MdnsDiscovery::new(&service, "bob").unwrap();
+        mdns.advertise(&DiscoveryInfo::new().with("role", "child").unwrap(), 4242)
+            .unwrap();
+        thread::sleep(Duration::from_secs(10));
+    }
+
+    #[test]
+    #[ignore = "needs multicast on the loopback interface"]
+    fn peers_sharing_our_display_name_are_found_across_processes() {
+        let service = format!("t{}", std::process::id());
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args([
+                "--ignored",
+                "--exact",
+                "mdns::tests::advertise_for_the_loopback_test",
+            ])
+            .env(CHILD_SERVICE, &service)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let mut mdns = MdnsDiscovery::new(&service, "bob").unwrap();
+        mdns.advertise(&DiscoveryInfo::new(), 4343).unwrap();
+        let (tx, found) = mpsc::channel();
+        mdns.browse(move |event| {
+            let _ = tx.send(event.clone());
+        })
+        .unwrap();
+
+        let event = found.recv_timeout(Duration::from_secs(8));
+        let _ = child.kill();
+        let _ = child.wait();
+        match event {
+            Ok(MdnsEvent::Found { name, info, addrs }) => {
+                assert!(name.starts_with("bob-"), "{}", name);
+                assert_ne!(name, mdns.instance_name());
+                assert_eq!(info.get("role"), Some("child"));
+                assert!(addrs.iter().all(|addr| addr.port() == 4242));
+            }
+            other => panic!("Expected the child to be found, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,556 @@
+// In-process backend running the simulated network in real time.
+//
+// A `MemoryNetwork` owns a `SimNetwork` and a pump thread that moves its
+// clock along with the wall clock, so link conditions (latency, loss,
+// partitions, ...) apply exactly as they do in the harness, only without the
+// manual `advance`. Every device joins through `join` and gets a
+// `MemoryBackend`. Devices that find each other connect on their own, the
+// one with the smaller name sends the invitation.
+//
+// Streams don't go through the simulation: they are a local socket pair
+// handed straight to the peer.
+//
+// A device can capture its traffic with `enable_capture`, in the same
+// format as the MPC session so captures from either replay the same way.
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, trace, warn};
+
+use crate::aggregator::DiscoveryEvent;
+use crate::backend::{Backend, BackendEvent, Stream};
+use crate::capture::{CaptureEvent, CaptureWriter};
+use crate::discovery_info::DiscoveryInfo;
+use crate::frame::{Frame, FrameKind};
+use crate::mesh::NodeId;
+use crate::metrics::DisconnectReason;
+use crate::sim::{PeerState, SimConfig, SimEventKind, SimNetwork};
+
+/// How often the pump thread moves the simulation forward
+const PUMP_TICK: Duration = Duration::from_millis(1);
+
+type EventCallback = Arc<dyn Fn(BackendEvent) + Send + Sync>;
+type DiscoveryCallback = Arc<dyn Fn(&DiscoveryEvent) + Send + Sync>;
+
+struct Device {
+    on_event: EventCallback,
+    on_discovery: Option<DiscoveryCallback>,
+    // Peers reported as joined and not yet as left
+    connected: HashSet<NodeId>,
+    capture: Option<CaptureWriter>,
+}
+
+impl Device {
+    fn capture(&mut self, peer: &str, event: CaptureEvent) {
+        if let Some(writer) = self.capture.as_mut()
+            && let Err(e) = writer.record(peer, &event)
+        {
+            warn!("Failed to capture {:?}: {}", event, e);
+        }
+    }
+}
+
+struct Inner {
+    sim: Mutex<SimNetwork>,
+    devices: Mutex<HashMap<NodeId, Device>>,
+}
+
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Inner>,
+}
+
+impl MemoryNetwork {
+    /// Start the network. The pump thread stops once the network and every
+    /// backend on it are dropped.
+    pub fn new(config: SimConfig) -> Result<Self, String> {
+        let inner = Arc::new(Inner {
+            sim: Mutex::new(SimNetwork::new(config)),
+            devices: Mutex::new(HashMap::new()),
+        });
+        let weak = Arc::downgrade(&inner);
+        thread::Builder::new()
+            .name("memory-net".to_string())
+            .spawn(move || run_pump(weak))
+            .map_err(|e| e.to_string())?;
+        Ok(Self { inner })
+    }
+
+    /// Add a device called `name`. `on_event` is called from the pump
+    /// thread.
+    pub fn join(
+        &self,
+        name: &str,
+        on_event: impl Fn(BackendEvent) + Send + Sync + 'static,
+    ) -> Result<MemoryBackend, String> {
+        self.inner.sim.lock().unwrap().add_device(name)?;
+        self.inner.devices.lock().unwrap().insert(
+            name.to_string(),
+            Device {
+                on_event: Arc::new(on_event),
+                on_discovery: None,
+                connected: HashSet::new(),
+                capture: None,
+            },
+        );
+        Ok(MemoryBackend {
+            name: name.to_string(),
+            network: self.clone(),
+            stopped: false,
+        })
+    }
+
+    /// Change link conditions, partitions and so on while running
+    pub fn with_sim<R>(&self, f: impl FnOnce(&mut SimNetwork) -> R) -> R {
+        f(&mut self.inner.sim.lock().unwrap())
+    }
+}
+
+fn run_pump(inner: Weak<Inner>) {
+    let mut last = Instant::now();
+    loop {
+        thread::sleep(PUMP_TICK);
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+
+        let now = Instant::now();
+        let events = {
+            let mut sim = inner.sim.lock().unwrap();
+            sim.advance(now - last);
+            let events = sim.drain_events();
+            // Connect whoever finds each other
+            for event in &events {
+                if let SimEventKind::PeerFound { peer } = &event.kind
+                    && event.device < *peer
+                    && sim.state(&event.device, peer) == PeerState::NotConnected
+                    && let Err(e) = sim.invite(&event.device, peer)
+                {
+                    debug!("{} failed to invite {}: {}", event.device, peer, e);
+                }
+            }
+            events
+        };
+        last = now;
+
+        for event in events {
+            dispatch(&inner, &event.device, event.kind);
+        }
+    }
+    debug!("Memory network stopped");
+}
+
+// Turn a simulation event into backend events. Callbacks run without any
+// lock held so they can send right away.
+fn dispatch(inner: &Inner, device: &str, kind: SimEventKind) {
+    let mut devices = inner.devices.lock().unwrap();
+    let Some(state) = devices.get_mut(device) else {
+        return;
+    };
+    let event = match kind {
+        SimEventKind::PeerFound { peer } => {
+            state.capture(&peer, CaptureEvent::Found);
+            let on_discovery = state.on_discovery.clone();
+            drop(devices);
+            if let Some(cb) = on_discovery {
+                cb(&DiscoveryEvent::Found {
+                    node: peer,
+                    info: DiscoveryInfo::new(),
+                    addrs: Vec::new(),
+                });
+            }
+            return;
+        }
+        SimEventKind::PeerLost { peer } => {
+            state.capture(&peer, CaptureEvent::Lost);
+            let on_discovery = state.on_discovery.clone();
+            drop(devices);
+            if let Some(cb) = on_discovery {
+                cb(&DiscoveryEvent::Lost { node: peer });
+            }
+            return;
+        }
+        SimEventKind::StateChanged {
+            peer,
+            state: peer_state,
+        } => match peer_state {
+            PeerState::Connected if state.connected.insert(peer.clone()) => {
+                state.capture(&peer, CaptureEvent::Connected);
+                BackendEvent::Joined { peer }
+            }
+            PeerState::NotConnected if state.connected.remove(&peer) => {
+                state.capture(&peer, disconnected(DisconnectReason::Dropped));
+                BackendEvent::Left { peer }
+            }
+            _ => return,
+        },
+        SimEventKind::Data { peer, bytes, .. } => match Frame::decode(&bytes) {
+            Ok(frame) => match frame.kind {
+                // Whatever the simulation reports afterwards is not news
+                FrameKind::Goodbye if state.connected.remove(&peer) => {
+                    state.capture(&peer, CaptureEvent::Received { frame: bytes });
+                    state.capture(&peer, disconnected(DisconnectReason::Goodbye));
+                    BackendEvent::Left { peer }
+                }
+                FrameKind::Goodbye => return,
+                FrameKind::Data => {
+                    state.capture(&peer, CaptureEvent::Received { frame: bytes });
+                    BackendEvent::Data {
+                        peer,
+                        data: frame.payload,
+                    }
+                }
+                _ => {
+                    state.capture(&peer, CaptureEvent::Received { frame: bytes });
+                    BackendEvent::Frame { peer, frame }
+                }
+            },
+            Err(e) => {
+                // Kept so a replay can reproduce whatever choked on it
+                state.capture(&peer, CaptureEvent::Received { frame: bytes });
+                warn!("Bad frame from {}: {}", peer, e);
+                return;
+            }
+        },
+    };
+    let on_event = state.on_event.clone();
+    drop(devices);
+    trace!("{} <- {:?}", device, event);
+    on_event(event);
+}
+
+fn disconnected(reason: DisconnectReason) -> CaptureEvent {
+    CaptureEvent::Disconnected {
+        reason: reason.name().to_string(),
+    }
+}
+
+pub struct MemoryBackend {
+    name: NodeId,
+    network: MemoryNetwork,
+    stopped: bool,
+}
+
+impl MemoryBackend {
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+
+    /// Report peers coming into and going out of range. `on_event` is
+    /// called from the pump thread.
+    pub fn enable_discovery_events(
+        &self,
+        on_event: impl Fn(&DiscoveryEvent) + Send + Sync + 'static,
+    ) {
+        if let Some(device) = self
+            .network
+            .inner
+            .devices
+            .lock()
+            .unwrap()
+            .get_mut(&self.name)
+        {
+            device.on_discovery = Some(Arc::new(on_event));
+        }
+    }
+
+    pub fn disable_discovery_events(&self) {
+        if let Some(device) = self
+            .network
+            .inner
+            .devices
+            .lock()
+            .unwrap()
+            .get_mut(&self.name)
+        {
+            device.on_discovery = None;
+        }
+    }
+
+    /// Write every frame sent and received, and every peer state change, to
+    /// a capture file at `path`. Replaces any capture already running.
+    pub fn enable_capture(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let writer = CaptureWriter::create(path, &self.name)?;
+        if let Some(device) = self
+            .network
+            .inner
+            .devices
+            .lock()
+            .unwrap()
+            .get_mut(&self.name)
+        {
+            device.capture = Some(writer);
+        }
+        Ok(())
+    }
+
+    pub fn disable_capture(&self) {
+        if let Some(device) = self
+            .network
+            .inner
+            .devices
+            .lock()
+            .unwrap()
+            .get_mut(&self.name)
+        {
+            device.capture = None;
+        }
+    }
+
+    /// Say goodbye, then drop out of range of everybody. Safe to call more
+    /// than once.
+    pub fn shutdown(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.stopped = true;
+
+        let goodbye = Frame::goodbye().encode();
+        let mut sim = self.network.inner.sim.lock().unwrap();
+        let connected = sim.connected_peers(&self.name);
+        for peer in &connected {
+            if let Err(e) = sim.send(&self.name, peer, &goodbye, true) {
+                debug!("Failed to send goodbye to {}: {}", peer, e);
+            }
+        }
+        let others: Vec<String> = sim
+            .devices()
+            .filter(|device| *device != self.name)
+            .map(|device| device.to_string())
+            .collect();
+        for other in others {
+            sim.set_in_range(&self.name, &other, false);
+        }
+        drop(sim);
+        let device = self
+            .network
+            .inner
+            .devices
+            .lock()
+            .unwrap()
+            .remove(&self.name);
+        if let Some(mut device) = device {
+            for peer in &connected {
+                let frame = goodbye.clone();
+                device.capture(
+                    peer,
+                    CaptureEvent::Sent {
+                        frame,
+                        reliably: true,
+                    },
+                );
+                device.capture(peer, disconnected(DisconnectReason::Shutdown));
+            }
+        }
+    }
+}
+
+impl Drop for MemoryBackend {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn local_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn connected_peers(&self) -> Vec<NodeId> {
+        self.network
+            .inner
+            .sim
+            .lock()
+            .unwrap()
+            .connected_peers(&self.name)
+    }
+
+    fn send_frame(&self, frame: &Frame, peers: &[NodeId], reliably: bool) -> Result<(), String> {
+        let bytes = frame.encode();
+        let mut sim = self.network.inner.sim.lock().unwrap();
+        for peer in peers {
+            sim.send(&self.name, peer, &bytes, reliably)?;
+        }
+        drop(sim);
+
+        if let Some(device) = self
+            .network
+            .inner
+            .devices
+            .lock()
+            .unwrap()
+            .get_mut(&self.name)
+            && device.capture.is_some()
+        {
+            for peer in peers {
+                let frame = bytes.clone();
+                device.capture(peer, CaptureEvent::Sent { frame, reliably });
+            }
+        }
+        Ok(())
+    }
+
+    fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String> {
+        if !self.connected_peers().iter().any(|p| p == peer) {
+            return Err(format!("Peer {} is not connected", peer));
+        }
+        let on_event = match self.network.inner.devices.lock().unwrap().get(peer) {
+            Some(device) => device.on_event.clone(),
+            None => return Err(format!("Peer {} is not connected", peer)),
+        };
+        let (local, remote) = UnixStream::pair().map_err(|e| e.to_string())?;
+        on_event(BackendEvent::Stream {
+            peer: self.name.clone(),
+            name: name.to_string(),
+            stream: Box::new(remote),
+        });
+        Ok(Box::new(local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::backend::conformance::{self, Node, WAIT};
+    use crate::capture::Capture;
+    use crate::sim::LinkConfig;
+
+    fn join(network: &MemoryNetwork, name: &str) -> Node<MemoryBackend> {
+        let (on_event, events) = conformance::events();
+        let backend = network.join(name, on_event).unwrap();
+        Node { backend, events }
+    }
+
+    fn connected_pair(config: SimConfig) -> (Node<MemoryBackend>, Node<MemoryBackend>) {
+        let network = MemoryNetwork::new(config).unwrap();
+        let a = join(&network, "a");
+        let b = join(&network, "b");
+        a.expect_joined("b");
+        b.expect_joined("a");
+        (a, b)
+    }
+
+    fn next_data(node: &Node<MemoryBackend>) -> Vec<u8> {
+        node.expect(|event| match event {
+            BackendEvent::Data { data, .. } => Some(data),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (mut a, b) = connected_pair(SimConfig::default());
+        conformance::frames_round_trip(&a, &b);
+
+        // The goodbye makes b report a as gone right away
+        a.backend.shutdown();
+        b.expect_left("a");
+        assert!(b.backend.connected_peers().is_empty());
+    }
+
+    #[test]
+    fn streams_round_trip() {
+        let (a, b) = connected_pair(SimConfig::default());
+        conformance::streams_round_trip(&a, &b);
+    }
+
+    #[test]
+    fn streams_need_a_connected_peer() {
+        let network = MemoryNetwork::new(SimConfig::default()).unwrap();
+        conformance::streams_need_a_connected_peer(&join(&network, "a"));
+    }
+
+    #[test]
+    fn lossy_links_only_lose_unreliable_frames() {
+        let (a, b) = connected_pair(SimConfig::default());
+        let lossy = LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        };
+        a.backend
+            .network()
+            .with_sim(|sim| sim.set_link_both("a", "b", lossy));
+
+        let to_b = ["b".to_string()];
+        a.backend.send(b"lost", &to_b, false).unwrap();
+        a.backend.send(b"kept", &to_b, true).unwrap();
+        assert_eq!(next_data(&b), b"kept");
+    }
+
+    #[test]
+    fn partitions_disconnect_until_healed() {
+        let config = SimConfig {
+            disconnect_timeout: Duration::from_millis(100),
+            ..SimConfig::default()
+        };
+        let (a, b) = connected_pair(config);
+        let network = a.backend.network();
+
+        network.with_sim(|sim| sim.partition(&[vec!["a".to_string()], vec!["b".to_string()]]));
+        a.expect_left("b");
+        b.expect_left("a");
+        assert!(a.backend.send(b"hi", &["b".to_string()], true).is_err());
+
+        network.with_sim(|sim| sim.heal());
+        a.expect_joined("b");
+        b.expect_joined("a");
+    }
+
+    #[test]
+    fn discovery_events_follow_range() {
+        let network = MemoryNetwork::new(SimConfig::default()).unwrap();
+        let a = join(&network, "a");
+        let (tx, discovered) = mpsc::channel();
+        a.backend.enable_discovery_events(move |event| {
+            let _ = tx.send(event.clone());
+        });
+
+        let _b = join(&network, "b");
+        match discovered.recv_timeout(WAIT).unwrap() {
+            DiscoveryEvent::Found { node, .. } => assert_eq!(node, "b"),
+            other => panic!("expected b to be found, got {:?}", other),
+        }
+        network.with_sim(|sim| sim.set_in_range("a", "b", false));
+        match discovered.recv_timeout(WAIT).unwrap() {
+            DiscoveryEvent::Lost { node } => assert_eq!(node, "b"),
+            other => panic!("expected b to be lost, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn captures_record_traffic_and_peer_changes() {
+        let path = crate::file_format::scratch("memory-capture");
+        let (mut a, b) = connected_pair(SimConfig::default());
+        a.backend.enable_capture(&path).unwrap();
+
+        a.backend.send(b"out", &["b".to_string()], true).unwrap();
+        b.backend.send(b"in", &["a".to_string()], true).unwrap();
+        assert_eq!(next_data(&a), b"in");
+        a.backend.shutdown();
+
+        let capture = Capture::open(&path).unwrap();
+        let events: Vec<&CaptureEvent> = capture
+            .records
+            .iter()
+            .inspect(|record| assert_eq!(record.peer, "b"))
+            .map(|record| &record.event)
+            .collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                CaptureEvent::Sent { .. },
+                CaptureEvent::Received { .. },
+                CaptureEvent::Sent { .. },
+                CaptureEvent::Disconnected { .. },
+            ]
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
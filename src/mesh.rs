@@ -0,0 +1,779 @@
+// Multi-hop relay between peers that aren't directly connected.
+//
+// Every node runs a small distance-vector protocol over its direct
+// connections: it periodically advertises the destinations it can reach and
+// how many hops away they are, and picks the neighbor with the shortest
+// fresh route as next hop. Data addressed to another node is wrapped in a
+// `Relay` frame and forwarded hop by hop.
+//
+// Loops are kept out the DSDV way. Each node stamps its own entry with a
+// sequence number that only it increments (in steps of two), a route is only
+// replaced by a newer or equally fresh but shorter one, and a broken route is
+// advertised as unreachable with the odd number right after the last one seen.
+// Routes learned from a neighbor are advertised back to it as unreachable
+// (poisoned reverse). Data frames additionally carry a TTL and an id so a
+// frame caught in a transient loop dies out and duplicates are dropped.
+//
+// Like the other protocol layers this is a pure state machine: tell it about
+// neighbors, feed it frames and the current time, and send whatever `poll`
+// returns to the named neighbor.
+//
+// Route frame payload: [count: u16][(node len: u8, node, seq: u32, hops: u8)*]
+// Relay frame payload: [ttl: u8][id: u64][src len: u8][src][dst len: u8][dst][data]
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use log::{trace, warn};
+
+use crate::frame::{Frame, FrameKind};
+
+/// Name of a node, unique within the mesh
+pub type NodeId = String;
+
+/// Hop count that means "unreachable"
+pub const INFINITY: u8 = 16;
+
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    /// How often the routing table is advertised to every neighbor
+    pub advertise_interval: Duration,
+    /// Routes that haven't been refreshed for this long are dropped
+    pub route_timeout: Duration,
+    /// Hops a data frame may take before it is discarded
+    pub ttl: u8,
+    /// Number of recent message ids remembered for duplicate suppression
+    pub seen_capacity: usize,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            advertise_interval: Duration::from_secs(2),
+            route_timeout: Duration::from_secs(7),
+            ttl: 8,
+            seen_capacity: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    NoRoute,
+    TtlExpired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeshEvent {
+    /// A destination became reachable
+    RouteAdded {
+        node: NodeId,
+        next_hop: NodeId,
+        hops: u8,
+    },
+    /// A better or replacement route was picked
+    RouteChanged {
+        node: NodeId,
+        next_hop: NodeId,
+        hops: u8,
+    },
+    /// A destination is no longer reachable
+    RouteLost { node: NodeId },
+    /// A frame we were relaying had to be discarded
+    Dropped {
+        source: NodeId,
+        destination: NodeId,
+        reason: DropReason,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub next_hop: NodeId,
+    pub hops: u8,
+    seq: u32,
+    refreshed: Instant,
+}
+
+impl Route {
+    pub fn is_reachable(&self) -> bool {
+        self.hops < INFINITY
+    }
+}
+
+#[derive(Debug)]
+pub struct Mesh {
+    local: NodeId,
+    config: MeshConfig,
+    seq: u32,
+    neighbors: HashSet<NodeId>,
+    routes: BTreeMap<NodeId, Route>,
+    next_advertisement: Instant,
+    // Set when the table changed and neighbors should hear about it now
+    triggered: bool,
+    next_id: u64,
+    seen: HashSet<(NodeId, u64)>,
+    seen_order: VecDeque<(NodeId, u64)>,
+    outgoing: Vec<(NodeId, Frame)>,
+    events: Vec<MeshEvent>,
+}
+
+impl Mesh {
+    /// `seed` picks the first message id, so a restarted node isn't taken for
+    /// a duplicate of its previous run
+    pub fn new(local: &str, config: MeshConfig, seed: u64, now: Instant) -> Self {
+        Self {
+            local: local.to_string(),
+            config,
+            seq: 0,
+            neighbors: HashSet::new(),
+            routes: BTreeMap::new(),
+            next_advertisement: now,
+            triggered: false,
+            next_id: seed,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn local(&self) -> &str {
+        &self.local
+    }
+
+    pub fn config(&self) -> &MeshConfig {
+        &self.config
+    }
+
+    /// A direct connection to `peer` came up
+    pub fn neighbor_up(&mut self, peer: &str, now: Instant) {
+        if peer == self.local || !self.neighbors.insert(peer.to_string()) {
+            return;
+        }
+        // Keep the sequence number we know so the neighbor's own adverts
+        // are still recognised as fresh
+        let seq = self.routes.get(peer).map_or(0, |route| route.seq);
+        self.set_route(peer, peer, 1, seq, now);
+        self.triggered = true;
+    }
+
+    /// The direct connection to `peer` went away
+    pub fn neighbor_down(&mut self, peer: &str, now: Instant) {
+        if !self.neighbors.remove(peer) {
+            return;
+        }
+        let broken: Vec<NodeId> = self
+            .routes
+            .iter()
+            .filter(|(_, route)| route.next_hop == peer && route.is_reachable())
+            .map(|(node, _)| node.clone())
+            .collect();
+        for node in broken {
+            self.break_route(&node, now);
+        }
+    }
+
+    pub fn is_neighbor(&self, peer: &str) -> bool {
+        self.neighbors.contains(peer)
+    }
+
+    /// Reachable destinations with their next hop and distance
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &Route)> {
+        self.routes
+            .iter()
+            .filter(|(_, route)| route.is_reachable())
+            .map(|(node, route)| (node.as_str(), route))
+    }
+
+    pub fn next_hop(&self, node: &str) -> Option<&str> {
+        self.routes
+            .get(node)
+            .filter(|route| route.is_reachable())
+            .map(|route| route.next_hop.as_str())
+    }
+
+    /// Send `data` to `node`, directly connected or not
+    pub fn send_to(&mut self, node: &str, data: &[u8]) -> Result<(), String> {
+        if node == self.local {
+            return Err("Can't send to ourselves".to_string());
+        }
+        let next_hop = self
+            .next_hop(node)
+            .ok_or_else(|| format!("No route to {}", node))?
+            .to_string();
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let local = self.local.clone();
+        self.remember(&local, id);
+
+        let frame = encode_relay(self.config.ttl, id, &local, node, data)?;
+        self.outgoing.push((next_hop, frame));
+        Ok(())
+    }
+
+    /// Handle a `Route` or `Relay` frame from the neighbor `from`. Returns
+    /// the source and payload of data addressed to us.
+    pub fn on_frame(
+        &mut self,
+        from: &str,
+        frame: &Frame,
+        now: Instant,
+    ) -> Result<Option<(NodeId, Vec<u8>)>, String> {
+        match frame.kind {
+            FrameKind::Route => {
+                self.on_route(from, &frame.payload, now)?;
+                Ok(None)
+            }
+            FrameKind::Relay => self.on_relay(&frame.payload),
+            other => Err(format!("Unexpected {:?} frame on mesh", other)),
+        }
+    }
+
+    /// Frames to send, each paired with the neighbor it goes to
+    pub fn poll(&mut self, now: Instant) -> Vec<(NodeId, Frame)> {
+        self.expire(now);
+
+        if now >= self.next_advertisement {
+            self.seq = self.seq.wrapping_add(2);
+            self.next_advertisement = now + self.config.advertise_interval;
+            self.triggered = true;
+        }
+        if self.triggered {
+            self.triggered = false;
+            self.advertise();
+        }
+
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Earliest instant `poll` has something to do
+    pub fn next_timeout(&self) -> Option<Instant> {
+        let expiry = self
+            .routes
+            .values()
+            .map(|route| route.refreshed + self.config.route_timeout)
+            .min();
+        Some(expiry.map_or(self.next_advertisement, |at| {
+            at.min(self.next_advertisement)
+        }))
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        self.triggered || !self.outgoing.is_empty()
+    }
+
+    pub fn drain_events(&mut self) -> Vec<MeshEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn set_route(&mut self, node: &str, next_hop: &str, hops: u8, seq: u32, now: Instant) {
+        let route = Route {
+            next_hop: next_hop.to_string(),
+            hops,
+            seq,
+            refreshed: now,
+        };
+        let event = match self.routes.get(node) {
+            Some(old) if old.is_reachable() => (old.next_hop != route.next_hop || old.hops != hops)
+                .then(|| MeshEvent::RouteChanged {
+                    node: node.to_string(),
+                    next_hop: next_hop.to_string(),
+                    hops,
+                }),
+            _ => Some(MeshEvent::RouteAdded {
+                node: node.to_string(),
+                next_hop: next_hop.to_string(),
+                hops,
+            }),
+        };
+        if let Some(event) = event {
+            self.events.push(event);
+            self.triggered = true;
+        }
+        self.routes.insert(node.to_string(), route);
+    }
+
+    // Keep the entry around as unreachable for a while so the breakage
+    // propagates, with an odd sequence number that beats the last real one
+    fn break_route(&mut self, node: &str, now: Instant) {
+        let Some(route) = self.routes.get_mut(node) else {
+            return;
+        };
+        if !route.is_reachable() {
+            return;
+        }
+        route.hops = INFINITY;
+        route.seq = route.seq.wrapping_add(1) | 1;
+        route.refreshed = now;
+        self.events.push(MeshEvent::RouteLost {
+            node: node.to_string(),
+        });
+        self.triggered = true;
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.route_timeout;
+        let stale: Vec<(NodeId, bool)> = self
+            .routes
+            .iter()
+            .filter(|(_, route)| now.saturating_duration_since(route.refreshed) >= timeout)
+            .map(|(node, route)| (node.clone(), route.is_reachable()))
+            .collect();
+        for (node, reachable) in stale {
+            if reachable {
+                self.break_route(&node, now);
+            } else {
+                self.routes.remove(&node);
+            }
+        }
+    }
+
+    fn advertise(&mut self) {
+        let neighbors: Vec<NodeId> = self.neighbors.iter().cloned().collect();
+        for neighbor in neighbors {
+            let mut entries = vec![(self.local.as_str(), self.seq, 0)];
+            for (node, route) in &self.routes {
+                if *node == neighbor {
+                    continue;
+                }
+                // Poisoned reverse: don't offer a neighbor its own routes back
+                let hops = if route.next_hop == neighbor {
+                    INFINITY
+                } else {
+                    route.hops
+                };
+                entries.push((node.as_str(), route.seq, hops));
+            }
+            match encode_routes(&entries) {
+                Ok(frame) => self.outgoing.push((neighbor, frame)),
+                Err(e) => warn!("Can't advertise routes to {}: {}", neighbor, e),
+            }
+        }
+    }
+
+    fn on_route(&mut self, from: &str, body: &[u8], now: Instant) -> Result<(), String> {
+        if !self.neighbors.contains(from) {
+            return Err(format!("Route advertisement from non-neighbor {}", from));
+        }
+
+        for (node, seq, hops) in decode_routes(body)? {
+            if node == self.local {
+                continue;
+            }
+            let hops = hops.saturating_add(1).min(INFINITY);
+
+            let Some(current) = self.routes.get(&node) else {
+                if hops < INFINITY {
+                    self.set_route(&node, from, hops, seq, now);
+                }
+                continue;
+            };
+
+            let newer = seq_newer(seq, current.seq);
+            let same_seq = seq == current.seq;
+            let via_current = current.next_hop == from;
+
+            if hops >= INFINITY {
+                // Only the current next hop can take a route away
+                if via_current && (newer || same_seq) {
+                    if let Some(route) = self.routes.get_mut(&node) {
+                        route.seq = seq;
+                    }
+                    self.break_route(&node, now);
+                }
+                continue;
+            }
+
+            if newer || (same_seq && (hops < current.hops || via_current)) {
+                self.set_route(&node, from, hops, seq, now);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_relay(&mut self, body: &[u8]) -> Result<Option<(NodeId, Vec<u8>)>, String> {
+        let relay = decode_relay(body)?;
+        if !self.remember(&relay.source, relay.id) {
+            trace!(
+                "Dropping duplicate relay {} from {}",
+                relay.id, relay.source
+            );
+            return Ok(None);
+        }
+
+        if relay.destination == self.local {
+            return Ok(Some((relay.source, relay.data.to_vec())));
+        }
+
+        let next_hop = match self.next_hop(&relay.destination) {
+            Some(hop) if relay.ttl > 1 => hop.to_string(),
+            hop => {
+                let reason = if hop.is_some() {
+                    DropReason::TtlExpired
+                } else {
+                    DropReason::NoRoute
+                };
+                self.events.push(MeshEvent::Dropped {
+                    source: relay.source,
+                    destination: relay.destination,
+                    reason,
+                });
+                return Ok(None);
+            }
+        };
+        let frame = encode_relay(
+            relay.ttl - 1,
+            relay.id,
+            &relay.source,
+            &relay.destination,
+            relay.data,
+        )?;
+        self.outgoing.push((next_hop, frame));
+        Ok(None)
+    }
+
+    // Returns false if the message was already seen
+    fn remember(&mut self, source: &str, id: u64) -> bool {
+        let key = (source.to_string(), id);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.seen_order.push_back(key);
+        while self.seen_order.len() > self.config.seen_capacity {
+            if let Some(old) = self.seen_order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+// Serial number arithmetic so the sequence can wrap
+fn seq_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+fn push_node(body: &mut Vec<u8>, node: &str) -> Result<(), String> {
+    let len = u8::try_from(node.len()).map_err(|_| format!("Node id too long: {}", node))?;
+    body.push(len);
+    body.extend_from_slice(node.as_bytes());
+    Ok(())
+}
+
+// Reads a length prefixed node id, returning it and the rest of the input
+fn read_node(body: &[u8]) -> Result<(NodeId, &[u8]), String> {
+    let (&len, rest) = body
+        .split_first()
+        .ok_or_else(|| "Truncated node id".to_string())?;
+    let len = len as usize;
+    if rest.len() < len {
+        return Err("Truncated node id".to_string());
+    }
+    let node = String::from_utf8(rest[..len].to_vec()).map_err(|e| e.to_string())?;
+    Ok((node, &rest[len..]))
+}
+
+fn encode_routes(entries: &[(&str, u32, u8)]) -> Result<Frame, String> {
+    let count = u16::try_from(entries.len()).map_err(|_| "Too many routes".to_string())?;
+    let mut body = count.to_be_bytes().to_vec();
+    for (node, seq, hops) in entries {
+        push_node(&mut body, node)?;
+        body.extend_from_slice(&seq.to_be_bytes());
+        body.push(*hops);
+    }
+    Ok(Frame::new(FrameKind::Route, body))
+}
+
+fn decode_routes(body: &[u8]) -> Result<Vec<(NodeId, u32, u8)>, String> {
+    if body.len() < 2 {
+        return Err(format!("Truncated route frame ({} bytes)", body.len()));
+    }
+    let count = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let mut rest = &body[2..];
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (node, tail) = read_node(rest)?;
+        if tail.len() < 5 {
+            return Err("Truncated route entry".to_string());
+        }
+        let seq = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]);
+        entries.push((node, seq, tail[4]));
+        rest = &tail[5..];
+    }
+    Ok(entries)
+}
+
+struct Relay<'a> {
+    ttl: u8,
+    id: u64,
+    source: NodeId,
+    destination: NodeId,
+    data: &'a [u8],
+}
+
+fn encode_relay(
+    ttl: u8,
+    id: u64,
+    source: &str,
+    destination: &str,
+    data: &[u8],
+) -> Result<Frame, String> {
+    let mut body = Vec::with_capacity(11 + source.len() + destination.len() + data.len());
+    body.push(ttl);
+    body.extend_from_slice(&id.to_be_bytes());
+    push_node(&mut body, source)?;
+    push_node(&mut body, destination)?;
+    body.extend_from_slice(data);
+    Ok(Frame::new(FrameKind::Relay, body))
+}
+
+fn decode_relay(body: &[u8]) -> Result<Relay<'_>, String> {
+    if body.len() < 9 {
+        return Err(format!("Truncated relay frame ({} bytes)", body.len()));
+    }
+    let ttl = body[0];
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&body[1..9]);
+    let (source, rest) = read_node(&body[9..])?;
+    let (destination, data) = read_node(rest)?;
+    Ok(Relay {
+        ttl,
+        id: u64::from_be_bytes(id),
+        source,
+        destination,
+        data,
+    })
+}
+
+// Drives overlay state machines linked by perfect links, for the mesh and
+// gossip tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::collections::BTreeMap;
+    use std::time::Instant;
+
+    use super::NodeId;
+    use crate::frame::Frame;
+
+    pub(crate) trait Overlay {
+        fn neighbor_up(&mut self, peer: &str, now: Instant);
+        fn poll(&mut self, now: Instant) -> Vec<(NodeId, Frame)>;
+        /// What the frame delivered, as (origin, data)
+        fn on_frame(
+            &mut self,
+            from: &str,
+            frame: &Frame,
+            now: Instant,
+        ) -> Option<(NodeId, Vec<u8>)>;
+    }
+
+    impl Overlay for super::Mesh {
+        fn neighbor_up(&mut self, peer: &str, now: Instant) {
+            super::Mesh::neighbor_up(self, peer, now)
+        }
+
+        fn poll(&mut self, now: Instant) -> Vec<(NodeId, Frame)> {
+            super::Mesh::poll(self, now)
+        }
+
+        fn on_frame(
+            &mut self,
+            from: &str,
+            frame: &Frame,
+            now: Instant,
+        ) -> Option<(NodeId, Vec<u8>)> {
+            super::Mesh::on_frame(self, from, frame, now).unwrap()
+        }
+    }
+
+    // Nodes linked in a line, in order. `new` gets each node's position and
+    // name.
+    pub(crate) fn line<T: Overlay>(
+        names: &[&str],
+        now: Instant,
+        new: impl Fn(usize, &str) -> T,
+    ) -> BTreeMap<NodeId, T> {
+        let mut nodes: BTreeMap<NodeId, T> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), new(i, name)))
+            .collect();
+        for pair in names.windows(2) {
+            nodes.get_mut(pair[0]).unwrap().neighbor_up(pair[1], now);
+            nodes.get_mut(pair[1]).unwrap().neighbor_up(pair[0], now);
+        }
+        nodes
+    }
+
+    // Delivers frames until every node is quiet. Returns the messages each
+    // node delivered, as (node, origin, data).
+    pub(crate) fn settle<T: Overlay>(
+        nodes: &mut BTreeMap<NodeId, T>,
+        now: Instant,
+    ) -> Vec<(NodeId, NodeId, Vec<u8>)> {
+        let mut delivered = Vec::new();
+        for _ in 0..100 {
+            let mut frames = Vec::new();
+            for (name, node) in nodes.iter_mut() {
+                for (to, frame) in node.poll(now) {
+                    frames.push((name.clone(), to, frame));
+                }
+            }
+            if frames.is_empty() {
+                return delivered;
+            }
+            for (from, to, frame) in frames {
+                let Some(node) = nodes.get_mut(&to) else {
+                    continue;
+                };
+                if let Some((origin, data)) = node.on_frame(&from, &frame, now) {
+                    delivered.push((to, origin, data));
+                }
+            }
+        }
+        panic!("nodes never settled");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::testing::settle;
+    use super::*;
+
+    // Meshes linked in a line, in order
+    fn line(names: &[&str], now: Instant) -> BTreeMap<NodeId, Mesh> {
+        testing::line(names, now, |_, name| {
+            Mesh::new(name, MeshConfig::default(), 0, now)
+        })
+    }
+
+    #[test]
+    fn routes_spread_along_a_line() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b", "c", "d"], now);
+        settle(&mut meshes, now);
+
+        let a = &meshes["a"];
+        assert_eq!(a.next_hop("d"), Some("b"));
+        let hops: Vec<(&str, u8)> = a.routes().map(|(node, route)| (node, route.hops)).collect();
+        assert_eq!(hops, [("b", 1), ("c", 2), ("d", 3)]);
+        assert_eq!(meshes["d"].next_hop("a"), Some("c"));
+    }
+
+    #[test]
+    fn data_is_relayed_across_hops() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b", "c"], now);
+        settle(&mut meshes, now);
+
+        meshes.get_mut("a").unwrap().send_to("c", b"hi").unwrap();
+        let delivered = settle(&mut meshes, now);
+        assert_eq!(
+            delivered,
+            [("c".to_string(), "a".to_string(), b"hi".to_vec())]
+        );
+    }
+
+    #[test]
+    fn nodes_without_a_route_are_refused() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b"], now);
+        settle(&mut meshes, now);
+
+        let a = meshes.get_mut("a").unwrap();
+        assert!(a.send_to("z", b"hi").is_err());
+        assert!(a.send_to("a", b"hi").is_err());
+    }
+
+    #[test]
+    fn a_broken_link_takes_its_routes_down() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b", "c"], now);
+        settle(&mut meshes, now);
+        meshes.get_mut("a").unwrap().drain_events();
+
+        meshes.get_mut("b").unwrap().neighbor_down("c", now);
+        meshes.get_mut("c").unwrap().neighbor_down("b", now);
+        settle(&mut meshes, now);
+
+        let a = meshes.get_mut("a").unwrap();
+        assert_eq!(a.next_hop("c"), None);
+        assert_eq!(
+            a.drain_events(),
+            [MeshEvent::RouteLost {
+                node: "c".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn routes_nobody_refreshes_expire() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b", "c"], now);
+        settle(&mut meshes, now);
+
+        // Only a keeps running, b and c go quiet
+        let a = meshes.get_mut("a").unwrap();
+        let later = now + a.config().route_timeout;
+        a.poll(later);
+        assert_eq!(a.routes().count(), 0);
+    }
+
+    #[test]
+    fn relays_die_when_their_ttl_runs_out() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b", "c"], now);
+        settle(&mut meshes, now);
+
+        let frame = encode_relay(1, 7, "a", "c", b"hi").unwrap();
+        let b = meshes.get_mut("b").unwrap();
+        b.drain_events();
+        assert_eq!(b.on_frame("a", &frame, now).unwrap(), None);
+        assert!(b.poll(now).is_empty());
+        assert_eq!(
+            b.drain_events(),
+            [MeshEvent::Dropped {
+                source: "a".to_string(),
+                destination: "c".to_string(),
+                reason: DropReason::TtlExpired,
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_relays_are_dropped() {
+        let now = Instant::now();
+        let mut meshes = line(&["a", "b"], now);
+        settle(&mut meshes, now);
+
+        let frame = encode_relay(8, 7, "a", "b", b"hi").unwrap();
+        let b = meshes.get_mut("b").unwrap();
+        assert!(b.on_frame("a", &frame, now).unwrap().is_some());
+        assert_eq!(b.on_frame("a", &frame, now).unwrap(), None);
+    }
+
+    #[test]
+    fn routes_from_strangers_are_rejected() {
+        let now = Instant::now();
+        let mut mesh = Mesh::new("a", MeshConfig::default(), 0, now);
+        let frame = encode_routes(&[("z", 2, 0)]).unwrap();
+        assert!(mesh.on_frame("z", &frame, now).is_err());
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        assert!(decode_routes(&[0, 1, 3, b'a']).is_err());
+        assert!(decode_relay(&[8, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_newer(1, u32::MAX));
+        assert!(!seq_newer(u32::MAX, 1));
+        assert!(!seq_newer(4, 4));
+    }
+}
@@ -0,0 +1,626 @@
+// Per-peer counters and histograms for link health.
+//
+// `Metrics` is plain bookkeeping: the session calls it on every send,
+// receive, connect attempt and disconnect, and `snapshot` turns it into a
+// `MetricsSnapshot` that can be inspected or printed. With the `metrics`
+// feature every update is also forwarded to the `metrics` crate facade, so
+// whatever recorder the application installs (Prometheus, statsd, ...) sees
+// the same numbers. Facade metric names start with the prefix given to `new`.
+//
+// Connect durations run from the first attempt to the connection coming up.
+// An attempt that ends without a connection counts as a connect failure, not
+// a disconnect.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::mesh::NodeId;
+
+/// Upper bounds of the histogram buckets, anything above the last one goes
+/// into a final open bucket
+pub const BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS.len() + 1],
+    count: u64,
+    sum: Duration,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: Duration) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum / self.count as u32)
+    }
+
+    /// Estimate of quantile `q` (0.0 to 1.0): the upper bound of the bucket
+    /// it falls in, capped at the largest value seen
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let max = self.max?;
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(BUCKETS.get(bucket).map_or(max, |bound| (*bound).min(max)));
+            }
+        }
+        Some(max)
+    }
+
+    /// Count per bucket with its upper bound, `None` for the open bucket
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| (BUCKETS.get(bucket).copied(), *count))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DisconnectReason {
+    /// The peer said goodbye
+    Goodbye,
+    /// The connection went away without a goodbye
+    Dropped,
+    /// We shut the session down
+    Shutdown,
+    /// We disconnected the peer for breaking the inbound policy
+    Kicked,
+}
+
+impl DisconnectReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisconnectReason::Goodbye => "goodbye",
+            DisconnectReason::Dropped => "dropped",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::Kicked => "kicked",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, other: Traffic) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerMetrics {
+    pub sent_reliable: Traffic,
+    pub sent_unreliable: Traffic,
+    /// MPC doesn't say which mode data arrived in, so received traffic isn't
+    /// split
+    pub received: Traffic,
+    pub send_errors: u64,
+    pub connect_attempts: u64,
+    pub connect_failures: u64,
+    pub connects: u64,
+    pub connect_duration: Histogram,
+    pub disconnects: BTreeMap<DisconnectReason, u64>,
+    /// How long the current connection has been up, `None` if not connected
+    pub connected_for: Option<Duration>,
+    /// Messages waiting in the send queue
+    pub queue_depth: usize,
+    pub queue_depth_max: usize,
+}
+
+impl PeerMetrics {
+    pub fn is_connected(&self) -> bool {
+        self.connected_for.is_some()
+    }
+
+    pub fn sent(&self) -> Traffic {
+        let mut sent = self.sent_reliable;
+        sent.add(self.sent_unreliable);
+        sent
+    }
+
+    pub fn disconnects(&self) -> u64 {
+        self.disconnects.values().sum()
+    }
+
+    fn merge(&mut self, other: &PeerMetrics) {
+        self.sent_reliable.add(other.sent_reliable);
+        self.sent_unreliable.add(other.sent_unreliable);
+        self.received.add(other.received);
+        self.send_errors += other.send_errors;
+        self.connect_attempts += other.connect_attempts;
+        self.connect_failures += other.connect_failures;
+        self.connects += other.connects;
+        self.connect_duration.merge(&other.connect_duration);
+        for (reason, count) in &other.disconnects {
+            *self.disconnects.entry(*reason).or_default() += count;
+        }
+        self.connected_for = self.connected_for.max(other.connected_for);
+        self.queue_depth += other.queue_depth;
+        self.queue_depth_max += other.queue_depth_max;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Time since collection started
+    pub uptime: Duration,
+    pub peers: BTreeMap<NodeId, PeerMetrics>,
+}
+
+impl MetricsSnapshot {
+    pub fn connected(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.is_connected())
+            .count()
+    }
+
+    /// Everything added up over all peers
+    pub fn totals(&self) -> PeerMetrics {
+        let mut totals = PeerMetrics::default();
+        for peer in self.peers.values() {
+            totals.merge(peer);
+        }
+        totals
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>9} {:>17} {:>17} {:>17} {:>6} {:>9} {:>11} {:>13} {:>7}",
+            "peer",
+            "state",
+            "sent reliable",
+            "sent unreliable",
+            "received",
+            "errors",
+            "connects",
+            "connect p50",
+            "disconnects",
+            "queue"
+        )?;
+        let totals = self.totals();
+        let rows = self
+            .peers
+            .iter()
+            .map(|(peer, metrics)| {
+                let state = if metrics.is_connected() {
+                    "connected"
+                } else {
+                    "gone"
+                };
+                (peer.as_str(), state.to_string(), metrics)
+            })
+            .chain(std::iter::once((
+                "total",
+                format!("{}/{}", self.connected(), self.peers.len()),
+                &totals,
+            )));
+        for (peer, state, metrics) in rows {
+            let p50 = metrics
+                .connect_duration
+                .quantile(0.5)
+                .map(|p50| format!("{:.0?}", p50))
+                .unwrap_or_else(|| "-".to_string());
+            let disconnects = metrics
+                .disconnects
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason.name(), count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                f,
+                "{:<24} {:>9} {:>17} {:>17} {:>17} {:>6} {:>9} {:>11} {:>13} {:>7}",
+                peer,
+                state,
+                traffic(metrics.sent_reliable),
+                traffic(metrics.sent_unreliable),
+                traffic(metrics.received),
+                metrics.send_errors,
+                format!("{}/{}", metrics.connects, metrics.connect_attempts),
+                p50,
+                if disconnects.is_empty() {
+                    "-".to_string()
+                } else {
+                    disconnects
+                },
+                format!("{}/{}", metrics.queue_depth, metrics.queue_depth_max)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn traffic(traffic: Traffic) -> String {
+    format!("{}/{}B", traffic.messages, traffic.bytes)
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    metrics: PeerMetrics,
+    connected_since: Option<Instant>,
+    attempt_started: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    // Only the facade names anything
+    #[cfg(feature = "metrics")]
+    prefix: &'static str,
+    started: Instant,
+    peers: HashMap<NodeId, PeerState>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new("session", Instant::now())
+    }
+}
+
+impl Metrics {
+    pub fn new(
+        #[cfg_attr(not(feature = "metrics"), allow(unused_variables))] prefix: &'static str,
+        now: Instant,
+    ) -> Self {
+        Self {
+            #[cfg(feature = "metrics")]
+            prefix,
+            started: now,
+            peers: HashMap::new(),
+        }
+    }
+
+    fn peer(&mut self, peer: &str) -> &mut PeerState {
+        self.peers.entry(peer.to_string()).or_default()
+    }
+
+    pub fn sent(&mut self, peer: &str, bytes: usize, reliably: bool) {
+        let state = self.peer(peer);
+        let traffic = if reliably {
+            &mut state.metrics.sent_reliable
+        } else {
+            &mut state.metrics.sent_unreliable
+        };
+        traffic.messages += 1;
+        traffic.bytes += bytes as u64;
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = labels(peer, &[("mode", mode(reliably))]);
+            ::metrics::counter!(self.key("sent_messages_total"), labels.clone()).increment(1);
+            ::metrics::counter!(self.key("sent_bytes_total"), labels).increment(bytes as u64);
+        }
+    }
+
+    pub fn send_failed(
+        &mut self,
+        peer: &str,
+        #[cfg_attr(not(feature = "metrics"), allow(unused_variables))] reliably: bool,
+    ) {
+        self.peer(peer).metrics.send_errors += 1;
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!(
+            self.key("send_errors_total"),
+            labels(peer, &[("mode", mode(reliably))])
+        )
+        .increment(1);
+    }
+
+    pub fn received(&mut self, peer: &str, bytes: usize) {
+        let received = &mut self.peer(peer).metrics.received;
+        received.messages += 1;
+        received.bytes += bytes as u64;
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = labels(peer, &[]);
+            ::metrics::counter!(self.key("received_messages_total"), labels.clone()).increment(1);
+            ::metrics::counter!(self.key("received_bytes_total"), labels).increment(bytes as u64);
+        }
+    }
+
+    /// An invitation to or from the peer went out. Retries while an earlier
+    /// attempt is pending don't restart the connect clock.
+    pub fn connect_attempt(&mut self, peer: &str, now: Instant) {
+        let state = self.peer(peer);
+        state.metrics.connect_attempts += 1;
+        state.attempt_started.get_or_insert(now);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!(self.key("connect_attempts_total"), labels(peer, &[])).increment(1);
+    }
+
+    pub fn connected(&mut self, peer: &str, now: Instant) {
+        let state = self.peer(peer);
+        if state.connected_since.is_some() {
+            return;
+        }
+        state.connected_since = Some(now);
+        state.metrics.connects += 1;
+        let took = state
+            .attempt_started
+            .take()
+            .map(|started| now.saturating_duration_since(started));
+        if let Some(took) = took {
+            state.metrics.connect_duration.record(took);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let labels = labels(peer, &[]);
+            ::metrics::counter!(self.key("connects_total"), labels.clone()).increment(1);
+            if let Some(took) = took {
+                ::metrics::histogram!(self.key("connect_duration_seconds"), labels)
+                    .record(took.as_secs_f64());
+            }
+            self.report_connected();
+        }
+    }
+
+    /// The peer is gone. Ignored unless it was connected or connecting, so
+    /// reporting the same disconnect twice is harmless.
+    pub fn disconnected(&mut self, peer: &str, reason: DisconnectReason) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+        if state.connected_since.take().is_some() {
+            *state.metrics.disconnects.entry(reason).or_default() += 1;
+
+            #[cfg(feature = "metrics")]
+            {
+                ::metrics::counter!(
+                    self.key("disconnects_total"),
+                    labels(peer, &[("reason", reason.name())])
+                )
+                .increment(1);
+                self.report_connected();
+            }
+        } else if state.attempt_started.take().is_some() {
+            state.metrics.connect_failures += 1;
+
+            #[cfg(feature = "metrics")]
+            ::metrics::counter!(self.key("connect_failures_total"), labels(peer, &[])).increment(1);
+        }
+    }
+
+    pub fn queue_depth(&mut self, peer: &str, depth: usize) {
+        let metrics = &mut self.peer(peer).metrics;
+        metrics.queue_depth = depth;
+        metrics.queue_depth_max = metrics.queue_depth_max.max(depth);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::gauge!(self.key("queue_depth"), labels(peer, &[])).set(depth as f64);
+    }
+
+    pub fn is_connected(&self, peer: &str) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|state| state.connected_since.is_some())
+    }
+
+    pub fn snapshot(&self, now: Instant) -> MetricsSnapshot {
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer, state)| {
+                let mut metrics = state.metrics.clone();
+                metrics.connected_for = state
+                    .connected_since
+                    .map(|since| now.saturating_duration_since(since));
+                (peer.clone(), metrics)
+            })
+            .collect();
+        MetricsSnapshot {
+            uptime: now.saturating_duration_since(self.started),
+            peers,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn key(&self, name: &str) -> String {
+        format!("{}_{}", self.prefix, name)
+    }
+
+    #[cfg(feature = "metrics")]
+    fn report_connected(&self) {
+        let connected = self
+            .peers
+            .values()
+            .filter(|state| state.connected_since.is_some())
+            .count();
+        ::metrics::gauge!(self.key("connected_peers")).set(connected as f64);
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn mode(reliably: bool) -> &'static str {
+    if reliably { "reliable" } else { "unreliable" }
+}
+
+#[cfg(feature = "metrics")]
+fn labels(peer: &str, extra: &[(&'static str, &'static str)]) -> Vec<::metrics::Label> {
+    let mut labels = vec![::metrics::Label::new("peer", peer.to_string())];
+    labels.extend(
+        extra
+            .iter()
+            .map(|(key, value)| ::metrics::Label::new(*key, *value)),
+    );
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn histogram(values: &[Duration]) -> Histogram {
+        let mut histogram = Histogram::default();
+        for value in values {
+            histogram.record(*value);
+        }
+        histogram
+    }
+
+    #[test]
+    fn quantiles_are_bucket_bounds_capped_at_the_max() {
+        let histogram = histogram(&[ms(1), ms(4), ms(20), ms(3000)]);
+        assert_eq!(histogram.quantile(0.0), Some(ms(1)));
+        assert_eq!(histogram.quantile(0.5), Some(ms(5)));
+        assert_eq!(histogram.quantile(0.75), Some(ms(25)));
+        assert_eq!(histogram.quantile(1.0), Some(ms(3000)));
+        assert_eq!(histogram.quantile(7.0), Some(ms(3000)));
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(756_250)));
+
+        let empty = Histogram::default();
+        assert_eq!((empty.quantile(0.5), empty.mean()), (None, None));
+    }
+
+    #[test]
+    fn values_past_the_last_bucket_land_in_the_open_one() {
+        let histogram = histogram(&[Duration::from_secs(60)]);
+        assert_eq!(histogram.buckets().last(), Some((None, 1)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn merged_histograms_match_recording_everything_once() {
+        let mut merged = histogram(&[ms(1), ms(300)]);
+        merged.merge(&histogram(&[ms(40), ms(2)]));
+        merged.merge(&Histogram::default());
+        assert_eq!(merged, histogram(&[ms(1), ms(300), ms(40), ms(2)]));
+        assert_eq!((merged.min(), merged.max()), (Some(ms(1)), Some(ms(300))));
+        assert_eq!(merged.count(), 4);
+        assert_eq!(merged.sum(), ms(343));
+    }
+
+    #[test]
+    fn connects_are_timed_from_the_first_attempt() {
+        let start = Instant::now();
+        let mut metrics = Metrics::new("test", start);
+        metrics.connect_attempt("b", start);
+        metrics.connect_attempt("b", start + ms(10));
+        metrics.connected("b", start + ms(30));
+        metrics.connected("b", start + ms(40));
+
+        let snapshot = metrics.snapshot(start + ms(100));
+        let b = &snapshot.peers["b"];
+        assert_eq!(b.connect_attempts, 2);
+        assert_eq!(b.connects, 1);
+        assert_eq!(b.connect_duration.max(), Some(ms(30)));
+        assert_eq!(b.connected_for, Some(ms(70)));
+        assert_eq!(snapshot.connected(), 1);
+    }
+
+    #[test]
+    fn disconnects_and_failed_attempts_are_told_apart() {
+        let now = Instant::now();
+        let mut metrics = Metrics::new("test", now);
+        metrics.connect_attempt("b", now);
+        metrics.connected("b", now);
+        metrics.disconnected("b", DisconnectReason::Goodbye);
+        metrics.disconnected("b", DisconnectReason::Goodbye);
+        metrics.connect_attempt("b", now);
+        metrics.disconnected("b", DisconnectReason::Dropped);
+        metrics.disconnected("unknown", DisconnectReason::Dropped);
+
+        let b = &metrics.snapshot(now).peers["b"];
+        assert_eq!(b.disconnects(), 1);
+        assert_eq!(b.disconnects[&DisconnectReason::Goodbye], 1);
+        assert_eq!(b.connect_failures, 1);
+        assert!(!metrics.is_connected("b"));
+    }
+
+    #[test]
+    fn totals_add_up_every_peer() {
+        let now = Instant::now();
+        let mut metrics = Metrics::new("test", now);
+        metrics.sent("b", 10, true);
+        metrics.sent("b", 5, false);
+        metrics.sent("c", 20, true);
+        metrics.send_failed("c", true);
+        metrics.received("c", 7);
+        metrics.queue_depth("b", 3);
+        metrics.queue_depth("b", 1);
+
+        let totals = metrics.snapshot(now).totals();
+        assert_eq!(
+            totals.sent(),
+            Traffic {
+                messages: 3,
+                bytes: 35
+            }
+        );
+        assert_eq!(totals.sent_reliable.bytes, 30);
+        assert_eq!(totals.received.bytes, 7);
+        assert_eq!(totals.send_errors, 1);
+        assert_eq!((totals.queue_depth, totals.queue_depth_max), (1, 3));
+    }
+}
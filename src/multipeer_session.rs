@@ -1,46 +1,119 @@
 #![allow(unused_unsafe)]
-use objc2::rc::Allocated;
-use objc2::{Encoding, Message, RefEncode, exception};
-use objc2::{MainThreadMarker, class, msg_send, rc::Retained, sel};
-use objc2_foundation::{NSAutoreleasePool, NSData, NSError, NSURL};
-use objc2_foundation::{NSInputStream, NSObjectProtocol};
+use objc2::{DefinedClass, define_class, msg_send, rc::Retained};
+use objc2::{Message, exception};
+use objc2_foundation::{NSAutoreleasePool, NSData, NSError, NSObject, NSURL};
+use objc2_foundation::{NSInputStream, NSObjectProtocol, NSOutputStream, NSStream, NSStreamStatus};
 use objc2_foundation::{NSProgress, NSString};
+use objc2_multipeer_connectivity::MCEncryptionPreference;
 use objc2_multipeer_connectivity::MCSessionSendDataMode;
 use objc2_multipeer_connectivity::MCSessionState;
 use objc2_multipeer_connectivity::{
-    MCAdvertiserAssistant, MCBrowserViewController, MCPeerID, MCSession,
-};
-use objc2_multipeer_connectivity::{
-    MCNearbyServiceAdvertiser, MCNearbyServiceBrowser, MCSessionDelegate,
+    MCNearbyServiceAdvertiser, MCNearbyServiceAdvertiserDelegate, MCNearbyServiceBrowser,
+    MCNearbyServiceBrowserDelegate, MCSessionDelegate,
 };
+use objc2_multipeer_connectivity::{MCPeerID, MCSession};
 
-use objc2_foundation::NSArray;
+use objc2_foundation::{NSArray, NSDictionary};
 
+use block2::Block;
+use objc2::runtime::Bool;
 use objc2::runtime::ProtocolObject;
-use objc2::runtime::{AnyClass, AnyObject};
 
 use objc2::AllocAnyThread;
-use objc2::MainThreadOnly;
 
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::marker::PhantomData;
-use std::sync::{Arc, RwLock};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, info, trace, warn};
+use tracing::{Instrument, Span, debug_span, field, info_span};
+
+use iroh_discovery_playground::SHUTDOWN_GRACE;
+use iroh_discovery_playground::address_book::{
+    AddressBook, AddressBookConfig, IDENTITY_KEY, PeerEntry, Trust,
+};
+use iroh_discovery_playground::aggregator::DiscoveryEvent;
+use iroh_discovery_playground::backend::{Backend, BackendEvent, Stream};
+use iroh_discovery_playground::capture::{CaptureEvent, CaptureWriter};
+use iroh_discovery_playground::compression::{
+    Codec, Compression, CompressionConfig, HELLO_KEY, HELLO_VALUE, is_compressed,
+};
+use iroh_discovery_playground::discovery_info::{DiscoveryInfo, service_type};
+use iroh_discovery_playground::dispatch::{self, PeerLayers};
+use iroh_discovery_playground::frame::{Frame, FrameKind};
+use iroh_discovery_playground::gossip::{Gossip, GossipConfig, GossipMessage, MessageId};
+use iroh_discovery_playground::inbound::{
+    Aliases, InboundConfig, InboundPolicy, Verdict, Violation,
+};
+use iroh_discovery_playground::mesh::{Mesh, MeshConfig, NodeId};
+use iroh_discovery_playground::metrics::{DisconnectReason, Metrics, MetricsSnapshot};
+use iroh_discovery_playground::outbox::{Outbox, OutboxConfig, OutboxEvent};
+use iroh_discovery_playground::pairing::{PAIRING_KEY, is_pairing_intent};
+use iroh_discovery_playground::reconnect::{ReconnectEvent, ReconnectPolicy, ReconnectSupervisor};
+use iroh_discovery_playground::reliable::{ReliableChannel, ReliableConfig, ReliableEvent};
+use iroh_discovery_playground::send_queue::{OutboundMessage, Priority, QueueConfig, SendQueue};
+use iroh_discovery_playground::session_pool::SessionPool;
+
+/// How long an invitation we send stays open, in seconds
+const INVITE_TIMEOUT_SECS: f64 = 10.0;
+/// Upper bound on how long the reconnect thread sleeps between polls
+const RECONNECT_IDLE_TICK: Duration = Duration::from_secs(1);
+/// Upper bound on how long the retransmission thread sleeps between polls
+const ACKED_IDLE_TICK: Duration = Duration::from_millis(500);
+/// How often the send queue dispatcher checks whether it should stop
+const SEND_QUEUE_IDLE_TICK: Duration = Duration::from_millis(500);
+/// Upper bound on how long the mesh thread sleeps between polls
+const MESH_IDLE_TICK: Duration = Duration::from_secs(1);
+/// Upper bound on how long the gossip thread sleeps between polls
+const GOSSIP_IDLE_TICK: Duration = Duration::from_secs(1);
 
-use log::{debug, error, info, trace, warn};
+type PeerCallback = Box<dyn Fn(&MCPeerID)>;
+type DataCallback = Box<dyn Fn(&NSData, &MCPeerID)>;
+type ReconnectCallback = Arc<dyn Fn(&ReconnectEvent<String>) + Send + Sync>;
+type ReliableCallback = Arc<dyn Fn(&str, &ReliableEvent) + Send + Sync>;
+type MeshCallback = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+// Cloned out of their slot with `callback` and called without the lock, so
+// they can disable themselves
+type DiscoveryCallback = Arc<dyn Fn(&DiscoveryEvent) + Send + Sync>;
+type FrameCallback = Arc<dyn Fn(&str, &Frame) + Send + Sync>;
+type EventCallback = Arc<dyn Fn(BackendEvent) + Send + Sync>;
+type PairingCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+fn callback<T: ?Sized>(slot: &Mutex<Option<Arc<T>>>) -> Option<Arc<T>> {
+    slot.lock().unwrap().clone()
+}
 
 pub struct MultipeerSession {
     service_type: Retained<NSString>,
     peer_id: Retained<MCPeerID>,
-    session: Option<Retained<MCSession>>,
     service_advertiser: Option<Retained<MCNearbyServiceAdvertiser>>,
     service_browser: Option<Retained<MCNearbyServiceBrowser>>,
     delegate: Option<Retained<ProtocolObject<dyn MCSessionDelegate>>>,
+    browser_delegate: Option<Retained<ProtocolObject<dyn MCNearbyServiceBrowserDelegate>>>,
+    advertiser_delegate: Option<Retained<ProtocolObject<dyn MCNearbyServiceAdvertiserDelegate>>>,
+    encryption: Option<MCEncryptionPreference>,
+    tracker: Arc<PeerTracker>,
+    reconnect_thread: Option<thread::JoinHandle<()>>,
+    acked_thread: Option<thread::JoinHandle<()>>,
+    send_thread: Option<thread::JoinHandle<()>>,
+    mesh_thread: Option<thread::JoinHandle<()>>,
+    gossip_thread: Option<thread::JoinHandle<()>>,
+    discovery_info: DiscoveryInfo,
+    identity: Option<String>,
+    advertising: bool,
+    browsing: bool,
     #[doc(hidden)]
-    on_peer_joined: Option<Box<dyn Fn(&MCPeerID)>>,
+    on_peer_joined: Option<PeerCallback>,
     #[doc(hidden)]
-    on_peer_left: Option<Box<dyn Fn(&MCPeerID)>>,
+    on_peer_left: Option<PeerCallback>,
     #[doc(hidden)]
-    on_data_received: Option<Box<dyn Fn(&NSData, &MCPeerID)>>,
+    on_data_received: Option<DataCallback>,
 }
 
 // Manual Debug implementation to skip the callback fields
@@ -49,42 +122,112 @@ impl fmt::Debug for MultipeerSession {
         f.debug_struct("MultipeerSession")
             .field("service_type", &self.service_type)
             .field("peer_id", &self.peer_id)
-            .field("session", &self.session)
+            .field("sessions", &self.tracker.sessions())
             .field("service_advertiser", &self.service_advertiser)
             .field("service_browser", &self.service_browser)
             .field("delegate", &self.delegate)
+            .field("browser_delegate", &self.browser_delegate)
+            .field("discovery_info", &self.discovery_info)
+            .field("advertising", &self.advertising)
+            .field("browsing", &self.browsing)
             .finish()
     }
 }
 
+/// Settings fixed when the session is created
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Name other peers see, at most 63 bytes
+    pub display_name: String,
+    /// `None` keeps whatever MPC uses by default
+    pub encryption: Option<MCEncryptionPreference>,
+    /// What peers' address books know us by, usually
+    /// `address_book::identity_of_key` of our static key. Advertised as the
+    /// `id` discovery info entry and sent along with our invitations.
+    pub identity: Option<String>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            display_name: "rust-peer".to_string(),
+            encryption: None,
+            identity: None,
+        }
+    }
+}
+
 impl MultipeerSession {
+    #[allow(dead_code, reason = "the CLI builds sessions with `with_options`")]
     pub fn new(
         service_name: &str,
         on_data: impl Fn(&NSData, &MCPeerID) + 'static + std::panic::UnwindSafe,
         on_joined: impl Fn(&MCPeerID) + 'static + std::panic::UnwindSafe,
         on_left: impl Fn(&MCPeerID) + 'static + std::panic::UnwindSafe,
+    ) -> Self {
+        Self::with_options(
+            service_name,
+            SessionOptions::default(),
+            on_data,
+            on_joined,
+            on_left,
+        )
+    }
+
+    pub fn with_options(
+        service_name: &str,
+        options: SessionOptions,
+        on_data: impl Fn(&NSData, &MCPeerID) + 'static + std::panic::UnwindSafe,
+        on_joined: impl Fn(&MCPeerID) + 'static + std::panic::UnwindSafe,
+        on_left: impl Fn(&MCPeerID) + 'static + std::panic::UnwindSafe,
     ) -> Self {
         exception::catch(|| unsafe {
             let _pool = NSAutoreleasePool::new();
 
-            let formatted_name = format!("iroh-{}", service_name)
-                .chars()
-                .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
-                .take(15)
-                .collect::<String>();
+            let formatted_name = service_type(service_name);
 
             let service_type = NSString::from_str(&formatted_name);
 
-            let device_name = NSString::from_str("rust-peer");
+            let device_name = NSString::from_str(&options.display_name);
             let peer_id = MCPeerID::initWithDisplayName(MCPeerID::alloc(), &device_name);
 
+            let hello = DiscoveryInfo::new()
+                .with(HELLO_KEY, HELLO_VALUE)
+                .expect("hello entry fits");
+            let discovery_info = match &options.identity {
+                Some(identity) => hello
+                    .clone()
+                    .with(IDENTITY_KEY, identity)
+                    .unwrap_or_else(|e| {
+                        warn!("Not advertising our identity: {}", e);
+                        hello
+                    }),
+                None => hello,
+            };
+
             let mut session = Self {
                 service_type,
                 peer_id,
-                session: None,
+                encryption: options.encryption,
                 service_advertiser: None,
                 service_browser: None,
                 delegate: None,
+                browser_delegate: None,
+                advertiser_delegate: None,
+                tracker: Arc::new(PeerTracker::new(info_span!(
+                    "mpc_session",
+                    local = %options.display_name,
+                    service = %formatted_name
+                ))),
+                reconnect_thread: None,
+                acked_thread: None,
+                send_thread: None,
+                mesh_thread: None,
+                gossip_thread: None,
+                discovery_info,
+                identity: options.identity,
+                advertising: true,
+                browsing: true,
                 on_peer_joined: Some(Box::new(on_joined)),
                 on_peer_left: Some(Box::new(on_left)),
                 on_data_received: Some(Box::new(on_data)),
@@ -102,38 +245,45 @@ impl MultipeerSession {
 
             let mc_session = {
                 let _inner_pool = AutoreleasePool::new();
-                MCSession::initWithPeer(MCSession::alloc(), self.peer_id.as_ref())
+                make_session(&self.peer_id, self.encryption)
             };
 
             let delegate_obj = {
                 let _inner_pool = AutoreleasePool::new();
 
-                let delegate_impl = SessionDelegate::new(
+                let delegate = SessionDelegate::new(SessionDelegateState::new(
                     self.on_data_received.take(),
                     self.on_peer_joined.take(),
                     self.on_peer_left.take(),
-                );
-
-                let boxed = Box::new(delegate_impl);
-                let raw_ptr = Box::into_raw(boxed);
-                let retained = Retained::from_raw(raw_ptr).expect("Failed to retain delegate");
-                ProtocolObject::from_retained(retained)
+                    self.tracker.clone(),
+                ));
+                ProtocolObject::from_retained(delegate)
             };
 
             {
                 let _inner_pool = AutoreleasePool::new();
                 mc_session.setDelegate(Some(&delegate_obj));
+                *self.tracker.shards.lock().unwrap() = Some(ThreadSafe(Shards {
+                    peer_id: self.peer_id.clone(),
+                    encryption: self.encryption,
+                    delegate: delegate_obj.clone(),
+                    sessions: vec![mc_session],
+                    pool: SessionPool::default(),
+                }));
+            }
+
+            {
+                let _inner_pool = AutoreleasePool::new();
+                let delegate = AdvertiserDelegate::new(self.tracker.clone());
+                self.advertiser_delegate = Some(ProtocolObject::from_retained(delegate));
             }
 
             let advertiser = {
                 let _inner_pool = AutoreleasePool::new();
-                let adv = MCNearbyServiceAdvertiser::initWithPeer_discoveryInfo_serviceType(
-                    MCNearbyServiceAdvertiser::alloc(),
-                    self.peer_id.as_ref(),
-                    None,
-                    self.service_type.as_ref(),
-                );
-                adv.startAdvertisingPeer();
+                let adv = self.make_advertiser();
+                if self.advertising {
+                    adv.startAdvertisingPeer();
+                }
                 adv
             };
 
@@ -144,173 +294,3113 @@ impl MultipeerSession {
                     self.peer_id.as_ref(),
                     self.service_type.as_ref(),
                 );
-                br.startBrowsingForPeers();
+                if self.browsing {
+                    br.startBrowsingForPeers();
+                }
                 br
             };
 
+            let browser_delegate_obj = {
+                let _inner_pool = AutoreleasePool::new();
+
+                let delegate: Retained<ProtocolObject<dyn MCNearbyServiceBrowserDelegate>> =
+                    ProtocolObject::from_retained(BrowserDelegate::new(self.tracker.clone()));
+                browser.setDelegate(Some(&delegate));
+                delegate
+            };
+
             {
                 let _inner_pool = AutoreleasePool::new();
-                self.session = Some(mc_session);
                 self.service_advertiser = Some(advertiser);
                 self.service_browser = Some(browser);
                 self.delegate = Some(delegate_obj);
+                self.browser_delegate = Some(browser_delegate_obj);
             }
         }
     }
 
-    pub fn send_to_peers(
-        &self,
-        data: &[u8],
-        peers: &[Retained<MCPeerID>],
-        reliably: bool,
-    ) -> Result<(), String> {
-        if let Some(session) = &self.session {
-            unsafe {
-                let _pool = AutoreleasePool::new();
+    // The advertiser's discovery info is fixed at init time, so changing it
+    // means building a new advertiser. The MCSession is not involved.
+    unsafe fn make_advertiser(&self) -> Retained<MCNearbyServiceAdvertiser> {
+        unsafe {
+            let info = if self.discovery_info.is_empty() {
+                None
+            } else {
+                let keys: Vec<Retained<NSString>> = self
+                    .discovery_info
+                    .iter()
+                    .map(|(k, _)| NSString::from_str(k))
+                    .collect();
+                let values: Vec<Retained<NSString>> = self
+                    .discovery_info
+                    .iter()
+                    .map(|(_, v)| NSString::from_str(v))
+                    .collect();
+                let key_refs: Vec<&NSString> = keys.iter().map(|k| k.as_ref()).collect();
+                let value_refs: Vec<&NSString> = values.iter().map(|v| v.as_ref()).collect();
+                Some(NSDictionary::from_slices(&key_refs, &value_refs))
+            };
 
-                let ns_data = NSData::from_vec(data.to_vec());
-                let peer_refs: Vec<&MCPeerID> = peers.iter().map(|p| p.as_ref()).collect();
-                let peer_array = NSArray::from_slice(&peer_refs);
+            let advertiser = MCNearbyServiceAdvertiser::initWithPeer_discoveryInfo_serviceType(
+                MCNearbyServiceAdvertiser::alloc(),
+                self.peer_id.as_ref(),
+                info.as_deref(),
+                self.service_type.as_ref(),
+            );
+            advertiser.setDelegate(self.advertiser_delegate.as_deref());
+            advertiser
+        }
+    }
 
-                let mode = if reliably {
-                    MCSessionSendDataMode::Reliable
-                } else {
-                    MCSessionSendDataMode::Unreliable
-                };
+    /// Start or stop advertising without touching connected peers.
+    pub fn set_advertising(&mut self, enabled: bool) {
+        if self.advertising == enabled {
+            return;
+        }
+        self.advertising = enabled;
 
-                session
-                    .sendData_toPeers_withMode_error(ns_data.as_ref(), &peer_array, mode)
-                    .map_err(|e| e.to_string())
+        if let Some(advertiser) = &self.service_advertiser {
+            unsafe {
+                let _pool = AutoreleasePool::new();
+                if enabled {
+                    debug!("Resuming advertising");
+                    advertiser.startAdvertisingPeer();
+                } else {
+                    debug!("Pausing advertising");
+                    advertiser.stopAdvertisingPeer();
+                }
             }
-        } else {
-            Err("Session not initialized".to_string())
         }
     }
 
-    pub fn connected_peers(&self) -> Vec<Retained<MCPeerID>> {
-        if let Some(session) = &self.session {
+    /// Start or stop browsing without touching connected peers.
+    pub fn set_browsing(&mut self, enabled: bool) {
+        if self.browsing == enabled {
+            return;
+        }
+        self.browsing = enabled;
+
+        if let Some(browser) = &self.service_browser {
             unsafe {
                 let _pool = AutoreleasePool::new();
-
-                let peer_array = unsafe { session.connectedPeers() };
-                let count = unsafe { peer_array.count() };
-                let mut peers = Vec::with_capacity(count as usize);
-
-                for i in 0..count {
-                    let peer = unsafe { peer_array.objectAtIndex(i) };
-                    peers.push(peer);
+                if enabled {
+                    debug!("Resuming browsing");
+                    browser.startBrowsingForPeers();
+                } else {
+                    debug!("Pausing browsing");
+                    browser.stopBrowsingForPeers();
                 }
-                peers
             }
-        } else {
-            Vec::new()
         }
     }
-}
 
-// #[thread_kind = SafeMainThreadOnly]
-pub struct SessionDelegate {
-    on_data_received: Option<Box<dyn Fn(&NSData, &MCPeerID)>>,
-    on_peer_joined: Option<Box<dyn Fn(&MCPeerID)>>,
-    on_peer_left: Option<Box<dyn Fn(&MCPeerID)>>,
-}
+    #[allow(dead_code, reason = "the CLI tracks this itself")]
+    pub fn is_advertising(&self) -> bool {
+        self.advertising
+    }
 
-impl SessionDelegate {
-    fn new(
-        on_data: Option<Box<dyn Fn(&NSData, &MCPeerID)>>,
-        on_joined: Option<Box<dyn Fn(&MCPeerID)>>,
-        on_left: Option<Box<dyn Fn(&MCPeerID)>>,
-    ) -> Self {
-        Self {
-            on_data_received: on_data,
-            on_peer_joined: on_joined,
-            on_peer_left: on_left,
-        }
+    #[allow(dead_code, reason = "the CLI tracks this itself")]
+    pub fn is_browsing(&self) -> bool {
+        self.browsing
     }
-}
 
-unsafe impl RefEncode for SessionDelegate {
-    const ENCODING_REF: Encoding = Encoding::Object;
-}
+    #[allow(dead_code, reason = "the CLI tracks this itself")]
+    pub fn discovery_info(&self) -> &DiscoveryInfo {
+        &self.discovery_info
+    }
+
+    /// Replace the advertised discovery info.
+    ///
+    /// Only the advertiser is rebuilt; the MCSession and its connected peers
+    /// are left alone. If advertising is paused the new info is used once it
+    /// is resumed. The `id` entry always carries our identity if we have one.
+    pub fn set_discovery_info(&mut self, mut info: DiscoveryInfo) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+        if let Some(identity) = &self.identity {
+            info.insert(IDENTITY_KEY, identity)?;
+        }
+        info.insert(HELLO_KEY, HELLO_VALUE)?;
+        if info == self.discovery_info {
+            return Ok(());
+        }
+        self.discovery_info = info;
+
+        exception::catch(std::panic::AssertUnwindSafe(|| unsafe {
+            let _pool = AutoreleasePool::new();
+
+            if let Some(old) = self.service_advertiser.take() {
+                old.stopAdvertisingPeer();
+            }
 
-unsafe impl Message for SessionDelegate {}
-unsafe impl NSObjectProtocol for SessionDelegate {}
+            let advertiser = self.make_advertiser();
+            if self.advertising {
+                advertiser.startAdvertisingPeer();
+            }
+            self.service_advertiser = Some(advertiser);
+        }))
+        .map_err(|e| format!("Failed to update discovery info: {:?}", e))
+    }
 
-unsafe impl MCSessionDelegate for SessionDelegate {
-    unsafe fn session_peer_didChangeState(
+    #[allow(dead_code, reason = "the CLI sends through `Backend`")]
+    pub fn send_to_peers(
         &self,
-        _session: &MCSession,
-        peer_id: &MCPeerID,
-        state: MCSessionState,
-    ) {
+        data: &[u8],
+        peers: &[Retained<MCPeerID>],
+        reliably: bool,
+    ) -> Result<(), String> {
+        self.send_frame(&Frame::data(data), peers, reliably)
+    }
+
+    /// Connected peers across all MCSession shards
+    pub fn connected_peers(&self) -> Vec<Retained<MCPeerID>> {
         unsafe {
             let _pool = AutoreleasePool::new();
-            match state {
-                MCSessionState::Connected => {
-                    if let Some(cb) = &self.on_peer_joined {
-                        unsafe { cb(peer_id) };
+
+            let mut peers = Vec::new();
+            for session in self.tracker.sessions() {
+                let peer_array = unsafe { session.connectedPeers() };
+                for i in 0..peer_array.count() {
+                    let peer = unsafe { peer_array.objectAtIndex(i) };
+                    // Still in the session as far as MPC knows, not to us
+                    if !self.tracker.is_kicked(&self.tracker.node_of(&peer)) {
+                        peers.push(peer);
                     }
                 }
-                MCSessionState::NotConnected => {
-                    if let Some(cb) = &self.on_peer_left {
-                        unsafe { cb(peer_id) };
-                    }
+            }
+            peers
+        }
+    }
+
+    /// Number of MCSession instances in use, MPC caps each one at 8 peers
+    #[allow(dead_code, reason = "no subcommand shows session shards")]
+    pub fn session_count(&self) -> usize {
+        self.tracker.sessions().len()
+    }
+
+    /// Traffic, connection and queue counters per peer since the session
+    /// was created
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.tracker
+            .metrics
+            .lock()
+            .unwrap()
+            .snapshot(Instant::now())
+    }
+
+    /// Write every frame sent and received, and peers being found, lost,
+    /// connected and disconnected, to a capture file at `path`. Replaces any
+    /// capture already running.
+    ///
+    /// Captures replay into the memory backend, see `capture::replay`.
+    pub fn enable_capture(&mut self, path: impl AsRef<Path>) -> Result<(), String> {
+        let local = unsafe { self.peer_id.displayName().to_string() };
+        let writer = CaptureWriter::create(path, &local)?;
+        info!("Capturing traffic to {}", writer.path().display());
+        *self.tracker.capture.lock().unwrap() = Some(writer);
+        Ok(())
+    }
+
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_capture(&mut self) {
+        self.tracker.capture.lock().unwrap().take();
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.delegate.is_some()
+    }
+
+    /// Say goodbye to connected peers, then tear the session down.
+    ///
+    /// Prefer this over just dropping the session: peers get the goodbye
+    /// right away instead of waiting for MPC to time the connection out.
+    pub async fn shutdown(&mut self) {
+        if !self.is_initialized() {
+            return;
+        }
+
+        let span = self.tracker.span.clone();
+        async {
+            let peers = self.connected_peers();
+            if !peers.is_empty() {
+                debug!("Sending goodbye to {} peers", peers.len());
+                if let Err(e) = self.send_frame(&Frame::goodbye(), &peers, true) {
+                    warn!("Failed to send goodbye: {}", e);
                 }
-                _ => {}
+
+                // Give the reliable channel a chance to flush the goodbye
+                tokio::time::sleep(SHUTDOWN_GRACE).await;
             }
+
+            self.teardown();
         }
+        .instrument(span)
+        .await
     }
 
-    unsafe fn session_didReceiveData_fromPeer(
-        &self,
-        _session: &MCSession,
-        data: &NSData,
-        peer_id: &MCPeerID,
-    ) {
+    /// Invite a peer the browser has found into a session with us.
+    ///
+    /// The outcome arrives through the joined callback, or not at all if the
+    /// peer declines or the invitation times out.
+    pub fn invite(&self, name: &str) -> Result<(), String> {
+        self.invite_with_context(name, false)
+    }
+
+    /// Invite a peer, telling it we want to pair. It learns through its
+    /// pairing request callback, the pairing itself runs over the secure
+    /// channel, see `pairing`.
+    pub fn invite_to_pair(&self, name: &str) -> Result<(), String> {
+        self.invite_with_context(name, true)
+    }
+
+    fn invite_with_context(&self, name: &str, pair: bool) -> Result<(), String> {
+        let Some(browser) = &self.service_browser else {
+            return Err("Session not initialized".to_string());
+        };
+        if !self.tracker.allows(name) {
+            return Err(format!("Peer {} is blocked", name));
+        }
+        let Some(peer) = self
+            .tracker
+            .found
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|peer| peer.0.clone())
+        else {
+            return Err(format!("Peer {} has not been found", name));
+        };
+        let Some(session) = (unsafe { self.tracker.session_for(name) }) else {
+            return Err("Session is shutting down".to_string());
+        };
+
+        let _enter = self.tracker.connect_attempt(name).entered();
         unsafe {
             let _pool = AutoreleasePool::new();
-            if let Some(cb) = &self.on_data_received {
-                unsafe { cb(data, peer_id) };
+            debug!("Inviting {}", name);
+            let context = self.invitation_context(pair)?;
+            let context = (!context.is_empty()).then(|| NSData::with_bytes(&context.encode()));
+            browser.invitePeer_toSession_withContext_timeout(
+                &peer,
+                &session,
+                context.as_deref(),
+                INVITE_TIMEOUT_SECS,
+            );
+        }
+        Ok(())
+    }
+
+    // Tells the invited peer who we are and what we want
+    fn invitation_context(&self, pair: bool) -> Result<DiscoveryInfo, String> {
+        let mut context = DiscoveryInfo::new().with(HELLO_KEY, HELLO_VALUE)?;
+        if let Some(identity) = &self.identity {
+            context.insert(IDENTITY_KEY, identity)?;
+        }
+        if pair {
+            context.insert(PAIRING_KEY, "1")?;
+        }
+        Ok(context)
+    }
+
+    /// Re-invite peers that dropped out once the browser sees them again.
+    ///
+    /// Attempts back off exponentially with jitter according to `policy`;
+    /// `on_event` is called (from a background thread) for every step.
+    pub fn enable_reconnect(
+        &mut self,
+        policy: ReconnectPolicy,
+        on_event: impl Fn(&ReconnectEvent<String>) + Send + Sync + 'static,
+    ) -> Result<(), String> {
+        let Some(browser) = &self.service_browser else {
+            return Err("Session not initialized".to_string());
+        };
+        let browser = ThreadSafe(browser.clone());
+        // Reconnected peers learn who we are like invited ones
+        let context = self.invitation_context(false)?.encode();
+
+        self.disable_reconnect();
+
+        let (wake, wake_rx) = mpsc::channel();
+        *self.tracker.reconnect.lock().unwrap() = Some(ReconnectState {
+            supervisor: ReconnectSupervisor::new(policy, rand::random()),
+            on_event: Arc::new(on_event),
+            wake,
+        });
+
+        let tracker = self.tracker.clone();
+        let handle = thread::Builder::new()
+            .name("mpc-reconnect".to_string())
+            .spawn(move || run_reconnect(tracker, browser, context, wake_rx))
+            .map_err(|e| e.to_string())?;
+        self.reconnect_thread = Some(handle);
+        Ok(())
+    }
+
+    pub fn disable_reconnect(&mut self) {
+        // Dropping the state closes the wake channel, which stops the thread
+        self.tracker.reconnect.lock().unwrap().take();
+        if let Some(handle) = self.reconnect_thread.take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Turn on acknowledged delivery for `send_acked`.
+    ///
+    /// Messages go out in unreliable mode and are retransmitted until the
+    /// peer acks them; `on_event` reports acks and failures per peer (from a
+    /// background thread). Receiving acked messages works without this.
+    #[allow(dead_code, reason = "no acked subcommand yet")]
+    pub fn enable_acked_delivery(
+        &mut self,
+        config: ReliableConfig,
+        on_event: impl Fn(&str, &ReliableEvent) + Send + Sync + 'static,
+    ) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        self.disable_acked_delivery();
+
+        let (wake, wake_rx) = mpsc::channel();
+        {
+            let mut acked = self.tracker.acked.lock().unwrap();
+            acked.config = config;
+            acked.on_event = Some(Arc::new(on_event));
+            acked.wake = Some(wake);
+        }
+
+        let tracker = self.tracker.clone();
+        let thread = thread::Builder::new()
+            .name("mpc-acked".to_string())
+            .spawn(move || run_acked(tracker, wake_rx))
+            .map_err(|e| e.to_string())?;
+        self.acked_thread = Some(thread);
+        Ok(())
+    }
+
+    pub fn disable_acked_delivery(&mut self) {
+        {
+            let mut acked = self.tracker.acked.lock().unwrap();
+            // Dropping the sender stops the thread
+            acked.wake.take();
+            acked.on_event.take();
+        }
+        if let Some(handle) = self.acked_thread.take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Send `data` to a single peer with acknowledged delivery, returning the
+    /// sequence number reported back through the event callback.
+    #[allow(dead_code, reason = "no acked subcommand yet")]
+    pub fn send_acked(&self, data: &[u8], peer_id: &MCPeerID) -> Result<u64, String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+        let name = self.tracker.node_of(peer_id);
+
+        let (seq, frames) = {
+            let mut acked = self.tracker.acked.lock().unwrap();
+            if acked.wake.is_none() {
+                return Err("Acknowledged delivery is not enabled".to_string());
             }
+            let now = Instant::now();
+            let channel = acked.channel(&name);
+            let seq = channel.send(data, now)?;
+            let frames = channel.poll_transmit(now);
+            // Closed once the message is acked or given up on
+            let span = debug_span!(
+                parent: &self.tracker.span_for(&name),
+                "acked_send",
+                peer = %name,
+                seq,
+                bytes = data.len(),
+                outcome = field::Empty
+            );
+            acked.transfers.insert((name.clone(), seq), span);
+            acked.wake();
+            (seq, frames)
+        };
+
+        // A failed send is retried by the retransmission timer
+        match unsafe { self.tracker.connected_peer(&name) } {
+            Some((session, peer_id)) => {
+                if let Err(e) = unsafe { send_to_peer(&self.tracker, &session, &peer_id, &frames) }
+                {
+                    warn!("Failed to send acked message to {}: {}", name, e);
+                }
+            }
+            None => debug!("Peer {} not connected, holding acked message", name),
         }
+        Ok(seq)
     }
 
-    unsafe fn session_didReceiveStream_withName_fromPeer(
-        &self,
-        _session: &MCSession,
-        _stream: &NSInputStream,
-        _stream_name: &NSString,
-        _peer_id: &MCPeerID,
-    ) {
-        unsafe {
-            let _pool = AutoreleasePool::new();
+    /// Keep messages for peers that aren't connected and deliver them once
+    /// they connect, surviving restarts through the file at `path`.
+    ///
+    /// Delivery rides on acknowledged delivery, so that has to be enabled as
+    /// well. `on_event` reports receipts and expired messages (from the
+    /// delegate or a background thread).
+    #[allow(dead_code, reason = "no outbox subcommand yet")]
+    pub fn enable_outbox(
+        &mut self,
+        path: impl AsRef<Path>,
+        config: OutboxConfig,
+        on_event: impl Fn(&OutboxEvent) + Send + Sync + 'static,
+    ) -> Result<(), String> {
+        let outbox = Outbox::open(path, config)?;
+        if !outbox.is_empty() {
+            info!(
+                "Outbox has {} messages for {} peers",
+                outbox.len(),
+                outbox.peers().len()
+            );
         }
+
+        self.tracker.acked.lock().unwrap().outbox = Some(OutboxState {
+            outbox,
+            sending: HashMap::new(),
+            on_event: Arc::new(on_event),
+        });
+
+        // Peers that are already here get their messages right away
+        for peer in self.connected_peers() {
+            let name = self.tracker.node_of(&peer);
+            self.flush_outbox(&name);
+        }
+        Ok(())
+    }
+
+    /// Stop delivering from the outbox. Stored messages stay on disk.
+    #[allow(dead_code, reason = "no outbox subcommand yet")]
+    pub fn disable_outbox(&mut self) {
+        self.tracker.acked.lock().unwrap().outbox.take();
     }
 
-    unsafe fn session_didStartReceivingResourceWithName_fromPeer_withProgress(
+    /// Send `data` to `peer` now if it is connected, otherwise keep it in
+    /// the outbox until it connects or `ttl` runs out. Returns the id the
+    /// receipt will carry.
+    #[allow(dead_code, reason = "no outbox subcommand yet")]
+    pub fn send_or_store(
         &self,
-        _session: &MCSession,
-        _resource_name: &NSString,
-        _peer_id: &MCPeerID,
-        _progress: &NSProgress,
-    ) {
-        unsafe {
-            let _pool = AutoreleasePool::new();
+        data: &[u8],
+        peer: &str,
+        ttl: Option<Duration>,
+    ) -> Result<u64, String> {
+        let id = {
+            let mut acked = self.tracker.acked.lock().unwrap();
+            let Some(state) = acked.outbox.as_mut() else {
+                return Err("Outbox is not enabled".to_string());
+            };
+            state.outbox.push(peer, data, ttl, SystemTime::now())?
+        };
+        self.flush_outbox(peer);
+        Ok(id)
+    }
+
+    fn flush_outbox(&self, name: &str) {
+        let frames = self.tracker.flush_outbox(name);
+        if frames.is_empty() {
+            return;
+        }
+        // Anything that doesn't make it is retransmitted or retried later
+        if let Some((session, peer_id)) = unsafe { self.tracker.connected_peer(name) }
+            && let Err(e) = unsafe { send_to_peer(&self.tracker, &session, &peer_id, &frames) }
+        {
+            warn!("Failed to send outbox messages to {}: {}", name, e);
         }
     }
 
-    unsafe fn session_didFinishReceivingResourceWithName_fromPeer_atURL_withError(
-        &self,
-        _session: &MCSession,
-        _resource_name: &NSString,
-        _peer_id: &MCPeerID,
-        _location_url: Option<&NSURL>,
-        _error: Option<&NSError>,
+    /// Report what the browser finds and loses, in the model the discovery
+    /// aggregator takes. `on_event` is called from the browser delegate.
+    pub fn enable_discovery_events(
+        &mut self,
+        on_event: impl Fn(&DiscoveryEvent) + Send + Sync + 'static,
     ) {
-        unsafe {
-            let _pool = AutoreleasePool::new();
+        *self.tracker.on_discovery.lock().unwrap() = Some(Arc::new(on_event));
+    }
+
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_discovery_events(&mut self) {
+        self.tracker.on_discovery.lock().unwrap().take();
+    }
+
+    /// Report peers joining and leaving and the data they send as
+    /// `BackendEvent`s, with the node ids the `Backend` impl takes. Unlike
+    /// the `MCPeerID`s the callbacks given to `new` get, these tell peers
+    /// with the same display name apart.
+    pub fn enable_backend_events(
+        &mut self,
+        on_event: impl Fn(BackendEvent) + Send + Sync + 'static,
+    ) {
+        *self.tracker.on_backend_event.lock().unwrap() = Some(Arc::new(on_event));
+    }
+
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_backend_events(&mut self) {
+        self.tracker.on_backend_event.lock().unwrap().take();
+    }
+
+    /// Hand secure channel frames to `on_frame`, with the node id of the
+    /// peer they came from. The session doesn't run the channel itself, see
+    /// `noise::SecureChannels`.
+    pub fn enable_frame_events(&mut self, on_frame: impl Fn(&str, &Frame) + Send + Sync + 'static) {
+        *self.tracker.on_frame.lock().unwrap() = Some(Arc::new(on_frame));
+    }
+
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_frame_events(&mut self) {
+        self.tracker.on_frame.lock().unwrap().take();
+    }
+
+    /// Say whether a secure channel runs on top of the session. With one,
+    /// plaintext goodbyes are ignored: anybody could have sent them, the
+    /// ones that count arrive encrypted through the frame events.
+    pub fn set_secure(&mut self, secure: bool) {
+        self.tracker.secure.store(secure, Ordering::Relaxed);
+    }
+
+    /// Call `on_request` with the name of every peer whose invitation says
+    /// it wants to pair. The invitation is accepted as any other.
+    pub fn enable_pairing_requests(&mut self, on_request: impl Fn(&str) + Send + Sync + 'static) {
+        *self.tracker.on_pairing_request.lock().unwrap() = Some(Arc::new(on_request));
+    }
+
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_pairing_requests(&mut self) {
+        self.tracker.on_pairing_request.lock().unwrap().take();
+    }
+
+    /// Filter and rate limit what peers send us, see `inbound`. Peers that
+    /// break the limits or send malformed frames are disconnected and banned
+    /// for a while, and their invitations declined.
+    pub fn enable_inbound_policy(&mut self, config: InboundConfig) {
+        *self.tracker.inbound.lock().unwrap() = Some(InboundPolicy::new(config));
+    }
+
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_inbound_policy(&mut self) {
+        self.tracker.inbound.lock().unwrap().take();
+    }
+
+    /// Compress payloads for peers that can decode them, see `compression`.
+    /// Peers that are already connected get our Hello right away.
+    pub fn enable_compression(&mut self, config: CompressionConfig) {
+        let compression = Compression::new(config);
+        let hello = compression.hello();
+        *self.tracker.compression.lock().unwrap() = Some(compression);
+        self.send_hello(hello);
+    }
+
+    /// Stop compressing, and tell connected peers with a Hello that offers
+    /// no codecs so they stop too. Frames they compressed before it arrives
+    /// still decode.
+    #[allow(dead_code, reason = "the CLI never turns it off")]
+    pub fn disable_compression(&mut self) {
+        let hello = match self.tracker.compression.lock().unwrap().as_mut() {
+            Some(compression) if compression.is_enabled() => {
+                compression.disable();
+                compression.hello()
+            }
+            _ => return,
+        };
+        self.send_hello(hello);
+    }
+
+    fn send_hello(&self, hello: Frame) {
+        let frames = self
+            .connected_peers()
+            .iter()
+            .map(|peer| self.tracker.node_of(peer))
+            .filter(|name| self.tracker.understands_hello(name))
+            .map(|name| (name, hello.clone()))
+            .collect();
+        unsafe { send_to_neighbors(&self.tracker, frames) };
+    }
+
+    /// Peers the inbound policy banned, with the time left on their ban
+    #[allow(dead_code, reason = "no subcommand manages bans yet")]
+    pub fn banned_peers(&self) -> Vec<(NodeId, Duration)> {
+        self.tracker
+            .inbound
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|policy| policy.banned(Instant::now()))
+            .unwrap_or_default()
+    }
+
+    /// Let a banned peer back in before its ban runs out
+    #[allow(dead_code, reason = "no subcommand manages bans yet")]
+    pub fn unban_peer(&self, name: &str) -> Result<(), String> {
+        {
+            let mut inbound = self.tracker.inbound.lock().unwrap();
+            let Some(policy) = inbound.as_mut() else {
+                return Err("Inbound policy is not enabled".to_string());
+            };
+            if !policy.unban(name) {
+                return Err(format!("{} is not banned", name));
+            }
+        }
+        self.tracker.lift_kicks();
+        Ok(())
+    }
+
+    /// Remember peers across runs in the file at `path`.
+    ///
+    /// Every sighting, invitation and connection is recorded. Stale entries
+    /// are pruned when the book is opened.
+    #[allow(dead_code, reason = "the CLI keeps its own address book")]
+    pub fn enable_address_book(
+        &mut self,
+        path: impl AsRef<Path>,
+        config: AddressBookConfig,
+    ) -> Result<(), String> {
+        let mut book = AddressBook::open(path, config)?;
+        let pruned = book.prune(SystemTime::now())?;
+        info!(
+            "Address book knows {} peers, pruned {}",
+            book.len(),
+            pruned.len()
+        );
+        *self.tracker.address_book.lock().unwrap() = Some(book);
+        Ok(())
+    }
+
+    /// Stop recording peers. The book stays on disk.
+    #[allow(dead_code, reason = "the CLI keeps its own address book")]
+    pub fn disable_address_book(&mut self) {
+        self.tracker.address_book.lock().unwrap().take();
+    }
+
+    /// Everything in the address book, most recently seen first
+    #[allow(dead_code, reason = "the CLI keeps its own address book")]
+    pub fn known_peers(&self) -> Vec<PeerEntry> {
+        self.tracker
+            .address_book
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|book| {
+                book.seen_since(SystemTime::UNIX_EPOCH)
+                    .into_iter()
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Set the trust level of a known peer. Blocking a peer stops
+    /// invitations in both directions but leaves a live connection alone.
+    #[allow(dead_code, reason = "the CLI keeps its own address book")]
+    pub fn set_peer_trust(&self, identity: &str, trust: Trust) -> Result<(), String> {
+        let name = {
+            let mut book = self.tracker.address_book.lock().unwrap();
+            let Some(book) = book.as_mut() else {
+                return Err("Address book is not enabled".to_string());
+            };
+            book.set_trust(identity, trust)?;
+            book.get(identity).map(|entry| entry.display_name.clone())
+        };
+        if trust == Trust::Blocked
+            && let Some(name) = name
+        {
+            self.tracker
+                .with_reconnect(|supervisor, _| supervisor.forget(&name));
+        }
+        Ok(())
+    }
+
+    /// Drop stale peers from the address book, returning their identities
+    #[allow(dead_code, reason = "the CLI keeps its own address book")]
+    pub fn prune_address_book(&self) -> Result<Vec<String>, String> {
+        match self.tracker.address_book.lock().unwrap().as_mut() {
+            Some(book) => book.prune(SystemTime::now()),
+            None => Err("Address book is not enabled".to_string()),
+        }
+    }
+
+    /// Route `send_queued` through a bounded per-peer queue drained by a
+    /// dispatcher thread, so bulk data can't starve control messages.
+    #[allow(dead_code, reason = "no queued subcommand yet")]
+    pub fn enable_send_queue(&mut self, config: QueueConfig) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        self.disable_send_queue();
+
+        let queue = Arc::new(SendQueue::new(config));
+        *self.tracker.queue.lock().unwrap() = Some(queue.clone());
+
+        let tracker = self.tracker.clone();
+        let thread = thread::Builder::new()
+            .name("mpc-send".to_string())
+            .spawn(move || run_send_queue(queue, tracker))
+            .map_err(|e| e.to_string())?;
+        self.send_thread = Some(thread);
+        Ok(())
+    }
+
+    /// Stop accepting queued sends. Whatever is already queued is still
+    /// dispatched before this returns.
+    pub fn disable_send_queue(&mut self) {
+        if let Some(queue) = self.tracker.queue.lock().unwrap().take() {
+            queue.close();
+        }
+        if let Some(handle) = self.send_thread.take() {
+            let _ = handle.join();
         }
     }
+
+    /// Queue `data` for each peer, waiting while their lane is full.
+    #[allow(dead_code, reason = "no queued subcommand yet")]
+    pub async fn send_queued(
+        &self,
+        data: &[u8],
+        peers: &[Retained<MCPeerID>],
+        priority: Priority,
+        reliably: bool,
+    ) -> Result<(), String> {
+        let Some(queue) = self.tracker.queue.lock().unwrap().clone() else {
+            return Err("Send queue is not enabled".to_string());
+        };
+
+        let frame = Frame::data(data);
+        for peer in peers {
+            let name = self.tracker.node_of(peer);
+            let message = OutboundMessage {
+                priority,
+                reliable: reliably,
+                data: self.tracker.encode_for(&name, &frame),
+            };
+            queue.send(name.clone(), message).await?;
+            self.tracker
+                .metrics
+                .lock()
+                .unwrap()
+                .queue_depth(&name, queue.len(&name));
+        }
+        Ok(())
+    }
+
+    /// Join the mesh relay: advertise routes to connected peers and forward
+    /// their traffic, so `send_to` reaches nodes several hops away.
+    ///
+    /// Mesh nodes go by their identity, or by their display name if they
+    /// have none. `on_message` is called (from the delegate or a background
+    /// thread) with the originating node for every mesh message addressed
+    /// to us.
+    #[allow(dead_code, reason = "no mesh subcommand yet")]
+    pub fn enable_mesh(
+        &mut self,
+        config: MeshConfig,
+        on_message: impl Fn(&str, &[u8]) + Send + Sync + 'static,
+    ) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        self.disable_mesh();
+
+        let now = Instant::now();
+        let mut mesh = Mesh::new(&self.overlay_id(), config, rand::random(), now);
+        for peer in self.connected_peers() {
+            mesh.neighbor_up(&self.tracker.overlay_id(&self.tracker.node_of(&peer)), now);
+        }
+
+        let (wake, wake_rx) = mpsc::channel();
+        *self.tracker.mesh.lock().unwrap() = Some(MeshState {
+            mesh,
+            on_message: Arc::new(on_message),
+            wake,
+        });
+
+        let tracker = self.tracker.clone();
+        let thread = thread::Builder::new()
+            .name("mpc-mesh".to_string())
+            .spawn(move || run_mesh(tracker, wake_rx))
+            .map_err(|e| e.to_string())?;
+        self.mesh_thread = Some(thread);
+        Ok(())
+    }
+
+    // What the mesh and gossip layers know us by, see
+    // `PeerTracker::overlay_id`
+    fn overlay_id(&self) -> NodeId {
+        self.identity
+            .clone()
+            .unwrap_or_else(|| unsafe { self.peer_id.displayName().to_string() })
+    }
+
+    pub fn disable_mesh(&mut self) {
+        // Dropping the state closes the wake channel, which stops the thread
+        self.tracker.mesh.lock().unwrap().take();
+        if let Some(handle) = self.mesh_thread.take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Send `data` to a mesh node, relayed through other peers if it isn't
+    /// directly connected
+    #[allow(dead_code, reason = "no mesh subcommand yet")]
+    pub fn send_to(&self, node: &str, data: &[u8]) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        let frames = {
+            let mut mesh = self.tracker.mesh.lock().unwrap();
+            let Some(state) = mesh.as_mut() else {
+                return Err("Mesh is not enabled".to_string());
+            };
+            state.mesh.send_to(node, data)?;
+            state.mesh.poll(Instant::now())
+        };
+        unsafe { send_to_neighbors(&self.tracker, frames) };
+        Ok(())
+    }
+
+    /// Take part in gossip broadcasts: forward other peers' broadcasts and
+    /// catch up newly connected peers on recent ones.
+    ///
+    /// `on_message` is called (from the delegate or a background thread)
+    /// once for every broadcast that reaches us.
+    #[allow(dead_code, reason = "no subcommand gossips yet")]
+    pub fn enable_gossip(
+        &mut self,
+        config: GossipConfig,
+        on_message: impl Fn(&GossipMessage) + Send + Sync + 'static,
+    ) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        self.disable_gossip();
+
+        let now = Instant::now();
+        let mut gossip = Gossip::new(&self.overlay_id(), config, rand::random());
+        for peer in self.connected_peers() {
+            gossip.neighbor_up(&self.tracker.overlay_id(&self.tracker.node_of(&peer)), now);
+        }
+
+        let (wake, wake_rx) = mpsc::channel();
+        *self.tracker.gossip.lock().unwrap() = Some(GossipState {
+            gossip,
+            on_message: Arc::new(on_message),
+            wake,
+        });
+
+        let tracker = self.tracker.clone();
+        let thread = thread::Builder::new()
+            .name("mpc-gossip".to_string())
+            .spawn(move || run_gossip(tracker, wake_rx))
+            .map_err(|e| e.to_string())?;
+        self.gossip_thread = Some(thread);
+        Ok(())
+    }
+
+    pub fn disable_gossip(&mut self) {
+        // Dropping the state closes the wake channel, which stops the thread
+        self.tracker.gossip.lock().unwrap().take();
+        if let Some(handle) = self.gossip_thread.take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Broadcast `data` to every device in the connected mesh, not just the
+    /// directly connected peers
+    #[allow(dead_code, reason = "no subcommand gossips yet")]
+    pub fn broadcast(&self, data: &[u8]) -> Result<MessageId, String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        let (id, frames) = {
+            let mut gossip = self.tracker.gossip.lock().unwrap();
+            let Some(state) = gossip.as_mut() else {
+                return Err("Gossip is not enabled".to_string());
+            };
+            let now = Instant::now();
+            let id = state.gossip.broadcast(data, now);
+            state.wake();
+            (id, state.gossip.poll(now))
+        };
+        unsafe { send_to_neighbors(&self.tracker, frames) };
+        Ok(id)
+    }
+
+    fn send_frame(
+        &self,
+        frame: &Frame,
+        peers: &[Retained<MCPeerID>],
+        reliably: bool,
+    ) -> Result<(), String> {
+        let parent = match peers {
+            [peer] => self.tracker.span_for(&self.tracker.node_of(peer)),
+            _ => self.tracker.span.clone(),
+        };
+        let span = debug_span!(
+            parent: &parent,
+            "send",
+            kind = ?frame.kind,
+            bytes = frame.payload.len(),
+            peers = peers.len(),
+            reliably,
+            outcome = field::Empty
+        );
+        let _enter = span.enter();
+        let result = self.send_frame_to_shards(frame, peers, reliably);
+        span.record(
+            "outcome",
+            match &result {
+                Ok(()) => "ok",
+                Err(e) => e.as_str(),
+            },
+        );
+        result
+    }
+
+    fn send_frame_to_shards(
+        &self,
+        frame: &Frame,
+        peers: &[Retained<MCPeerID>],
+        reliably: bool,
+    ) -> Result<(), String> {
+        if !self.is_initialized() {
+            return Err("Session not initialized".to_string());
+        }
+
+        unsafe {
+            let _pool = AutoreleasePool::new();
+
+            let mode = if reliably {
+                MCSessionSendDataMode::Reliable
+            } else {
+                MCSessionSendDataMode::Unreliable
+            };
+
+            // Every shard only takes the peers it is connected to
+            let mut remaining: Vec<&MCPeerID> = peers.iter().map(|p| p.as_ref()).collect();
+            for session in self.tracker.sessions() {
+                if remaining.is_empty() {
+                    break;
+                }
+                let connected = session.connectedPeers();
+                let (here, elsewhere): (Vec<&MCPeerID>, Vec<&MCPeerID>) =
+                    remaining.into_iter().partition(|peer| {
+                        (0..connected.count()).any(|i| *connected.objectAtIndex(i) == **peer)
+                    });
+                remaining = elsewhere;
+                if here.is_empty() {
+                    continue;
+                }
+
+                for (encoded, group) in self.tracker.encode_for_all(frame, here) {
+                    let ns_data = NSData::from_vec(encoded);
+                    let peer_array = NSArray::from_slice(&group);
+                    let result = session.sendData_toPeers_withMode_error(
+                        ns_data.as_ref(),
+                        &peer_array,
+                        mode,
+                    );
+                    for peer in &group {
+                        self.tracker.record_send(
+                            &self.tracker.node_of(peer),
+                            &ns_data,
+                            reliably,
+                            &result,
+                        );
+                    }
+                    result.map_err(|e| e.to_string())?;
+                }
+            }
+
+            if remaining.is_empty() {
+                Ok(())
+            } else {
+                let mut metrics = self.tracker.metrics.lock().unwrap();
+                for peer in &remaining {
+                    metrics.send_failed(&self.tracker.node_of(peer), reliably);
+                }
+                Err(format!("{} peers are not connected", remaining.len()))
+            }
+        }
+    }
+
+    // Stop advertising and browsing first so no new connections come in,
+    // then disconnect every session and detach the delegates. Safe to call
+    // more than once.
+    fn teardown(&mut self) {
+        let span = self.tracker.span.clone();
+        let _enter = span.enter();
+        self.disable_reconnect();
+        self.disable_acked_delivery();
+        self.disable_send_queue();
+        self.disable_mesh();
+        self.disable_gossip();
+
+        for peer in self.connected_peers() {
+            let name = self.tracker.node_of(&peer);
+            self.tracker
+                .connection_closed(&name, DisconnectReason::Shutdown);
+        }
+
+        unsafe {
+            let _pool = AutoreleasePool::new();
+
+            if let Some(advertiser) = self.service_advertiser.take() {
+                debug!("Stopping advertiser");
+                advertiser.stopAdvertisingPeer();
+                advertiser.setDelegate(None);
+            }
+            self.advertiser_delegate.take();
+
+            if let Some(browser) = self.service_browser.take() {
+                debug!("Stopping browser");
+                browser.stopBrowsingForPeers();
+                browser.setDelegate(None);
+            }
+            self.browser_delegate.take();
+
+            if let Some(shards) = self.tracker.shards.lock().unwrap().take() {
+                for session in shards.0.sessions {
+                    debug!("Disconnecting session");
+                    session.disconnect();
+                    session.setDelegate(None);
+                }
+            }
+
+            self.delegate.take();
+        }
+    }
+}
+
+// Lets code written against `Backend` run over MPC as well. Events arrive
+// through `enable_backend_events`.
+impl Backend for MultipeerSession {
+    fn local_name(&self) -> String {
+        unsafe { self.peer_id.displayName().to_string() }
+    }
+
+    fn connected_peers(&self) -> Vec<NodeId> {
+        MultipeerSession::connected_peers(self)
+            .iter()
+            .map(|peer| self.tracker.node_of(peer))
+            .collect()
+    }
+
+    fn send_frame(&self, frame: &Frame, peers: &[NodeId], reliably: bool) -> Result<(), String> {
+        let connected = MultipeerSession::connected_peers(self);
+        let mut targets = Vec::new();
+        for name in peers {
+            let peer = connected
+                .iter()
+                .find(|peer| self.tracker.node_of(peer) == *name)
+                .ok_or_else(|| format!("Peer {} is not connected", name))?;
+            targets.push(peer.clone());
+        }
+        MultipeerSession::send_frame(self, frame, &targets, reliably)
+    }
+
+    /// The stream only carries data to the peer, see `OutgoingStream`
+    fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String> {
+        let (session, peer_id) = unsafe { self.tracker.connected_peer(peer) }
+            .ok_or_else(|| format!("Peer {} is not connected", peer))?;
+        let stream = unsafe {
+            let _pool = AutoreleasePool::new();
+            session.startStreamWithName_toPeer_error(&NSString::from_str(name), &peer_id)
+        }
+        .map_err(|e| format!("Failed to open stream to {}: {:?}", peer, e))?;
+        Ok(Box::new(OutgoingStream::new(stream)))
+    }
+
+    fn compress_for(&self, peer: &str, frame: &Frame) -> Frame {
+        match self.tracker.compression.lock().unwrap().as_ref() {
+            Some(compression) => compression.compress(peer, frame),
+            None => frame.clone(),
+        }
+    }
+
+    fn decompress(&self, frame: Frame) -> Result<Frame, String> {
+        self.tracker.decompress(frame)
+    }
+}
+
+// MPC streams run one way, from the peer that started them to the one they
+// were started to. The opening side gets an `OutgoingStream` it can only
+// write to, the other side an `IncomingStream` it can only read; the other
+// direction fails with `Unsupported`. The streams aren't scheduled on a run
+// loop, reads and writes block until they can go ahead. Opening one takes a
+// while, so the first read or write waits for that instead of whoever hands
+// the stream over: the delegate callback or the application's own thread.
+struct OutgoingStream {
+    stream: Retained<NSOutputStream>,
+    opened: bool,
+}
+
+struct IncomingStream {
+    stream: Retained<NSInputStream>,
+    opened: bool,
+}
+
+impl OutgoingStream {
+    fn new(stream: Retained<NSOutputStream>) -> Self {
+        unsafe { stream.open() };
+        Self {
+            stream,
+            opened: false,
+        }
+    }
+
+    fn ready(&mut self) -> io::Result<()> {
+        if !self.opened {
+            unsafe { wait_open(&self.stream) }?;
+            self.opened = true;
+        }
+        Ok(())
+    }
+}
+
+impl IncomingStream {
+    fn new(stream: Retained<NSInputStream>) -> Self {
+        unsafe { stream.open() };
+        Self {
+            stream,
+            opened: false,
+        }
+    }
+
+    fn ready(&mut self) -> io::Result<()> {
+        if !self.opened {
+            unsafe { wait_open(&self.stream) }?;
+            self.opened = true;
+        }
+        Ok(())
+    }
+}
+
+// SAFETY: a stream is only used by whoever owns the wrapper, one thread at
+// a time, and a polled NSStream isn't tied to any thread's run loop.
+unsafe impl Send for OutgoingStream {}
+unsafe impl Send for IncomingStream {}
+
+/// How long a stream may take to open
+const STREAM_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Waits for a stream `open` was called on to finish opening
+unsafe fn wait_open(stream: &NSStream) -> io::Result<()> {
+    unsafe {
+        let deadline = Instant::now() + STREAM_OPEN_TIMEOUT;
+        loop {
+            match stream.streamStatus() {
+                NSStreamStatus::NotOpen | NSStreamStatus::Opening => {}
+                NSStreamStatus::Error => return Err(stream_error(stream)),
+                _ => return Ok(()),
+            }
+            if Instant::now() >= deadline {
+                stream.close();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "stream didn't open",
+                ));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn stream_error(stream: &NSStream) -> io::Error {
+    match unsafe { stream.streamError() } {
+        Some(e) => io::Error::other(format!("{:?}", e)),
+        None => io::Error::other("stream failed"),
+    }
+}
+
+fn one_way() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "MPC streams only run one way")
+}
+
+impl Write for OutgoingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.ready()?;
+        let written = unsafe {
+            self.stream
+                .write_maxLength(NonNull::from(buf).cast(), buf.len())
+        };
+        match written {
+            n if n > 0 => Ok(n as usize),
+            0 => Err(io::Error::new(io::ErrorKind::WriteZero, "stream is full")),
+            _ => Err(stream_error(&self.stream)),
+        }
+    }
+
+    // Writes go straight to MPC
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for OutgoingStream {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(one_way())
+    }
+}
+
+impl Read for IncomingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.ready()?;
+        let len = buf.len();
+        match unsafe { self.stream.read_maxLength(NonNull::from(buf).cast(), len) } {
+            n if n >= 0 => Ok(n as usize),
+            _ => Err(stream_error(&self.stream)),
+        }
+    }
+}
+
+impl Write for IncomingStream {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(one_way())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Closing the outgoing side is what ends the stream for the peer
+impl Drop for OutgoingStream {
+    fn drop(&mut self) {
+        unsafe { self.stream.close() };
+    }
+}
+
+impl Drop for IncomingStream {
+    fn drop(&mut self) {
+        unsafe { self.stream.close() };
+    }
+}
+
+impl Drop for MultipeerSession {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+// User callbacks collected while the state they report on is locked, to be
+// run once it is unlocked. That way a callback can call back into the
+// session.
+#[derive(Default)]
+struct Deferred(Vec<Box<dyn FnOnce()>>);
+
+impl Deferred {
+    fn push(&mut self, callback: impl FnOnce() + 'static) {
+        self.0.push(Box::new(callback));
+    }
+
+    fn run(self) {
+        for callback in self.0 {
+            callback();
+        }
+    }
+}
+
+// Moves MPC objects to other threads. Only implemented for the types below,
+// each of which we have checked is fine to use off the thread that made it.
+struct ThreadSafe<T>(T);
+
+// SAFETY: the browser is only used to send invitations, which MPC accepts
+// from any thread and serialises internally.
+unsafe impl Send for ThreadSafe<Retained<MCNearbyServiceBrowser>> {}
+// SAFETY: MCPeerID is immutable once created.
+unsafe impl Send for ThreadSafe<Retained<MCPeerID>> {}
+// SAFETY: sessions, their delegate and our peer id are only used behind the
+// `shards` mutex, to send and to look up peers, both thread safe in MPC.
+unsafe impl Send for ThreadSafe<Shards> {}
+
+struct ReconnectState {
+    supervisor: ReconnectSupervisor<String>,
+    on_event: ReconnectCallback,
+    wake: mpsc::Sender<()>,
+}
+
+impl ReconnectState {
+    fn emit(&mut self, deferred: &mut Deferred) {
+        for event in self.supervisor.drain_events() {
+            debug!("Reconnect: {:?}", event);
+            let cb = self.on_event.clone();
+            deferred.push(move || cb(&event));
+        }
+    }
+
+    fn wake(&self) {
+        let _ = self.wake.send(());
+    }
+}
+
+struct OutboxState {
+    outbox: Outbox,
+    // Outbox ids of messages on the wire, by peer and sequence number
+    sending: HashMap<(String, u64), u64>,
+    on_event: Arc<dyn Fn(&OutboxEvent) + Send + Sync>,
+}
+
+impl OutboxState {
+    fn emit(&mut self, deferred: &mut Deferred) {
+        for event in self.outbox.drain_events() {
+            debug!("Outbox: {:?}", event);
+            let cb = self.on_event.clone();
+            deferred.push(move || cb(&event));
+        }
+    }
+}
+
+#[derive(Default)]
+struct AckedState {
+    // Per-peer channels, created on first use
+    channels: HashMap<String, ReliableChannel>,
+    // Spans of messages on the wire, by peer and sequence number
+    transfers: HashMap<(String, u64), Span>,
+    config: ReliableConfig,
+    on_event: Option<ReliableCallback>,
+    wake: Option<mpsc::Sender<()>>,
+    outbox: Option<OutboxState>,
+}
+
+impl AckedState {
+    fn channel(&mut self, name: &str) -> &mut ReliableChannel {
+        let config = &self.config;
+        self.channels
+            .entry(name.to_string())
+            .or_insert_with(|| ReliableChannel::new(config.clone()))
+    }
+
+    // Callbacks go into `deferred`, to run once the state is unlocked
+    fn emit(&mut self, name: &str, deferred: &mut Deferred) {
+        let Some(channel) = self.channels.get_mut(name) else {
+            return;
+        };
+        for event in channel.drain_events() {
+            let (seq, outcome) = match event {
+                ReliableEvent::Acked { seq } => (seq, "acked"),
+                ReliableEvent::Failed { seq } => (seq, "failed"),
+            };
+            let span = self
+                .transfers
+                .remove(&(name.to_string(), seq))
+                .unwrap_or_else(Span::none);
+            span.record("outcome", outcome);
+            let _enter = span.enter();
+            debug!("Acked delivery to {}: {:?}", name, event);
+
+            // Outbox messages are reported as receipts instead
+            if let Some(state) = self.outbox.as_mut()
+                && let Some(id) = state.sending.remove(&(name.to_string(), seq))
+            {
+                match event {
+                    ReliableEvent::Acked { .. } => {
+                        if let Err(e) = state.outbox.delivered(id) {
+                            warn!("Failed to update outbox: {}", e);
+                        }
+                    }
+                    ReliableEvent::Failed { .. } => state.outbox.retry_later(id),
+                }
+                state.emit(deferred);
+                continue;
+            }
+
+            if let Some(cb) = &self.on_event {
+                let (cb, name) = (cb.clone(), name.to_string());
+                deferred.push(move || cb(&name, &event));
+            }
+        }
+    }
+
+    // Puts the peer's stored messages on its channel, returning the frames
+    // to send
+    fn flush_outbox(&mut self, name: &str) -> Vec<Frame> {
+        if self.wake.is_none() {
+            return Vec::new();
+        }
+        let Some(state) = self.outbox.as_mut() else {
+            return Vec::new();
+        };
+        let entries = state.outbox.take_pending(name, SystemTime::now());
+        if entries.is_empty() {
+            return Vec::new();
+        }
+
+        let now = Instant::now();
+        let config = &self.config;
+        let channel = self
+            .channels
+            .entry(name.to_string())
+            .or_insert_with(|| ReliableChannel::new(config.clone()));
+        for entry in entries {
+            match channel.send(&entry.data, now) {
+                Ok(seq) => {
+                    state.sending.insert((name.to_string(), seq), entry.id);
+                    let span = debug_span!(
+                        "outbox_send",
+                        peer = %name,
+                        id = entry.id,
+                        seq,
+                        bytes = entry.data.len(),
+                        outcome = field::Empty
+                    );
+                    self.transfers.insert((name.to_string(), seq), span);
+                }
+                Err(e) => {
+                    debug!("Holding outbox message {} for {}: {}", entry.id, name, e);
+                    state.outbox.retry_later(entry.id);
+                }
+            }
+        }
+        channel.poll_transmit(now)
+    }
+
+    fn expire_outbox(&mut self, deferred: &mut Deferred) {
+        if let Some(state) = self.outbox.as_mut() {
+            if let Err(e) = state.outbox.expire(SystemTime::now()) {
+                warn!("Failed to update outbox: {}", e);
+            }
+            state.emit(deferred);
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+}
+
+struct MeshState {
+    mesh: Mesh,
+    on_message: MeshCallback,
+    wake: mpsc::Sender<()>,
+}
+
+impl MeshState {
+    fn emit(&mut self) {
+        for event in self.mesh.drain_events() {
+            debug!("Mesh: {:?}", event);
+        }
+    }
+
+    fn wake(&self) {
+        let _ = self.wake.send(());
+    }
+}
+
+struct GossipState {
+    gossip: Gossip,
+    on_message: Arc<dyn Fn(&GossipMessage) + Send + Sync>,
+    wake: mpsc::Sender<()>,
+}
+
+impl GossipState {
+    fn wake(&self) {
+        let _ = self.wake.send(());
+    }
+}
+
+// The MCSession instances behind one MultipeerSession, all sharing the same
+// delegate. New peers go wherever the pool puts them.
+struct Shards {
+    peer_id: Retained<MCPeerID>,
+    encryption: Option<MCEncryptionPreference>,
+    delegate: Retained<ProtocolObject<dyn MCSessionDelegate>>,
+    sessions: Vec<Retained<MCSession>>,
+    pool: SessionPool<String>,
+}
+
+// Node ids of the peers we have come across. Display names aren't unique,
+// so the first peer with a name gets the name itself and any other peer
+// with it `name#2`, `name#3` and so on. A peer keeps its id until it is
+// both lost and disconnected.
+#[derive(Default)]
+struct PeerIds(Vec<(ThreadSafe<Retained<MCPeerID>>, NodeId)>);
+
+impl PeerIds {
+    fn node_of(&mut self, peer_id: &MCPeerID) -> NodeId {
+        if let Some((_, node)) = self.0.iter().find(|(known, _)| *known.0 == *peer_id) {
+            return node.clone();
+        }
+        let name = unsafe { peer_id.displayName().to_string() };
+        let node = (1..)
+            .map(|n| match n {
+                1 => name.clone(),
+                n => format!("{}#{}", name, n),
+            })
+            .find(|node| self.0.iter().all(|(_, other)| other != node))
+            .expect("ids run out after the peers do");
+        self.0.push((ThreadSafe(peer_id.retain()), node.clone()));
+        node
+    }
+
+    fn peer_of(&self, node: &str) -> Option<Retained<MCPeerID>> {
+        self.0
+            .iter()
+            .find(|(_, other)| other == node)
+            .map(|(peer_id, _)| peer_id.0.clone())
+    }
+
+    fn forget(&mut self, node: &str) {
+        self.0.retain(|(_, other)| other != node);
+    }
+}
+
+// State shared between the session, its delegates and the background
+// threads. Peers are keyed by node id, see `PeerIds`.
+struct PeerTracker {
+    shards: Mutex<Option<ThreadSafe<Shards>>>,
+    peers: Mutex<PeerIds>,
+    // Peers the browser currently sees
+    found: Mutex<HashMap<String, ThreadSafe<Retained<MCPeerID>>>>,
+    reconnect: Mutex<Option<ReconnectState>>,
+    acked: Mutex<AckedState>,
+    queue: Mutex<Option<Arc<SendQueue<String>>>>,
+    mesh: Mutex<Option<MeshState>>,
+    gossip: Mutex<Option<GossipState>>,
+    address_book: Mutex<Option<AddressBook>>,
+    // Identities peers told us through their discovery info or invitation.
+    // Peers without one stay out of the address book.
+    identities: Mutex<HashMap<String, String>>,
+    // Peers that advertised `HELLO_KEY`, older ones take a Hello for a
+    // malformed frame
+    hello_peers: Mutex<HashSet<String>>,
+    // Peers the inbound policy threw out that MPC still has in a session.
+    // Their traffic is ignored until MPC reports them gone.
+    kicked: Mutex<HashSet<String>>,
+    // A secure channel runs on top, see `set_secure`
+    secure: AtomicBool,
+    on_discovery: Mutex<Option<DiscoveryCallback>>,
+    on_frame: Mutex<Option<FrameCallback>>,
+    on_backend_event: Mutex<Option<EventCallback>>,
+    on_pairing_request: Mutex<Option<PairingCallback>>,
+    inbound: Mutex<Option<InboundPolicy>>,
+    compression: Mutex<Option<Compression>>,
+    metrics: Mutex<Metrics>,
+    capture: Mutex<Option<CaptureWriter>>,
+    // Parent of every span the session opens
+    span: Span,
+    // One span per peer from the first invitation until it is gone
+    connections: Mutex<HashMap<String, Span>>,
+}
+
+impl PeerTracker {
+    fn new(span: Span) -> Self {
+        Self {
+            shards: Mutex::new(None),
+            peers: Mutex::new(PeerIds::default()),
+            found: Mutex::new(HashMap::new()),
+            reconnect: Mutex::new(None),
+            acked: Mutex::new(AckedState::default()),
+            queue: Mutex::new(None),
+            mesh: Mutex::new(None),
+            gossip: Mutex::new(None),
+            address_book: Mutex::new(None),
+            identities: Mutex::new(HashMap::new()),
+            hello_peers: Mutex::new(HashSet::new()),
+            kicked: Mutex::new(HashSet::new()),
+            secure: AtomicBool::new(false),
+            on_discovery: Mutex::new(None),
+            on_frame: Mutex::new(None),
+            on_backend_event: Mutex::new(None),
+            on_pairing_request: Mutex::new(None),
+            inbound: Mutex::new(None),
+            compression: Mutex::new(None),
+            metrics: Mutex::new(Metrics::new("mpc", Instant::now())),
+            capture: Mutex::new(None),
+            span,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn node_of(&self, peer_id: &MCPeerID) -> NodeId {
+        self.peers.lock().unwrap().node_of(peer_id)
+    }
+
+    // The peer is neither found nor connected any more, its id may go to
+    // another peer
+    fn forget_peer(&self, name: &str) {
+        self.peers.lock().unwrap().forget(name);
+        self.identities.lock().unwrap().remove(name);
+        self.hello_peers.lock().unwrap().remove(name);
+    }
+
+    // The mesh and gossip layers pass node ids on to other devices, so
+    // they know a peer by its identity, which is the same everywhere. Only
+    // peers that have none go by their node id.
+    fn overlay_id(&self, name: &str) -> NodeId {
+        self.identity_of(name).unwrap_or_else(|| name.to_string())
+    }
+
+    // The node id of the neighbor the mesh or gossip layer means
+    fn node_for_overlay(&self, id: &str) -> NodeId {
+        self.identities
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, identity)| *identity == id)
+            .map_or_else(|| id.to_string(), |(name, _)| name.clone())
+    }
+
+    // The peer's connection span, or the session span if there is none
+    fn span_for(&self, name: &str) -> Span {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| self.span.clone())
+    }
+
+    fn connection_span(&self, name: &str) -> Span {
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                info_span!(
+                    parent: &self.span,
+                    "connection",
+                    peer = %name,
+                    shard = field::Empty,
+                    outcome = field::Empty
+                )
+            })
+            .clone()
+    }
+
+    // The peer left or never made it. Reporting it twice is harmless.
+    fn connection_closed(&self, name: &str, reason: DisconnectReason) {
+        let was_connected = {
+            let mut metrics = self.metrics.lock().unwrap();
+            let was_connected = metrics.is_connected(name);
+            metrics.disconnected(name, reason);
+            was_connected
+        };
+        if was_connected {
+            self.capture(name, || CaptureEvent::Disconnected {
+                reason: reason.name().to_string(),
+            });
+        }
+        if let Some(policy) = self.inbound.lock().unwrap().as_mut() {
+            policy.peer_gone(name);
+        }
+        if let Some(compression) = self.compression.lock().unwrap().as_mut() {
+            compression.peer_gone(name);
+        }
+        if let Some(span) = self.connections.lock().unwrap().remove(name) {
+            let outcome = if was_connected {
+                reason.name()
+            } else {
+                "failed"
+            };
+            span.record("outcome", outcome);
+        }
+    }
+    fn sessions(&self) -> Vec<Retained<MCSession>> {
+        self.shards
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|shards| shards.0.sessions.clone())
+            .unwrap_or_default()
+    }
+
+    // Session the peer is (or is about to be) connected in. A new shard is
+    // created once every session is full.
+    unsafe fn session_for(&self, name: &str) -> Option<Retained<MCSession>> {
+        let mut shards = self.shards.lock().unwrap();
+        let shards = &mut shards.as_mut()?.0;
+        let assignment = shards.pool.assign(&name.to_string());
+        if assignment.new_shard {
+            unsafe {
+                let _pool = AutoreleasePool::new();
+                let session = make_session(&shards.peer_id, shards.encryption);
+                session.setDelegate(Some(&shards.delegate));
+                shards.sessions.push(session);
+            }
+            info!(
+                "All sessions full, started session {} for {}",
+                assignment.shard, name
+            );
+        }
+        shards.sessions.get(assignment.shard).cloned()
+    }
+
+    fn release_slot(&self, name: &str) {
+        if let Some(shards) = self.shards.lock().unwrap().as_mut() {
+            shards.0.pool.release(&name.to_string());
+        }
+    }
+
+    unsafe fn connected_peer(
+        &self,
+        name: &str,
+    ) -> Option<(Retained<MCSession>, Retained<MCPeerID>)> {
+        if self.is_kicked(name) {
+            return None;
+        }
+        self.sessions().into_iter().find_map(|session| {
+            let peer_id = unsafe { session.connectedPeers() }
+                .iter()
+                .find(|peer| self.node_of(peer) == name)?;
+            Some((session, peer_id))
+        })
+    }
+
+    fn with_reconnect(&self, f: impl FnOnce(&mut ReconnectSupervisor<String>, Instant)) {
+        let mut deferred = Deferred::default();
+        if let Some(state) = self.reconnect.lock().unwrap().as_mut() {
+            f(&mut state.supervisor, Instant::now());
+            state.emit(&mut deferred);
+            state.wake();
+        }
+        deferred.run();
+    }
+
+    fn with_address_book(&self, f: impl FnOnce(&mut AddressBook) -> Result<(), String>) {
+        if let Some(book) = self.address_book.lock().unwrap().as_mut()
+            && let Err(e) = f(book)
+        {
+            warn!("Failed to update address book: {}", e);
+        }
+    }
+
+    // Whether the address book and the inbound policy let us talk to the
+    // peer at all
+    fn allows(&self, name: &str) -> bool {
+        let identity = self.identity_of(name);
+        let book_allows = match (self.address_book.lock().unwrap().as_ref(), &identity) {
+            (Some(book), Some(identity)) => book.allows(identity),
+            _ => true,
+        };
+        book_allows && self.inbound_admits(name).is_ok()
+    }
+
+    // Hands `f` what else the inbound policy may call the peer: its display
+    // name and its identity
+    fn with_aliases<R>(&self, name: &str, f: impl FnOnce(&Aliases) -> R) -> R {
+        let display_name = self
+            .peers
+            .lock()
+            .unwrap()
+            .peer_of(name)
+            .map(|peer_id| unsafe { peer_id.displayName().to_string() });
+        let identity = self.identity_of(name);
+        f(&Aliases {
+            display_name: display_name.as_deref(),
+            identity: identity.as_deref(),
+        })
+    }
+
+    fn identity_of(&self, name: &str) -> Option<String> {
+        self.identities.lock().unwrap().get(name).cloned()
+    }
+
+    // What the peer's discovery info or invitation context says about it
+    fn learn_peer(&self, name: &str, info: &DiscoveryInfo) {
+        if let Some(identity) = info.get(IDENTITY_KEY) {
+            self.identities
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), identity.to_string());
+        }
+        if info.get(HELLO_KEY).is_some() {
+            self.hello_peers.lock().unwrap().insert(name.to_string());
+        }
+    }
+
+    // A kick only lasts while the inbound policy keeps the peer out, so it
+    // lapses with the ban even if the link never went down
+    fn is_kicked(&self, name: &str) -> bool {
+        if !self.kicked.lock().unwrap().contains(name) {
+            return false;
+        }
+        if self.inbound_admits(name).is_err() {
+            return true;
+        }
+        self.kicked.lock().unwrap().remove(name);
+        false
+    }
+
+    // Forget the kicks of peers the inbound policy lets back in
+    fn lift_kicks(&self) {
+        let kicked: Vec<String> = self.kicked.lock().unwrap().iter().cloned().collect();
+        for name in kicked {
+            self.is_kicked(&name);
+        }
+    }
+
+    fn understands_hello(&self, name: &str) -> bool {
+        self.hello_peers.lock().unwrap().contains(name)
+    }
+
+    // Records a sighting of the peer if we know its identity, then hands
+    // the identity to `f`
+    fn observe(
+        &self,
+        name: &str,
+        info: &DiscoveryInfo,
+        f: impl FnOnce(&mut AddressBook, &str) -> Result<(), String>,
+    ) {
+        let Some(identity) = self.identity_of(name) else {
+            return;
+        };
+        self.with_address_book(|book| {
+            book.observe(&identity, name, name, info, SystemTime::now())?;
+            f(book, &identity)
+        });
+    }
+
+    // What the inbound policy makes of a message from the peer, `Accept`
+    // without one
+    fn inbound_admits(&self, name: &str) -> Result<(), Violation> {
+        self.with_aliases(name, |aliases| {
+            match self.inbound.lock().unwrap().as_ref() {
+                Some(policy) => policy.admits(name, aliases, Instant::now()),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn inbound_message(&self, name: &str, len: usize) -> Verdict {
+        self.with_aliases(name, |aliases| {
+            match self.inbound.lock().unwrap().as_mut() {
+                Some(policy) => policy.on_message(name, aliases, len, Instant::now()),
+                None => Verdict::Accept,
+            }
+        })
+    }
+
+    fn inbound_malformed(&self, name: &str) -> Verdict {
+        self.with_aliases(name, |aliases| {
+            match self.inbound.lock().unwrap().as_mut() {
+                Some(policy) => policy.on_malformed(name, aliases, Instant::now()),
+                None => Verdict::Accept,
+            }
+        })
+    }
+
+    // Hello to send a peer that just connected, if we compress and it
+    // understands one
+    fn hello_for(&self, name: &str) -> Option<Frame> {
+        if !self.understands_hello(name) {
+            return None;
+        }
+        self.compression
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|compression| compression.hello())
+    }
+
+    fn on_hello(&self, name: &str, frame: &Frame) {
+        // Whatever it advertised, it speaks Hello
+        self.hello_peers.lock().unwrap().insert(name.to_string());
+        let mut compression = self.compression.lock().unwrap();
+        let Some(compression) = compression.as_mut() else {
+            debug!("Compression is off, ignoring hello from {}", name);
+            return;
+        };
+        match compression.on_hello(name, frame) {
+            Ok(Some(codec)) => debug!("Compressing with {:?} for {}", codec, name),
+            Ok(None) => debug!("No codec in common with {}", name),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    // The frame encoded as it goes out to the peer, compressed if we
+    // negotiated a codec
+    fn encode_for(&self, name: &str, frame: &Frame) -> Vec<u8> {
+        match self.compression.lock().unwrap().as_ref() {
+            Some(compression) => compression.compress(name, frame).encode(),
+            None => frame.encode(),
+        }
+    }
+
+    // Like `encode_for`, once for each group of peers that negotiated the
+    // same codec
+    unsafe fn encode_for_all<'a>(
+        &self,
+        frame: &Frame,
+        peers: Vec<&'a MCPeerID>,
+    ) -> Vec<(Vec<u8>, Vec<&'a MCPeerID>)> {
+        let compression = self.compression.lock().unwrap();
+        let Some(compression) = compression.as_ref() else {
+            return vec![(frame.encode(), peers)];
+        };
+        let mut groups: Vec<(Option<Codec>, Vec<&MCPeerID>)> = Vec::new();
+        for peer in peers {
+            let codec = compression.codec_for(&self.node_of(peer));
+            match groups.iter_mut().find(|(other, _)| *other == codec) {
+                Some((_, group)) => group.push(peer),
+                None => groups.push((codec, vec![peer])),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(codec, group)| (compression.compress_with(codec, frame).encode(), group))
+            .collect()
+    }
+
+    // Undo compression on a frame that came in. Compressed frames are an
+    // error while compression is off, we never sent a Hello for them.
+    fn decompress(&self, frame: Frame) -> Result<Frame, String> {
+        match self.compression.lock().unwrap().as_ref() {
+            Some(compression) => compression.decompress(frame),
+            None if is_compressed(&frame) => {
+                Err("Compressed frame, but compression is off".to_string())
+            }
+            None => Ok(frame),
+        }
+    }
+
+    // Invitation to or from the peer is under way. Returns the connection
+    // span.
+    fn connect_attempt(&self, name: &str) -> Span {
+        self.metrics
+            .lock()
+            .unwrap()
+            .connect_attempt(name, Instant::now());
+        self.observe(name, &DiscoveryInfo::new(), |book, identity| {
+            book.record_attempt(identity)
+        });
+        self.connection_span(name)
+    }
+
+    fn peer_found(&self, name: String, peer_id: &MCPeerID, info: &DiscoveryInfo) {
+        // Before the callbacks, so they can invite the peer right away
+        self.found
+            .lock()
+            .unwrap()
+            .insert(name.clone(), ThreadSafe(peer_id.retain()));
+        self.capture(&name, || CaptureEvent::Found);
+        self.learn_peer(&name, info);
+        self.observe(&name, info, |_, _| Ok(()));
+        if let Some(cb) = callback(&self.on_discovery) {
+            cb(&DiscoveryEvent::Found {
+                node: name.clone(),
+                info: info.clone(),
+                addrs: Vec::new(),
+            });
+        }
+        if self.allows(&name) {
+            self.with_reconnect(|supervisor, now| supervisor.on_found(&name, now));
+        } else {
+            debug!("Not tracking blocked peer {}", name);
+        }
+    }
+
+    fn peer_lost(&self, name: &str) {
+        self.found.lock().unwrap().remove(name);
+        if unsafe { self.connected_peer(name) }.is_none() {
+            self.forget_peer(name);
+        }
+        self.capture(name, || CaptureEvent::Lost);
+        if let Some(cb) = callback(&self.on_discovery) {
+            cb(&DiscoveryEvent::Lost {
+                node: name.to_string(),
+            });
+        }
+        self.with_reconnect(|supervisor, now| supervisor.on_lost(&name.to_string(), now));
+    }
+
+    fn peer_connected(&self, name: &str) {
+        self.metrics.lock().unwrap().connected(name, Instant::now());
+        self.capture(name, || CaptureEvent::Connected);
+        let shard = self
+            .shards
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|shards| shards.0.pool.shard_of(&name.to_string()));
+        if let Some(shard) = shard {
+            self.connection_span(name).record("shard", shard);
+        }
+        self.observe(name, &DiscoveryInfo::new(), |book, identity| {
+            book.record_connected(identity, SystemTime::now())
+        });
+        self.with_reconnect(|supervisor, _| supervisor.on_connected(&name.to_string()));
+        let neighbor = self.overlay_id(name);
+        self.with_mesh(|mesh, now| mesh.neighbor_up(&neighbor, now));
+        self.with_gossip(|gossip, now| gossip.neighbor_up(&neighbor, now));
+    }
+
+    // Counts one send to the peer, or a send error
+    fn record_send<T, E>(&self, name: &str, data: &NSData, reliably: bool, result: &Result<T, E>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        match result {
+            Ok(_) => metrics.sent(name, data.len(), reliably),
+            Err(_) => metrics.send_failed(name, reliably),
+        }
+        drop(metrics);
+        if result.is_ok() {
+            self.capture(name, || CaptureEvent::Sent {
+                frame: data.to_vec(),
+                reliably,
+            });
+        }
+    }
+
+    // The event is only built if someone listens
+    fn backend_event(&self, event: impl FnOnce() -> BackendEvent) {
+        if let Some(cb) = callback(&self.on_backend_event) {
+            cb(event());
+        }
+    }
+
+    // The event is only built while a capture is running
+    fn capture(&self, name: &str, event: impl FnOnce() -> CaptureEvent) {
+        if let Some(writer) = self.capture.lock().unwrap().as_mut() {
+            let event = event();
+            if let Err(e) = writer.record(name, &event) {
+                warn!("Failed to capture {:?}: {}", event, e);
+            }
+        }
+    }
+
+    fn with_gossip(&self, f: impl FnOnce(&mut Gossip, Instant)) {
+        if let Some(state) = self.gossip.lock().unwrap().as_mut() {
+            f(&mut state.gossip, Instant::now());
+            state.wake();
+        }
+    }
+
+    // Hands new broadcasts to the callback and returns the frames to pass
+    // on, each with the neighbor it goes to
+    fn gossip_frame(&self, name: &str, frame: &Frame) -> Vec<(NodeId, Frame)> {
+        let neighbor = self.overlay_id(name);
+        let (message, frames) = {
+            let mut gossip = self.gossip.lock().unwrap();
+            let Some(state) = gossip.as_mut() else {
+                return Vec::new();
+            };
+            let now = Instant::now();
+            let message = match state.gossip.on_frame(&neighbor, frame, now) {
+                Ok(Some(message)) => Some((state.on_message.clone(), message)),
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("Bad gossip frame from {}: {}", name, e);
+                    None
+                }
+            };
+            state.wake();
+            (message, state.gossip.poll(now))
+        };
+        if let Some((cb, message)) = message {
+            cb(&message);
+        }
+        frames
+    }
+
+    fn with_mesh(&self, f: impl FnOnce(&mut Mesh, Instant)) {
+        if let Some(state) = self.mesh.lock().unwrap().as_mut() {
+            f(&mut state.mesh, Instant::now());
+            state.emit();
+            state.wake();
+        }
+    }
+
+    // Hands mesh messages addressed to us to the callback and returns the
+    // frames to pass on, each with the neighbor it goes to
+    fn mesh_frame(&self, name: &str, frame: &Frame) -> Vec<(NodeId, Frame)> {
+        let neighbor = self.overlay_id(name);
+        let (message, frames) = {
+            let mut mesh = self.mesh.lock().unwrap();
+            let Some(state) = mesh.as_mut() else {
+                return Vec::new();
+            };
+            let now = Instant::now();
+            let message = match state.mesh.on_frame(&neighbor, frame, now) {
+                Ok(Some(message)) => Some((state.on_message.clone(), message)),
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("Bad mesh frame from {}: {}", name, e);
+                    None
+                }
+            };
+            let frames = state.mesh.poll(now);
+            state.emit();
+            (message, frames)
+        };
+        if let Some((cb, (source, payload))) = message {
+            cb(&source, &payload);
+        }
+        frames
+    }
+
+    fn flush_outbox(&self, name: &str) -> Vec<Frame> {
+        let mut acked = self.acked.lock().unwrap();
+        let frames = acked.flush_outbox(name);
+        acked.wake();
+        frames
+    }
+
+    // Returns the payload to deliver, if any, and the frames (acks) to send
+    // back to the peer right away.
+    fn acked_frame(&self, name: &str, frame: &Frame) -> (Option<Vec<u8>>, Vec<Frame>) {
+        let mut deferred = Deferred::default();
+        let result = {
+            let mut acked = self.acked.lock().unwrap();
+            let now = Instant::now();
+            let channel = acked.channel(name);
+            let payload = channel.on_frame(frame, now).unwrap_or_else(|e| {
+                warn!("Bad acked frame from {}: {}", name, e);
+                None
+            });
+            let frames = channel.poll_transmit(now);
+            acked.emit(name, &mut deferred);
+            acked.wake();
+            (payload, frames)
+        };
+        deferred.run();
+        result
+    }
+
+    // The peer is gone, whether it dropped, said goodbye or was kicked
+    fn peer_disconnected(&self, name: &str) {
+        let mut deferred = Deferred::default();
+        {
+            let mut acked = self.acked.lock().unwrap();
+            if let Some(channel) = acked.channels.get_mut(name) {
+                channel.fail_all();
+            }
+            acked.emit(name, &mut deferred);
+            acked.channels.remove(name);
+        }
+        deferred.run();
+
+        if let Some(queue) = self.queue.lock().unwrap().as_ref() {
+            let dropped = queue.remove_peer(&name.to_string());
+            if dropped > 0 {
+                debug!("Dropped {} queued messages for {}", dropped, name);
+            }
+        }
+    }
+
+    fn reconnect_later(&self, name: &str) {
+        let still_found = self.found.lock().unwrap().contains_key(name);
+        self.with_reconnect(|supervisor, now| {
+            let name = name.to_string();
+            supervisor.on_disconnected(&name, now);
+            if still_found {
+                supervisor.on_found(&name, now);
+            }
+        });
+    }
+}
+
+fn run_reconnect(
+    tracker: Arc<PeerTracker>,
+    browser: ThreadSafe<Retained<MCNearbyServiceBrowser>>,
+    context: Vec<u8>,
+    wake: mpsc::Receiver<()>,
+) {
+    let browser = &browser.0;
+    let _enter = tracker.span.enter();
+
+    loop {
+        let timeout = match tracker.reconnect.lock().unwrap().as_ref() {
+            Some(state) => state
+                .supervisor
+                .next_deadline()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+                .unwrap_or(RECONNECT_IDLE_TICK)
+                .min(RECONNECT_IDLE_TICK),
+            None => break,
+        };
+        if let Err(mpsc::RecvTimeoutError::Disconnected) = wake.recv_timeout(timeout) {
+            break;
+        }
+
+        let due = match tracker.reconnect.lock().unwrap().as_mut() {
+            Some(state) => state.supervisor.poll(Instant::now()),
+            None => break,
+        };
+
+        // Invite without holding the lock, the delegate needs it for every
+        // state change
+        let mut blocked = Vec::new();
+        let mut missed = Vec::new();
+        for name in due {
+            if !tracker.allows(&name) {
+                debug!("Not re-inviting blocked peer {}", name);
+                blocked.push(name);
+                continue;
+            }
+            let peer = tracker
+                .found
+                .lock()
+                .unwrap()
+                .get(&name)
+                .map(|peer| peer.0.clone());
+            match (peer, unsafe { tracker.session_for(&name) }) {
+                (Some(peer), Some(session)) => unsafe {
+                    let _pool = AutoreleasePool::new();
+                    let _enter = tracker.connect_attempt(&name).entered();
+                    debug!("Re-inviting {}", name);
+                    browser.invitePeer_toSession_withContext_timeout(
+                        &peer,
+                        &session,
+                        Some(&NSData::with_bytes(&context)),
+                        INVITE_TIMEOUT_SECS,
+                    );
+                },
+                // Lost again before we got to it, count it as a failed attempt
+                _ => missed.push(name),
+            }
+        }
+
+        let mut deferred = Deferred::default();
+        match tracker.reconnect.lock().unwrap().as_mut() {
+            Some(state) => {
+                let now = Instant::now();
+                for name in &blocked {
+                    state.supervisor.forget(name);
+                }
+                for name in &missed {
+                    state.supervisor.on_invite_failed(name, now);
+                }
+                state.emit(&mut deferred);
+            }
+            None => break,
+        }
+        deferred.run();
+    }
+
+    debug!("Reconnect thread stopped");
+}
+
+fn run_acked(tracker: Arc<PeerTracker>, wake: mpsc::Receiver<()>) {
+    let _enter = tracker.span.enter();
+    loop {
+        let timeout = {
+            let acked = tracker.acked.lock().unwrap();
+            if acked.wake.is_none() {
+                break;
+            }
+            acked
+                .channels
+                .values()
+                .filter_map(|channel| channel.next_timeout())
+                .min()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+                .unwrap_or(ACKED_IDLE_TICK)
+                .min(ACKED_IDLE_TICK)
+        };
+        if let Err(mpsc::RecvTimeoutError::Disconnected) = wake.recv_timeout(timeout) {
+            break;
+        }
+
+        let mut deferred = Deferred::default();
+        let mut retransmits = Vec::new();
+        {
+            let mut acked = tracker.acked.lock().unwrap();
+            let now = Instant::now();
+            let names: Vec<String> = acked.channels.keys().cloned().collect();
+            for name in names {
+                let frames = acked.channel(&name).poll_transmit(now);
+                acked.emit(&name, &mut deferred);
+                if !frames.is_empty() {
+                    retransmits.push((name, frames));
+                }
+            }
+            acked.expire_outbox(&mut deferred);
+        }
+        deferred.run();
+
+        for (name, frames) in retransmits {
+            unsafe {
+                let _pool = AutoreleasePool::new();
+                match tracker.connected_peer(&name) {
+                    Some((session, peer_id)) => {
+                        if let Err(e) = send_to_peer(&tracker, &session, &peer_id, &frames) {
+                            warn!("Failed to retransmit to {}: {}", name, e);
+                        }
+                    }
+                    None => debug!("Peer {} not connected, holding retransmissions", name),
+                }
+            }
+        }
+    }
+
+    debug!("Acked delivery thread stopped");
+}
+
+fn run_mesh(tracker: Arc<PeerTracker>, wake: mpsc::Receiver<()>) {
+    let _enter = tracker.span.enter();
+    loop {
+        let timeout = match tracker.mesh.lock().unwrap().as_ref() {
+            Some(state) => state
+                .mesh
+                .next_timeout()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+                .unwrap_or(MESH_IDLE_TICK)
+                .min(MESH_IDLE_TICK),
+            None => break,
+        };
+        if let Err(mpsc::RecvTimeoutError::Disconnected) = wake.recv_timeout(timeout) {
+            break;
+        }
+
+        let frames = {
+            let mut mesh = tracker.mesh.lock().unwrap();
+            let Some(state) = mesh.as_mut() else {
+                break;
+            };
+            let frames = state.mesh.poll(Instant::now());
+            state.emit();
+            frames
+        };
+        unsafe { send_to_neighbors(&tracker, frames) };
+    }
+
+    debug!("Mesh thread stopped");
+}
+
+fn run_gossip(tracker: Arc<PeerTracker>, wake: mpsc::Receiver<()>) {
+    let _enter = tracker.span.enter();
+    loop {
+        let timeout = match tracker.gossip.lock().unwrap().as_ref() {
+            Some(state) => state
+                .gossip
+                .next_timeout()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+                .unwrap_or(GOSSIP_IDLE_TICK)
+                .min(GOSSIP_IDLE_TICK),
+            None => break,
+        };
+        if let Err(mpsc::RecvTimeoutError::Disconnected) = wake.recv_timeout(timeout) {
+            break;
+        }
+
+        let frames = match tracker.gossip.lock().unwrap().as_mut() {
+            Some(state) => state.gossip.poll(Instant::now()),
+            None => break,
+        };
+        unsafe { send_to_neighbors(&tracker, frames) };
+    }
+
+    debug!("Gossip thread stopped");
+}
+
+fn run_send_queue(queue: Arc<SendQueue<String>>, tracker: Arc<PeerTracker>) {
+    let _enter = tracker.span.enter();
+    loop {
+        let Some((name, message)) = queue.recv_timeout(SEND_QUEUE_IDLE_TICK) else {
+            if queue.is_closed() && queue.is_empty() {
+                break;
+            }
+            continue;
+        };
+
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            tracker
+                .metrics
+                .lock()
+                .unwrap()
+                .queue_depth(&name, queue.len(&name));
+            let span = debug_span!(
+                parent: &tracker.span_for(&name),
+                "send_queued",
+                peer = %name,
+                priority = ?message.priority,
+                bytes = message.data.len(),
+                reliably = message.reliable,
+                outcome = field::Empty
+            );
+            let _enter = span.enter();
+            let Some((session, peer_id)) = tracker.connected_peer(&name) else {
+                debug!("Peer {} not connected, dropping queued message", name);
+                span.record("outcome", "not connected");
+                continue;
+            };
+
+            let mode = if message.reliable {
+                MCSessionSendDataMode::Reliable
+            } else {
+                MCSessionSendDataMode::Unreliable
+            };
+            let ns_data = NSData::from_vec(message.data);
+            let peer_array = NSArray::from_slice(&[peer_id.as_ref()]);
+            let result = session.sendData_toPeers_withMode_error(&ns_data, &peer_array, mode);
+            tracker.record_send(&name, &ns_data, message.reliable, &result);
+            match result {
+                Ok(()) => span.record("outcome", "ok"),
+                Err(e) => {
+                    warn!("Failed to send queued message to {}: {:?}", name, e);
+                    span.record("outcome", "failed")
+                }
+            };
+        }
+    }
+
+    debug!("Send queue dispatcher stopped");
+}
+
+unsafe fn make_session(
+    peer_id: &MCPeerID,
+    encryption: Option<MCEncryptionPreference>,
+) -> Retained<MCSession> {
+    unsafe {
+        match encryption {
+            Some(preference) => MCSession::initWithPeer_securityIdentity_encryptionPreference(
+                MCSession::alloc(),
+                peer_id,
+                None,
+                preference,
+            ),
+            None => MCSession::initWithPeer(MCSession::alloc(), peer_id),
+        }
+    }
+}
+
+// Sends in unreliable mode, the acked delivery layer takes care of losses
+unsafe fn send_to_peer(
+    tracker: &PeerTracker,
+    session: &MCSession,
+    peer_id: &MCPeerID,
+    frames: &[Frame],
+) -> Result<(), String> {
+    unsafe {
+        let _pool = AutoreleasePool::new();
+        let name = tracker.node_of(peer_id);
+        let peer_array = NSArray::from_slice(&[peer_id]);
+        for frame in frames {
+            let ns_data = NSData::from_vec(tracker.encode_for(&name, frame));
+            let result = session.sendData_toPeers_withMode_error(
+                ns_data.as_ref(),
+                &peer_array,
+                MCSessionSendDataMode::Unreliable,
+            );
+            tracker.record_send(&name, &ns_data, false, &result);
+            result.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+// Frames for the mesh and gossip layers and compression Hellos, each
+// addressed to a connected peer, by its identity for the mesh and gossip
+// layers. Everything but route adverts goes out reliably since these
+// layers have no end-to-end retransmission, route adverts are repeated
+// anyway.
+unsafe fn send_to_neighbors(tracker: &PeerTracker, frames: Vec<(NodeId, Frame)>) {
+    unsafe {
+        let _pool = AutoreleasePool::new();
+        for (neighbor, frame) in frames {
+            let name = tracker.node_for_overlay(&neighbor);
+            let Some((session, peer_id)) = tracker.connected_peer(&name) else {
+                debug!("Neighbor {} not connected, dropping {:?}", name, frame.kind);
+                continue;
+            };
+            let reliably = frame.kind != FrameKind::Route;
+            let mode = if reliably {
+                MCSessionSendDataMode::Reliable
+            } else {
+                MCSessionSendDataMode::Unreliable
+            };
+            let ns_data = NSData::from_vec(tracker.encode_for(&name, &frame));
+            let peer_array = NSArray::from_slice(&[peer_id.as_ref()]);
+            let result = session.sendData_toPeers_withMode_error(&ns_data, &peer_array, mode);
+            tracker.record_send(&name, &ns_data, reliably, &result);
+            if let Err(e) = result {
+                warn!("Failed to send {:?} frame to {}: {:?}", frame.kind, name, e);
+            }
+        }
+    }
+}
+
+// State of the session delegate, see `SessionDelegate`
+pub struct SessionDelegateState {
+    on_data_received: Option<DataCallback>,
+    on_peer_joined: Option<PeerCallback>,
+    on_peer_left: Option<PeerCallback>,
+    // Peers that already said goodbye or were kicked, so their NotConnected
+    // isn't reported twice
+    departed: Mutex<HashSet<String>>,
+    // Peers that reached Connected, the others' NotConnected is a failed
+    // invitation
+    connected: Mutex<HashSet<String>>,
+    tracker: Arc<PeerTracker>,
+}
+
+impl SessionDelegateState {
+    fn new(
+        on_data: Option<DataCallback>,
+        on_joined: Option<PeerCallback>,
+        on_left: Option<PeerCallback>,
+        tracker: Arc<PeerTracker>,
+    ) -> Self {
+        Self {
+            on_data_received: on_data,
+            on_peer_joined: on_joined,
+            on_peer_left: on_left,
+            departed: Mutex::new(HashSet::new()),
+            connected: Mutex::new(HashSet::new()),
+            tracker,
+        }
+    }
+
+    fn peer_left(&self, peer_id: &MCPeerID) {
+        if let Some(cb) = &self.on_peer_left {
+            cb(peer_id);
+        }
+    }
+
+    // MPC can't drop a single peer from a session, we can only leave the
+    // session. The peer is gone for everything above MPC right away and its
+    // traffic ignored from then on, then the session disconnects. Peers
+    // that shared it drop too, and with reconnect on are invited again.
+    unsafe fn kick(&self, session: &MCSession, peer_id: &MCPeerID, name: &str, why: Violation) {
+        if !self.tracker.kicked.lock().unwrap().insert(name.to_string()) {
+            return;
+        }
+        warn!("Disconnecting {}: {}", name, why);
+        let mut layers = self.layers(session, peer_id);
+        dispatch::depart(&mut layers, name, DisconnectReason::Kicked);
+        unsafe {
+            let others = session.connectedPeers().count().saturating_sub(1);
+            if others > 0 {
+                warn!(
+                    "Dropping {} other peers sharing a session with {}",
+                    others, name
+                );
+            }
+            session.disconnect();
+        }
+    }
+
+    fn layers<'a>(&'a self, session: &'a MCSession, peer_id: &'a MCPeerID) -> DelegateLayers<'a> {
+        DelegateLayers {
+            delegate: self,
+            session,
+            peer_id,
+        }
+    }
+}
+
+// The session's protocol layers as seen from one delegate callback
+struct DelegateLayers<'a> {
+    delegate: &'a SessionDelegateState,
+    session: &'a MCSession,
+    peer_id: &'a MCPeerID,
+}
+
+impl PeerLayers for DelegateLayers<'_> {
+    fn deliver(&mut self, peer: &str, payload: Vec<u8>) {
+        if let Some(cb) = &self.delegate.on_data_received {
+            cb(&NSData::with_bytes(&payload), self.peer_id);
+        }
+        self.delegate.tracker.backend_event(|| BackendEvent::Data {
+            peer: peer.to_string(),
+            data: payload,
+        });
+    }
+
+    fn acked(&mut self, peer: &str, frame: &Frame) -> Option<Vec<u8>> {
+        let tracker = &self.delegate.tracker;
+        let (payload, frames) = tracker.acked_frame(peer, frame);
+        if let Err(e) = unsafe { send_to_peer(tracker, self.session, self.peer_id, &frames) } {
+            warn!("Failed to ack {}: {}", peer, e);
+        }
+        payload
+    }
+
+    fn mesh(&mut self, peer: &str, frame: &Frame) {
+        let tracker = &self.delegate.tracker;
+        let frames = tracker.mesh_frame(peer, frame);
+        unsafe { send_to_neighbors(tracker, frames) };
+    }
+
+    fn gossip(&mut self, peer: &str, frame: &Frame) {
+        let tracker = &self.delegate.tracker;
+        let frames = tracker.gossip_frame(peer, frame);
+        unsafe { send_to_neighbors(tracker, frames) };
+    }
+
+    fn secure(&mut self, peer: &str, frame: &Frame) {
+        match callback(&self.delegate.tracker.on_frame) {
+            Some(cb) => cb(peer, frame),
+            None => debug!("Dropping {:?} frame from {}", frame.kind, peer),
+        }
+    }
+
+    fn hello(&mut self, peer: &str, frame: &Frame) {
+        self.delegate.tracker.on_hello(peer, frame);
+    }
+
+    fn neighbor_down(&mut self, peer: &str) {
+        let tracker = &self.delegate.tracker;
+        let neighbor = tracker.overlay_id(peer);
+        tracker.with_mesh(|mesh, now| mesh.neighbor_down(&neighbor, now));
+        tracker.with_gossip(|gossip, _| gossip.neighbor_down(&neighbor));
+    }
+
+    fn mark_departed(&mut self, peer: &str) -> bool {
+        self.delegate
+            .departed
+            .lock()
+            .unwrap()
+            .insert(peer.to_string())
+    }
+
+    fn clear_departed(&mut self, peer: &str) -> bool {
+        self.delegate.departed.lock().unwrap().remove(peer)
+    }
+
+    fn take_connected(&mut self, peer: &str) -> bool {
+        self.delegate.connected.lock().unwrap().remove(peer)
+    }
+
+    fn invite_failed(&mut self, peer: &str) {
+        let tracker = &self.delegate.tracker;
+        // Counted as a failed attempt, not a disconnect
+        tracker.connection_closed(peer, DisconnectReason::Dropped);
+        tracker
+            .with_reconnect(|supervisor, now| supervisor.on_invite_failed(&peer.to_string(), now));
+    }
+
+    fn release(&mut self, peer: &str, reason: DisconnectReason) {
+        let tracker = &self.delegate.tracker;
+        tracker.connection_closed(peer, reason);
+        tracker.peer_disconnected(peer);
+    }
+
+    fn reconnect_later(&mut self, peer: &str) {
+        self.delegate.tracker.reconnect_later(peer);
+    }
+
+    fn forget(&mut self, peer: &str) {
+        self.delegate
+            .tracker
+            .with_reconnect(|supervisor, _| supervisor.forget(&peer.to_string()));
+    }
+
+    fn left(&mut self, peer: &str) {
+        self.delegate.peer_left(self.peer_id);
+        self.delegate.tracker.backend_event(|| BackendEvent::Left {
+            peer: peer.to_string(),
+        });
+    }
+}
+
+// What the delegate methods do, `SessionDelegate` forwards them here
+impl SessionDelegateState {
+    unsafe fn peer_changed_state(
+        &self,
+        session: &MCSession,
+        peer_id: &MCPeerID,
+        state: MCSessionState,
+    ) {
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            let name = self.tracker.node_of(peer_id);
+            match state {
+                MCSessionState::Connected if self.tracker.is_kicked(&name) => {
+                    debug!("Ignoring kicked peer {} connecting", name);
+                }
+                MCSessionState::Connected => {
+                    let _enter = self.tracker.connection_span(&name).entered();
+                    debug!("Peer {} connected", name);
+                    self.departed.lock().unwrap().remove(&name);
+                    self.connected.lock().unwrap().insert(name.clone());
+                    self.tracker.peer_connected(&name);
+                    if let Some(cb) = &self.on_peer_joined {
+                        unsafe { cb(peer_id) };
+                    }
+                    self.tracker
+                        .backend_event(|| BackendEvent::Joined { peer: name.clone() });
+                    if let Some(hello) = self.tracker.hello_for(&name) {
+                        unsafe { send_to_neighbors(&self.tracker, vec![(name.clone(), hello)]) };
+                    }
+
+                    let frames = self.tracker.flush_outbox(&name);
+                    if let Err(e) =
+                        unsafe { send_to_peer(&self.tracker, session, peer_id, &frames) }
+                    {
+                        warn!("Failed to send outbox messages to {}: {}", name, e);
+                    }
+                }
+                MCSessionState::NotConnected
+                    if self.tracker.kicked.lock().unwrap().remove(&name) =>
+                {
+                    // Departed when it was kicked
+                    self.connected.lock().unwrap().remove(&name);
+                    self.tracker.release_slot(&name);
+                    if !self.tracker.found.lock().unwrap().contains_key(&name) {
+                        self.tracker.forget_peer(&name);
+                    }
+                }
+                MCSessionState::NotConnected => {
+                    let _enter = self.tracker.span_for(&name).entered();
+                    self.tracker.release_slot(&name);
+                    let mut layers = self.layers(session, peer_id);
+                    dispatch::on_disconnected(&mut layers, &name);
+                    if !self.tracker.found.lock().unwrap().contains_key(&name) {
+                        self.tracker.forget_peer(&name);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    unsafe fn received_data(&self, session: &MCSession, data: &NSData, peer_id: &MCPeerID) {
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            let name = self.tracker.node_of(peer_id);
+            if self.tracker.is_kicked(&name) {
+                trace!("Ignoring {} bytes from kicked peer {}", data.len(), name);
+                return;
+            }
+            // Covers the data callback too, so the application's handling
+            // of the message shows up under it
+            let span = debug_span!(
+                parent: &self.tracker.span_for(&name),
+                "recv",
+                peer = %name,
+                bytes = data.len(),
+                kind = field::Empty
+            );
+            let _enter = span.enter();
+            self.tracker
+                .metrics
+                .lock()
+                .unwrap()
+                .received(&name, data.len());
+            let bytes = data.to_vec();
+            self.tracker.capture(&name, || CaptureEvent::Received {
+                frame: bytes.clone(),
+            });
+            // Peers that aren't let in at all aren't worth decoding
+            if let Err(why) = self.tracker.inbound_admits(&name) {
+                unsafe { self.kick(session, peer_id, &name, why) };
+                return;
+            }
+            let frame = match Frame::decode(&bytes).and_then(|frame| self.tracker.decompress(frame))
+            {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("Dropping frame from {}: {}", name, e);
+                    if let Verdict::Disconnect(why) = self.tracker.inbound_malformed(&name) {
+                        unsafe { self.kick(session, peer_id, &name, why) };
+                    }
+                    return;
+                }
+            };
+            span.record("kind", field::debug(&frame.kind));
+            // Byte rates count what the frame decompressed to
+            match self.tracker.inbound_message(&name, frame.payload.len()) {
+                Verdict::Accept => {}
+                Verdict::Drop(why) => {
+                    debug!("Dropping frame from {}: {}", name, why);
+                    return;
+                }
+                Verdict::Disconnect(why) => {
+                    unsafe { self.kick(session, peer_id, &name, why) };
+                    return;
+                }
+            }
+            // With the secure channel on, anybody could have sent a plaintext
+            // goodbye. The one that counts comes encrypted, or the link drops.
+            let secure = self.tracker.secure.load(Ordering::Relaxed);
+            if secure && frame.kind == FrameKind::Goodbye {
+                warn!("Ignoring unencrypted goodbye from {}", name);
+                return;
+            }
+
+            let mut layers = self.layers(session, peer_id);
+            dispatch::on_frame(&mut layers, &name, frame);
+        }
+    }
+
+    // Handed to the application as a `BackendEvent::Stream`, nobody else
+    // takes streams. A stream counts as one message for the inbound policy.
+    unsafe fn received_stream(
+        &self,
+        session: &MCSession,
+        stream: &NSInputStream,
+        stream_name: &NSString,
+        peer_id: &MCPeerID,
+    ) {
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            let name = self.tracker.node_of(peer_id);
+            let stream_name = stream_name.to_string();
+            if self.tracker.is_kicked(&name) {
+                debug!("Ignoring stream {} from kicked peer {}", stream_name, name);
+                stream.close();
+                return;
+            }
+            let _enter = self.tracker.span_for(&name).entered();
+            match self.tracker.inbound_message(&name, 0) {
+                Verdict::Accept => {}
+                Verdict::Drop(why) => {
+                    debug!("Dropping stream {} from {}: {}", stream_name, name, why);
+                    stream.close();
+                    return;
+                }
+                Verdict::Disconnect(why) => {
+                    stream.close();
+                    unsafe { self.kick(session, peer_id, &name, why) };
+                    return;
+                }
+            }
+            // Returns right away, the application's first read waits for
+            // the stream to open
+            debug!("Stream {} from {}", stream_name, name);
+            let stream = IncomingStream::new(stream.retain());
+            self.tracker.backend_event(|| BackendEvent::Stream {
+                peer: name,
+                name: stream_name,
+                stream: Box::new(stream),
+            });
+        }
+    }
+}
+
+pub struct BrowserDelegateState {
+    tracker: Arc<PeerTracker>,
+}
+
+impl BrowserDelegateState {
+    unsafe fn found_peer(
+        &self,
+        _browser: &MCNearbyServiceBrowser,
+        peer_id: &MCPeerID,
+        info: Option<&NSDictionary<NSString, NSString>>,
+    ) {
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            let name = self.tracker.node_of(peer_id);
+            let _enter = self.tracker.span.enter();
+            debug!("Found peer {}", name);
+
+            let mut discovery_info = DiscoveryInfo::new();
+            if let Some(info) = info {
+                let (keys, values) = info.to_vecs();
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    if let Err(e) = discovery_info.insert(&key.to_string(), &value.to_string()) {
+                        debug!("Ignoring discovery info from {}: {}", name, e);
+                    }
+                }
+            }
+            self.tracker.peer_found(name, peer_id, &discovery_info);
+        }
+    }
+
+    unsafe fn lost_peer(&self, _browser: &MCNearbyServiceBrowser, peer_id: &MCPeerID) {
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            let name = self.tracker.node_of(peer_id);
+            let _enter = self.tracker.span.enter();
+            debug!("Lost peer {}", name);
+            self.tracker.peer_lost(&name);
+        }
+    }
+}
+
+// Accepts invitations into whichever session shard has room, unless the
+// address book has the peer blocked
+pub struct AdvertiserDelegateState {
+    tracker: Arc<PeerTracker>,
+}
+
+impl AdvertiserDelegateState {
+    unsafe fn received_invitation(
+        &self,
+        _advertiser: &MCNearbyServiceAdvertiser,
+        peer_id: &MCPeerID,
+        context: Option<&NSData>,
+        invitation_handler: &Block<dyn Fn(Bool, *mut MCSession)>,
+    ) {
+        unsafe {
+            let _pool = AutoreleasePool::new();
+            let name = self.tracker.node_of(peer_id);
+            let _enter = self.tracker.span.enter();
+            let context = context.map(|context| context.to_vec());
+            if let Some(info) = context
+                .as_deref()
+                .and_then(|context| DiscoveryInfo::decode(context).ok())
+            {
+                self.tracker.learn_peer(&name, &info);
+            }
+            if !self.tracker.allows(&name) {
+                debug!("Declining invitation from blocked peer {}", name);
+                invitation_handler.call((Bool::NO, ptr::null_mut()));
+                return;
+            }
+            match self.tracker.session_for(&name) {
+                Some(session) => {
+                    let _enter = self.tracker.connect_attempt(&name).entered();
+                    debug!("Accepting invitation from {}", name);
+                    invitation_handler.call((Bool::YES, Retained::as_ptr(&session) as *mut _));
+                    if context.as_deref().is_some_and(is_pairing_intent)
+                        && let Some(cb) = callback(&self.tracker.on_pairing_request)
+                    {
+                        cb(&name);
+                    }
+                }
+                None => {
+                    debug!(
+                        "Declining invitation from {}, session is shutting down",
+                        name
+                    );
+                    invitation_handler.call((Bool::NO, ptr::null_mut()));
+                }
+            }
+        }
+    }
+}
+
+// The delegates are Objective-C objects that MPC retains, declared like
+// the one in `transport`. Their methods forward to the state they hold.
+define_class!(
+    #[unsafe(super(NSObject))]
+    #[name = "IrohMpcSessionDelegate"]
+    #[ivars = SessionDelegateState]
+    pub struct SessionDelegate;
+
+    unsafe impl NSObjectProtocol for SessionDelegate {}
+
+    unsafe impl MCSessionDelegate for SessionDelegate {
+        #[unsafe(method(session:peer:didChangeState:))]
+        fn peer_changed_state(
+            &self,
+            session: &MCSession,
+            peer_id: &MCPeerID,
+            state: MCSessionState,
+        ) {
+            unsafe { self.ivars().peer_changed_state(session, peer_id, state) }
+        }
+
+        #[unsafe(method(session:didReceiveData:fromPeer:))]
+        fn received_data(&self, session: &MCSession, data: &NSData, peer_id: &MCPeerID) {
+            unsafe { self.ivars().received_data(session, data, peer_id) }
+        }
+
+        #[unsafe(method(session:didReceiveStream:withName:fromPeer:))]
+        fn received_stream(
+            &self,
+            session: &MCSession,
+            stream: &NSInputStream,
+            stream_name: &NSString,
+            peer_id: &MCPeerID,
+        ) {
+            unsafe {
+                self.ivars()
+                    .received_stream(session, stream, stream_name, peer_id)
+            }
+        }
+
+        // Resources aren't used
+        #[unsafe(method(session:didStartReceivingResourceWithName:fromPeer:withProgress:))]
+        fn started_receiving_resource(
+            &self,
+            _session: &MCSession,
+            _resource_name: &NSString,
+            _peer_id: &MCPeerID,
+            _progress: &NSProgress,
+        ) {
+        }
+
+        #[unsafe(method(session:didFinishReceivingResourceWithName:fromPeer:atURL:withError:))]
+        fn finished_receiving_resource(
+            &self,
+            _session: &MCSession,
+            _resource_name: &NSString,
+            _peer_id: &MCPeerID,
+            _location_url: Option<&NSURL>,
+            _error: Option<&NSError>,
+        ) {
+        }
+    }
+);
+
+define_class!(
+    #[unsafe(super(NSObject))]
+    #[name = "IrohMpcBrowserDelegate"]
+    #[ivars = BrowserDelegateState]
+    pub struct BrowserDelegate;
+
+    unsafe impl NSObjectProtocol for BrowserDelegate {}
+
+    unsafe impl MCNearbyServiceBrowserDelegate for BrowserDelegate {
+        #[unsafe(method(browser:foundPeer:withDiscoveryInfo:))]
+        fn found_peer(
+            &self,
+            browser: &MCNearbyServiceBrowser,
+            peer_id: &MCPeerID,
+            info: Option<&NSDictionary<NSString, NSString>>,
+        ) {
+            unsafe { self.ivars().found_peer(browser, peer_id, info) }
+        }
+
+        #[unsafe(method(browser:lostPeer:))]
+        fn lost_peer(&self, browser: &MCNearbyServiceBrowser, peer_id: &MCPeerID) {
+            unsafe { self.ivars().lost_peer(browser, peer_id) }
+        }
+    }
+);
+
+define_class!(
+    #[unsafe(super(NSObject))]
+    #[name = "IrohMpcAdvertiserDelegate"]
+    #[ivars = AdvertiserDelegateState]
+    pub struct AdvertiserDelegate;
+
+    unsafe impl NSObjectProtocol for AdvertiserDelegate {}
+
+    unsafe impl MCNearbyServiceAdvertiserDelegate for AdvertiserDelegate {
+        #[unsafe(method(advertiser:didReceiveInvitationFromPeer:withContext:invitationHandler:))]
+        fn received_invitation(
+            &self,
+            advertiser: &MCNearbyServiceAdvertiser,
+            peer_id: &MCPeerID,
+            context: Option<&NSData>,
+            invitation_handler: &Block<dyn Fn(Bool, *mut MCSession)>,
+        ) {
+            unsafe {
+                self.ivars()
+                    .received_invitation(advertiser, peer_id, context, invitation_handler)
+            }
+        }
+    }
+);
+
+impl SessionDelegate {
+    fn new(state: SessionDelegateState) -> Retained<Self> {
+        let this = Self::alloc().set_ivars(state);
+        unsafe { msg_send![super(this), init] }
+    }
+}
+
+impl BrowserDelegate {
+    fn new(tracker: Arc<PeerTracker>) -> Retained<Self> {
+        let this = Self::alloc().set_ivars(BrowserDelegateState { tracker });
+        unsafe { msg_send![super(this), init] }
+    }
+}
+
+impl AdvertiserDelegate {
+    fn new(tracker: Arc<PeerTracker>) -> Retained<Self> {
+        let this = Self::alloc().set_ivars(AdvertiserDelegateState { tracker });
+        unsafe { msg_send![super(this), init] }
+    }
 }
 
 struct AutoreleasePool {
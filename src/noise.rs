@@ -0,0 +1,1398 @@
+// Noise encrypted channel over any backend's data channel.
+//
+// MPC's own encryption is all or nothing and we can't see what it does.
+// This runs a Noise handshake with each peer as soon as it connects and
+// encrypts every frame sent to it afterwards, giving forward secrecy and
+// binding the channel to the peer's static key whatever the transport.
+//
+// XX is used when we don't know the peer's static key, IK when we do
+// (pinned, or learned from an earlier handshake) and the config asks for
+// it. A responder that can't complete an IK handshake, because we had a
+// stale key for it, answers with a restart and we start over with XX.
+// The peer with the smaller name initiates, like invitations do. Peers with
+// the same name both initiate, and the one whose first message carries the
+// smaller ephemeral key keeps the initiator role. The prologue carries both
+// names, so a handshake relayed to a peer under a different name fails.
+//
+// Like the reliable channel this is a pure state machine: feed it frames
+// and the current time, send whatever `poll_transmit` returns. Handshake
+// frames should go out reliably. Transport messages carry their nonce, so
+// secure frames survive unreliable sends and reordering; a sliding window
+// drops replays.
+//
+// Handshake frame: flags say which message it is, payload is the Noise
+// message. Secure frame payload: the encoded inner frame, split into chunks
+// of at most 65515 bytes, each encrypted behind its index and the number of
+// chunks,
+//   chunks * [nonce: u64][len: u16][ciphertext of [index: u16][count: u16][chunk]]
+// The chunks of a frame take consecutive nonces. A frame is only decoded
+// when every chunk is there, in order, so chunks can't be dropped or spliced
+// in from other frames.
+//
+// Compression has to happen before encryption, ciphertext doesn't compress.
+// Frames are compressed with `Backend::compress_for` of the wrapped backend
+// before they are sealed, and decompressed once opened.
+//
+// The static key is an X25519 key of its own, in a key file next to the
+// address book. Nothing here uses iroh, so there is no iroh node key to
+// reuse; if there ever is one, its Ed25519 secret converts to an X25519 one
+// and `Keypair::from_private` takes it.
+//
+// Key file format: [magic "IDPK"][version: u8][private key: 32 bytes]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+use crate::backend::{Backend, BackendEvent, Stream};
+use crate::frame::{Frame, FrameKind};
+use crate::mesh::NodeId;
+
+const XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"iroh-discovery-playground noise 3";
+
+/// Handshake frame flag: first message of a handshake
+pub const FLAG_INIT: u8 = 0x01;
+/// Handshake frame flag: the message belongs to an IK handshake
+pub const FLAG_IK: u8 = 0x02;
+/// Handshake frame flag: the responder gave up on an IK handshake, start
+/// over with XX
+pub const FLAG_RESTART: u8 = 0x04;
+
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+const CHUNK_HEADER: usize = 4;
+const MAX_CHUNK: usize = MAX_MESSAGE - TAG_LEN - CHUNK_HEADER;
+const REPLAY_WINDOW: u64 = 64;
+
+const KEY_MAGIC: &[u8; 4] = b"IDPK";
+const KEY_VERSION: u8 = 1;
+
+pub type PublicKey = [u8; 32];
+
+/// Hex form of a public key, for logs and the command line
+pub fn key_to_hex(key: &PublicKey) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn key_from_hex(hex: &str) -> Result<PublicKey, String> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("Expected 64 hex digits, got {:?}", hex));
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("Expected 64 hex digits, got {:?}", hex))?;
+    }
+    Ok(key)
+}
+
+/// Static X25519 key pair identifying this device
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keypair {
+    private: [u8; 32],
+    public: PublicKey,
+}
+
+// Manual Debug implementation so the private key never ends up in logs
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &key_to_hex(&self.public))
+            .finish()
+    }
+}
+
+impl Keypair {
+    pub fn generate() -> Result<Self, String> {
+        let keypair = Builder::new(params(XX))
+            .generate_keypair()
+            .map_err(|e| format!("Failed to generate key: {}", e))?;
+        let mut private = [0u8; 32];
+        private.copy_from_slice(&keypair.private);
+        Ok(Self::from_private(private))
+    }
+
+    pub fn from_private(private: [u8; 32]) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("Curve25519 is always available");
+        dh.set(&private);
+        let mut public = [0u8; 32];
+        public.copy_from_slice(dh.pubkey());
+        Self { private, public }
+    }
+
+    /// Key pair stored at `path`, or a new one saved there if there is none
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        match fs::read(path) {
+            Ok(bytes) => {
+                if bytes.len() != 37 || &bytes[..4] != KEY_MAGIC {
+                    return Err(format!("{} is not a key file", path.display()));
+                }
+                if bytes[4] != KEY_VERSION {
+                    return Err(format!("Unsupported key file version {}", bytes[4]));
+                }
+                let mut private = [0u8; 32];
+                private.copy_from_slice(&bytes[5..]);
+                Ok(Self::from_private(private))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keypair = Self::generate()?;
+                keypair.save(path)?;
+                Ok(keypair)
+            }
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Write the key pair to `path`, readable by the owner only
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut bytes = Vec::with_capacity(37);
+        bytes.extend_from_slice(KEY_MAGIC);
+        bytes.push(KEY_VERSION);
+        bytes.extend_from_slice(&self.private);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(&bytes))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+}
+
+fn params(pattern: &str) -> snow::params::NoiseParams {
+    pattern.parse().expect("Noise pattern names are valid")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakePattern {
+    /// Both sides send their static key, one round trip and a half
+    Xx,
+    /// One round trip when the responder's key is known, XX otherwise
+    Ik,
+}
+
+#[derive(Debug, Clone)]
+pub struct NoiseConfig {
+    pub pattern: HandshakePattern,
+    /// Handshakes not finished by then are reported as failed
+    pub handshake_timeout: Duration,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self {
+            pattern: HandshakePattern::Ik,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoiseEvent {
+    /// Frames to and from the peer are encrypted from now on
+    Established { peer: NodeId, remote_key: PublicKey },
+    /// The handshake timed out or the peer couldn't prove its identity
+    Failed { peer: NodeId, reason: String },
+}
+
+enum Channel {
+    Initiating {
+        handshake: Box<HandshakeState>,
+        ik: bool,
+        // Our ephemeral key, to settle simultaneous initiations
+        ephemeral: Vec<u8>,
+        deadline: Instant,
+    },
+    Responding {
+        handshake: Box<HandshakeState>,
+        deadline: Instant,
+    },
+    Established(Box<Established>),
+}
+
+struct Established {
+    transport: StatelessTransportState,
+    remote_key: PublicKey,
+    handshake_hash: Vec<u8>,
+    next_nonce: u64,
+    replay: ReplayWindow,
+}
+
+// Nonces seen recently: the highest one and a bitmap of the ones below it
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, nonce: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if nonce > highest => true,
+            Some(highest) => {
+                let age = highest - nonce;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        match self.highest {
+            Some(highest) if nonce <= highest => self.seen |= 1 << (highest - nonce),
+            Some(highest) => {
+                let shift = nonce - highest;
+                self.seen = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.seen << shift
+                };
+                self.seen |= 1;
+                self.highest = Some(nonce);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(nonce);
+            }
+        }
+    }
+}
+
+struct KnownKey {
+    key: PublicKey,
+    // Pinned keys are required, learned ones only used for IK
+    pinned: bool,
+}
+
+pub struct SecureChannels {
+    local: NodeId,
+    keypair: Keypair,
+    config: NoiseConfig,
+    channels: HashMap<NodeId, Channel>,
+    known: HashMap<NodeId, KnownKey>,
+    outgoing: Vec<(NodeId, Frame)>,
+    events: Vec<NoiseEvent>,
+}
+
+// Manual Debug implementation since handshake states aren't Debug
+impl fmt::Debug for SecureChannels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecureChannels")
+            .field("local", &self.local)
+            .field("keypair", &self.keypair)
+            .field("channels", &self.channels.len())
+            .field("known", &self.known.len())
+            .finish()
+    }
+}
+
+impl SecureChannels {
+    pub fn new(local: &str, keypair: Keypair, config: NoiseConfig) -> Self {
+        Self {
+            local: local.to_string(),
+            keypair,
+            config,
+            channels: HashMap::new(),
+            known: HashMap::new(),
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &NoiseConfig {
+        &self.config
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public
+    }
+
+    /// Only accept `peer` if it proves it holds `key`
+    pub fn pin(&mut self, peer: &str, key: PublicKey) {
+        self.known
+            .insert(peer.to_string(), KnownKey { key, pinned: true });
+    }
+
+    /// The peer connected. Starts the handshake if it is ours to start.
+    pub fn peer_up(&mut self, peer: &str, now: Instant) -> Result<(), String> {
+        self.channels.remove(peer);
+        if self.local.as_str() <= peer {
+            self.initiate(peer, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn peer_down(&mut self, peer: &str) {
+        self.channels.remove(peer);
+    }
+
+    pub fn is_established(&self, peer: &str) -> bool {
+        matches!(self.channels.get(peer), Some(Channel::Established(_)))
+    }
+
+    pub fn established_peers(&self) -> Vec<NodeId> {
+        self.channels
+            .iter()
+            .filter(|(_, channel)| matches!(channel, Channel::Established(_)))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    /// Static key the peer proved it holds
+    pub fn remote_key(&self, peer: &str) -> Option<PublicKey> {
+        match self.channels.get(peer)? {
+            Channel::Established(channel) => Some(channel.remote_key),
+            _ => None,
+        }
+    }
+
+    /// Hash of the whole handshake transcript, the same on both sides
+    pub fn handshake_hash(&self, peer: &str) -> Option<&[u8]> {
+        match self.channels.get(peer)? {
+            Channel::Established(channel) => Some(&channel.handshake_hash),
+            _ => None,
+        }
+    }
+
+    /// Handle a frame from `peer`. Handshake frames are consumed, secure
+    /// frames return the frame they carry. Anything else is refused, the
+    /// peer should have encrypted it.
+    pub fn on_frame(
+        &mut self,
+        peer: &str,
+        frame: &Frame,
+        now: Instant,
+    ) -> Result<Option<Frame>, String> {
+        match frame.kind {
+            FrameKind::Handshake => {
+                self.on_handshake(peer, frame, now)?;
+                Ok(None)
+            }
+            FrameKind::Secure => self.open(peer, &frame.payload).map(Some),
+            kind => Err(format!("Unencrypted {:?} frame from {}", kind, peer)),
+        }
+    }
+
+    /// Encrypt `frame` for `peer`
+    pub fn seal(&mut self, peer: &str, frame: &Frame) -> Result<Frame, String> {
+        let Some(Channel::Established(channel)) = self.channels.get_mut(peer) else {
+            return Err(format!("No secure channel to {}", peer));
+        };
+        let plaintext = frame.encode();
+        let count = u16::try_from(plaintext.len().div_ceil(MAX_CHUNK))
+            .map_err(|_| format!("Frame of {} bytes is too large to encrypt", plaintext.len()))?;
+        let mut payload = Vec::with_capacity(plaintext.len() + 32);
+        let mut message = Vec::with_capacity(MAX_MESSAGE);
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        for (index, chunk) in plaintext.chunks(MAX_CHUNK).enumerate() {
+            message.clear();
+            message.extend_from_slice(&(index as u16).to_be_bytes());
+            message.extend_from_slice(&count.to_be_bytes());
+            message.extend_from_slice(chunk);
+            let nonce = channel.next_nonce;
+            channel.next_nonce += 1;
+            let len = channel
+                .transport
+                .write_message(nonce, &message, &mut buf)
+                .map_err(|e| format!("Failed to encrypt for {}: {}", peer, e))?;
+            payload.extend_from_slice(&nonce.to_be_bytes());
+            payload.extend_from_slice(&(len as u16).to_be_bytes());
+            payload.extend_from_slice(&buf[..len]);
+        }
+        Ok(Frame::new(FrameKind::Secure, payload))
+    }
+
+    /// Handshake frames to send, reliably, each with the peer it goes to
+    pub fn poll_transmit(&mut self) -> Vec<(NodeId, Frame)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Fail handshakes that ran out of time
+    pub fn poll(&mut self, now: Instant) {
+        let expired: Vec<NodeId> = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| match channel {
+                Channel::Initiating { deadline, .. } | Channel::Responding { deadline, .. } => {
+                    *deadline <= now
+                }
+                Channel::Established(_) => false,
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in expired {
+            self.fail(&peer, "Handshake timed out".to_string());
+        }
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.channels
+            .values()
+            .filter_map(|channel| match channel {
+                Channel::Initiating { deadline, .. } | Channel::Responding { deadline, .. } => {
+                    Some(*deadline)
+                }
+                Channel::Established(_) => None,
+            })
+            .min()
+    }
+
+    pub fn drain_events(&mut self) -> Vec<NoiseEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn prologue(&self, initiator: &str, responder: &str) -> Result<Vec<u8>, String> {
+        let mut prologue = PROLOGUE.to_vec();
+        for name in [initiator, responder] {
+            let len = u16::try_from(name.len())
+                .map_err(|_| format!("Name of {} bytes is too long for a handshake", name.len()))?;
+            prologue.extend_from_slice(&len.to_be_bytes());
+            prologue.extend_from_slice(name.as_bytes());
+        }
+        Ok(prologue)
+    }
+
+    fn initiate(&mut self, peer: &str, now: Instant) -> Result<(), String> {
+        let remote_key = match self.config.pattern {
+            HandshakePattern::Ik => self.known.get(peer).map(|known| known.key),
+            HandshakePattern::Xx => None,
+        };
+        let prologue = self.prologue(&self.local, peer)?;
+        let builder = Builder::new(params(if remote_key.is_some() { IK } else { XX }))
+            .local_private_key(&self.keypair.private)
+            .prologue(&prologue);
+        let builder = match &remote_key {
+            Some(key) => builder.remote_public_key(key),
+            None => builder,
+        };
+        let mut handshake = builder
+            .build_initiator()
+            .map_err(|e| format!("Failed to start handshake with {}: {}", peer, e))?;
+
+        let message = write_handshake(&mut handshake, peer)?;
+        let flags = if remote_key.is_some() {
+            FLAG_INIT | FLAG_IK
+        } else {
+            FLAG_INIT
+        };
+        // Both patterns open with the ephemeral key
+        let ephemeral = message[..32].to_vec();
+        self.send_handshake(peer, flags, message);
+        self.channels.insert(
+            peer.to_string(),
+            Channel::Initiating {
+                handshake: Box::new(handshake),
+                ik: remote_key.is_some(),
+                ephemeral,
+                deadline: now + self.config.handshake_timeout,
+            },
+        );
+        Ok(())
+    }
+
+    fn on_handshake(&mut self, peer: &str, frame: &Frame, now: Instant) -> Result<(), String> {
+        if frame.flags & FLAG_INIT != 0 {
+            return self.respond(peer, frame, now);
+        }
+
+        if frame.flags & FLAG_RESTART != 0 {
+            let Some(Channel::Initiating { ik: true, .. }) = self.channels.get(peer) else {
+                return Err(format!("Unexpected handshake restart from {}", peer));
+            };
+            if self.known.get(peer).is_some_and(|known| known.pinned) {
+                self.fail(peer, "Peer doesn't hold the pinned key".to_string());
+                return Ok(());
+            }
+            // Our key for it was stale
+            self.known.remove(peer);
+            return self.initiate(peer, now);
+        }
+
+        let Some(channel) = self.channels.remove(peer) else {
+            return Err(format!("Handshake message from {} out of the blue", peer));
+        };
+        let (mut handshake, initiator) = match channel {
+            Channel::Initiating { handshake, .. } => (handshake, true),
+            Channel::Responding { handshake, .. } => (handshake, false),
+            established @ Channel::Established(_) => {
+                self.channels.insert(peer.to_string(), established);
+                return Err(format!(
+                    "Handshake message from {} after the handshake",
+                    peer
+                ));
+            }
+        };
+
+        if let Err(e) = read_handshake(&mut handshake, peer, &frame.payload) {
+            self.fail(peer, e);
+            return Ok(());
+        }
+        // The initiator's last XX message
+        if initiator && !handshake.is_handshake_finished() {
+            let message = write_handshake(&mut handshake, peer)?;
+            self.send_handshake(peer, 0, message);
+        }
+        if handshake.is_handshake_finished() {
+            self.finish(peer, *handshake)
+        } else {
+            self.channels.insert(
+                peer.to_string(),
+                Channel::Responding {
+                    handshake,
+                    deadline: now + self.config.handshake_timeout,
+                },
+            );
+            Ok(())
+        }
+    }
+
+    fn respond(&mut self, peer: &str, frame: &Frame, now: Instant) -> Result<(), String> {
+        if self.local.as_str() < peer {
+            return Err(format!(
+                "{} started a handshake that is ours to start",
+                peer
+            ));
+        }
+        // Same name, both of us started. The smaller ephemeral key wins.
+        if let Some(Channel::Initiating { ephemeral, .. }) = self.channels.get(peer)
+            && self.local == peer
+            && ephemeral.as_slice() < frame.payload.get(..32).unwrap_or_default()
+        {
+            return Ok(());
+        }
+        // Whatever we had with the peer is over, it started again
+        self.channels.remove(peer);
+
+        let ik = frame.flags & FLAG_IK != 0;
+        let prologue = self.prologue(peer, &self.local)?;
+        let mut handshake = Builder::new(params(if ik { IK } else { XX }))
+            .local_private_key(&self.keypair.private)
+            .prologue(&prologue)
+            .build_responder()
+            .map_err(|e| format!("Failed to answer handshake from {}: {}", peer, e))?;
+
+        if let Err(e) = read_handshake(&mut handshake, peer, &frame.payload) {
+            if ik {
+                // Most likely it has an old key of ours
+                self.send_handshake(peer, FLAG_RESTART, Vec::new());
+            } else {
+                self.fail(peer, e);
+            }
+            return Ok(());
+        }
+        let message = write_handshake(&mut handshake, peer)?;
+        self.send_handshake(peer, 0, message);
+
+        if handshake.is_handshake_finished() {
+            self.finish(peer, handshake)
+        } else {
+            self.channels.insert(
+                peer.to_string(),
+                Channel::Responding {
+                    handshake: Box::new(handshake),
+                    deadline: now + self.config.handshake_timeout,
+                },
+            );
+            Ok(())
+        }
+    }
+
+    fn finish(&mut self, peer: &str, handshake: HandshakeState) -> Result<(), String> {
+        let mut remote_key = [0u8; 32];
+        match handshake.get_remote_static() {
+            Some(key) if key.len() == 32 => remote_key.copy_from_slice(key),
+            _ => {
+                self.fail(peer, "Peer sent no static key".to_string());
+                return Ok(());
+            }
+        }
+        if let Some(known) = self.known.get(peer)
+            && known.pinned
+            && known.key != remote_key
+        {
+            let reason = format!("Peer presented key {}", key_to_hex(&remote_key));
+            self.fail(peer, reason);
+            return Ok(());
+        }
+
+        let handshake_hash = handshake.get_handshake_hash().to_vec();
+        let transport = handshake
+            .into_stateless_transport_mode()
+            .map_err(|e| format!("Failed to finish handshake with {}: {}", peer, e))?;
+        self.known
+            .entry(peer.to_string())
+            .or_insert(KnownKey {
+                key: remote_key,
+                pinned: false,
+            })
+            .key = remote_key;
+        self.channels.insert(
+            peer.to_string(),
+            Channel::Established(Box::new(Established {
+                transport,
+                remote_key,
+                handshake_hash,
+                next_nonce: 0,
+                replay: ReplayWindow::default(),
+            })),
+        );
+        self.events.push(NoiseEvent::Established {
+            peer: peer.to_string(),
+            remote_key,
+        });
+        Ok(())
+    }
+
+    fn open(&mut self, peer: &str, mut payload: &[u8]) -> Result<Frame, String> {
+        let Some(Channel::Established(channel)) = self.channels.get_mut(peer) else {
+            return Err(format!("Secure frame from {} without a channel", peer));
+        };
+        let mut plaintext = Vec::with_capacity(payload.len());
+        let mut nonces: Vec<u64> = Vec::new();
+        let mut count = None;
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        while !payload.is_empty() {
+            if payload.len() < 10 {
+                return Err(format!("Truncated secure frame from {}", peer));
+            }
+            let nonce = u64::from_be_bytes(payload[..8].try_into().unwrap());
+            let len = u16::from_be_bytes(payload[8..10].try_into().unwrap()) as usize;
+            let Some(ciphertext) = payload.get(10..10 + len) else {
+                return Err(format!("Truncated secure frame from {}", peer));
+            };
+            if !channel.replay.is_fresh(nonce) {
+                return Err(format!("Replayed secure frame from {}", peer));
+            }
+            if let Some(&first) = nonces.first()
+                && Some(nonce) != first.checked_add(nonces.len() as u64)
+            {
+                return Err(format!(
+                    "Secure frame from {} with chunks out of order",
+                    peer
+                ));
+            }
+            let read = channel
+                .transport
+                .read_message(nonce, ciphertext, &mut buf)
+                .map_err(|e| format!("Failed to decrypt frame from {}: {}", peer, e))?;
+            if read < CHUNK_HEADER {
+                return Err(format!("Truncated secure frame from {}", peer));
+            }
+            let index = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+            let total = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if index != nonces.len() || *count.get_or_insert(total) != total {
+                return Err(format!(
+                    "Secure frame from {} with chunks out of order",
+                    peer
+                ));
+            }
+            nonces.push(nonce);
+            plaintext.extend_from_slice(&buf[CHUNK_HEADER..read]);
+            payload = &payload[10 + len..];
+        }
+        if count != Some(nonces.len()) {
+            return Err(format!("Secure frame from {} is missing chunks", peer));
+        }
+        for nonce in nonces {
+            channel.replay.mark(nonce);
+        }
+        let frame = Frame::decode(&plaintext)?;
+        if matches!(frame.kind, FrameKind::Handshake | FrameKind::Secure) {
+            return Err(format!("Nested {:?} frame from {}", frame.kind, peer));
+        }
+        Ok(frame)
+    }
+
+    fn send_handshake(&mut self, peer: &str, flags: u8, message: Vec<u8>) {
+        let mut frame = Frame::new(FrameKind::Handshake, message);
+        frame.flags = flags;
+        self.outgoing.push((peer.to_string(), frame));
+    }
+
+    fn fail(&mut self, peer: &str, reason: String) {
+        self.channels.remove(peer);
+        self.events.push(NoiseEvent::Failed {
+            peer: peer.to_string(),
+            reason,
+        });
+    }
+}
+
+fn write_handshake(handshake: &mut HandshakeState, peer: &str) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    let len = handshake
+        .write_message(&[], &mut buf)
+        .map_err(|e| format!("Handshake with {} failed: {}", peer, e))?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn read_handshake(
+    handshake: &mut HandshakeState,
+    peer: &str,
+    message: &[u8],
+) -> Result<(), String> {
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    handshake
+        .read_message(message, &mut buf)
+        .map(|_| ())
+        .map_err(|e| format!("Handshake with {} failed: {}", peer, e))
+}
+
+/// Backend that encrypts every frame with the peer's secure channel.
+///
+/// Only peers with an established channel count as connected. Events of
+/// the wrapped backend go through `handle` on their way to the application,
+/// which runs the handshakes and decrypts what comes in. Streams aren't
+/// encrypted, so they are refused both ways.
+pub struct SecureBackend<'a> {
+    inner: &'a dyn Backend,
+    channels: &'a Mutex<SecureChannels>,
+}
+
+impl<'a> SecureBackend<'a> {
+    pub fn new(inner: &'a dyn Backend, channels: &'a Mutex<SecureChannels>) -> Self {
+        Self { inner, channels }
+    }
+
+    /// Run an event of the wrapped backend through the channels and return
+    /// what the application should see instead: `Joined` once the
+    /// handshake is done, `Data` once decrypted. Nothing unencrypted gets
+    /// through.
+    pub fn handle(&self, event: BackendEvent) -> Vec<BackendEvent> {
+        let now = Instant::now();
+        let mut out = Vec::new();
+        match event {
+            BackendEvent::Joined { peer } => {
+                if let Err(e) = self.channels.lock().unwrap().peer_up(&peer, now) {
+                    warn!("{}", e);
+                }
+            }
+            BackendEvent::Left { peer } => {
+                // A plaintext goodbye shows up as `Left` while the link is
+                // still there. Anybody could have sent it, only the link
+                // going down counts.
+                if self.inner.connected_peers().contains(&peer) {
+                    warn!("Ignoring unencrypted goodbye from {}", peer);
+                } else {
+                    let mut channels = self.channels.lock().unwrap();
+                    if channels.is_established(&peer) {
+                        out.push(BackendEvent::Left { peer: peer.clone() });
+                    }
+                    channels.peer_down(&peer);
+                }
+            }
+            BackendEvent::Frame { peer, frame }
+                if matches!(frame.kind, FrameKind::Handshake | FrameKind::Secure) =>
+            {
+                let mut channels = self.channels.lock().unwrap();
+                let opened = channels
+                    .on_frame(&peer, &frame, now)
+                    .and_then(|frame| frame.map(|frame| self.inner.decompress(frame)).transpose());
+                match opened {
+                    Ok(Some(frame)) => match frame.kind {
+                        FrameKind::Data => out.push(BackendEvent::Data {
+                            peer,
+                            data: frame.payload,
+                        }),
+                        FrameKind::Goodbye => {
+                            channels.peer_down(&peer);
+                            out.push(BackendEvent::Left { peer });
+                        }
+                        _ => out.push(BackendEvent::Frame { peer, frame }),
+                    },
+                    Ok(None) => {}
+                    Err(e) => warn!("Dropping frame: {}", e),
+                }
+            }
+            BackendEvent::Data { peer, .. } => {
+                warn!("Dropping unencrypted message from {}", peer);
+            }
+            BackendEvent::Frame { peer, frame } => {
+                warn!("Dropping unencrypted {:?} frame from {}", frame.kind, peer);
+            }
+            BackendEvent::Stream { peer, name, .. } => {
+                warn!(
+                    "Refusing stream {} from {}, streams aren't encrypted",
+                    name, peer
+                );
+            }
+        }
+        out.extend(self.poll());
+        out
+    }
+
+    /// Fail handshakes that took too long and send the handshake messages
+    /// queued up. Returns `Joined` for every handshake that finished, and
+    /// `Left` for peers whose link went away without a word.
+    pub fn poll(&self) -> Vec<BackendEvent> {
+        let connected = self.inner.connected_peers();
+        let mut out = Vec::new();
+        let (events, frames) = {
+            let mut channels = self.channels.lock().unwrap();
+            // Their `Left` may have been a goodbye we ignored
+            for peer in channels.established_peers() {
+                if !connected.contains(&peer) {
+                    channels.peer_down(&peer);
+                    out.push(BackendEvent::Left { peer });
+                }
+            }
+            channels.poll(Instant::now());
+            (channels.drain_events(), channels.poll_transmit())
+        };
+        for (peer, frame) in frames {
+            if let Err(e) = self.inner.send_frame(&frame, &[peer], true) {
+                warn!("Failed to send handshake: {}", e);
+            }
+        }
+
+        for event in events {
+            match event {
+                NoiseEvent::Established { peer, remote_key } => {
+                    info!(
+                        "Secure channel to {}, key {}",
+                        peer,
+                        key_to_hex(&remote_key)
+                    );
+                    out.push(BackendEvent::Joined { peer });
+                }
+                NoiseEvent::Failed { peer, reason } => {
+                    warn!("No secure channel to {}: {}", peer, reason);
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Backend for SecureBackend<'_> {
+    fn local_name(&self) -> String {
+        self.inner.local_name()
+    }
+
+    fn connected_peers(&self) -> Vec<NodeId> {
+        let channels = self.channels.lock().unwrap();
+        self.inner
+            .connected_peers()
+            .into_iter()
+            .filter(|peer| channels.is_established(peer))
+            .collect()
+    }
+
+    fn send_frame(&self, frame: &Frame, peers: &[NodeId], reliably: bool) -> Result<(), String> {
+        for peer in peers {
+            let frame = self.inner.compress_for(peer, frame);
+            let sealed = self.channels.lock().unwrap().seal(peer, &frame)?;
+            self.inner
+                .send_frame(&sealed, std::slice::from_ref(peer), reliably)?;
+        }
+        Ok(())
+    }
+
+    fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String> {
+        Err(format!(
+            "Streams aren't encrypted, not opening {} to {} over a secure channel",
+            name, peer
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::compression::{Compression, CompressionConfig, is_compressed};
+    use crate::memory_backend::{MemoryBackend, MemoryNetwork};
+    use crate::sim::SimConfig;
+
+    const WAIT: Duration = Duration::from_secs(5);
+    // Long enough for a handshake that is going to finish
+    const SETTLE: Duration = Duration::from_millis(500);
+
+    // Inner backend that keeps a copy of everything sent through it, and
+    // negotiates compression the way the MPC session does once it has some
+    struct Recorder {
+        inner: MemoryBackend,
+        sent: Mutex<Vec<Frame>>,
+        compression: Option<Mutex<Compression>>,
+    }
+
+    impl Recorder {
+        // What the backend makes of an event before passing it on
+        fn receive(&self, event: BackendEvent) -> Option<BackendEvent> {
+            let Some(compression) = &self.compression else {
+                return Some(event);
+            };
+            match event {
+                BackendEvent::Joined { peer } => {
+                    let hello = compression.lock().unwrap().hello();
+                    self.send_frame(&hello, std::slice::from_ref(&peer), true)
+                        .unwrap();
+                    Some(BackendEvent::Joined { peer })
+                }
+                BackendEvent::Frame { peer, frame } if frame.kind == FrameKind::Hello => {
+                    compression.lock().unwrap().on_hello(&peer, &frame).unwrap();
+                    None
+                }
+                BackendEvent::Frame { peer, frame } => Some(BackendEvent::Frame {
+                    peer,
+                    frame: self.decompress(frame).unwrap(),
+                }),
+                event => Some(event),
+            }
+        }
+    }
+
+    impl Backend for Recorder {
+        fn local_name(&self) -> String {
+            self.inner.local_name()
+        }
+
+        fn connected_peers(&self) -> Vec<NodeId> {
+            self.inner.connected_peers()
+        }
+
+        fn send_frame(
+            &self,
+            frame: &Frame,
+            peers: &[NodeId],
+            reliably: bool,
+        ) -> Result<(), String> {
+            for peer in peers {
+                let frame = self.compress_for(peer, frame);
+                self.sent.lock().unwrap().push(frame.clone());
+                self.inner
+                    .send_frame(&frame, std::slice::from_ref(peer), reliably)?;
+            }
+            Ok(())
+        }
+
+        fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String> {
+            self.inner.open_stream(peer, name)
+        }
+
+        fn compress_for(&self, peer: &str, frame: &Frame) -> Frame {
+            match &self.compression {
+                Some(compression) => compression.lock().unwrap().compress(peer, frame),
+                None => frame.clone(),
+            }
+        }
+
+        fn decompress(&self, frame: Frame) -> Result<Frame, String> {
+            match &self.compression {
+                Some(compression) => compression.lock().unwrap().decompress(frame),
+                None => Ok(frame),
+            }
+        }
+    }
+
+    struct Node {
+        backend: Recorder,
+        channels: Mutex<SecureChannels>,
+        events: mpsc::Receiver<BackendEvent>,
+        // What the application got to see
+        seen: Vec<BackendEvent>,
+    }
+
+    impl Node {
+        fn join(network: &MemoryNetwork, name: &str, pattern: HandshakePattern) -> Self {
+            let (tx, events) = mpsc::channel();
+            let inner = network
+                .join(name, move |event| {
+                    let _ = tx.send(event);
+                })
+                .unwrap();
+            let config = NoiseConfig {
+                pattern,
+                ..NoiseConfig::default()
+            };
+            Self {
+                backend: Recorder {
+                    inner,
+                    sent: Mutex::new(Vec::new()),
+                    compression: None,
+                },
+                channels: Mutex::new(SecureChannels::new(
+                    name,
+                    Keypair::generate().unwrap(),
+                    config,
+                )),
+                events,
+                seen: Vec::new(),
+            }
+        }
+
+        fn compressing(mut self) -> Self {
+            let compression = Compression::new(CompressionConfig::default());
+            self.backend.compression = Some(Mutex::new(compression));
+            self
+        }
+
+        fn secure(&self) -> SecureBackend<'_> {
+            SecureBackend::new(&self.backend, &self.channels)
+        }
+
+        fn pump(&mut self) {
+            let mut seen = Vec::new();
+            {
+                let secure = self.secure();
+                while let Ok(event) = self.events.try_recv() {
+                    if let Some(event) = self.backend.receive(event) {
+                        seen.extend(secure.handle(event));
+                    }
+                }
+                seen.extend(secure.poll());
+            }
+            self.seen.extend(seen);
+        }
+
+        fn public_key(&self) -> PublicKey {
+            self.channels.lock().unwrap().public_key()
+        }
+
+        fn handshakes_sent(&self) -> Vec<u8> {
+            self.backend
+                .sent
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|frame| frame.kind == FrameKind::Handshake)
+                .map(|frame| frame.flags)
+                .collect()
+        }
+
+        fn count(&self, wanted: impl Fn(&BackendEvent) -> bool) -> usize {
+            self.seen.iter().filter(|event| wanted(event)).count()
+        }
+
+        fn joined(&self) -> usize {
+            self.count(|event| matches!(event, BackendEvent::Joined { .. }))
+        }
+
+        fn received(&self) -> Vec<Vec<u8>> {
+            self.seen
+                .iter()
+                .filter_map(|event| match event {
+                    BackendEvent::Data { data, .. } => Some(data.clone()),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    fn network() -> MemoryNetwork {
+        MemoryNetwork::new(SimConfig {
+            discovery_delay: Duration::from_millis(10),
+            disconnect_timeout: Duration::from_millis(50),
+            ..SimConfig::default()
+        })
+        .unwrap()
+    }
+
+    // Pumps both nodes until `done` holds, false if it didn't within `wait`
+    fn run(
+        a: &mut Node,
+        b: &mut Node,
+        wait: Duration,
+        done: impl Fn(&Node, &Node) -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline {
+            a.pump();
+            b.pump();
+            if done(a, b) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+
+    fn established(a: &mut Node, b: &mut Node) {
+        let joined = run(a, b, WAIT, |a, b| a.joined() > 0 && b.joined() > 0);
+        assert!(joined, "no secure channel");
+    }
+
+    #[test]
+    fn xx_handshake_encrypts_data() {
+        let network = network();
+        let mut a = Node::join(&network, "a", HandshakePattern::Xx);
+        let mut b = Node::join(&network, "b", HandshakePattern::Xx);
+        established(&mut a, &mut b);
+
+        assert_eq!(a.handshakes_sent(), [FLAG_INIT, 0]);
+        assert_eq!(b.handshakes_sent(), [0]);
+        let (a_key, b_key) = (a.public_key(), b.public_key());
+        assert_eq!(a.channels.lock().unwrap().remote_key("b"), Some(b_key));
+        assert_eq!(b.channels.lock().unwrap().remote_key("a"), Some(a_key));
+        assert_eq!(
+            a.channels.lock().unwrap().handshake_hash("b"),
+            b.channels.lock().unwrap().handshake_hash("a")
+        );
+
+        a.secure().send(b"hello", &["b".to_string()], true).unwrap();
+        assert!(run(&mut a, &mut b, WAIT, |_, b| !b.received().is_empty()));
+        assert_eq!(b.received(), [b"hello".to_vec()]);
+        let sent = a.backend.sent.lock().unwrap();
+        assert!(sent.iter().all(|frame| frame.kind != FrameKind::Data));
+    }
+
+    #[test]
+    fn frames_are_compressed_before_they_are_encrypted() {
+        let network = network();
+        let mut a = Node::join(&network, "a", HandshakePattern::Xx).compressing();
+        let mut b = Node::join(&network, "b", HandshakePattern::Xx).compressing();
+        established(&mut a, &mut b);
+
+        let json = br#"{"kind":"status","battery":97,"charging":false}"#.repeat(100);
+        a.secure().send(&json, &["b".to_string()], true).unwrap();
+        assert!(run(&mut a, &mut b, WAIT, |_, b| !b.received().is_empty()));
+        let len = json.len();
+        assert_eq!(b.received(), [json]);
+        let sent = a.backend.sent.lock().unwrap();
+        let hellos = sent.iter().filter(|frame| frame.kind == FrameKind::Hello);
+        assert_eq!(hellos.count(), 1);
+        let secure = sent
+            .iter()
+            .rfind(|frame| frame.kind == FrameKind::Secure)
+            .unwrap();
+        assert!(!is_compressed(secure));
+        assert!(secure.payload.len() < len / 4);
+    }
+
+    #[test]
+    fn ik_handshake_with_a_known_key() {
+        let network = network();
+        let mut a = Node::join(&network, "a", HandshakePattern::Ik);
+        let mut b = Node::join(&network, "b", HandshakePattern::Ik);
+        let b_key = b.public_key();
+        a.channels.lock().unwrap().pin("b", b_key);
+        established(&mut a, &mut b);
+
+        // One round trip
+        assert_eq!(a.handshakes_sent(), [FLAG_INIT | FLAG_IK]);
+        assert_eq!(b.handshakes_sent(), [0]);
+        assert_eq!(a.channels.lock().unwrap().remote_key("b"), Some(b_key));
+    }
+
+    #[test]
+    fn stale_key_restarts_with_xx() {
+        let network = network();
+        let mut a = Node::join(&network, "a", HandshakePattern::Ik);
+        let mut b = Node::join(&network, "b", HandshakePattern::Ik);
+        established(&mut a, &mut b);
+
+        // b comes back with a new key, a only has the one it learned
+        network.with_sim(|sim| sim.set_in_range("a", "b", false));
+        let left = run(&mut a, &mut b, WAIT, |a, _| {
+            a.count(|event| matches!(event, BackendEvent::Left { .. })) > 0
+        });
+        assert!(left, "a never noticed b leaving");
+        let config = b.channels.lock().unwrap().config().clone();
+        *b.channels.lock().unwrap() =
+            SecureChannels::new("b", Keypair::generate().unwrap(), config);
+        a.backend.sent.lock().unwrap().clear();
+        network.with_sim(|sim| sim.set_in_range("a", "b", true));
+
+        assert!(run(&mut a, &mut b, WAIT, |a, b| a.joined() > 1
+            && b.joined() > 1));
+        assert_eq!(a.handshakes_sent(), [FLAG_INIT | FLAG_IK, FLAG_INIT, 0]);
+        assert_eq!(
+            a.channels.lock().unwrap().remote_key("b"),
+            Some(b.public_key())
+        );
+    }
+
+    #[test]
+    fn pinned_key_mismatch_never_connects() {
+        for pattern in [HandshakePattern::Xx, HandshakePattern::Ik] {
+            let network = network();
+            let mut a = Node::join(&network, "a", pattern);
+            let mut b = Node::join(&network, "b", pattern);
+            let impostor = Keypair::generate().unwrap().public();
+            a.channels.lock().unwrap().pin("b", impostor);
+
+            assert!(
+                !run(&mut a, &mut b, SETTLE, |a, _| a.joined() > 0),
+                "{:?}",
+                pattern
+            );
+            assert!(!a.channels.lock().unwrap().is_established("b"));
+            assert!(a.secure().connected_peers().is_empty());
+        }
+    }
+
+    #[test]
+    fn replays_and_plaintext_are_dropped() {
+        let network = network();
+        let mut a = Node::join(&network, "a", HandshakePattern::Xx);
+        let mut b = Node::join(&network, "b", HandshakePattern::Xx);
+        established(&mut a, &mut b);
+
+        a.secure().send(b"once", &["b".to_string()], true).unwrap();
+        let sealed = a.backend.sent.lock().unwrap().last().unwrap().clone();
+        assert_eq!(sealed.kind, FrameKind::Secure);
+        let to_b = ["b".to_string()];
+        a.backend.inner.send_frame(&sealed, &to_b, true).unwrap();
+        a.backend.inner.send(b"plain", &to_b, true).unwrap();
+        a.backend
+            .inner
+            .send_frame(&Frame::new(FrameKind::Route, Vec::new()), &to_b, true)
+            .unwrap();
+        a.backend
+            .inner
+            .send_frame(&Frame::goodbye(), &to_b, true)
+            .unwrap();
+        a.secure().send(b"after", &["b".to_string()], true).unwrap();
+
+        assert!(run(&mut a, &mut b, WAIT, |_, b| b.received().len() >= 2));
+        assert_eq!(b.received(), [b"once".to_vec(), b"after".to_vec()]);
+        assert!(b.seen.iter().all(|event| !matches!(
+            event,
+            BackendEvent::Frame { .. } | BackendEvent::Left { .. }
+        )));
+        assert!(b.channels.lock().unwrap().is_established("a"));
+    }
+
+    #[test]
+    fn streams_are_refused() {
+        let network = network();
+        let mut a = Node::join(&network, "a", HandshakePattern::Xx);
+        let mut b = Node::join(&network, "b", HandshakePattern::Xx);
+        established(&mut a, &mut b);
+
+        assert!(a.secure().open_stream("b", "file").is_err());
+        // Opened underneath the secure channel
+        drop(a.backend.inner.open_stream("b", "file").unwrap());
+        b.pump();
+        assert_eq!(
+            b.count(|event| matches!(event, BackendEvent::Stream { .. })),
+            0
+        );
+    }
+
+    #[test]
+    fn peers_with_the_same_name_settle_who_initiates() {
+        let now = Instant::now();
+        let config = NoiseConfig {
+            pattern: HandshakePattern::Xx,
+            ..NoiseConfig::default()
+        };
+        let mut one = SecureChannels::new("same", Keypair::generate().unwrap(), config.clone());
+        let mut two = SecureChannels::new("same", Keypair::generate().unwrap(), config);
+        one.peer_up("same", now).unwrap();
+        two.peer_up("same", now).unwrap();
+
+        for _ in 0..4 {
+            for (_, frame) in one.poll_transmit() {
+                two.on_frame("same", &frame, now).unwrap();
+            }
+            for (_, frame) in two.poll_transmit() {
+                one.on_frame("same", &frame, now).unwrap();
+            }
+        }
+        assert!(one.is_established("same"));
+        assert!(two.is_established("same"));
+        assert_eq!(one.handshake_hash("same"), two.handshake_hash("same"));
+    }
+
+    // Splits a secure frame payload into its chunks
+    fn chunks(payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut rest = payload;
+        while !rest.is_empty() {
+            let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+            chunks.push(rest[..10 + len].to_vec());
+            rest = &rest[10 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn chunks_of_a_frame_stay_together() {
+        let now = Instant::now();
+        let config = NoiseConfig {
+            pattern: HandshakePattern::Xx,
+            ..NoiseConfig::default()
+        };
+        let mut a = SecureChannels::new("a", Keypair::generate().unwrap(), config.clone());
+        let mut b = SecureChannels::new("b", Keypair::generate().unwrap(), config);
+        a.peer_up("b", now).unwrap();
+        b.peer_up("a", now).unwrap();
+        for _ in 0..2 {
+            for (_, frame) in a.poll_transmit() {
+                b.on_frame("a", &frame, now).unwrap();
+            }
+            for (_, frame) in b.poll_transmit() {
+                a.on_frame("b", &frame, now).unwrap();
+            }
+        }
+        assert!(b.is_established("a"));
+
+        let big = Frame::data(&vec![7u8; 2 * MAX_CHUNK + 100]);
+        let secure = |chunks: &[&Vec<u8>]| {
+            Frame::new(
+                FrameKind::Secure,
+                chunks.iter().flat_map(|c| c.iter().copied()).collect(),
+            )
+        };
+        let first = a.seal("b", &big).unwrap();
+        let second = a.seal("b", &big).unwrap();
+        let (first, second) = (chunks(&first.payload), chunks(&second.payload));
+        assert_eq!(first.len(), 3);
+
+        let dropped = secure(&[&first[0], &first[2]]);
+        assert!(b.on_frame("a", &dropped, now).is_err());
+        let truncated = secure(&[&first[0], &first[1]]);
+        assert!(b.on_frame("a", &truncated, now).is_err());
+        let reordered = secure(&[&first[0], &first[2], &first[1]]);
+        assert!(b.on_frame("a", &reordered, now).is_err());
+        let spliced = secure(&[&first[0], &first[1], &second[2]]);
+        assert!(b.on_frame("a", &spliced, now).is_err());
+        let tail = secure(&[&first[1], &first[2]]);
+        assert!(b.on_frame("a", &tail, now).is_err());
+
+        // None of that used up the nonces of the real frames
+        let whole = secure(&[&first[0], &first[1], &first[2]]);
+        assert_eq!(b.on_frame("a", &whole, now).unwrap(), Some(big.clone()));
+        let whole = secure(&[&second[0], &second[1], &second[2]]);
+        assert_eq!(b.on_frame("a", &whole, now).unwrap(), Some(big));
+    }
+
+    #[test]
+    fn names_too_long_for_the_prologue_are_refused() {
+        let mut channels =
+            SecureChannels::new("a", Keypair::generate().unwrap(), NoiseConfig::default());
+        let long = "b".repeat(usize::from(u16::MAX) + 1);
+        assert!(channels.peer_up(&long, Instant::now()).is_err());
+        assert!(channels.poll_transmit().is_empty());
+        // Just short enough still works
+        let long = "b".repeat(usize::from(u16::MAX));
+        channels.peer_up(&long, Instant::now()).unwrap();
+        assert_eq!(channels.poll_transmit().len(), 1);
+    }
+}
@@ -0,0 +1,432 @@
+// Store-and-forward outbox for peers that aren't connected right now.
+//
+// Messages are queued per peer with an expiry and written to disk, so they
+// survive a restart. When the peer connects the session takes the pending
+// messages out, sends them with acknowledged delivery and reports back:
+// an ack removes the message and produces a delivery receipt, a failure puts
+// it back for the next connection. Messages that expire first are dropped
+// with an `Expired` event instead.
+//
+// Wall-clock time is used throughout since expiry has to survive restarts.
+//
+// File format, all integers big endian:
+//   [magic "IDPO"][version: u8][next id: u64][count: u32]
+//   count * [id: u64][queued at: u64 secs][expires at: u64 secs]
+//           [peer len: u8][peer][data len: u32][data]
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::file_format::{Reader, unix_secs, write_atomic};
+use crate::mesh::NodeId;
+
+const FILE_MAGIC: &[u8; 4] = b"IDPO";
+const FILE_VERSION: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Expiry for messages queued without an explicit ttl
+    pub default_ttl: Duration,
+    /// Messages that can be waiting for a single peer
+    pub max_per_peer: usize,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            default_ttl: Duration::from_secs(24 * 60 * 60),
+            max_per_peer: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboxEvent {
+    /// The peer acknowledged the message
+    Delivered { id: u64, peer: NodeId },
+    /// The message expired before the peer showed up
+    Expired { id: u64, peer: NodeId },
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub peer: NodeId,
+    pub data: Vec<u8>,
+    pub queued_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+impl fmt::Debug for OutboxEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboxEntry")
+            .field("id", &self.id)
+            .field("peer", &self.peer)
+            .field("len", &self.data.len())
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    config: OutboxConfig,
+    entries: BTreeMap<u64, OutboxEntry>,
+    next_id: u64,
+    // Handed out by `take_pending` and not settled yet
+    in_flight: HashSet<u64>,
+    events: Vec<OutboxEvent>,
+}
+
+impl Outbox {
+    /// Outbox backed by `path`, loading whatever was stored there before
+    pub fn open(path: impl AsRef<Path>, config: OutboxConfig) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let mut outbox = Self::in_memory(config);
+        match fs::read(&path) {
+            Ok(bytes) => outbox.load(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+        outbox.path = Some(path);
+        Ok(outbox)
+    }
+
+    /// Outbox that forgets everything when dropped
+    pub fn in_memory(config: OutboxConfig) -> Self {
+        Self {
+            path: None,
+            config,
+            entries: BTreeMap::new(),
+            next_id: 0,
+            in_flight: HashSet::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &OutboxConfig {
+        &self.config
+    }
+
+    /// Queue `data` for `peer`, returning the id used in its receipt
+    pub fn push(
+        &mut self,
+        peer: &str,
+        data: &[u8],
+        ttl: Option<Duration>,
+        now: SystemTime,
+    ) -> Result<u64, String> {
+        if peer.len() > u8::MAX as usize {
+            return Err(format!("Peer name too long: {}", peer));
+        }
+        if self.pending_for(peer) >= self.config.max_per_peer {
+            return Err(format!("Outbox for {} is full", peer));
+        }
+
+        let id = self.next_id;
+        self.next_id = id
+            .checked_add(1)
+            .ok_or_else(|| "Outbox ran out of ids".to_string())?;
+        self.entries.insert(
+            id,
+            OutboxEntry {
+                id,
+                peer: peer.to_string(),
+                data: data.to_vec(),
+                queued_at: now,
+                expires_at: now + ttl.unwrap_or(self.config.default_ttl),
+            },
+        );
+        // Not queued unless it is on disk too
+        if let Err(e) = self.save() {
+            self.entries.remove(&id);
+            self.next_id = id;
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// Messages for `peer` that are ready to go out. They count as in
+    /// flight until `delivered` or `retry_later` is called for them.
+    pub fn take_pending(&mut self, peer: &str, now: SystemTime) -> Vec<OutboxEntry> {
+        let pending: Vec<OutboxEntry> = self
+            .entries
+            .values()
+            .filter(|entry| entry.peer == peer && entry.expires_at > now)
+            .filter(|entry| !self.in_flight.contains(&entry.id))
+            .cloned()
+            .collect();
+        self.in_flight.extend(pending.iter().map(|entry| entry.id));
+        pending
+    }
+
+    /// The peer acknowledged message `id`
+    pub fn delivered(&mut self, id: u64) -> Result<(), String> {
+        self.in_flight.remove(&id);
+        let Some(entry) = self.entries.remove(&id) else {
+            return Ok(());
+        };
+        self.events.push(OutboxEvent::Delivered {
+            id,
+            peer: entry.peer,
+        });
+        self.save()
+    }
+
+    /// Sending message `id` failed, keep it for the next connection
+    pub fn retry_later(&mut self, id: u64) {
+        self.in_flight.remove(&id);
+    }
+
+    /// Drop messages past their expiry that aren't in flight
+    pub fn expire(&mut self, now: SystemTime) -> Result<(), String> {
+        let expired: Vec<u64> = self
+            .entries
+            .values()
+            .filter(|entry| entry.expires_at <= now && !self.in_flight.contains(&entry.id))
+            .map(|entry| entry.id)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        for id in expired {
+            if let Some(entry) = self.entries.remove(&id) {
+                self.events.push(OutboxEvent::Expired {
+                    id,
+                    peer: entry.peer,
+                });
+            }
+        }
+        self.save()
+    }
+
+    pub fn pending_for(&self, peer: &str) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.peer == peer)
+            .count()
+    }
+
+    /// Peers with messages waiting
+    pub fn peers(&self) -> Vec<NodeId> {
+        let peers: BTreeSet<&NodeId> = self.entries.values().map(|entry| &entry.peer).collect();
+        peers.into_iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn drain_events(&mut self) -> Vec<OutboxEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => write_atomic(path, &self.encode()),
+            None => Ok(()),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(FILE_MAGIC);
+        out.push(FILE_VERSION);
+        out.extend_from_slice(&self.next_id.to_be_bytes());
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in self.entries.values() {
+            out.extend_from_slice(&entry.id.to_be_bytes());
+            out.extend_from_slice(&unix_secs(entry.queued_at).to_be_bytes());
+            out.extend_from_slice(&unix_secs(entry.expires_at).to_be_bytes());
+            out.push(entry.peer.len() as u8);
+            out.extend_from_slice(entry.peer.as_bytes());
+            out.extend_from_slice(&(entry.data.len() as u32).to_be_bytes());
+            out.extend_from_slice(&entry.data);
+        }
+        out
+    }
+
+    fn load(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut reader = Reader::new(bytes, "outbox file");
+        if reader.take(4)? != FILE_MAGIC {
+            return Err("Not an outbox file".to_string());
+        }
+        let version = reader.u8()?;
+        if version != FILE_VERSION {
+            return Err(format!("Unsupported outbox version {}", version));
+        }
+        self.next_id = reader.u64()?;
+        let count = reader.u32()?;
+        for _ in 0..count {
+            let id = reader.u64()?;
+            let queued_at = reader.time()?;
+            let expires_at = reader.time()?;
+            let peer = reader.string()?;
+            let data_len = reader.u32()? as usize;
+            let data = reader.take(data_len)?.to_vec();
+            let next_id = id
+                .checked_add(1)
+                .ok_or_else(|| format!("Bad message id {} in outbox file", id))?;
+            self.next_id = self.next_id.max(next_id);
+            self.entries.insert(
+                id,
+                OutboxEntry {
+                    id,
+                    peer,
+                    data,
+                    queued_at,
+                    expires_at,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_format::scratch;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn acked_messages_produce_receipts() {
+        let now = SystemTime::now();
+        let mut outbox = Outbox::in_memory(OutboxConfig::default());
+        let id = outbox.push("b", b"hi", None, now).unwrap();
+
+        let pending = outbox.take_pending("b", now);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].data, b"hi");
+        assert!(outbox.take_pending("b", now).is_empty());
+
+        outbox.delivered(id).unwrap();
+        assert!(outbox.is_empty());
+        assert_eq!(
+            outbox.drain_events(),
+            [OutboxEvent::Delivered {
+                id,
+                peer: "b".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn failed_messages_wait_for_the_next_connection() {
+        let now = SystemTime::now();
+        let mut outbox = Outbox::in_memory(OutboxConfig::default());
+        let id = outbox.push("b", b"hi", None, now).unwrap();
+
+        outbox.take_pending("b", now);
+        outbox.retry_later(id);
+        assert_eq!(outbox.take_pending("b", now).len(), 1);
+        assert!(outbox.drain_events().is_empty());
+    }
+
+    #[test]
+    fn expired_messages_are_dropped_unless_in_flight() {
+        let now = SystemTime::now();
+        let ttl = Duration::from_secs(10);
+        let mut outbox = Outbox::in_memory(OutboxConfig::default());
+        let sent = outbox.push("b", b"sent", Some(ttl), now).unwrap();
+        outbox.take_pending("b", now);
+        let waiting = outbox.push("b", b"waiting", Some(ttl), now).unwrap();
+
+        let later = now + ttl;
+        assert!(outbox.take_pending("b", later).is_empty());
+        outbox.expire(later).unwrap();
+        assert_eq!(
+            outbox.drain_events(),
+            [OutboxEvent::Expired {
+                id: waiting,
+                peer: "b".to_string()
+            }]
+        );
+        assert_eq!(outbox.len(), 1);
+
+        outbox.delivered(sent).unwrap();
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn queues_are_bounded_per_peer() {
+        let now = SystemTime::now();
+        let config = OutboxConfig {
+            max_per_peer: 1,
+            ..OutboxConfig::default()
+        };
+        let mut outbox = Outbox::in_memory(config);
+        outbox.push("b", b"1", None, now).unwrap();
+        assert!(outbox.push("b", b"2", None, now).is_err());
+        outbox.push("c", b"1", None, now).unwrap();
+        assert_eq!(outbox.peers(), ["b", "c"]);
+
+        assert!(outbox.push(&"x".repeat(256), b"1", None, now).is_err());
+    }
+
+    #[test]
+    fn messages_survive_a_restart() {
+        let path = scratch("outbox-restart");
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        let first = outbox.push("b", b"first", None, now).unwrap();
+        outbox.push("c", b"second", None, now).unwrap();
+        outbox.delivered(first).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        assert_eq!(outbox.len(), 1);
+        let pending = outbox.take_pending("c", now);
+        assert_eq!(pending[0].data, b"second");
+        assert_eq!(pending[0].queued_at, now);
+        // Ids keep counting up so receipts stay unambiguous
+        assert!(outbox.push("c", b"third", None, now).unwrap() > pending[0].id);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn foreign_files_are_refused() {
+        let path = scratch("outbox-foreign");
+        fs::write(&path, b"nope").unwrap();
+        assert!(Outbox::open(&path, OutboxConfig::default()).is_err());
+        fs::write(&path, b"IDPO\x01\0\0").unwrap();
+        assert!(Outbox::open(&path, OutboxConfig::default()).is_err());
+
+        // A message with the last possible id
+        let mut corrupt = b"IDPO\x01".to_vec();
+        corrupt.extend_from_slice(&0u64.to_be_bytes());
+        corrupt.extend_from_slice(&1u32.to_be_bytes());
+        corrupt.extend_from_slice(&u64::MAX.to_be_bytes());
+        corrupt.extend_from_slice(&[0; 16]);
+        corrupt.extend_from_slice(b"\x01b\0\0\0\0");
+        fs::write(&path, corrupt).unwrap();
+        assert!(Outbox::open(&path, OutboxConfig::default()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn messages_that_cant_be_saved_are_not_queued() {
+        let path = scratch("outbox-unsaved");
+        let now = SystemTime::now();
+        let mut outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        // The temporary file can't be written over a directory
+        let tmp = path.with_extension("tmp");
+        fs::create_dir_all(&tmp).unwrap();
+        assert!(outbox.push("b", b"hi", None, now).is_err());
+        assert!(outbox.is_empty());
+        assert!(outbox.take_pending("b", now).is_empty());
+
+        fs::remove_dir(&tmp).unwrap();
+        outbox.push("b", b"hi", None, now).unwrap();
+        assert_eq!(outbox.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,484 @@
+// Out-of-band pairing over the secure channel.
+//
+// Both ends of a Noise handshake end up with the same handshake hash. A man
+// in the middle runs two handshakes with different hashes, but he picks
+// keys in both, so he could grind them until a short code derived from the
+// hashes alone matches. The code therefore also covers a random nonce from
+// each side, exchanged commit-then-reveal: the side with the lower static
+// key sends a hash of its nonce, the other side answers with its nonce, and
+// only then is the first nonce revealed. Neither side can pick its nonce
+// after seeing the other's, so a man in the middle gets one guess at the
+// code per attempt.
+//
+// Each device shows the code, six digits and six emoji, and the user checks
+// that both show the same and confirms on each side. Once both sides
+// confirmed, the peer's static key is recorded as trusted in the address
+// book and pinned from then on.
+//
+// MPC invitation contexts are discovery info entries (see
+// `DiscoveryInfo::encode`). Invitations asking to pair carry `PAIRING_KEY`,
+// so the invited side can tell the user what is going on.
+//
+// Pair frames travel inside the secure channel. `FLAG_COMMIT` carries the
+// hash of a nonce, `FLAG_NONCE` a nonce, without either the flags carry the
+// user's decision and the payload is empty.
+
+use std::fmt;
+
+use snow::params::HashChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+
+use crate::discovery_info::DiscoveryInfo;
+use crate::frame::{Frame, FrameKind};
+use crate::mesh::NodeId;
+use crate::noise::PublicKey;
+
+/// Invitation context entry of peers that want to pair
+pub const PAIRING_KEY: &str = "pair";
+
+/// Pair frame flag: the user saw the same code, without it they didn't
+pub const FLAG_CONFIRM: u8 = 0x01;
+/// Pair frame flag: the payload is the hash of the sender's nonce
+pub const FLAG_COMMIT: u8 = 0x02;
+/// Pair frame flag: the payload is the sender's nonce
+pub const FLAG_NONCE: u8 = 0x04;
+
+pub type Nonce = [u8; 32];
+
+// Keep the code and the commitments independent from anything else derived
+// from the same inputs
+const SAS_LABEL: &[u8] = b"iroh-discovery-playground sas";
+const COMMIT_LABEL: &[u8] = b"iroh-discovery-playground commit";
+
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+pub fn is_pairing_intent(context: &[u8]) -> bool {
+    DiscoveryInfo::decode(context).is_ok_and(|info| info.get(PAIRING_KEY).is_some())
+}
+
+fn blake2s(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hash = DefaultResolver
+        .resolve_hash(&HashChoice::Blake2s)
+        .expect("BLAKE2s is built into snow");
+    for part in parts {
+        hash.input(part);
+    }
+    let mut out = [0u8; 32];
+    hash.result(&mut out);
+    out
+}
+
+fn commitment(nonce: &Nonce) -> [u8; 32] {
+    blake2s(&[COMMIT_LABEL, nonce])
+}
+
+/// Short authentication string both sides of a handshake derive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sas {
+    number: u32,
+    emoji: [u8; 6],
+}
+
+impl Sas {
+    /// `committed` is the nonce that was committed to first
+    pub fn derive(handshake_hash: &[u8], committed: &Nonce, answered: &Nonce) -> Self {
+        let out = blake2s(&[SAS_LABEL, handshake_hash, committed, answered]);
+
+        // 20 bits for the digits, 36 bits for the emoji. The modulo bias is
+        // too small to matter.
+        let number = u32::from_be_bytes([out[0], out[1], out[2], out[3]]) % 1_000_000;
+        let bits = u64::from_be_bytes([
+            out[4], out[5], out[6], out[7], out[8], out[9], out[10], out[11],
+        ]);
+        let mut emoji = [0u8; 6];
+        for (i, index) in emoji.iter_mut().enumerate() {
+            *index = ((bits >> (58 - 6 * i)) & 0x3f) as u8;
+        }
+        Self { number, emoji }
+    }
+
+    /// Six digits in two groups, "042 917"
+    pub fn digits(&self) -> String {
+        format!("{:03} {:03}", self.number / 1000, self.number % 1000)
+    }
+
+    /// Emoji with their names, for terminals that can't show them
+    pub fn emoji(&self) -> Vec<(&'static str, &'static str)> {
+        self.emoji
+            .iter()
+            .map(|&index| EMOJI[index as usize])
+            .collect()
+    }
+}
+
+impl fmt::Display for Sas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.digits())?;
+        for (emoji, _) in self.emoji() {
+            write!(f, " {}", emoji)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingOutcome {
+    /// Both users saw the same code
+    Paired,
+    /// Our user said the codes differ
+    Rejected,
+    /// The peer's user said the codes differ
+    RejectedByPeer,
+}
+
+/// One pairing attempt with a peer whose secure channel is established
+#[derive(Debug)]
+pub struct Pairing {
+    peer: NodeId,
+    remote_key: PublicKey,
+    handshake_hash: Vec<u8>,
+    nonce: Nonce,
+    // We commit to our nonce, the peer answers with its own
+    commits: bool,
+    peer_commitment: Option<[u8; 32]>,
+    peer_nonce: Option<Nonce>,
+    sas: Option<Sas>,
+    local: Option<bool>,
+    remote: Option<bool>,
+}
+
+impl Pairing {
+    /// `nonce` must be fresh and random for every attempt
+    pub fn new(
+        peer: &str,
+        local_key: PublicKey,
+        remote_key: PublicKey,
+        handshake_hash: &[u8],
+        nonce: Nonce,
+    ) -> Self {
+        Self {
+            peer: peer.to_string(),
+            remote_key,
+            handshake_hash: handshake_hash.to_vec(),
+            nonce,
+            commits: local_key < remote_key,
+            peer_commitment: None,
+            peer_nonce: None,
+            sas: None,
+            local: None,
+            remote: None,
+        }
+    }
+
+    /// Frame to send first, if it is ours to commit
+    pub fn start(&self) -> Option<Frame> {
+        self.commits
+            .then(|| pair_frame(FLAG_COMMIT, commitment(&self.nonce).to_vec()))
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Key to trust once paired
+    pub fn remote_key(&self) -> &PublicKey {
+        &self.remote_key
+    }
+
+    /// `None` until both nonces are known
+    pub fn sas(&self) -> Option<&Sas> {
+        self.sas.as_ref()
+    }
+
+    /// Whether our user has been shown the code and still has to decide
+    pub fn is_waiting_for_user(&self) -> bool {
+        self.sas.is_some() && self.local.is_none()
+    }
+
+    /// Record our user's decision and return the frame telling the peer,
+    /// to be sent through the secure channel
+    pub fn confirm(&mut self, same_code: bool) -> Frame {
+        self.local = Some(same_code);
+        let flags = if same_code { FLAG_CONFIRM } else { 0 };
+        pair_frame(flags, Vec::new())
+    }
+
+    /// Take in the peer's commitment, nonce or decision. Returns the frame
+    /// to answer with, if any.
+    pub fn on_frame(&mut self, frame: &Frame) -> Result<Option<Frame>, String> {
+        if frame.kind != FrameKind::Pair {
+            return Err(format!("Not a pairing frame: {:?}", frame.kind));
+        }
+        if frame.flags & FLAG_COMMIT != 0 {
+            if self.commits || self.peer_commitment.is_some() {
+                return Err(format!("Unexpected commitment from {}", self.peer));
+            }
+            self.peer_commitment = Some(self.hash_payload(frame)?);
+            return Ok(Some(pair_frame(FLAG_NONCE, self.nonce.to_vec())));
+        }
+        if frame.flags & FLAG_NONCE != 0 {
+            if self.peer_nonce.is_some() {
+                return Err(format!("{} sent its nonce twice", self.peer));
+            }
+            let nonce = self.hash_payload(frame)?;
+            if self.commits {
+                // Revealed only now that the peer is bound to its nonce
+                self.peer_nonce = Some(nonce);
+                self.sas = Some(Sas::derive(&self.handshake_hash, &self.nonce, &nonce));
+                return Ok(Some(pair_frame(FLAG_NONCE, self.nonce.to_vec())));
+            }
+            match self.peer_commitment {
+                None => {
+                    return Err(format!("{} sent a nonce before committing", self.peer));
+                }
+                Some(expected) if expected != commitment(&nonce) => {
+                    return Err(format!(
+                        "{} revealed a nonce it didn't commit to",
+                        self.peer
+                    ));
+                }
+                Some(_) => {}
+            }
+            self.peer_nonce = Some(nonce);
+            self.sas = Some(Sas::derive(&self.handshake_hash, &nonce, &self.nonce));
+            return Ok(None);
+        }
+        if self.sas.is_none() {
+            return Err(format!("{} decided before the codes were shown", self.peer));
+        }
+        if self.remote.is_some() {
+            return Err(format!("{} decided twice", self.peer));
+        }
+        self.remote = Some(frame.flags & FLAG_CONFIRM != 0);
+        Ok(None)
+    }
+
+    fn hash_payload(&self, frame: &Frame) -> Result<[u8; 32], String> {
+        frame
+            .payload
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("Bad pairing frame from {}", self.peer))
+    }
+
+    /// `None` until both sides confirmed or either one rejected
+    pub fn outcome(&self) -> Option<PairingOutcome> {
+        match (self.local, self.remote) {
+            (Some(false), _) => Some(PairingOutcome::Rejected),
+            (_, Some(false)) => Some(PairingOutcome::RejectedByPeer),
+            (Some(true), Some(true)) => Some(PairingOutcome::Paired),
+            _ => None,
+        }
+    }
+}
+
+fn pair_frame(flags: u8, payload: Vec<u8>) -> Frame {
+    let mut frame = Frame::new(FrameKind::Pair, payload);
+    frame.flags = flags;
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 32] = [42; 32];
+
+    // Both ends of one handshake, `a` commits
+    fn start() -> (Pairing, Pairing) {
+        (
+            Pairing::new("b", [1; 32], [2; 32], &HASH, [10; 32]),
+            Pairing::new("a", [2; 32], [1; 32], &HASH, [20; 32]),
+        )
+    }
+
+    // Runs the nonce exchange
+    fn pair() -> (Pairing, Pairing) {
+        let (mut a, mut b) = start();
+        assert!(b.start().is_none());
+        let commit = a.start().unwrap();
+        let nonce = b.on_frame(&commit).unwrap().unwrap();
+        let reveal = a.on_frame(&nonce).unwrap().unwrap();
+        assert_eq!(b.on_frame(&reveal), Ok(None));
+        (a, b)
+    }
+
+    #[test]
+    fn both_ends_of_a_handshake_show_the_same_code() {
+        let (a, b) = pair();
+        let sas = a.sas().unwrap();
+        assert_eq!(Some(sas), b.sas());
+        assert_eq!(*sas, Sas::derive(&HASH, &[10; 32], &[20; 32]));
+        assert_ne!(*sas, Sas::derive(&[43; 32], &[10; 32], &[20; 32]));
+        assert_ne!(*sas, Sas::derive(&HASH, &[11; 32], &[20; 32]));
+        assert_ne!(*sas, Sas::derive(&HASH, &[20; 32], &[10; 32]));
+
+        let digits = sas.digits();
+        assert_eq!(digits.len(), 7);
+        assert!(
+            digits
+                .chars()
+                .filter(|c| *c != ' ')
+                .all(|c| c.is_ascii_digit())
+        );
+        assert_eq!(sas.emoji().len(), 6);
+        assert!(sas.to_string().starts_with(&digits));
+    }
+
+    #[test]
+    fn the_committed_nonce_is_revealed_last() {
+        let (mut a, mut b) = start();
+        let commit = a.start().unwrap();
+        assert_eq!(commit.flags, FLAG_COMMIT);
+        assert_ne!(commit.payload, [10; 32]);
+        let nonce = b.on_frame(&commit).unwrap().unwrap();
+        assert_eq!(
+            (nonce.flags, nonce.payload.as_slice()),
+            (FLAG_NONCE, &[20; 32][..])
+        );
+        assert!(a.sas().is_none() && b.sas().is_none());
+        assert!(!b.is_waiting_for_user());
+
+        let reveal = a.on_frame(&nonce).unwrap().unwrap();
+        assert_eq!(reveal.payload, [10; 32]);
+        assert!(a.is_waiting_for_user());
+        b.on_frame(&reveal).unwrap();
+        assert!(b.is_waiting_for_user());
+    }
+
+    #[test]
+    fn nonces_that_dont_match_the_commitment_are_refused() {
+        let (a, mut b) = start();
+        b.on_frame(&a.start().unwrap()).unwrap();
+        let forged = pair_frame(FLAG_NONCE, vec![11; 32]);
+        assert!(b.on_frame(&forged).is_err());
+        assert!(b.sas().is_none());
+
+        // Nor can the peer skip the commitment or commit in our place
+        let (_, mut b) = start();
+        assert!(b.on_frame(&pair_frame(FLAG_NONCE, vec![10; 32])).is_err());
+        let (mut a, _) = start();
+        assert!(a.on_frame(&pair_frame(FLAG_COMMIT, vec![0; 32])).is_err());
+        assert!(a.on_frame(&pair_frame(FLAG_NONCE, vec![0; 5])).is_err());
+    }
+
+    #[test]
+    fn pairing_needs_both_users_to_confirm() {
+        let (mut a, mut b) = pair();
+        assert!(a.is_waiting_for_user());
+
+        let frame = a.confirm(true);
+        assert_eq!(a.outcome(), None);
+        b.on_frame(&frame).unwrap();
+        assert_eq!(b.outcome(), None);
+
+        a.on_frame(&b.confirm(true)).unwrap();
+        assert_eq!(a.outcome(), Some(PairingOutcome::Paired));
+        assert_eq!(b.outcome(), Some(PairingOutcome::Paired));
+        assert_eq!(a.remote_key(), &[2; 32]);
+    }
+
+    #[test]
+    fn either_user_can_reject() {
+        let (mut a, mut b) = pair();
+        let frame = a.confirm(false);
+        assert_eq!(a.outcome(), Some(PairingOutcome::Rejected));
+        b.on_frame(&frame).unwrap();
+        assert_eq!(b.outcome(), Some(PairingOutcome::RejectedByPeer));
+        // A confirmation after the peer said no doesn't change anything
+        b.confirm(true);
+        assert_eq!(b.outcome(), Some(PairingOutcome::RejectedByPeer));
+    }
+
+    #[test]
+    fn peers_decide_once_with_pair_frames() {
+        let (mut a, mut b) = start();
+        let frame = b.confirm(true);
+        assert!(a.on_frame(&frame).is_err());
+
+        let (mut a, mut b) = pair();
+        let frame = b.confirm(true);
+        a.on_frame(&frame).unwrap();
+        assert!(a.on_frame(&frame).is_err());
+        assert!(
+            b.on_frame(&Frame::new(FrameKind::Data, Vec::new()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn pairing_intents() {
+        let context = DiscoveryInfo::new().with(PAIRING_KEY, "1").unwrap();
+        assert!(is_pairing_intent(&context.encode()));
+
+        let plain = DiscoveryInfo::new().with("id", "k1").unwrap();
+        assert!(!is_pairing_intent(&plain.encode()));
+        assert!(!is_pairing_intent(b""));
+        assert!(!is_pairing_intent(b"\xffgarbage"));
+    }
+}
@@ -0,0 +1,452 @@
+// Reconnect supervisor.
+//
+// Pure state machine, no MPC in here: the session feeds it connection state
+// changes and browser sightings, and asks it which peers are due for another
+// invitation. Time is always passed in so the same logic can run on a
+// virtual clock.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delay before the first re-invitation
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between attempts
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after each failed attempt
+    pub multiplier: f64,
+    /// Fraction of the delay randomised in both directions, 0.0 to disable
+    pub jitter: f64,
+    /// Attempts before giving up on a peer
+    pub max_attempts: u32,
+    /// How long a lost peer is remembered while it is out of range
+    pub remember_for: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 8,
+            remember_for: Duration::from_secs(600),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before attempt number `attempt` (starting at 1), without jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let secs = self.initial_backoff.as_secs_f64() * exp;
+        Duration::from_secs_f64(secs.min(self.max_backoff.as_secs_f64()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconnectEvent<P> {
+    /// A connected peer dropped and will be re-invited once seen again
+    Lost { peer: P },
+    /// Next invitation scheduled after `delay`
+    Scheduled {
+        peer: P,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Invitation is being sent now
+    Attempt { peer: P, attempt: u32 },
+    /// Peer is connected again
+    Reconnected { peer: P, attempts: u32 },
+    /// Attempt budget exhausted
+    GaveUp { peer: P, attempts: u32 },
+    /// Peer was out of range for longer than `remember_for`
+    Forgotten { peer: P },
+}
+
+#[derive(Debug)]
+struct PeerRecord {
+    attempts: u32,
+    lost_at: Instant,
+    found: bool,
+    next_attempt: Option<Instant>,
+    in_flight: bool,
+}
+
+pub struct ReconnectSupervisor<P> {
+    policy: ReconnectPolicy,
+    rng: StdRng,
+    peers: HashMap<P, PeerRecord>,
+    events: Vec<ReconnectEvent<P>>,
+}
+
+// Manual Debug implementation to skip the rng
+impl<P: fmt::Debug> fmt::Debug for ReconnectSupervisor<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectSupervisor")
+            .field("policy", &self.policy)
+            .field("peers", &self.peers)
+            .finish()
+    }
+}
+
+impl<P: Clone + Eq + Hash> ReconnectSupervisor<P> {
+    pub fn new(policy: ReconnectPolicy, seed: u64) -> Self {
+        Self {
+            policy,
+            rng: StdRng::seed_from_u64(seed),
+            peers: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &ReconnectPolicy {
+        &self.policy
+    }
+
+    /// Whether the peer is currently being reconnected
+    pub fn is_tracking(&self, peer: &P) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn on_connected(&mut self, peer: &P) {
+        if let Some(record) = self.peers.remove(peer) {
+            self.events.push(ReconnectEvent::Reconnected {
+                peer: peer.clone(),
+                attempts: record.attempts,
+            });
+        }
+    }
+
+    /// A peer went to NotConnected, either by dropping out or because our
+    /// invitation failed.
+    pub fn on_disconnected(&mut self, peer: &P, now: Instant) {
+        if self.peers.contains_key(peer) {
+            self.on_invite_failed(peer, now);
+            return;
+        }
+        self.peers.insert(
+            peer.clone(),
+            PeerRecord {
+                attempts: 0,
+                lost_at: now,
+                found: false,
+                next_attempt: None,
+                in_flight: false,
+            },
+        );
+        self.events
+            .push(ReconnectEvent::Lost { peer: peer.clone() });
+    }
+
+    /// An invitation failed before the peer connected. Settles our attempt
+    /// if it was one, peers that were never connected aren't tracked.
+    pub fn on_invite_failed(&mut self, peer: &P, now: Instant) {
+        let Some(record) = self.peers.get_mut(peer) else {
+            return;
+        };
+        if !record.in_flight {
+            return;
+        }
+        record.in_flight = false;
+
+        if record.attempts >= self.policy.max_attempts {
+            let attempts = record.attempts;
+            self.peers.remove(peer);
+            self.events.push(ReconnectEvent::GaveUp {
+                peer: peer.clone(),
+                attempts,
+            });
+        } else if record.found {
+            self.schedule(peer, now);
+        }
+    }
+
+    /// The browser sees the peer (again).
+    pub fn on_found(&mut self, peer: &P, now: Instant) {
+        let Some(record) = self.peers.get_mut(peer) else {
+            return;
+        };
+        record.found = true;
+        if record.next_attempt.is_none() && !record.in_flight {
+            self.schedule(peer, now);
+        }
+    }
+
+    /// The browser lost sight of the peer; pending attempts wait until it is
+    /// found again.
+    pub fn on_lost(&mut self, peer: &P, now: Instant) {
+        if let Some(record) = self.peers.get_mut(peer) {
+            record.found = false;
+            record.next_attempt = None;
+            record.lost_at = now;
+        }
+    }
+
+    /// Stop tracking a peer, e.g. after it said goodbye
+    pub fn forget(&mut self, peer: &P) {
+        self.peers.remove(peer);
+    }
+
+    /// Peers whose invitation is due. The caller is expected to invite them
+    /// and report the outcome through `on_connected`/`on_disconnected`.
+    pub fn poll(&mut self, now: Instant) -> Vec<P> {
+        let mut due = Vec::new();
+        let mut forgotten = Vec::new();
+
+        for (peer, record) in self.peers.iter_mut() {
+            if !record.found
+                && !record.in_flight
+                && now.saturating_duration_since(record.lost_at) >= self.policy.remember_for
+            {
+                forgotten.push(peer.clone());
+                continue;
+            }
+            if record.next_attempt.is_some_and(|at| at <= now) {
+                record.next_attempt = None;
+                record.in_flight = true;
+                record.attempts += 1;
+                due.push(peer.clone());
+                self.events.push(ReconnectEvent::Attempt {
+                    peer: peer.clone(),
+                    attempt: record.attempts,
+                });
+            }
+        }
+
+        for peer in forgotten {
+            self.peers.remove(&peer);
+            self.events.push(ReconnectEvent::Forgotten { peer });
+        }
+
+        due
+    }
+
+    /// Earliest instant `poll` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter_map(|record| {
+                record.next_attempt.or_else(|| {
+                    (!record.found && !record.in_flight)
+                        .then(|| record.lost_at + self.policy.remember_for)
+                })
+            })
+            .min()
+    }
+
+    pub fn drain_events(&mut self) -> Vec<ReconnectEvent<P>> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn schedule(&mut self, peer: &P, now: Instant) {
+        let Some(record) = self.peers.get_mut(peer) else {
+            return;
+        };
+        let attempt = record.attempts + 1;
+        let base = self.policy.backoff(attempt).as_secs_f64();
+        let delay = if self.policy.jitter > 0.0 {
+            let spread = base * self.policy.jitter;
+            Duration::from_secs_f64(
+                self.rng
+                    .gen_range((base - spread)..=(base + spread))
+                    .max(0.0),
+            )
+        } else {
+            Duration::from_secs_f64(base)
+        };
+
+        record.next_attempt = Some(now + delay);
+        self.events.push(ReconnectEvent::Scheduled {
+            peer: peer.clone(),
+            attempt,
+            delay,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter,
+            max_attempts: 4,
+            remember_for: Duration::from_secs(60),
+        }
+    }
+
+    // Drops the peer and has the browser see it again right away
+    fn lose(supervisor: &mut ReconnectSupervisor<&'static str>, now: Instant) {
+        supervisor.on_disconnected(&"b", now);
+        supervisor.on_found(&"b", now);
+    }
+
+    // Lets every scheduled attempt fail, returning the delays in order
+    fn fail_all(supervisor: &mut ReconnectSupervisor<&'static str>) -> Vec<Duration> {
+        let mut delays = Vec::new();
+        while let Some(at) = supervisor.next_deadline() {
+            if !supervisor.is_tracking(&"b") {
+                break;
+            }
+            assert_eq!(supervisor.poll(at), vec!["b"]);
+            supervisor.on_disconnected(&"b", at);
+        }
+        for event in supervisor.drain_events() {
+            if let ReconnectEvent::Scheduled { delay, .. } = event {
+                delays.push(delay);
+            }
+        }
+        delays
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        let policy = policy(0.0);
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| policy.backoff(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn attempts_follow_the_backoff_without_jitter() {
+        let now = Instant::now();
+        let mut supervisor = ReconnectSupervisor::new(policy(0.0), 0);
+        lose(&mut supervisor, now);
+        let delays = fail_all(&mut supervisor);
+        let expected: Vec<Duration> = (1..=4)
+            .map(|attempt| policy(0.0).backoff(attempt))
+            .collect();
+        assert_eq!(delays, expected);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = policy(0.5);
+        for seed in 0..50 {
+            let now = Instant::now();
+            let mut supervisor = ReconnectSupervisor::new(policy.clone(), seed);
+            lose(&mut supervisor, now);
+            for (i, delay) in fail_all(&mut supervisor).into_iter().enumerate() {
+                let base = policy.backoff(i as u32 + 1).as_secs_f64();
+                let delay = delay.as_secs_f64();
+                assert!(
+                    delay >= base * 0.5 - 1e-9,
+                    "seed {}: {} below bound",
+                    seed,
+                    delay
+                );
+                assert!(
+                    delay <= base * 1.5 + 1e-9,
+                    "seed {}: {} above bound",
+                    seed,
+                    delay
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_attempt_budget() {
+        let now = Instant::now();
+        let mut supervisor = ReconnectSupervisor::new(policy(0.2), 7);
+        lose(&mut supervisor, now);
+        let delays = fail_all(&mut supervisor);
+        assert_eq!(delays.len(), 4);
+        assert!(!supervisor.is_tracking(&"b"));
+
+        // Only the last failure gives up, after the fourth attempt
+        lose(&mut supervisor, now);
+        let mut events = Vec::new();
+        while let Some(at) = supervisor.next_deadline() {
+            supervisor.poll(at);
+            supervisor.on_disconnected(&"b", at);
+            events.extend(supervisor.drain_events());
+            if !supervisor.is_tracking(&"b") {
+                break;
+            }
+        }
+        let attempts = events
+            .iter()
+            .filter(|event| matches!(event, ReconnectEvent::Attempt { .. }))
+            .count();
+        assert_eq!(attempts, 4);
+        assert_eq!(
+            events.last(),
+            Some(&ReconnectEvent::GaveUp {
+                peer: "b",
+                attempts: 4
+            })
+        );
+    }
+
+    #[test]
+    fn reconnecting_resets_the_budget() {
+        let now = Instant::now();
+        let mut supervisor = ReconnectSupervisor::new(policy(0.0), 0);
+        lose(&mut supervisor, now);
+        let at = supervisor.next_deadline().unwrap();
+        assert_eq!(supervisor.poll(at), vec!["b"]);
+        supervisor.on_connected(&"b");
+        assert!(!supervisor.is_tracking(&"b"));
+        supervisor.drain_events();
+
+        lose(&mut supervisor, at);
+        assert_eq!(fail_all(&mut supervisor).len(), 4);
+    }
+
+    #[test]
+    fn failed_invites_only_settle_attempts() {
+        let now = Instant::now();
+        let mut supervisor = ReconnectSupervisor::new(policy(0.0), 0);
+        supervisor.on_invite_failed(&"b", now);
+        assert!(!supervisor.is_tracking(&"b"));
+        assert!(supervisor.drain_events().is_empty());
+
+        lose(&mut supervisor, now);
+        let at = supervisor.next_deadline().unwrap();
+        assert_eq!(supervisor.poll(at), vec!["b"]);
+        supervisor.drain_events();
+        supervisor.on_invite_failed(&"b", at);
+        assert_eq!(
+            supervisor.drain_events(),
+            [ReconnectEvent::Scheduled {
+                peer: "b",
+                attempt: 2,
+                delay: policy(0.0).backoff(2)
+            }]
+        );
+        // Nothing in flight any more, a stray failure changes nothing
+        supervisor.on_invite_failed(&"b", at);
+        assert!(supervisor.drain_events().is_empty());
+    }
+
+    #[test]
+    fn out_of_range_peers_wait_and_are_forgotten() {
+        let now = Instant::now();
+        let mut supervisor = ReconnectSupervisor::new(policy(0.0), 0);
+        supervisor.on_disconnected(&"b", now);
+        assert!(supervisor.poll(now + Duration::from_secs(59)).is_empty());
+        assert!(supervisor.is_tracking(&"b"));
+
+        supervisor.poll(now + Duration::from_secs(60));
+        assert!(!supervisor.is_tracking(&"b"));
+        assert!(
+            supervisor
+                .drain_events()
+                .contains(&ReconnectEvent::Forgotten { peer: "b" })
+        );
+    }
+}
@@ -0,0 +1,597 @@
+// Acknowledged delivery on top of unreliable sends.
+//
+// MPC's reliable mode is a single ordered stream per peer, so one lost packet
+// holds up everything behind it. This layer keeps MPC in unreliable mode and
+// adds its own sequence numbers, selective acks and retransmission timers.
+// Messages are handed over as soon as they arrive, in whatever order.
+//
+// Like the reconnect supervisor this is a pure state machine: feed it frames
+// and the current time, send whatever `poll_transmit` returns.
+//
+// The delivery mode is picked by the sender and travels in the frame flags,
+// so a peer acks and dedups correctly without any local configuration.
+//
+// Reliable frame payload: [seq: u64][floor: u64][data]
+//   `floor` is the lowest sequence number the sender still cares about, so the
+//   receiver can drop dedup state for messages the sender gave up on.
+// Ack frame payload: [cumulative: u64][range count: u8][(start: u64, end: u64)*]
+//   everything below `cumulative` has been received, ranges are inclusive.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use crate::frame::{Frame, FrameKind};
+
+/// Frame flag asking the receiver to suppress duplicates
+pub const FLAG_EXACTLY_ONCE: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Every received copy is handed over, retransmissions may show up twice
+    AtLeastOnce,
+    /// Duplicates are suppressed on the receiving side
+    ExactlyOnce,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReliableConfig {
+    pub delivery: Delivery,
+    /// Retransmission timeout used until we have an RTT sample
+    pub initial_rto: Duration,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Retransmissions before a message is reported as failed
+    pub max_retransmits: u32,
+    /// Maximum number of unacknowledged messages
+    pub window: usize,
+    /// Maximum number of selective ack ranges per ack frame
+    pub max_sack_ranges: usize,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        Self {
+            delivery: Delivery::ExactlyOnce,
+            initial_rto: Duration::from_millis(200),
+            min_rto: Duration::from_millis(50),
+            max_rto: Duration::from_secs(5),
+            max_retransmits: 10,
+            window: 256,
+            max_sack_ranges: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReliableEvent {
+    /// The peer acknowledged the message
+    Acked { seq: u64 },
+    /// Retransmission budget exhausted without an ack
+    Failed { seq: u64 },
+}
+
+#[derive(Debug)]
+struct Pending {
+    payload: Vec<u8>,
+    sent_at: Instant,
+    deadline: Instant,
+    retransmits: u32,
+}
+
+#[derive(Debug)]
+pub struct ReliableChannel {
+    config: ReliableConfig,
+
+    // Sending side
+    next_seq: u64,
+    in_flight: BTreeMap<u64, Pending>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+
+    // Receiving side
+    recv_base: u64,
+    received: BTreeSet<u64>,
+    ack_pending: bool,
+
+    outgoing: Vec<Frame>,
+    events: Vec<ReliableEvent>,
+}
+
+impl ReliableChannel {
+    pub fn new(config: ReliableConfig) -> Self {
+        let rto = config.initial_rto;
+        Self {
+            config,
+            next_seq: 0,
+            in_flight: BTreeMap::new(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto,
+            recv_base: 0,
+            received: BTreeSet::new(),
+            ack_pending: false,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &ReliableConfig {
+        &self.config
+    }
+
+    /// Queue a message, returning its sequence number
+    pub fn send(&mut self, payload: &[u8], now: Instant) -> Result<u64, String> {
+        if self.in_flight.len() >= self.config.window {
+            return Err(format!(
+                "Send window full ({} unacknowledged)",
+                self.in_flight.len()
+            ));
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.insert(
+            seq,
+            Pending {
+                payload: payload.to_vec(),
+                sent_at: now,
+                deadline: now + self.rto,
+                retransmits: 0,
+            },
+        );
+        let frame = self.data_frame(seq, payload);
+        self.outgoing.push(frame);
+        Ok(seq)
+    }
+
+    /// Handle a `Reliable` or `Ack` frame from the peer. Returns the payload
+    /// to hand to the application, if any.
+    pub fn on_frame(&mut self, frame: &Frame, now: Instant) -> Result<Option<Vec<u8>>, String> {
+        match frame.kind {
+            FrameKind::Reliable => {
+                let exactly_once = frame.flags & FLAG_EXACTLY_ONCE != 0;
+                self.on_data(&frame.payload, exactly_once)
+            }
+            FrameKind::Ack => {
+                self.on_ack(&frame.payload, now)?;
+                Ok(None)
+            }
+            other => Err(format!("Unexpected {:?} frame on reliable channel", other)),
+        }
+    }
+
+    /// Frames to put on the wire: new messages, retransmissions and acks.
+    /// All of them are meant to be sent in unreliable mode.
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<Frame> {
+        let expired: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        for seq in expired {
+            let Some(pending) = self.in_flight.get_mut(&seq) else {
+                continue;
+            };
+            if pending.retransmits >= self.config.max_retransmits {
+                self.in_flight.remove(&seq);
+                self.events.push(ReliableEvent::Failed { seq });
+                continue;
+            }
+
+            pending.retransmits += 1;
+            let backoff = self.rto.saturating_mul(1 << pending.retransmits.min(16));
+            pending.deadline = now + backoff.min(self.config.max_rto);
+            let payload = pending.payload.clone();
+            let frame = self.data_frame(seq, &payload);
+            self.outgoing.push(frame);
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            let frame = self.ack_frame();
+            self.outgoing.push(frame);
+        }
+
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Earliest instant a retransmission timer fires
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.in_flight
+            .values()
+            .map(|pending| pending.deadline)
+            .min()
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        self.ack_pending || !self.outgoing.is_empty()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Give up on everything in flight, e.g. because the peer disconnected
+    pub fn fail_all(&mut self) {
+        let failed = std::mem::take(&mut self.in_flight);
+        self.events
+            .extend(failed.into_keys().map(|seq| ReliableEvent::Failed { seq }));
+    }
+
+    pub fn drain_events(&mut self) -> Vec<ReliableEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn floor(&self) -> u64 {
+        self.in_flight
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.next_seq)
+    }
+
+    fn data_frame(&self, seq: u64, payload: &[u8]) -> Frame {
+        let mut body = Vec::with_capacity(16 + payload.len());
+        body.extend_from_slice(&seq.to_be_bytes());
+        body.extend_from_slice(&self.floor().to_be_bytes());
+        body.extend_from_slice(payload);
+        let mut frame = Frame::new(FrameKind::Reliable, body);
+        if self.config.delivery == Delivery::ExactlyOnce {
+            frame.flags |= FLAG_EXACTLY_ONCE;
+        }
+        frame
+    }
+
+    fn ack_frame(&self) -> Frame {
+        let ranges = self.sack_ranges();
+        let mut body = Vec::with_capacity(9 + ranges.len() * 16);
+        body.extend_from_slice(&self.recv_base.to_be_bytes());
+        body.push(ranges.len() as u8);
+        for (start, end) in ranges {
+            body.extend_from_slice(&start.to_be_bytes());
+            body.extend_from_slice(&end.to_be_bytes());
+        }
+        Frame::new(FrameKind::Ack, body)
+    }
+
+    // Contiguous runs in the out-of-order receive set
+    fn sack_ranges(&self) -> Vec<(u64, u64)> {
+        let limit = self.config.max_sack_ranges.min(u8::MAX as usize);
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for &seq in &self.received {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == seq => *end = seq,
+                _ => {
+                    if ranges.len() == limit {
+                        break;
+                    }
+                    ranges.push((seq, seq));
+                }
+            }
+        }
+        ranges
+    }
+
+    fn on_data(&mut self, body: &[u8], exactly_once: bool) -> Result<Option<Vec<u8>>, String> {
+        if body.len() < 16 {
+            return Err(format!("Truncated reliable frame ({} bytes)", body.len()));
+        }
+        let seq = read_u64(&body[0..8]);
+        let floor = read_u64(&body[8..16]);
+        let data = &body[16..];
+        // The sender's floor is the lowest message it has in flight, this
+        // one included
+        if floor > seq {
+            return Err(format!("Reliable frame {} below its floor {}", seq, floor));
+        }
+
+        // Only messages within a window of the base are kept track of, so a
+        // peer can't make us remember arbitrarily many. The sender retries
+        // whatever is dropped here once the base has caught up.
+        let base = self.recv_base.max(floor);
+        match base.checked_add(self.config.window as u64) {
+            Some(end) if seq < end => {}
+            _ => return Ok(None),
+        }
+
+        // Anything below the floor was acked or abandoned by the sender
+        if floor > self.recv_base {
+            self.recv_base = floor;
+            self.received = self.received.split_off(&floor);
+            self.advance_base();
+        }
+
+        self.ack_pending = true;
+
+        let duplicate = seq < self.recv_base || self.received.contains(&seq);
+        if !duplicate {
+            self.received.insert(seq);
+            self.advance_base();
+        }
+
+        if duplicate && exactly_once {
+            return Ok(None);
+        }
+        Ok(Some(data.to_vec()))
+    }
+
+    // Everything in `received` is below `recv_base + window`, which fits in a
+    // u64, so the base can't overflow here
+    fn advance_base(&mut self) {
+        while self.received.remove(&self.recv_base) {
+            self.recv_base += 1;
+        }
+    }
+
+    fn on_ack(&mut self, body: &[u8], now: Instant) -> Result<(), String> {
+        if body.len() < 9 {
+            return Err(format!("Truncated ack frame ({} bytes)", body.len()));
+        }
+        let cumulative = read_u64(&body[0..8]);
+        let count = body[8] as usize;
+        if body.len() < 9 + count * 16 {
+            return Err(format!(
+                "Ack frame announces {} ranges but is too short",
+                count
+            ));
+        }
+
+        let mut acked: Vec<u64> = self
+            .in_flight
+            .range(..cumulative)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for i in 0..count {
+            let offset = 9 + i * 16;
+            let start = read_u64(&body[offset..offset + 8]);
+            let end = read_u64(&body[offset + 8..offset + 16]);
+            if start > end {
+                return Err(format!("Invalid ack range {}..={}", start, end));
+            }
+            acked.extend(self.in_flight.range(start..=end).map(|(seq, _)| *seq));
+        }
+
+        for seq in acked {
+            let Some(pending) = self.in_flight.remove(&seq) else {
+                continue;
+            };
+            // Karn's algorithm: only sample messages that were sent once
+            if pending.retransmits == 0 {
+                self.update_rtt(now.saturating_duration_since(pending.sent_at));
+            }
+            self.events.push(ReliableEvent::Acked { seq });
+        }
+        Ok(())
+    }
+
+    // RFC 6298 smoothing
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap_or(sample) + self.rttvar * 4;
+        self.rto = rto.clamp(self.config.min_rto, self.config.max_rto);
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::sim::{LinkConfig, PeerState, SimConfig, SimEventKind, SimNetwork};
+
+    const MESSAGES: u32 = 200;
+
+    struct Outcome {
+        // How often each message reached the receiver
+        received: BTreeMap<u32, usize>,
+        arrival_order: Vec<u32>,
+        acked: usize,
+        failed: usize,
+    }
+
+    // Sends `MESSAGES` messages from "a" to "b" over a simulated link until
+    // every one of them is acked or failed. The driver duplicates a frame
+    // with probability `duplicate`, which the simulator can't do by itself.
+    fn transfer(delivery: Delivery, link: LinkConfig, duplicate: f64, seed: u64) -> Outcome {
+        let mut sim = SimNetwork::new(SimConfig {
+            seed,
+            default_link: link,
+            ..SimConfig::default()
+        });
+        sim.add_device("a").unwrap();
+        sim.add_device("b").unwrap();
+        sim.advance(Duration::from_millis(200));
+        sim.invite("a", "b").unwrap();
+        sim.advance(Duration::from_millis(200));
+        assert_eq!(sim.state("a", "b"), PeerState::Connected);
+        sim.drain_events();
+
+        let config = ReliableConfig {
+            delivery,
+            max_retransmits: 20,
+            ..ReliableConfig::default()
+        };
+        let mut sender = ReliableChannel::new(config.clone());
+        let mut receiver = ReliableChannel::new(config);
+        let mut rng = StdRng::seed_from_u64(seed);
+        for i in 0..MESSAGES {
+            sender.send(&i.to_be_bytes(), sim.now()).unwrap();
+        }
+
+        let mut outcome = Outcome {
+            received: BTreeMap::new(),
+            arrival_order: Vec::new(),
+            acked: 0,
+            failed: 0,
+        };
+        let step = Duration::from_millis(1);
+        while outcome.acked + outcome.failed < MESSAGES as usize {
+            assert!(
+                sim.elapsed() < Duration::from_secs(120),
+                "transfer never finished"
+            );
+
+            let now = sim.now();
+            for (from, to, channel) in [("a", "b", &mut sender), ("b", "a", &mut receiver)] {
+                for frame in channel.poll_transmit(now) {
+                    let bytes = frame.encode();
+                    sim.send(from, to, &bytes, false).unwrap();
+                    if rng.gen_bool(duplicate) {
+                        sim.send(from, to, &bytes, false).unwrap();
+                    }
+                }
+            }
+
+            sim.advance(step);
+            let now = sim.now();
+            for event in sim.drain_events() {
+                let SimEventKind::Data { bytes, .. } = event.kind else {
+                    continue;
+                };
+                let frame = Frame::decode(&bytes).unwrap();
+                if event.device == "b" {
+                    if let Some(payload) = receiver.on_frame(&frame, now).unwrap() {
+                        let i = u32::from_be_bytes(payload.try_into().unwrap());
+                        *outcome.received.entry(i).or_default() += 1;
+                        outcome.arrival_order.push(i);
+                    }
+                } else {
+                    sender.on_frame(&frame, now).unwrap();
+                }
+            }
+
+            for event in sender.drain_events() {
+                match event {
+                    ReliableEvent::Acked { .. } => outcome.acked += 1,
+                    ReliableEvent::Failed { .. } => outcome.failed += 1,
+                }
+            }
+        }
+        outcome
+    }
+
+    fn lossy_link() -> LinkConfig {
+        LinkConfig {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(10),
+            loss: 0.2,
+            reorder: 0.2,
+            reorder_delay: Duration::from_millis(30),
+            ..LinkConfig::default()
+        }
+    }
+
+    #[test]
+    fn exactly_once_survives_loss_reordering_and_duplicates() {
+        for seed in 0..4 {
+            let outcome = transfer(Delivery::ExactlyOnce, lossy_link(), 0.2, seed);
+            assert_eq!(outcome.acked, MESSAGES as usize, "seed {}", seed);
+            assert_eq!(outcome.failed, 0, "seed {}", seed);
+            assert_eq!(outcome.received.len(), MESSAGES as usize, "seed {}", seed);
+            assert!(
+                outcome.received.values().all(|&copies| copies == 1),
+                "seed {}: duplicate handed over",
+                seed
+            );
+            assert!(
+                !outcome.arrival_order.is_sorted(),
+                "seed {}: nothing was reordered",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn at_least_once_delivers_everything_and_may_repeat() {
+        let mut repeated = false;
+        for seed in 0..4 {
+            let outcome = transfer(Delivery::AtLeastOnce, lossy_link(), 0.2, seed);
+            assert_eq!(outcome.acked, MESSAGES as usize, "seed {}", seed);
+            assert_eq!(outcome.failed, 0, "seed {}", seed);
+            assert_eq!(outcome.received.len(), MESSAGES as usize, "seed {}", seed);
+            repeated |= outcome.received.values().any(|&copies| copies > 1);
+        }
+        assert!(
+            repeated,
+            "duplicates should reach an at-least-once receiver"
+        );
+    }
+
+    #[test]
+    fn clean_link_needs_no_retransmissions() {
+        let outcome = transfer(Delivery::ExactlyOnce, LinkConfig::default(), 0.0, 0);
+        assert_eq!(outcome.acked, MESSAGES as usize);
+        assert!(outcome.arrival_order.is_sorted());
+    }
+
+    fn reliable_frame(seq: u64, floor: u64) -> Frame {
+        let mut body = Vec::new();
+        body.extend_from_slice(&seq.to_be_bytes());
+        body.extend_from_slice(&floor.to_be_bytes());
+        body.extend_from_slice(b"x");
+        let mut frame = Frame::new(FrameKind::Reliable, body);
+        frame.flags |= FLAG_EXACTLY_ONCE;
+        frame
+    }
+
+    #[test]
+    fn frames_outside_the_window_are_dropped() {
+        let now = Instant::now();
+        let mut receiver = ReliableChannel::new(ReliableConfig::default());
+        let window = receiver.config().window as u64;
+
+        assert_eq!(receiver.on_frame(&reliable_frame(window, 0), now), Ok(None));
+        assert_eq!(
+            receiver.on_frame(&reliable_frame(u64::MAX, u64::MAX), now),
+            Ok(None)
+        );
+        assert!(receiver.on_frame(&reliable_frame(3, 5), now).is_err());
+        assert!(receiver.received.is_empty());
+        assert!(!receiver.has_pending_output());
+
+        // The edge of the window is still fine, and so is a floor moving it
+        let edge = receiver.on_frame(&reliable_frame(window - 1, 0), now);
+        assert_eq!(edge, Ok(Some(b"x".to_vec())));
+        let moved = receiver.on_frame(&reliable_frame(1000, 1000), now);
+        assert_eq!(moved, Ok(Some(b"x".to_vec())));
+        assert_eq!(receiver.recv_base, 1001);
+        assert!(receiver.received.is_empty());
+    }
+
+    #[test]
+    fn dead_link_fails_every_message() {
+        let link = LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        };
+        let outcome = transfer(Delivery::ExactlyOnce, link, 0.0, 0);
+        assert_eq!(outcome.failed, MESSAGES as usize);
+        assert!(outcome.received.is_empty());
+    }
+}
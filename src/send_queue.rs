@@ -0,0 +1,385 @@
+// Bounded per-peer outbound queue.
+//
+// Each peer gets one lane per priority class so a backlog of bulk data can
+// never hold up control messages. Producers wait asynchronously for room in
+// their lane; a single dispatcher drains the queue, always taking the highest
+// priority message available and rotating between peers within a class.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Control = 0,
+    Interactive = 1,
+    Bulk = 2,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Control, Priority::Interactive, Priority::Bulk];
+}
+
+/// What to do with an unreliable message when its lane is full. Reliable
+/// messages always wait for room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the dispatcher to make room
+    Block,
+    /// Evict the oldest unreliable message in the lane
+    DropOldest,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Maximum messages per peer and priority class, at least 1
+    pub capacity: usize,
+    pub unreliable_policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            unreliable_policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct OutboundMessage {
+    pub priority: Priority,
+    pub reliable: bool,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for OutboundMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboundMessage")
+            .field("priority", &self.priority)
+            .field("reliable", &self.reliable)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub enqueued: u64,
+    pub dispatched: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct PeerLanes {
+    lanes: [VecDeque<OutboundMessage>; 3],
+}
+
+impl PeerLanes {
+    fn len(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.len()).sum()
+    }
+}
+
+#[derive(Debug)]
+struct Inner<P> {
+    peers: HashMap<P, PeerLanes>,
+    // Rotation order for fairness between peers
+    order: Vec<P>,
+    cursor: usize,
+    closed: bool,
+    stats: QueueStats,
+}
+
+enum Push {
+    Queued,
+    Full(OutboundMessage),
+    Closed,
+}
+
+#[derive(Debug)]
+pub struct SendQueue<P> {
+    config: QueueConfig,
+    inner: Mutex<Inner<P>>,
+    // Wakes producers waiting for room
+    space: Notify,
+    // Wakes the dispatcher waiting for messages
+    available: Condvar,
+}
+
+impl<P: Clone + Eq + Hash> SendQueue<P> {
+    pub fn new(mut config: QueueConfig) -> Self {
+        // Without room in a lane blocked producers would wait forever
+        config.capacity = config.capacity.max(1);
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                peers: HashMap::new(),
+                order: Vec::new(),
+                cursor: 0,
+                closed: false,
+                stats: QueueStats::default(),
+            }),
+            space: Notify::new(),
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Queue a message, waiting for room if the lane is full.
+    pub async fn send(&self, peer: P, message: OutboundMessage) -> Result<(), String> {
+        let mut message = message;
+        loop {
+            // Register interest before checking so a pop in between isn't missed
+            let notified = self.space.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.push(&peer, message) {
+                Push::Queued => return Ok(()),
+                Push::Closed => return Err("Send queue closed".to_string()),
+                Push::Full(returned) => {
+                    message = returned;
+                    notified.await;
+                }
+            }
+        }
+    }
+
+    /// Queue a message without waiting. Fails if the lane is full and the
+    /// overflow policy doesn't allow dropping.
+    pub fn try_send(&self, peer: P, message: OutboundMessage) -> Result<(), String> {
+        match self.push(&peer, message) {
+            Push::Queued => Ok(()),
+            Push::Full(message) => Err(format!(
+                "Send queue full for {:?} messages",
+                message.priority
+            )),
+            Push::Closed => Err("Send queue closed".to_string()),
+        }
+    }
+
+    fn push(&self, peer: &P, message: OutboundMessage) -> Push {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return Push::Closed;
+        }
+
+        if !inner.peers.contains_key(peer) {
+            inner.peers.insert(peer.clone(), PeerLanes::default());
+            inner.order.push(peer.clone());
+        }
+        let lane = &mut inner.peers.get_mut(peer).unwrap().lanes[message.priority as usize];
+
+        let mut dropped = 0;
+        if lane.len() >= self.config.capacity {
+            let can_drop =
+                !message.reliable && self.config.unreliable_policy == OverflowPolicy::DropOldest;
+            let victim = can_drop
+                .then(|| lane.iter().position(|queued| !queued.reliable))
+                .flatten();
+            match victim {
+                Some(index) => {
+                    lane.remove(index);
+                    dropped = 1;
+                }
+                None => return Push::Full(message),
+            }
+        }
+        lane.push_back(message);
+
+        inner.stats.enqueued += 1;
+        inner.stats.dropped += dropped;
+        self.available.notify_one();
+        Push::Queued
+    }
+
+    /// Take the next message to dispatch, waiting up to `timeout`. Returns
+    /// `None` on timeout or once the queue is closed and drained.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<(P, OutboundMessage)> {
+        let inner = self.inner.lock().unwrap();
+        let (mut inner, _) = self
+            .available
+            .wait_timeout_while(inner, timeout, |inner| {
+                !inner.closed && inner.peers.values().all(|lanes| lanes.len() == 0)
+            })
+            .unwrap();
+
+        let next = Self::pop(&mut inner);
+        if next.is_some() {
+            inner.stats.dispatched += 1;
+            self.space.notify_waiters();
+        }
+        next
+    }
+
+    fn pop(inner: &mut Inner<P>) -> Option<(P, OutboundMessage)> {
+        let count = inner.order.len();
+        for priority in Priority::ALL {
+            for offset in 0..count {
+                let index = (inner.cursor + offset) % count;
+                let peer = inner.order[index].clone();
+                let lanes = inner.peers.get_mut(&peer)?;
+                if let Some(message) = lanes.lanes[priority as usize].pop_front() {
+                    inner.cursor = (index + 1) % count;
+                    return Some((peer, message));
+                }
+            }
+        }
+        None
+    }
+
+    /// Forget everything queued for a peer, e.g. after it disconnected.
+    /// Returns the number of messages dropped.
+    pub fn remove_peer(&self, peer: &P) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(lanes) = inner.peers.remove(peer) else {
+            return 0;
+        };
+        inner.order.retain(|p| p != peer);
+        inner.cursor = 0;
+
+        let dropped = lanes.len();
+        inner.stats.dropped += dropped as u64;
+        self.space.notify_waiters();
+        dropped
+    }
+
+    /// Reject new messages and wake everybody up. Already queued messages
+    /// can still be drained.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.space.notify_waiters();
+        self.available.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().closed
+    }
+
+    pub fn len(&self, peer: &P) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .get(peer)
+            .map_or(0, |lanes| lanes.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .values()
+            .all(|lanes| lanes.len() == 0)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(priority: Priority, reliable: bool, data: u8) -> OutboundMessage {
+        OutboundMessage {
+            priority,
+            reliable,
+            data: vec![data],
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn zero_capacity_still_takes_a_message() {
+        let queue = SendQueue::new(QueueConfig {
+            capacity: 0,
+            unreliable_policy: OverflowPolicy::Block,
+        });
+        assert_eq!(queue.config().capacity, 1);
+        block_on(queue.send("a", message(Priority::Bulk, true, 1))).unwrap();
+        assert_eq!(queue.len(&"a"), 1);
+    }
+
+    #[test]
+    fn control_goes_before_bulk() {
+        let queue = SendQueue::new(QueueConfig::default());
+        queue
+            .try_send("a", message(Priority::Bulk, true, 1))
+            .unwrap();
+        queue
+            .try_send("b", message(Priority::Interactive, true, 2))
+            .unwrap();
+        queue
+            .try_send("a", message(Priority::Control, true, 3))
+            .unwrap();
+
+        let order: Vec<u8> = (0..3)
+            .map(|_| queue.recv_timeout(Duration::ZERO).unwrap().1.data[0])
+            .collect();
+        assert_eq!(order, [3, 2, 1]);
+    }
+
+    #[test]
+    fn full_unreliable_lane_drops_the_oldest() {
+        let queue = SendQueue::new(QueueConfig {
+            capacity: 2,
+            unreliable_policy: OverflowPolicy::DropOldest,
+        });
+        for data in 1..=3 {
+            queue
+                .try_send("a", message(Priority::Bulk, false, data))
+                .unwrap();
+        }
+        assert!(
+            queue
+                .try_send("a", message(Priority::Bulk, true, 4))
+                .is_err()
+        );
+        assert_eq!(queue.stats().dropped, 1);
+        assert_eq!(queue.recv_timeout(Duration::ZERO).unwrap().1.data, [2]);
+    }
+
+    #[test]
+    fn blocked_senders_wait_for_the_consumer() {
+        let queue = SendQueue::new(QueueConfig {
+            capacity: 1,
+            unreliable_policy: OverflowPolicy::Block,
+        });
+        queue
+            .try_send("a", message(Priority::Bulk, false, 1))
+            .unwrap();
+        let taken = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                // Set first, the sender may wake before `recv_timeout` returns
+                taken.store(true, std::sync::atomic::Ordering::SeqCst);
+                let (_, first) = queue.recv_timeout(Duration::ZERO).unwrap();
+                assert_eq!(first.data, [1]);
+            });
+            // Only gets room once the message before it is taken
+            block_on(queue.send("a", message(Priority::Bulk, false, 2))).unwrap();
+            assert!(taken.load(std::sync::atomic::Ordering::SeqCst));
+        });
+        assert_eq!(queue.recv_timeout(Duration::ZERO).unwrap().1.data, [2]);
+        assert_eq!(queue.stats().dropped, 0);
+    }
+}
@@ -0,0 +1,179 @@
+// Assignment of peers to MCSession shards.
+//
+// A single MCSession holds at most 8 peers including ourselves. The pool
+// keeps track of which shard every connecting or connected peer lives in and
+// decides where a new peer goes: the first shard with room, or a brand new
+// one once all of them are full. Shards are numbered from 0 and the numbers
+// are stable, an empty shard is simply reused later.
+//
+// Pure bookkeeping, the session creates the actual MCSession instances.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Remote peers a single MCSession can hold
+pub const MAX_PEERS_PER_SESSION: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub shard: usize,
+    /// The shard didn't exist before and has to be created
+    pub new_shard: bool,
+}
+
+#[derive(Debug)]
+pub struct SessionPool<P> {
+    capacity: usize,
+    shards: Vec<HashSet<P>>,
+    assigned: HashMap<P, usize>,
+}
+
+impl<P: Clone + Eq + Hash> Default for SessionPool<P> {
+    fn default() -> Self {
+        Self::new(MAX_PEERS_PER_SESSION)
+    }
+}
+
+impl<P: Clone + Eq + Hash> SessionPool<P> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            // There is always at least one session
+            shards: vec![HashSet::new()],
+            assigned: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Shard `peer` belongs in. A peer that is already assigned keeps its
+    /// shard.
+    pub fn assign(&mut self, peer: &P) -> Assignment {
+        if let Some(&shard) = self.assigned.get(peer) {
+            return Assignment {
+                shard,
+                new_shard: false,
+            };
+        }
+
+        let free = self
+            .shards
+            .iter()
+            .position(|peers| peers.len() < self.capacity);
+        let assignment = match free {
+            Some(shard) => Assignment {
+                shard,
+                new_shard: false,
+            },
+            None => {
+                self.shards.push(HashSet::new());
+                Assignment {
+                    shard: self.shards.len() - 1,
+                    new_shard: true,
+                }
+            }
+        };
+
+        self.shards[assignment.shard].insert(peer.clone());
+        self.assigned.insert(peer.clone(), assignment.shard);
+        assignment
+    }
+
+    /// Free the slot `peer` occupied, returning its shard
+    pub fn release(&mut self, peer: &P) -> Option<usize> {
+        let shard = self.assigned.remove(peer)?;
+        self.shards[shard].remove(peer);
+        Some(shard)
+    }
+
+    pub fn shard_of(&self, peer: &P) -> Option<usize> {
+        self.assigned.get(peer).copied()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_len(&self, shard: usize) -> usize {
+        self.shards.get(shard).map_or(0, |peers| peers.len())
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &P> {
+        self.assigned.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.assigned.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assigned.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_fill_a_shard_before_the_next_one_opens() {
+        let mut pool = SessionPool::new(2);
+        assert_eq!(pool.assign(&"a").shard, 0);
+        assert_eq!(pool.assign(&"b").shard, 0);
+        assert_eq!(
+            pool.assign(&"c"),
+            Assignment {
+                shard: 1,
+                new_shard: true
+            }
+        );
+        assert_eq!(pool.shard_count(), 2);
+        assert_eq!(pool.shard_len(0), 2);
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn assigned_peers_keep_their_shard() {
+        let mut pool = SessionPool::new(1);
+        pool.assign(&"a");
+        pool.assign(&"b");
+        assert_eq!(
+            pool.assign(&"b"),
+            Assignment {
+                shard: 1,
+                new_shard: false
+            }
+        );
+        assert_eq!(pool.shard_count(), 2);
+    }
+
+    #[test]
+    fn released_slots_are_reused() {
+        let mut pool = SessionPool::new(2);
+        for peer in ["a", "b", "c"] {
+            pool.assign(&peer);
+        }
+        assert_eq!(pool.release(&"a"), Some(0));
+        assert_eq!(pool.release(&"a"), None);
+        assert_eq!(pool.shard_of(&"a"), None);
+
+        assert_eq!(
+            pool.assign(&"d"),
+            Assignment {
+                shard: 0,
+                new_shard: false
+            }
+        );
+        assert_eq!(pool.shard_count(), 2);
+    }
+
+    #[test]
+    fn capacity_is_at_least_one() {
+        let pool: SessionPool<&str> = SessionPool::new(0);
+        assert_eq!(pool.capacity(), 1);
+        assert_eq!(pool.shard_count(), 1);
+        assert!(pool.is_empty());
+    }
+}
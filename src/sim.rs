@@ -0,0 +1,914 @@
+// Simulated link layer between named in-process devices.
+//
+// Everything runs on a virtual clock that only moves when `advance` is
+// called, and every random decision comes from one seeded rng, so a run is
+// fully reproducible from its seed. Devices see the same things an MPC
+// session would: peers being found and lost, connection state changes and
+// data arriving in reliable or unreliable mode.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Mirrors `MCSessionState` without pulling in MPC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerState {
+    NotConnected,
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkConfig {
+    /// One-way delay
+    pub latency: Duration,
+    /// Extra delay drawn uniformly from zero to this value
+    pub jitter: Duration,
+    /// Probability an unreliable message is lost
+    pub loss: f64,
+    /// Bytes per second, `None` for unlimited
+    pub bandwidth: Option<u64>,
+    /// Probability an unreliable message is held back by `reorder_delay`
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(5),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            bandwidth: None,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(20),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    /// Link used between devices that have no specific configuration
+    pub default_link: LinkConfig,
+    /// Delay between two devices coming into range and seeing each other
+    pub discovery_delay: Duration,
+    /// Delay between a link breaking and both sides noticing
+    pub disconnect_timeout: Duration,
+    /// How long an invitation waits before failing
+    pub invite_timeout: Duration,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            default_link: LinkConfig::default(),
+            discovery_delay: Duration::from_millis(100),
+            disconnect_timeout: Duration::from_secs(2),
+            invite_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum SimEventKind {
+    PeerFound {
+        peer: String,
+    },
+    PeerLost {
+        peer: String,
+    },
+    StateChanged {
+        peer: String,
+        state: PeerState,
+    },
+    Data {
+        peer: String,
+        bytes: Vec<u8>,
+        reliable: bool,
+    },
+}
+
+impl fmt::Debug for SimEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimEventKind::PeerFound { peer } => write!(f, "PeerFound({})", peer),
+            SimEventKind::PeerLost { peer } => write!(f, "PeerLost({})", peer),
+            SimEventKind::StateChanged { peer, state } => {
+                write!(f, "StateChanged({}, {:?})", peer, state)
+            }
+            SimEventKind::Data {
+                peer,
+                bytes,
+                reliable,
+            } => write!(
+                f,
+                "Data({}, {} bytes, reliable={})",
+                peer,
+                bytes.len(),
+                reliable
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimEvent {
+    /// Time since the simulation started
+    pub at: Duration,
+    /// Device observing the event
+    pub device: String,
+    pub kind: SimEventKind,
+}
+
+/// Changes that can be scripted to happen at a given time
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimAction {
+    /// Split devices into groups that can't reach each other
+    Partition(Vec<Vec<String>>),
+    Heal,
+    SetInRange {
+        a: String,
+        b: String,
+        in_range: bool,
+    },
+    SetLink {
+        from: String,
+        to: String,
+        config: LinkConfig,
+    },
+    /// Drop every connection of a device, as `MCSession::disconnect` would
+    Disconnect {
+        device: String,
+    },
+}
+
+#[derive(Debug)]
+enum Pending {
+    Deliver {
+        from: String,
+        to: String,
+        bytes: Vec<u8>,
+        reliable: bool,
+    },
+    // `device` sees `peer`, if the two are still in range by then
+    Found {
+        device: String,
+        peer: String,
+    },
+    // `device` loses sight of `peer`, if it ever saw it
+    Lost {
+        device: String,
+        peer: String,
+    },
+    Action(SimAction),
+    // Answer to the invitation numbered `invite`, later ones replace it
+    InviteResult {
+        from: String,
+        to: String,
+        invite: u64,
+    },
+    LinkDown {
+        a: String,
+        b: String,
+    },
+    // `peer` notices that `device` hung up, unless its state moved on since
+    Hangup {
+        device: String,
+        peer: String,
+        state: PeerState,
+    },
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    at: Duration,
+    seq: u64,
+    pending: Pending,
+}
+
+// Earliest first, ties broken by scheduling order
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Scheduled {}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    busy_until: Duration,
+    // Reliable messages never overtake each other
+    last_reliable: Duration,
+}
+
+fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+pub struct SimNetwork {
+    config: SimConfig,
+    rng: StdRng,
+    base: Instant,
+    now: Duration,
+    seq: u64,
+    devices: BTreeSet<String>,
+    out_of_range: HashSet<(String, String)>,
+    partition: Option<HashMap<String, usize>>,
+    links: HashMap<(String, String), LinkConfig>,
+    link_state: HashMap<(String, String), LinkState>,
+    // Pairs that can currently see each other
+    visible: HashSet<(String, String)>,
+    // (device, peer) for every peer a device was told it found and not
+    // told it lost since
+    found: HashSet<(String, String)>,
+    // Number of the latest invitation from one device to another
+    invites: HashMap<(String, String), u64>,
+    next_invite: u64,
+    states: HashMap<(String, String), PeerState>,
+    queue: BinaryHeap<Scheduled>,
+    events: Vec<SimEvent>,
+}
+
+// Manual Debug implementation to skip the rng and the event queue
+impl fmt::Debug for SimNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimNetwork")
+            .field("now", &self.now)
+            .field("devices", &self.devices)
+            .field("pending", &self.queue.len())
+            .finish()
+    }
+}
+
+impl SimNetwork {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            base: Instant::now(),
+            now: Duration::ZERO,
+            seq: 0,
+            devices: BTreeSet::new(),
+            out_of_range: HashSet::new(),
+            partition: None,
+            links: HashMap::new(),
+            link_state: HashMap::new(),
+            visible: HashSet::new(),
+            found: HashSet::new(),
+            invites: HashMap::new(),
+            next_invite: 0,
+            states: HashMap::new(),
+            queue: BinaryHeap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.config.seed
+    }
+
+    /// Time since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.now
+    }
+
+    /// Virtual time as an `Instant`, for state machines that take `now`
+    pub fn now(&self) -> Instant {
+        self.base + self.now
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        self.devices.iter().map(|d| d.as_str())
+    }
+
+    /// Add a device, in range of everybody already there
+    pub fn add_device(&mut self, name: &str) -> Result<(), String> {
+        if !self.devices.insert(name.to_string()) {
+            return Err(format!("Device {} already exists", name));
+        }
+        self.refresh_visibility();
+        Ok(())
+    }
+
+    pub fn set_link(&mut self, from: &str, to: &str, config: LinkConfig) {
+        self.links
+            .insert((from.to_string(), to.to_string()), config);
+    }
+
+    /// Same link configuration in both directions
+    pub fn set_link_both(&mut self, a: &str, b: &str, config: LinkConfig) {
+        self.set_link(a, b, config.clone());
+        self.set_link(b, a, config);
+    }
+
+    pub fn set_in_range(&mut self, a: &str, b: &str, in_range: bool) {
+        if in_range {
+            self.out_of_range.remove(&pair(a, b));
+        } else {
+            self.out_of_range.insert(pair(a, b));
+        }
+        self.refresh_visibility();
+    }
+
+    pub fn partition(&mut self, groups: &[Vec<String>]) {
+        let mut assignment = HashMap::new();
+        for (index, group) in groups.iter().enumerate() {
+            for device in group {
+                assignment.insert(device.clone(), index);
+            }
+        }
+        self.partition = Some(assignment);
+        self.refresh_visibility();
+    }
+
+    pub fn heal(&mut self) {
+        self.partition = None;
+        self.refresh_visibility();
+    }
+
+    /// Run `action` once the clock reaches `at` (time since start)
+    pub fn schedule(&mut self, at: Duration, action: SimAction) {
+        self.push(at, Pending::Action(action));
+    }
+
+    pub fn reachable(&self, a: &str, b: &str) -> bool {
+        if a == b || self.out_of_range.contains(&pair(a, b)) {
+            return false;
+        }
+        match &self.partition {
+            Some(groups) => groups.get(a) == groups.get(b),
+            None => true,
+        }
+    }
+
+    pub fn state(&self, device: &str, peer: &str) -> PeerState {
+        self.states
+            .get(&(device.to_string(), peer.to_string()))
+            .copied()
+            .unwrap_or(PeerState::NotConnected)
+    }
+
+    pub fn connected_peers(&self, device: &str) -> Vec<String> {
+        self.devices
+            .iter()
+            .filter(|peer| self.state(device, peer) == PeerState::Connected)
+            .cloned()
+            .collect()
+    }
+
+    /// Invite `to` into a session with `from`. The invitation is accepted
+    /// if the two can reach each other when it arrives.
+    pub fn invite(&mut self, from: &str, to: &str) -> Result<(), String> {
+        self.check_device(from)?;
+        self.check_device(to)?;
+        if self.state(from, to) != PeerState::NotConnected {
+            return Err(format!("{} is already connecting to {}", from, to));
+        }
+
+        self.set_state(from, to, PeerState::Connecting);
+        self.next_invite += 1;
+        let invite = self.next_invite;
+        self.invites
+            .insert((from.to_string(), to.to_string()), invite);
+        // Invitation travels there and the answer comes back
+        let rtt = self.sample_delay(from, to) + self.sample_delay(to, from);
+        let at = if self.reachable(from, to) {
+            self.now + rtt
+        } else {
+            self.now + self.config.invite_timeout
+        };
+        self.push(
+            at,
+            Pending::InviteResult {
+                from: from.to_string(),
+                to: to.to_string(),
+                invite,
+            },
+        );
+        Ok(())
+    }
+
+    /// Drop every connection of `device`; peers notice after one latency
+    pub fn disconnect(&mut self, device: &str) {
+        let peers: Vec<String> = self
+            .devices
+            .iter()
+            .filter(|peer| self.state(device, peer) != PeerState::NotConnected)
+            .cloned()
+            .collect();
+        for peer in peers {
+            self.set_state(device, &peer, PeerState::NotConnected);
+            let delay = self.sample_delay(device, &peer);
+            let state = self.state(&peer, device);
+            self.push(
+                self.now + delay,
+                Pending::Hangup {
+                    device: device.to_string(),
+                    peer,
+                    state,
+                },
+            );
+        }
+    }
+
+    pub fn send(
+        &mut self,
+        from: &str,
+        to: &str,
+        bytes: &[u8],
+        reliable: bool,
+    ) -> Result<(), String> {
+        if self.state(from, to) != PeerState::Connected {
+            return Err(format!("{} is not connected to {}", from, to));
+        }
+
+        let link = self.link(from, to).clone();
+        if !reliable && link.loss > 0.0 && self.rng.gen_bool(link.loss.min(1.0)) {
+            return Ok(());
+        }
+
+        let tx_time = match link.bandwidth {
+            Some(rate) if rate > 0 => Duration::from_secs_f64(bytes.len() as f64 / rate as f64),
+            _ => Duration::ZERO,
+        };
+        let mut delay = self.sample_delay(from, to);
+        if !reliable && link.reorder > 0.0 && self.rng.gen_bool(link.reorder.min(1.0)) {
+            delay += link.reorder_delay;
+        }
+
+        let now = self.now;
+        let state = self
+            .link_state
+            .entry((from.to_string(), to.to_string()))
+            .or_default();
+        let departure = state.busy_until.max(now);
+        state.busy_until = departure + tx_time;
+        let mut arrival = departure + tx_time + delay;
+        if reliable {
+            arrival = arrival.max(state.last_reliable);
+            state.last_reliable = arrival;
+        }
+
+        self.push(
+            arrival,
+            Pending::Deliver {
+                from: from.to_string(),
+                to: to.to_string(),
+                bytes: bytes.to_vec(),
+                reliable,
+            },
+        );
+        Ok(())
+    }
+
+    /// Time of the next thing that will happen, if anything is pending
+    pub fn next_event_at(&self) -> Option<Duration> {
+        self.queue.peek().map(|scheduled| scheduled.at)
+    }
+
+    /// Move the clock forward, processing everything due on the way
+    pub fn advance(&mut self, by: Duration) {
+        let target = self.now + by;
+        self.run_until(target);
+    }
+
+    pub fn run_until(&mut self, target: Duration) {
+        while let Some(next) = self.queue.peek() {
+            if next.at > target {
+                break;
+            }
+            let scheduled = self.queue.pop().unwrap();
+            self.now = self.now.max(scheduled.at);
+            self.process(scheduled.pending);
+        }
+        self.now = self.now.max(target);
+    }
+
+    pub fn drain_events(&mut self) -> Vec<SimEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Events for one device, leaving the others queued
+    pub fn drain_events_for(&mut self, device: &str) -> Vec<SimEvent> {
+        let (mine, others) = std::mem::take(&mut self.events)
+            .into_iter()
+            .partition(|event| event.device == device);
+        self.events = others;
+        mine
+    }
+
+    fn process(&mut self, pending: Pending) {
+        match pending {
+            Pending::Deliver {
+                from,
+                to,
+                bytes,
+                reliable,
+            } => {
+                // Anything still in the air when the link breaks is gone
+                if self.state(&to, &from) == PeerState::Connected && self.reachable(&from, &to) {
+                    self.emit(
+                        &to,
+                        SimEventKind::Data {
+                            peer: from,
+                            bytes,
+                            reliable,
+                        },
+                    );
+                }
+            }
+            Pending::Found { device, peer } => {
+                if self.visible.contains(&pair(&device, &peer))
+                    && self.found.insert((device.clone(), peer.clone()))
+                {
+                    self.emit(&device, SimEventKind::PeerFound { peer });
+                }
+            }
+            Pending::Lost { device, peer } => {
+                if self.found.remove(&(device.clone(), peer.clone())) {
+                    self.emit(&device, SimEventKind::PeerLost { peer });
+                }
+            }
+            Pending::Action(action) => self.apply(action),
+            Pending::InviteResult { from, to, invite } => {
+                let latest = self.invites.get(&(from.clone(), to.clone()));
+                if latest != Some(&invite) || self.state(&from, &to) != PeerState::Connecting {
+                    return;
+                }
+                let state = if self.reachable(&from, &to) {
+                    PeerState::Connected
+                } else {
+                    PeerState::NotConnected
+                };
+                self.set_state(&from, &to, state);
+                self.set_state(&to, &from, state);
+            }
+            Pending::LinkDown { a, b } => {
+                if self.reachable(&a, &b) {
+                    return;
+                }
+                for (device, peer) in [(&a, &b), (&b, &a)] {
+                    if self.state(device, peer) != PeerState::NotConnected {
+                        self.set_state(device, peer, PeerState::NotConnected);
+                    }
+                }
+            }
+            Pending::Hangup {
+                device,
+                peer,
+                state,
+            } => {
+                if self.state(&peer, &device) == state {
+                    self.set_state(&peer, &device, PeerState::NotConnected);
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, action: SimAction) {
+        match action {
+            SimAction::Partition(groups) => self.partition(&groups),
+            SimAction::Heal => self.heal(),
+            SimAction::SetInRange { a, b, in_range } => self.set_in_range(&a, &b, in_range),
+            SimAction::SetLink { from, to, config } => self.set_link(&from, &to, config),
+            SimAction::Disconnect { device } => self.disconnect(&device),
+        }
+    }
+
+    // Schedules found/lost for pairs whose reachability changed and
+    // connection timeouts for links that just broke.
+    fn refresh_visibility(&mut self) {
+        let devices: Vec<String> = self.devices.iter().cloned().collect();
+        for (i, a) in devices.iter().enumerate() {
+            for b in &devices[i + 1..] {
+                let key = pair(a, b);
+                let reachable = self.reachable(a, b);
+                let was_visible = self.visible.contains(&key);
+                if reachable == was_visible {
+                    continue;
+                }
+
+                if reachable {
+                    self.visible.insert(key);
+                    let at = self.now + self.config.discovery_delay;
+                    for (device, peer) in [(a, b), (b, a)] {
+                        let (device, peer) = (device.clone(), peer.clone());
+                        self.push(at, Pending::Found { device, peer });
+                    }
+                } else {
+                    self.visible.remove(&key);
+                    let now = self.now;
+                    for (device, peer) in [(a, b), (b, a)] {
+                        let (device, peer) = (device.clone(), peer.clone());
+                        self.push(now, Pending::Lost { device, peer });
+                    }
+                    self.push(
+                        now + self.config.disconnect_timeout,
+                        Pending::LinkDown {
+                            a: a.clone(),
+                            b: b.clone(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    fn set_state(&mut self, device: &str, peer: &str, state: PeerState) {
+        let previous = self
+            .states
+            .insert((device.to_string(), peer.to_string()), state);
+        if previous.unwrap_or(PeerState::NotConnected) != state {
+            self.emit(
+                device,
+                SimEventKind::StateChanged {
+                    peer: peer.to_string(),
+                    state,
+                },
+            );
+        }
+    }
+
+    fn link(&self, from: &str, to: &str) -> &LinkConfig {
+        self.links
+            .get(&(from.to_string(), to.to_string()))
+            .unwrap_or(&self.config.default_link)
+    }
+
+    fn sample_delay(&mut self, from: &str, to: &str) -> Duration {
+        let link = self.link(from, to);
+        let (latency, jitter) = (link.latency, link.jitter);
+        if jitter.is_zero() {
+            latency
+        } else {
+            latency + jitter.mul_f64(self.rng.gen_range(0.0..1.0))
+        }
+    }
+
+    fn check_device(&self, name: &str) -> Result<(), String> {
+        if self.devices.contains(name) {
+            Ok(())
+        } else {
+            Err(format!("Unknown device {}", name))
+        }
+    }
+
+    fn push(&mut self, at: Duration, pending: Pending) {
+        self.seq += 1;
+        self.queue.push(Scheduled {
+            at,
+            seq: self.seq,
+            pending,
+        });
+    }
+
+    fn emit(&mut self, device: &str, kind: SimEventKind) {
+        self.events.push(SimEvent {
+            at: self.now,
+            device: device.to_string(),
+            kind,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn connected(link: LinkConfig, seed: u64) -> SimNetwork {
+        let mut sim = SimNetwork::new(SimConfig {
+            seed,
+            default_link: link,
+            ..SimConfig::default()
+        });
+        sim.add_device("a").unwrap();
+        sim.add_device("b").unwrap();
+        sim.advance(100 * MS);
+        sim.invite("a", "b").unwrap();
+        sim.advance(100 * MS);
+        assert_eq!(sim.state("a", "b"), PeerState::Connected);
+        assert_eq!(sim.state("b", "a"), PeerState::Connected);
+        sim.drain_events();
+        sim
+    }
+
+    fn states(events: &[SimEvent]) -> Vec<(&str, PeerState)> {
+        events
+            .iter()
+            .filter_map(|event| match &event.kind {
+                SimEventKind::StateChanged { state, .. } => Some((event.device.as_str(), *state)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn devices_find_each_other_after_the_discovery_delay() {
+        let mut sim = SimNetwork::new(SimConfig::default());
+        sim.add_device("a").unwrap();
+        sim.add_device("b").unwrap();
+        sim.advance(99 * MS);
+        assert!(sim.drain_events().is_empty());
+        sim.advance(MS);
+        let found: Vec<_> = sim.drain_events().into_iter().map(|e| e.device).collect();
+        assert_eq!(found, ["a", "b"]);
+    }
+
+    #[test]
+    fn devices_out_of_range_before_discovery_never_see_each_other() {
+        let mut sim = SimNetwork::new(SimConfig::default());
+        sim.add_device("a").unwrap();
+        sim.add_device("b").unwrap();
+        sim.set_in_range("a", "b", false);
+        sim.advance(Duration::from_secs(1));
+        // Neither a stale Found nor a Lost for a peer never found
+        assert!(sim.drain_events().is_empty());
+
+        sim.set_in_range("a", "b", true);
+        sim.advance(Duration::from_secs(1));
+        sim.set_in_range("a", "b", false);
+        sim.advance(MS);
+        let kinds: Vec<_> = sim.drain_events().into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                SimEventKind::PeerFound { peer: "b".into() },
+                SimEventKind::PeerFound { peer: "a".into() },
+                SimEventKind::PeerLost { peer: "b".into() },
+                SimEventKind::PeerLost { peer: "a".into() },
+            ]
+        );
+    }
+
+    #[test]
+    fn old_invitations_dont_answer_new_ones() {
+        let mut sim = SimNetwork::new(SimConfig::default());
+        sim.add_device("a").unwrap();
+        sim.add_device("b").unwrap();
+        sim.set_in_range("a", "b", false);
+        sim.advance(Duration::from_secs(5));
+        sim.invite("a", "b").unwrap();
+        sim.advance(Duration::from_secs(1));
+        sim.disconnect("a");
+        sim.invite("a", "b").unwrap();
+
+        // The first one times out a second before the second one
+        sim.advance(Duration::from_millis(9500));
+        assert_eq!(sim.state("a", "b"), PeerState::Connecting);
+        sim.advance(Duration::from_millis(500));
+        assert_eq!(sim.state("a", "b"), PeerState::NotConnected);
+    }
+
+    #[test]
+    fn peer_notices_a_disconnect_one_latency_later() {
+        let mut sim = connected(LinkConfig::default(), 0);
+        sim.disconnect("a");
+        assert_eq!(sim.state("a", "b"), PeerState::NotConnected);
+        // b still thinks it is connected until the hangup reaches it
+        assert_eq!(sim.state("b", "a"), PeerState::Connected);
+        assert_eq!(
+            states(&sim.drain_events()),
+            [("a", PeerState::NotConnected)]
+        );
+
+        // Whatever b sends meanwhile is lost
+        sim.send("b", "a", b"late", true).unwrap();
+        sim.advance(5 * MS);
+        assert_eq!(sim.state("b", "a"), PeerState::NotConnected);
+        let events = sim.drain_events();
+        assert_eq!(states(&events), [("b", PeerState::NotConnected)]);
+        assert_eq!(events[0].at, sim.elapsed());
+        assert!(
+            !events
+                .iter()
+                .any(|e| matches!(e.kind, SimEventKind::Data { .. }))
+        );
+    }
+
+    #[test]
+    fn hangup_is_dropped_once_the_peer_moved_on() {
+        let mut sim = connected(LinkConfig::default(), 0);
+        sim.disconnect("a");
+        sim.disconnect("b");
+        sim.advance(10 * MS);
+        // One change each, no second NotConnected from the delayed hangups
+        assert_eq!(
+            states(&sim.drain_events()),
+            [
+                ("a", PeerState::NotConnected),
+                ("b", PeerState::NotConnected)
+            ]
+        );
+    }
+
+    #[test]
+    fn hangup_does_not_undo_a_new_connection() {
+        let mut sim = connected(LinkConfig::default(), 0);
+        sim.disconnect("a");
+        sim.disconnect("b");
+        sim.invite("b", "a").unwrap();
+        sim.advance(100 * MS);
+        assert_eq!(sim.state("a", "b"), PeerState::Connected);
+        assert_eq!(sim.state("b", "a"), PeerState::Connected);
+    }
+
+    #[test]
+    fn loss_only_hits_unreliable_messages() {
+        let link = LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        };
+        let mut sim = connected(link, 0);
+        sim.send("a", "b", b"dropped", false).unwrap();
+        sim.send("a", "b", b"kept", true).unwrap();
+        sim.advance(10 * MS);
+        let events = sim.drain_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0].kind, SimEventKind::Data { bytes, .. } if bytes == b"kept"));
+    }
+
+    #[test]
+    fn reliable_messages_keep_their_order() {
+        let link = LinkConfig {
+            jitter: 20 * MS,
+            ..LinkConfig::default()
+        };
+        let mut sim = connected(link, 3);
+        for i in 0..50u8 {
+            sim.send("a", "b", &[i], true).unwrap();
+        }
+        sim.advance(100 * MS);
+        let received: Vec<u8> = sim
+            .drain_events()
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                SimEventKind::Data { bytes, .. } => Some(bytes[0]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(received, (0..50).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn partition_breaks_the_link_after_the_timeout() {
+        let mut sim = connected(LinkConfig::default(), 0);
+        sim.partition(&[vec!["a".to_string()], vec!["b".to_string()]]);
+        sim.advance(MS);
+        let lost = sim
+            .drain_events()
+            .iter()
+            .filter(|e| matches!(e.kind, SimEventKind::PeerLost { .. }))
+            .count();
+        assert_eq!(lost, 2);
+        assert_eq!(sim.state("a", "b"), PeerState::Connected);
+
+        sim.advance(Duration::from_secs(2));
+        assert_eq!(sim.state("a", "b"), PeerState::NotConnected);
+        assert_eq!(sim.state("b", "a"), PeerState::NotConnected);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let run = |seed| {
+            let link = LinkConfig {
+                jitter: 10 * MS,
+                loss: 0.3,
+                reorder: 0.3,
+                ..LinkConfig::default()
+            };
+            let mut sim = connected(link, seed);
+            for i in 0..100u8 {
+                sim.send("a", "b", &[i], false).unwrap();
+            }
+            sim.advance(Duration::from_secs(1));
+            sim.drain_events()
+        };
+        assert_eq!(run(9), run(9));
+        assert_ne!(run(9), run(10));
+    }
+}
@@ -0,0 +1,531 @@
+// Backend over plain TCP, for peers without MultipeerConnectivity.
+//
+// Every connection opens with a hello from each side naming the peer and the
+// port it listens on. A session connection then carries frames, each
+// prefixed with its length; a stream connection is handed to the
+// application as raw bytes once the hellos are through, like an MPC stream.
+// TCP is always reliable, so the `reliably` flag makes no difference.
+//
+// If two peers dial each other at the same time both connections succeed.
+// The one dialed by the peer with the smaller name is kept on both sides and
+// the other is closed, without a Joined/Left pair for it. A connection
+// under the name of a connected peer that listens somewhere else is another
+// peer with the same name, and refused rather than taken for the first one.
+//
+// Hello, all integers big endian:
+//   [magic "IDPT"][version: u8][kind: u8][listen port: u16]
+//   [name len: u8][name][stream name len: u8][stream name]
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, trace, warn};
+
+use crate::backend::{Backend, BackendEvent, Stream};
+use crate::frame::{Frame, FrameKind};
+use crate::mesh::NodeId;
+
+/// Largest frame accepted from a peer
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+const HELLO_MAGIC: &[u8; 4] = b"IDPT";
+const HELLO_VERSION: u8 = 1;
+const KIND_SESSION: u8 = 0;
+const KIND_STREAM: u8 = 1;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type EventCallback = Arc<dyn Fn(BackendEvent) + Send + Sync>;
+
+#[derive(Debug)]
+struct Hello {
+    kind: u8,
+    listen_port: u16,
+    name: String,
+    stream_name: String,
+}
+
+impl Hello {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(HELLO_MAGIC);
+        out.push(HELLO_VERSION);
+        out.push(self.kind);
+        out.extend_from_slice(&self.listen_port.to_be_bytes());
+        out.push(self.name.len() as u8);
+        out.extend_from_slice(self.name.as_bytes());
+        out.push(self.stream_name.len() as u8);
+        out.extend_from_slice(self.stream_name.as_bytes());
+        out
+    }
+
+    fn read(stream: &mut TcpStream) -> Result<Self, String> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).map_err(|e| e.to_string())?;
+        if &header[..4] != HELLO_MAGIC {
+            return Err("Not a hello".to_string());
+        }
+        if header[4] != HELLO_VERSION {
+            return Err(format!("Unsupported hello version {}", header[4]));
+        }
+        let kind = header[5];
+        let listen_port = u16::from_be_bytes([header[6], header[7]]);
+        let name = read_string(stream)?;
+        let stream_name = read_string(stream)?;
+        Ok(Self {
+            kind,
+            listen_port,
+            name,
+            stream_name,
+        })
+    }
+}
+
+fn read_string(stream: &mut TcpStream) -> Result<String, String> {
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).map_err(|e| e.to_string())?;
+    let mut buf = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    String::from_utf8(buf).map_err(|e| e.to_string())
+}
+
+fn write_frame(stream: &mut TcpStream, frame: &Frame) -> std::io::Result<()> {
+    let bytes = frame.encode();
+    let mut out = Vec::with_capacity(4 + bytes.len());
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(&bytes);
+    stream.write_all(&out)
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).map_err(|e| e.to_string())?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(format!("Frame of {} bytes is too large", len));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+struct Conn {
+    id: u64,
+    writer: Mutex<TcpStream>,
+    // Where the peer accepts connections, for opening streams
+    listen_addr: SocketAddr,
+    dialed: bool,
+}
+
+struct Shared {
+    name: String,
+    listen_port: u16,
+    peers: Mutex<HashMap<NodeId, Arc<Conn>>>,
+    next_id: AtomicU64,
+    stopping: AtomicBool,
+    on_event: EventCallback,
+}
+
+impl Shared {
+    fn hello(&self, kind: u8, stream_name: &str) -> Hello {
+        Hello {
+            kind,
+            listen_port: self.listen_port,
+            name: self.name.clone(),
+            stream_name: stream_name.to_string(),
+        }
+    }
+
+    // Keep the connection and start reading from it, unless we already have
+    // the better one of a simultaneous dial
+    fn register(
+        self: &Arc<Self>,
+        peer: NodeId,
+        stream: TcpStream,
+        listen_addr: SocketAddr,
+        dialed: bool,
+    ) -> Result<(), String> {
+        let reader = stream
+            .try_clone()
+            .map_err(|e| format!("Failed to set up connection to {}: {}", peer, e))?;
+        let conn = Arc::new(Conn {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            writer: Mutex::new(stream),
+            listen_addr,
+            dialed,
+        });
+
+        let replaced = {
+            let mut peers = self.peers.lock().unwrap();
+            let replaced = match peers.get(&peer) {
+                Some(existing) if existing.listen_addr != listen_addr => {
+                    let _ = reader.shutdown(Shutdown::Both);
+                    return Err(format!(
+                        "{} at {} is already connected at {}",
+                        peer, listen_addr, existing.listen_addr
+                    ));
+                }
+                Some(existing) => {
+                    // Same direction means the peer came back on a fresh
+                    // connection, otherwise both of us dialed at once
+                    let keep_new =
+                        existing.dialed == dialed || dialed == (self.name.as_str() < peer.as_str());
+                    if !keep_new {
+                        debug!("Already connected to {}, closing the new connection", peer);
+                        let _ = reader.shutdown(Shutdown::Both);
+                        return Ok(());
+                    }
+                    debug!(
+                        "Replacing connection to {} with the one {} dialed",
+                        peer,
+                        peer.as_str().min(self.name.as_str())
+                    );
+                    let _ = existing.writer.lock().unwrap().shutdown(Shutdown::Both);
+                    true
+                }
+                None => false,
+            };
+            peers.insert(peer.clone(), conn.clone());
+            replaced
+        };
+
+        // Joined has to come before anything the reader delivers
+        if !replaced {
+            (self.on_event)(BackendEvent::Joined { peer: peer.clone() });
+        }
+        let shared = self.clone();
+        let name = peer.clone();
+        let spawned = thread::Builder::new()
+            .name("tcp-peer".to_string())
+            .spawn(move || run_reader(shared, name, conn.id, reader));
+        if let Err(e) = spawned {
+            warn!("Failed to start reader for {}: {}", peer, e);
+            self.peers.lock().unwrap().remove(&peer);
+            (self.on_event)(BackendEvent::Left { peer });
+        }
+        Ok(())
+    }
+
+    fn accept(self: &Arc<Self>, mut stream: TcpStream) -> Result<(), String> {
+        let remote = stream.peer_addr().map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let hello = Hello::read(&mut stream)?;
+        if hello.name == self.name {
+            return Err("Peer uses our own name".to_string());
+        }
+        stream
+            .write_all(&self.hello(KIND_SESSION, "").encode())
+            .map_err(|e| e.to_string())?;
+        stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+
+        match hello.kind {
+            KIND_SESSION => {
+                let listen_addr = SocketAddr::new(remote.ip(), hello.listen_port);
+                self.register(hello.name, stream, listen_addr, false)?;
+            }
+            KIND_STREAM => {
+                if !self.peers.lock().unwrap().contains_key(&hello.name) {
+                    return Err(format!("Stream from {} which isn't connected", hello.name));
+                }
+                (self.on_event)(BackendEvent::Stream {
+                    peer: hello.name,
+                    name: hello.stream_name,
+                    stream: Box::new(stream),
+                });
+            }
+            kind => return Err(format!("Unknown connection kind {}", kind)),
+        }
+        Ok(())
+    }
+}
+
+fn run_reader(shared: Arc<Shared>, name: NodeId, id: u64, mut stream: TcpStream) {
+    loop {
+        let bytes = match read_frame(&mut stream) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                trace!("Connection to {} closed: {}", name, e);
+                break;
+            }
+        };
+        match Frame::decode(&bytes) {
+            Ok(frame) => match frame.kind {
+                FrameKind::Goodbye => {
+                    debug!("Peer {} said goodbye", name);
+                    break;
+                }
+                FrameKind::Data => (shared.on_event)(BackendEvent::Data {
+                    peer: name.clone(),
+                    data: frame.payload,
+                }),
+                _ => (shared.on_event)(BackendEvent::Frame {
+                    peer: name.clone(),
+                    frame,
+                }),
+            },
+            Err(e) => warn!("Bad frame from {}: {}", name, e),
+        }
+    }
+
+    // A replaced connection has nothing left to report
+    let removed = {
+        let mut peers = shared.peers.lock().unwrap();
+        if peers.get(&name).is_some_and(|conn| conn.id == id) {
+            peers.remove(&name)
+        } else {
+            None
+        }
+    };
+    if let Some(conn) = removed {
+        let _ = conn.writer.lock().unwrap().shutdown(Shutdown::Both);
+        if !shared.stopping.load(Ordering::Relaxed) {
+            (shared.on_event)(BackendEvent::Left { peer: name });
+        }
+    }
+}
+
+pub struct TcpBackend {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    accept_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TcpBackend {
+    /// Listen on `addr` as `name`. `on_event` is called from background
+    /// threads.
+    pub fn bind(
+        name: &str,
+        addr: SocketAddr,
+        on_event: impl Fn(BackendEvent) + Send + Sync + 'static,
+    ) -> Result<Self, String> {
+        if name.is_empty() || name.len() > u8::MAX as usize {
+            return Err(format!("Invalid name: {:?}", name));
+        }
+        let listener = TcpListener::bind(addr).map_err(|e| e.to_string())?;
+        let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+        let shared = Arc::new(Shared {
+            name: name.to_string(),
+            listen_port: local_addr.port(),
+            peers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stopping: AtomicBool::new(false),
+            on_event: Arc::new(on_event),
+        });
+
+        let accepting = shared.clone();
+        let accept_thread = thread::Builder::new()
+            .name("tcp-accept".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accepting.stopping.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    // Handshakes can stall, don't hold up the next peer
+                    let shared = accepting.clone();
+                    let _ = thread::Builder::new()
+                        .name("tcp-handshake".to_string())
+                        .spawn(move || {
+                            if let Err(e) = shared.accept(stream) {
+                                debug!("Rejected connection: {}", e);
+                            }
+                        });
+                }
+                debug!("Accept thread stopped");
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            shared,
+            local_addr,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Connect to the peer listening on `addr`, returning its name
+    pub fn connect(&self, addr: SocketAddr) -> Result<NodeId, String> {
+        let mut stream =
+            TcpStream::connect_timeout(&addr, HANDSHAKE_TIMEOUT).map_err(|e| e.to_string())?;
+        let hello = self.handshake(&mut stream, KIND_SESSION, "")?;
+        let name = hello.name.clone();
+        self.shared.register(
+            hello.name,
+            stream,
+            SocketAddr::new(addr.ip(), hello.listen_port),
+            true,
+        )?;
+        Ok(name)
+    }
+
+    fn handshake(
+        &self,
+        stream: &mut TcpStream,
+        kind: u8,
+        stream_name: &str,
+    ) -> Result<Hello, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        stream
+            .write_all(&self.shared.hello(kind, stream_name).encode())
+            .map_err(|e| e.to_string())?;
+        let hello = Hello::read(stream)?;
+        stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+        if hello.name == self.shared.name {
+            return Err("Connected to ourselves".to_string());
+        }
+        Ok(hello)
+    }
+
+    /// Say goodbye to every peer and stop accepting connections. Safe to
+    /// call more than once.
+    pub fn shutdown(&mut self) {
+        if self.shared.stopping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let peers: Vec<(NodeId, Arc<Conn>)> = self.shared.peers.lock().unwrap().drain().collect();
+        for (name, conn) in peers {
+            let mut writer = conn.writer.lock().unwrap();
+            if let Err(e) = write_frame(&mut writer, &Frame::goodbye()) {
+                debug!("Failed to send goodbye to {}: {}", name, e);
+            }
+            // The peer closes its end once it has the goodbye
+            let _ = writer.shutdown(Shutdown::Write);
+        }
+
+        // Wake the accept loop so it sees `stopping`
+        let _ = TcpStream::connect(self.local_addr);
+        if let Some(handle) = self.accept_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for TcpBackend {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Backend for TcpBackend {
+    fn local_name(&self) -> String {
+        self.shared.name.clone()
+    }
+
+    fn connected_peers(&self) -> Vec<NodeId> {
+        self.shared.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn send_frame(&self, frame: &Frame, peers: &[NodeId], _reliably: bool) -> Result<(), String> {
+        for peer in peers {
+            let Some(conn) = self.shared.peers.lock().unwrap().get(peer).cloned() else {
+                return Err(format!("Peer {} is not connected", peer));
+            };
+            write_frame(&mut conn.writer.lock().unwrap(), frame)
+                .map_err(|e| format!("Failed to send to {}: {}", peer, e))?;
+        }
+        Ok(())
+    }
+
+    fn open_stream(&self, peer: &str, name: &str) -> Result<Box<dyn Stream>, String> {
+        if name.len() > u8::MAX as usize {
+            return Err(format!("Stream name too long: {}", name));
+        }
+        let Some(listen_addr) = self
+            .shared
+            .peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|conn| conn.listen_addr)
+        else {
+            return Err(format!("Peer {} is not connected", peer));
+        };
+        let mut stream = TcpStream::connect_timeout(&listen_addr, HANDSHAKE_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        let hello = self.handshake(&mut stream, KIND_STREAM, name)?;
+        if hello.name != peer {
+            return Err(format!(
+                "Expected {} at {}, found {}",
+                peer, listen_addr, hello.name
+            ));
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::conformance::{self, Node};
+
+    fn bind(name: &str) -> Node<TcpBackend> {
+        let (on_event, events) = conformance::events();
+        let backend =
+            TcpBackend::bind(name, SocketAddr::from(([127, 0, 0, 1], 0)), on_event).unwrap();
+        Node { backend, events }
+    }
+
+    fn connected_pair() -> (Node<TcpBackend>, Node<TcpBackend>) {
+        let a = bind("a");
+        let b = bind("b");
+        assert_eq!(a.backend.connect(b.backend.local_addr()).unwrap(), "b");
+        a.expect_joined("b");
+        b.expect_joined("a");
+        (a, b)
+    }
+
+    #[test]
+    fn frames_round_trip_over_loopback() {
+        let (mut a, b) = connected_pair();
+        conformance::frames_round_trip(&a, &b);
+
+        // The goodbye makes b report a as gone right away
+        a.backend.shutdown();
+        b.expect_left("a");
+        assert!(b.backend.connected_peers().is_empty());
+    }
+
+    #[test]
+    fn streams_round_trip_over_loopback() {
+        let (a, b) = connected_pair();
+        conformance::streams_round_trip(&a, &b);
+    }
+
+    #[test]
+    fn peers_sharing_a_name_dont_replace_each_other() {
+        let a = bind("a");
+        let b = bind("b");
+        let other_b = bind("b");
+        a.backend.connect(b.backend.local_addr()).unwrap();
+        a.expect_joined("b");
+        b.expect_joined("a");
+        assert!(a.backend.connect(other_b.backend.local_addr()).is_err());
+
+        conformance::frames_round_trip(&a, &b);
+    }
+
+    #[test]
+    fn streams_need_a_connected_peer() {
+        conformance::streams_need_a_connected_peer(&bind("a"));
+    }
+}
@@ -0,0 +1,423 @@
+// Full screen chat for the CLI.
+//
+// Messages scroll on the left, the roster on the right shows every peer
+// discovery or the backend told us about and how far along the connection
+// is. Everything, key presses included, arrives as an `Event` on the node's
+// channel, so the screen is redrawn after each batch of events and never
+// polls.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ratatui::Frame;
+use ratatui::crossterm::event::{self as term, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+
+use iroh_discovery_playground::aggregator::DiscoveryEvent;
+use iroh_discovery_playground::mesh::NodeId;
+
+use super::{Event, Node, save_stream};
+
+/// Oldest lines are dropped beyond this
+const MAX_LINES: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerState {
+    /// Discovered, we are not going to connect
+    Found,
+    /// Discovered, invitation or dial under way
+    Connecting,
+    Connected,
+    /// Was connected, still discovered
+    Left,
+    /// Discovery no longer sees it
+    Lost,
+}
+
+impl PeerState {
+    fn label(self) -> &'static str {
+        match self {
+            PeerState::Found => "found",
+            PeerState::Connecting => "connecting",
+            PeerState::Connected => "connected",
+            PeerState::Left => "left",
+            PeerState::Lost => "lost",
+        }
+    }
+
+    fn style(self) -> Style {
+        match self {
+            PeerState::Found => Style::new().fg(Color::Blue),
+            PeerState::Connecting => Style::new().fg(Color::Yellow),
+            PeerState::Connected => Style::new().fg(Color::Green),
+            PeerState::Left | PeerState::Lost => Style::new().fg(Color::DarkGray),
+        }
+    }
+}
+
+struct App {
+    name: String,
+    roster: BTreeMap<NodeId, PeerState>,
+    lines: Vec<Line<'static>>,
+    input: String,
+    // Lines scrolled up from the bottom
+    scroll: usize,
+    quit: bool,
+}
+
+/// Run the chat until the user quits or the backend goes away
+pub fn run(
+    node: &mut Node,
+    name: &str,
+    dir: &Path,
+    events: mpsc::Sender<Event>,
+) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    spawn_input(events.clone())?;
+
+    let mut app = App {
+        name: name.to_string(),
+        roster: BTreeMap::new(),
+        lines: Vec::new(),
+        input: String::new(),
+        scroll: 0,
+        quit: false,
+    };
+    app.notice("Type to send to everyone connected, /help lists the commands".to_string());
+
+    let mut terminal = ratatui::init();
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| app.draw(frame)) {
+            break Err(e.to_string());
+        }
+
+        // Handle everything that is queued before drawing again
+        let mut timeout = Duration::MAX;
+        let mut failed = None;
+        loop {
+            match node.next(timeout) {
+                Ok(Some(event)) => app.handle(node, dir, &events, event),
+                Ok(None) => break,
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            }
+            timeout = Duration::ZERO;
+        }
+        if let Some(e) = failed {
+            break Err(e);
+        }
+        if app.quit {
+            break Ok(());
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+// Forward key presses and resizes from a thread of their own. It blocks in
+// `read` for good once we quit, which is fine, the process is exiting.
+fn spawn_input(events: mpsc::Sender<Event>) -> Result<(), String> {
+    thread::Builder::new()
+        .name("tui-input".to_string())
+        .spawn(move || {
+            while let Ok(event) = term::read() {
+                if events.send(Event::Terminal(event)).is_err() {
+                    break;
+                }
+            }
+        })
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+impl App {
+    fn handle(&mut self, node: &Node, dir: &Path, events: &mpsc::Sender<Event>, event: Event) {
+        match event {
+            Event::Discovery {
+                event: DiscoveryEvent::Found { node: peer, .. },
+                ..
+            } => {
+                let state = self.roster.get(&peer).copied();
+                if state != Some(PeerState::Connected) {
+                    let state = if node.discovery.connect {
+                        PeerState::Connecting
+                    } else {
+                        PeerState::Found
+                    };
+                    self.roster.insert(peer, state);
+                }
+            }
+            Event::Discovery {
+                event: DiscoveryEvent::Lost { node: peer },
+                ..
+            } => {
+                // MPC stops seeing peers it is still connected to, and
+                // another source may still see this one
+                if let Some(state) = self.roster.get_mut(&peer)
+                    && *state != PeerState::Connected
+                    && node.peers.get(&peer).is_none()
+                {
+                    *state = PeerState::Lost;
+                }
+            }
+            Event::Joined { peer } => {
+                self.notice(format!("{} joined", peer));
+                self.roster.insert(peer, PeerState::Connected);
+            }
+            Event::Left { peer } => {
+                self.notice(format!("{} left", peer));
+                let state = if node.peers.get(&peer).is_some() {
+                    PeerState::Left
+                } else {
+                    PeerState::Lost
+                };
+                self.roster.insert(peer, state);
+            }
+            Event::Data { peer, data } => {
+                let text = match String::from_utf8(data) {
+                    Ok(text) => text,
+                    Err(e) => format!("{} bytes of binary data", e.as_bytes().len()),
+                };
+                self.message(&peer, text, false);
+            }
+            Event::Stream { peer, name, stream } => {
+                let events = events.clone();
+                save_stream(dir, peer, &name, stream, move |line| {
+                    let _ = events.send(Event::Notice(line));
+                });
+            }
+            Event::Notice(line) => self.notice(line),
+            Event::Terminal(term::Event::Key(key)) => self.key(node, events, key),
+            Event::Interrupted => self.quit = true,
+            Event::Frame { .. } | Event::Terminal(_) | Event::Input(_) | Event::ReplayDone(_) => {}
+        }
+    }
+
+    fn key(&mut self, node: &Node, events: &mpsc::Sender<Event>, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                self.command(node, events, line.trim());
+            }
+            KeyCode::Up => self.scroll_by(1),
+            KeyCode::Down => self.scroll_by(-1),
+            KeyCode::PageUp => self.scroll_by(10),
+            KeyCode::PageDown => self.scroll_by(-10),
+            KeyCode::End => self.scroll = 0,
+            _ => {}
+        }
+    }
+
+    fn command(&mut self, node: &Node, events: &mpsc::Sender<Event>, line: &str) {
+        let backend = node.backend();
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("", _) => {}
+            ("/quit", _) => self.quit = true,
+            ("/help", _) => {
+                for help in [
+                    "/msg <peer> <text>  send to one peer",
+                    "/send <peer> <path> send a file",
+                    "/clear              clear the messages",
+                    "/quit, Esc          leave",
+                    "Up/Down, PgUp/PgDn  scroll, End jumps back",
+                ] {
+                    self.notice(help.to_string());
+                }
+            }
+            ("/clear", _) => {
+                self.lines.clear();
+                self.scroll = 0;
+            }
+            ("/msg", rest) => match rest.split_once(' ') {
+                Some((peer, text)) => {
+                    match backend.send(text.as_bytes(), &[peer.to_string()], true) {
+                        Ok(()) => self.message(
+                            &format!("{} -> {}", self.name, peer),
+                            text.to_string(),
+                            true,
+                        ),
+                        Err(e) => self.notice(format!("Not sent: {}", e)),
+                    }
+                }
+                None => self.notice("Usage: /msg <peer> <text>".to_string()),
+            },
+            ("/send", rest) => match rest.split_once(' ') {
+                Some((peer, path)) => {
+                    if let Err(e) = send_file(node, peer, Path::new(path.trim()), events) {
+                        self.notice(format!("Not sent: {}", e));
+                    }
+                }
+                None => self.notice("Usage: /send <peer> <path>".to_string()),
+            },
+            _ if line.starts_with('/') => self.notice("Unknown command, try /help".to_string()),
+            _ => {
+                let peers = backend.connected_peers();
+                if peers.is_empty() {
+                    self.notice("Nobody is connected".to_string());
+                    return;
+                }
+                match backend.send(line.as_bytes(), &peers, true) {
+                    Ok(()) => self.message(&self.name.clone(), line.to_string(), true),
+                    Err(e) => self.notice(format!("Not sent: {}", e)),
+                }
+            }
+        }
+    }
+
+    fn message(&mut self, from: &str, text: String, ours: bool) {
+        let color = if ours { Color::Cyan } else { Color::Magenta };
+        self.push(Line::from(vec![
+            Span::styled(
+                format!("<{}> ", from),
+                Style::new().fg(color).add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(text),
+        ]));
+    }
+
+    fn notice(&mut self, text: String) {
+        self.push(Line::styled(
+            format!("-- {}", text),
+            Style::new().fg(Color::DarkGray),
+        ));
+    }
+
+    fn push(&mut self, line: Line<'static>) {
+        self.lines.push(line);
+        if self.lines.len() > MAX_LINES {
+            self.lines.remove(0);
+        }
+        // Keep what the user scrolled to in view
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.lines.len());
+        }
+    }
+
+    fn scroll_by(&mut self, lines: isize) {
+        self.scroll = self
+            .scroll
+            .saturating_add_signed(lines)
+            .min(self.lines.len());
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [messages, roster] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(32)]).areas(main);
+
+        // Newest at the bottom, `scroll` lines up from there
+        let height = messages.height.saturating_sub(2) as usize;
+        let end = self.lines.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let title = if self.scroll > 0 {
+            format!(" Messages ({} newer below) ", self.scroll)
+        } else {
+            " Messages ".to_string()
+        };
+        frame.render_widget(
+            Paragraph::new(self.lines[start..end].to_vec()).block(Block::bordered().title(title)),
+            messages,
+        );
+
+        let connected = self
+            .roster
+            .values()
+            .filter(|state| **state == PeerState::Connected)
+            .count();
+        let items: Vec<ListItem> = self
+            .roster
+            .iter()
+            .map(|(peer, state)| {
+                ListItem::new(Line::from(vec![
+                    Span::styled("● ", state.style()),
+                    Span::raw(peer.clone()),
+                    Span::styled(format!(" {}", state.label()), state.style()),
+                ]))
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items)
+                .block(Block::bordered().title(format!(" Peers ({} connected) ", connected))),
+            roster,
+        );
+
+        // Keep the end of a long line in view
+        let width = input.width.saturating_sub(3) as usize;
+        let typed = self.input.chars().count();
+        let hidden = typed.saturating_sub(width);
+        frame.render_widget(
+            Paragraph::new(self.input.as_str())
+                .scroll((0, hidden as u16))
+                .block(Block::bordered().title(format!(" {} ", self.name))),
+            input,
+        );
+        frame.set_cursor_position((input.x + 1 + (typed - hidden) as u16, input.y + 1));
+    }
+}
+
+// Start the stream right away so errors show up at once, copy in the
+// background and report through a notice when done. Backends may only
+// finish opening the stream on the first write, which happens there too.
+fn send_file(
+    node: &Node,
+    peer: &str,
+    path: &Path,
+    events: &mpsc::Sender<Event>,
+) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| format!("{} is not a file", path.display()))?;
+    let mut stream = node.backend().open_stream(peer, &name)?;
+
+    let events = events.clone();
+    let peer = peer.to_string();
+    let _ = events.send(Event::Notice(format!("Sending {} to {}", name, peer)));
+    let span = tracing::info_span!(
+        "send_file",
+        peer = %peer,
+        file = %name,
+        bytes = tracing::field::Empty
+    );
+    thread::spawn(move || {
+        let _enter = span.enter();
+        let started = Instant::now();
+        let result = io::copy(&mut file, &mut stream).and_then(|sent| stream.flush().map(|_| sent));
+        if let Ok(sent) = result {
+            span.record("bytes", sent);
+        }
+        let notice = match result {
+            Ok(sent) => format!(
+                "Sent {} ({} bytes) to {} in {:?}",
+                name,
+                sent,
+                peer,
+                started.elapsed()
+            ),
+            Err(e) => format!("Failed to send {} to {}: {}", name, peer, e),
+        };
+        let _ = events.send(Event::Notice(notice));
+    });
+    Ok(())
+}